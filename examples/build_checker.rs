@@ -1,68 +1,33 @@
-use rspirv::binary::Assemble;
-use rspirv::binary::Disassemble;
-use rspirv::dr::Builder;
-
-
-fn main() {
-
-    let mut b = Builder::new();
-    //b.set_version(1, 0);
-    //b.capability(spirv::Capability::Shader);
-    //b.memory_model(spirv::AddressingModel::Logical, spirv::MemoryModel::Simple);
-    let type_f32 = b.type_float(32);
-    let type_i32 = b.type_int(32, 1);
-    let type_bool = b.type_bool();
-    let type_v3f32 = b.type_vector(type_f32, 3);
-    let type_void = b.type_void();
-    let type_voidf = b.type_function(type_void, vec![]);
-    let fun = b
-        .begin_function(
-            type_void,
-            None,
-            // spirv::FunctionControl::DONT_INLINE | spirv::FunctionControl::CONST,
-            spirv::FunctionControl::NONE,
-            type_voidf,
-        )
-        .unwrap();
-
+use std::fs;
 
-    let pointer_input_f32 = b.type_pointer(None, spirv::StorageClass::Input, type_f32);
-    let pointer_input_v3f32 = b.type_pointer(None, spirv::StorageClass::Input, type_v3f32);
-    let pointer_output_v3f32 = b.type_pointer(None,  spirv::StorageClass::Output, type_v3f32);
+use codespan_reporting::term::termcolor::{StandardStream, ColorChoice};
+use codespan_reporting::term;
 
-    let var_pos = b.variable(pointer_input_v3f32, None, spirv::StorageClass::Input, None);
-    let var_scale = b.variable(pointer_input_f32, None, spirv::StorageClass::Input, None);
-    let var_color1 = b.variable(pointer_input_v3f32, None, spirv::StorageClass::Input, None);
-    let var_color2 = b.variable(pointer_input_v3f32, None, spirv::StorageClass::Input, None);
-    let var_col = b.variable(pointer_output_v3f32, None, spirv::StorageClass::Output, None);
-
-
-    let f32_2 = b.constant_f32(type_f32, 2.0);
-    let i32_2 = b.constant_u32(type_i32, 2);
-
-    b.begin_block(None).unwrap();
+use rspirv::binary::Disassemble;
 
-    let _1 = b.load(type_v3f32, None, var_pos, None, vec![]).unwrap();
-    let _2 = b.load(type_f32, None, var_scale, None, vec![]).unwrap();
-    let _3 = b.vector_times_scalar(type_v3f32, None, _1, _2).unwrap();
+use osl::compiler::{compile, Backend, ShaderOutput};
 
 
-    b.ret().unwrap();
-    b.end_function().unwrap();
-    b.entry_point(spirv::ExecutionModel::Vertex, 
-                  fun, 
-                  "checker", 
-                  vec![
-                    var_pos,
-                    var_scale,
-                    var_color1,
-                    var_color2,
-                    var_col,
-                  ]);
-    let module = b.module();
+fn main() {
 
-    // Assembling
-    let code = module.assemble();
+    let contents = fs::read_to_string("shaders/checker.osl").expect("Invalid file");
+
+    let shader = match compile(contents, Backend::SPIRV) {
+        Ok(shader) => shader,
+        Err(e) => {
+            let files = e.files();
+            let writer = StandardStream::stderr(ColorChoice::Always);
+            for report in e.reports() {
+                term::emit(&mut writer.lock(), &Default::default(), &files, &report).unwrap();
+            }
+            return;
+        }
+    };
+
+    let code = match shader.output {
+        ShaderOutput::SPIRV(words) => words,
+        _ => unreachable!(),
+    };
     assert!(code.len() > 20); // Module header contains 5 words
     assert_eq!(spirv::MAGIC_NUMBER, code[0]);
 
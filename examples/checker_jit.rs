@@ -0,0 +1,41 @@
+use std::fs;
+
+use codespan_reporting::term::termcolor::{StandardStream, ColorChoice};
+use codespan_reporting::term;
+
+use inkwell::context::Context;
+
+use osl::compiler::{Backend, CompileOptions, Value};
+use osl::compiler::jit::{ShaderExecutable, ShaderGlobals};
+
+
+fn main() {
+
+    let contents = fs::read_to_string("shaders/checker.osl").expect("Invalid file");
+
+    let context = Context::create();
+    let mut shader = match ShaderExecutable::new(&context, contents, &CompileOptions::new(Backend::LLVM)) {
+        Ok(shader) => shader,
+        Err(e) => {
+            let files = e.files();
+            let writer = StandardStream::stderr(ColorChoice::Always);
+            for report in e.reports() {
+                term::emit(&mut writer.lock(), &Default::default(), &files, &report).unwrap();
+            }
+            return;
+        }
+    };
+
+    shader.set_param("Scale", Value::Float(4.0)).unwrap();
+
+    // Sample a row of points across the checkerboard
+    for x in 0..8 {
+        shader.set_globals(ShaderGlobals {
+            p: [x as f32 * 0.125, 0.0, 0.0],
+            ..Default::default()
+        });
+        shader.run().unwrap();
+
+        println!("P.x = {:.3}: Col = {:?}", x as f32 * 0.125, shader.get_param("Col").unwrap());
+    }
+}
@@ -1,12 +1,28 @@
 use clap::Parser;
 
-/// Simple program to greet a person
+/// Compiles an Open Shading Language shader and reports its errors and warnings
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 pub struct CliArgs {
    pub input_file: String,
 
-   /// Number of times to greet
-   #[clap(short, long, value_parser, default_value_t = 1)]
-   count: u8,
+   /// Add a directory to the #include search path
+   #[clap(short = 'I', long = "include", value_parser)]
+   pub include_paths: Vec<String>,
+
+   /// Define a macro, as NAME or NAME=VALUE
+   #[clap(short = 'D', long = "define", value_parser)]
+   pub defines: Vec<String>,
+
+   /// Do not report any warnings
+   #[clap(short = 'w', long = "no-warnings", value_parser)]
+   pub no_warnings: bool,
+
+   /// Do not report a warning, given by code (W0001) or name (unused-variable)
+   #[clap(short = 'A', long = "allow", value_parser)]
+   pub allow: Vec<String>,
+
+   /// Fail the compile on any warning
+   #[clap(long = "Werror", value_parser)]
+   pub warnings_as_errors: bool,
 }
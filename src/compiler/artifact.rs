@@ -0,0 +1,152 @@
+use super::*;
+use super::ast::*;
+
+use crate::errors::*;
+
+use rspirv::binary::Disassemble;
+
+use codespan_reporting::diagnostic::Diagnostic;
+use codespan_reporting::files::SimpleFiles;
+
+/// The result of compiling a shader: the backend output along with everything an embedding
+/// application needs to know to bind the shader without parsing its source.
+#[derive(Debug, Clone)]
+pub struct CompiledShader {
+    pub name: String,
+    pub shader_type: ShaderTypes,
+    pub parameters: Vec<ShaderParameter>,
+    /// Metadata of the shader declaration itself.
+    pub metadata: Vec<Metadata>,
+    pub output: ShaderOutput,
+    /// Warnings raised while compiling the shader.
+    pub warnings: Vec<OSLCompilerWarning>,
+    /// The main file and every file it included.
+    pub sources: Vec<SourceFile>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ShaderOutput {
+    LLVM {bitcode: Vec<u8>, ir: String},
+    SPIRV(Vec<u32>),
+    OSO(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ShaderParameter {
+    pub name: String,
+    pub param_type: Types,
+    pub default: ParameterDefault,
+    pub output: bool,
+    pub metadata: Vec<Metadata>,
+}
+
+/// How a parameter gets its value when it is not bound.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParameterDefault {
+    /// No default was given, the parameter starts out as zero.
+    None,
+    Constant(Value),
+    /// A default that is computed when the shader runs, e.g. `P`. Holds the source text.
+    Expression(String),
+}
+
+/// A `[[ type name = value ]]` entry attached to a shader or parameter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub name: String,
+    pub value: Value,
+}
+
+/// A constant value, as used for parameter defaults and bindings.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i32),
+    Float(f32),
+    Triple([f32; 3]),
+    Matrix([f32; 16]),
+    String(String),
+    Array(Vec<Value>),
+}
+
+impl ShaderOutput {
+    /// The output as it would be written to a file. SPIR-V words are little endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            ShaderOutput::LLVM {bitcode, ..} => bitcode.clone(),
+            ShaderOutput::SPIRV(words) => words.iter().flat_map(|word| word.to_le_bytes()).collect(),
+            ShaderOutput::OSO(text) => text.clone().into_bytes(),
+        }
+    }
+
+    /// The output in human readable form: LLVM IR, SPIR-V assembly or the OSO text itself.
+    pub fn disassemble(&self) -> String {
+        match self {
+            ShaderOutput::LLVM {ir, ..} => ir.clone(),
+            ShaderOutput::SPIRV(words) => match rspirv::dr::load_words(words) {
+                Ok(module) => module.disassemble(),
+                Err(error) => format!("Invalid SPIR-V module: {}", error),
+            },
+            ShaderOutput::OSO(text) => text.clone(),
+        }
+    }
+}
+
+impl CompiledShader {
+    pub fn parameter(&self, name: &str) -> Option<&ShaderParameter> {
+        self.parameters.iter().find(|param| param.name == name)
+    }
+
+    /// Reports for the warnings, to be emitted with `files`.
+    pub fn reports(&self) -> Vec<Diagnostic<usize>> {
+        self.warnings.iter()
+            .map(|warning| include_notes(warning.report(), &self.sources))
+            .collect()
+    }
+
+    pub fn files(&self) -> SimpleFiles<String, String> {
+        source_files(&self.sources)
+    }
+}
+
+/// Collects the shader's name, type and parameter manifest from the checked program.
+pub fn build_artifact(program: &Vec<Stmt>, source: &str, output: ShaderOutput) -> Result<CompiledShader, OSLCompilerError> {
+    for stmt in program {
+        if let Stmt_::ShaderDeclaration {name, shader_type, params, metadata, ..} = &stmt.statement {
+            let mut parameters = Vec::new();
+
+            for param in params {
+                if let Expr_::Parameter {par_type, name, out, value, metadata} = &param.node {
+                    let param_type = get_var_type_value(par_type).unwrap();
+
+                    let default = match value.node {
+                        Expr_::EmptyExpression => ParameterDefault::None,
+                        _ => match get_constant_value(value, &param_type) {
+                            Some(constant) => ParameterDefault::Constant(constant),
+                            None => ParameterDefault::Expression(source[value.span.lo..value.span.hi].to_owned()),
+                        },
+                    };
+
+                    parameters.push(ShaderParameter {
+                        name: get_ident_value(name).unwrap(),
+                        param_type,
+                        default,
+                        output: *out,
+                        metadata: metadata.iter().map(get_metadata).collect::<Result<_, _>>()?,
+                    });
+                }
+            }
+
+            return Ok(CompiledShader {
+                name: get_ident_value(name).unwrap(),
+                shader_type: get_shader_type_value(shader_type).unwrap(),
+                parameters,
+                metadata: metadata.iter().map(get_metadata).collect::<Result<_, _>>()?,
+                output,
+                warnings: Vec::new(),
+                sources: Vec::new(),
+            });
+        }
+    }
+
+    Err(OSLCompilerError::MissingShader)
+}
@@ -1,4 +1,4 @@
-use crate::compiler::{Span, Types, Operators, ShaderTypes, Globals};
+use crate::compiler::{Span, Types, Operators, ShaderTypes, Globals, Value, Metadata};
 use crate::compiler::symtab::*;
 use crate::errors::*;
 
@@ -27,6 +27,8 @@ pub enum Stmt_ {
         value: Expr,
     },
     ReturnStatement(Expr),
+    BreakStatement,
+    ContinueStatement,
     StructDeclaration {
         name: Expr,
         body: Box<Stmt>,
@@ -36,17 +38,13 @@ pub enum Stmt_ {
         shader_type: Expr,
         params: Vec<Expr>,
         body: Box<Stmt>,
+        metadata: Vec<Expr>,
     },
+    /// `else if` is an `IfStatement` as the else body.
     IfStatement {
         condition: Expr,
         body: Box<Stmt>,
-    },
-    ElseIfStatement {
-        condition: Expr,
-        body: Box<Stmt>,
-    },
-    ElseStatement {
-        body: Box<Stmt>,
+        else_body: Option<Box<Stmt>>,
     },
     WhileStatement {
         condition: Expr,
@@ -56,10 +54,11 @@ pub enum Stmt_ {
         condition: Expr,
         body: Box<Stmt>,
     },
+    /// `initialization` holds declarations or expression statements, an empty `condition` always holds.
     ForStatement {
-        initialization: Expr,
+        initialization: Vec<Stmt>,
         condition: Expr,
-        iteration: Expr,
+        iteration: Vec<Expr>,
         body: Box<Stmt>,
     },
 }
@@ -93,6 +92,15 @@ pub enum Expr_ {
         name: Box<Expr>,
         out: bool,
         value: Box<Expr>,
+        metadata: Vec<Expr>,
+    },
+    /// A `[[ type name = value ]]` entry. Arrays hold the length they were declared with, which
+    /// is empty for `name[]`, and one value per element.
+    Metadata {
+        meta_type: Box<Expr>,
+        name: Box<Expr>,
+        array: Option<Box<Expr>>,
+        values: Box<Vec<Expr>>,
     },
     PointConstructor {
         point_type: Box<Expr>,
@@ -109,7 +117,15 @@ pub enum Expr_ {
     FunctionCallExpression {
         name: Box<Expr>,
         arguments: Box<Vec<Expr>>,
-    }
+    },
+    /// `condition ? if_true : if_false`, which only evaluates the branch it picks.
+    TernaryExpression {
+        condition: Box<Expr>,
+        if_true: Box<Expr>,
+        if_false: Box<Expr>,
+    },
+    /// A `{a, b, c}` list, which may only initialize an array.
+    InitializerList(Box<Vec<Expr>>),
 }
 
 pub fn get_ident_value(expr: &Expr) -> Option<String> {
@@ -126,6 +142,34 @@ pub fn get_var_type_value(expr: &Expr) -> Option<Types> {
     }
 }
 
+/// The type declared by `var_type name[length] = value`. Arrays declared without a length
+/// take it from their initializer list, if they have one.
+pub fn get_array_type(var_type: &Expr, length: &Expr, value: &Expr) -> Types {
+    let element = get_var_type_value(var_type).unwrap();
+    let length = match (&length.node, &value.node) {
+        (Expr_::IntLiteral(n), _) => Some((*n).max(0) as usize),
+        (_, Expr_::InitializerList(values)) => Some(values.len()),
+        _ => None,
+    };
+
+    Types::Array(Box::new(element), length)
+}
+
+/// Checks the length of a declared array. Only function parameters may leave it out.
+pub fn check_array_type(var_type: &Expr, name: &Expr, allow_unsized: bool) -> Result<(), OSLCompilerError> {
+    match get_var_type_value(var_type) {
+        Some(Types::Array(_, Some(0))) => Err(OSLCompilerError::InvalidArray {
+            message: String::from("Arrays must have at least one element"),
+            error: Item::new(var_type.span, "Declared here"),
+        }),
+        Some(Types::Array(_, None)) if !allow_unsized => Err(OSLCompilerError::InvalidArray {
+            message: format!("The array {} needs a length or an initializer list", get_ident_value(name).unwrap()),
+            error: Item::new(var_type.span, "Declared without a length"),
+        }),
+        _ => Ok(()),
+    }
+}
+
 pub fn get_shader_type_value(expr: &Expr) -> Option<ShaderTypes> {
     match &expr.node {
         Expr_::ShaderType(t) => Some(t.clone()),
@@ -133,6 +177,455 @@ pub fn get_shader_type_value(expr: &Expr) -> Option<ShaderTypes> {
     }
 }
 
+/// Flattens an `if` statement and its `else if` branches.
+/// Returns the (condition, body) pairs and the final `else` body if there is one.
+pub fn get_conditional_chain(stmt: &Stmt) -> (Vec<(&Expr, &Stmt)>, Option<&Stmt>) {
+    let mut branches = Vec::new();
+    let mut current = stmt;
+
+    while let Stmt_::IfStatement {condition, body, else_body} = &current.statement {
+        branches.push((condition, body.as_ref()));
+        match else_body {
+            Some(else_body) => current = else_body,
+            None => return (branches, None),
+        }
+    }
+
+    (branches, Some(current))
+}
+
+/// Whether running `stmt` always ends in a return. Loops are assumed to possibly run zero
+/// times, or to be left with a break.
+pub fn always_returns(stmt: &Stmt) -> bool {
+    match &stmt.statement {
+        Stmt_::ReturnStatement(_) => true,
+        // Whatever follows a break or continue is never run
+        Stmt_::BlockStatement(stmts) => stmts.iter()
+            .take_while(|stmt| !matches!(stmt.statement, Stmt_::BreakStatement | Stmt_::ContinueStatement))
+            .any(always_returns),
+        Stmt_::IfStatement {body, else_body: Some(else_body), ..} => always_returns(body) && always_returns(else_body),
+        _ => false,
+    }
+}
+
+/// The result type of a call, once it is resolved to a constructor or one function of an
+/// overload set.
+pub fn get_call_type(name: &Expr, arguments: &Vec<Expr>, symbols: &SymbolTable) -> Result<Types, OSLCompilerError> {
+    let function_name = match &name.node {
+        Expr_::VariableType(t) => return Ok(t.clone()),
+        Expr_::Ident(s) => s.clone(),
+        _ => return Err(OSLCompilerError::GenericError(Item::new(name.span, "Invalid function call"))),
+    };
+
+    if let Some(Symbols::Struct {fields, ..}) = symbols.find_reference(name.span, function_name.clone()) {
+        return get_constructor_type(name, &fields, arguments, symbols);
+    }
+
+    // arraylength takes an array of any type
+    if function_name == "arraylength" {
+        let mut arg_types = Vec::new();
+        for arg in arguments {
+            arg_types.push(get_expr_type(arg, symbols)?);
+        }
+
+        return match arg_types.as_slice() {
+            [Types::Array(..)] => Ok(Types::Int),
+            [arg_type] => Err(OSLCompilerError::MismatchedTypesArgument {
+                expected: Item::new(arguments[0].span, "Array"),
+                received: Item::new(arguments[0].span, format!("{:?}", arg_type)),
+            }),
+            _ => Err(OSLCompilerError::GenericError(Item::new(name.span, "arraylength takes one argument"))),
+        };
+    }
+
+    match resolve_function(name, arguments, symbols)? {
+        Symbols::Function {ret_type, ..} => Ok(ret_type.clone()),
+        _ => unreachable!("overload sets only hold functions"),
+    }
+}
+
+/// Picks the function of an overload set that a call refers to. The overload whose arguments
+/// fit best wins, and ties go to the preferred return type like in OSL.
+pub fn resolve_function<'a>(name: &Expr, arguments: &[Expr], symbols: &'a SymbolTable) -> Result<&'a Symbols, OSLCompilerError> {
+    let function_name = get_ident_value(name).unwrap();
+    let overloads = symbols.find_functions(name.span, &function_name);
+
+    let function = match overloads.as_slice() {
+        [] => return Err(OSLCompilerError::NonExistentIdent {ident: Item::new(name.span, function_name)}),
+        // A lone function gets errors about the exact argument that does not fit
+        [function] => *function,
+        _ => {
+            let mut arg_types = Vec::new();
+            for arg in arguments {
+                arg_types.push(get_expr_type(arg, symbols)?);
+            }
+
+            let ranked: Vec<(usize, &Symbols)> = overloads.iter()
+                .filter_map(|function| Some((rank_overload(function, &arg_types)?, *function)))
+                .collect();
+
+            let best = match ranked.iter().map(|(rank, _)| *rank).max() {
+                Some(best) => best,
+                None => return Err(OSLCompilerError::InvalidCall {
+                    message: format!("No overload of {} takes arguments of type ({})", function_name,
+                        arg_types.iter().map(|t| format!("{:?}", t)).collect::<Vec<_>>().join(", ")),
+                    error: Item::new(name.span, "Called here"),
+                    candidates: overloads.iter().map(|function| get_signature(function)).collect(),
+                }),
+            };
+
+            let mut tied: Vec<&Symbols> = ranked.into_iter()
+                .filter(|(rank, _)| *rank == best)
+                .map(|(_, function)| function)
+                .collect();
+            let preferred = tied.iter().map(|function| return_rank(function)).min().unwrap();
+            tied.retain(|function| return_rank(function) == preferred);
+
+            match tied.as_slice() {
+                [function] => *function,
+                _ => return Err(OSLCompilerError::InvalidCall {
+                    message: format!("The call to {} is ambiguous", function_name),
+                    error: Item::new(name.span, "Called here"),
+                    candidates: tied.iter().map(|function| get_signature(function)).collect(),
+                }),
+            }
+        },
+    };
+
+    if let Symbols::Function {params, ..} = function {
+        check_arguments(name, params, arguments, symbols)?;
+    }
+
+    Ok(function)
+}
+
+/// How well the arguments fit an overload, summed over its parameters: an exact match scores 3,
+/// int -> float 2 and the other promotions 1. None if any argument does not fit at all.
+fn rank_overload(function: &Symbols, arg_types: &[Types]) -> Option<usize> {
+    let params = match function {
+        Symbols::Function {params, ..} if params.len() == arg_types.len() => params,
+        _ => return None,
+    };
+
+    params.iter().zip(arg_types).map(|((param, out), arg)| match (param, arg) {
+        _ if param == arg => Some(3),
+        (Types::Array(_, None), _) if is_equivalent(param, arg) => Some(3),
+        (Types::Float, Types::Int) if !out => Some(2),
+        _ if *out && is_equivalent(param, arg) => Some(1),
+        _ if !out && is_promotable(param, arg) => Some(1),
+        _ => None,
+    }).sum()
+}
+
+/// Overloads that are otherwise tied prefer float results, then int, then the triples.
+fn return_rank(function: &Symbols) -> usize {
+    match function {
+        Symbols::Function {ret_type, ..} => match ret_type {
+            Types::Float => 0,
+            Types::Int => 1,
+            Types::Color => 2,
+            Types::Vector => 3,
+            Types::Point => 4,
+            Types::Normal => 5,
+            Types::Matrix => 6,
+            Types::String => 7,
+            _ => 8,
+        },
+        _ => usize::MAX,
+    }
+}
+
+/// A function as it reads in messages, e.g. `Float mix(Float, Float, Float)`.
+pub fn get_signature(function: &Symbols) -> String {
+    match function {
+        Symbols::Function {ret_type, name, params, ..} => {
+            let params: Vec<String> = params.iter()
+                .map(|(param_type, out)| format!("{}{:?}", if *out { "output " } else { "" }, param_type))
+                .collect();
+            format!("{:?} {}({})", ret_type, name, params.join(", "))
+        },
+        symbol => symbol.get_name(),
+    }
+}
+
+/// User functions take one argument per parameter. Outputs are copied back to the caller, so
+/// they need something to store into of an equivalent type.
+fn check_arguments(name: &Expr, params: &[(Types, bool)], arguments: &[Expr], symbols: &SymbolTable) -> Result<(), OSLCompilerError> {
+    let function_name = get_ident_value(name).unwrap();
+    if arguments.len() != params.len() {
+        return Err(OSLCompilerError::InvalidCall {
+            message: format!("The function {} takes {} arguments, received {}", function_name, params.len(), arguments.len()),
+            error: Item::new(name.span, "Called here"),
+            candidates: Vec::new(),
+        });
+    }
+
+    for (arg, (param_type, out)) in arguments.iter().zip(params) {
+        let arg_type = get_expr_type(arg, symbols)?;
+
+        if *out && check_lvalue(arg).is_err() {
+            return Err(OSLCompilerError::InvalidCall {
+                message: format!("Output arguments of {} must be variables, array elements, components or struct fields", function_name),
+                error: Item::new(arg.span, "Passed to an output parameter"),
+                candidates: Vec::new(),
+            });
+        }
+
+        let accepted = match out {
+            true => is_equivalent(param_type, &arg_type),
+            false => is_promotable(param_type, &arg_type),
+        };
+        if !accepted {
+            return Err(OSLCompilerError::MismatchedTypesArgument {
+                expected: Item::new(arg.span, format!("{:?}", param_type)),
+                received: Item::new(arg.span, format!("{:?}", arg_type)),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Struct constructors take one value per field, in declaration order.
+fn get_constructor_type(name: &Expr, fields: &[(String, Types)], arguments: &[Expr], symbols: &SymbolTable) -> Result<Types, OSLCompilerError> {
+    let struct_name = get_ident_value(name).unwrap();
+    if arguments.len() != fields.len() {
+        return Err(OSLCompilerError::InvalidStruct {
+            message: format!("The {} constructor takes {} values, received {}", struct_name, fields.len(), arguments.len()),
+            error: Item::new(name.span, "Constructed here"),
+        });
+    }
+
+    for (arg, (_, field_type)) in arguments.iter().zip(fields) {
+        let arg_type = get_expr_type(arg, symbols)?;
+        if !is_assignable(field_type, &arg_type) {
+            return Err(OSLCompilerError::MismatchedTypesArgument {
+                expected: Item::new(arg.span, format!("{:?}", field_type)),
+                received: Item::new(arg.span, format!("{:?}", arg_type)),
+            });
+        }
+    }
+
+    Ok(Types::Struct(struct_name))
+}
+
+pub fn is_triple(t: &Types) -> bool {
+    matches!(t, Types::Color | Types::Point | Types::Vector | Types::Normal)
+}
+
+pub fn is_scalar(t: &Types) -> bool {
+    matches!(t, Types::Int | Types::Float)
+}
+
+pub fn is_spatial(t: &Types) -> bool {
+    matches!(t, Types::Point | Types::Vector | Types::Normal)
+}
+
+/// Whether an argument of type `arg` shares its representation with a parameter of type
+/// `param`. Unsized array parameters accept arrays of any length.
+pub fn is_equivalent(param: &Types, arg: &Types) -> bool {
+    match (param, arg) {
+        (Types::Array(param, None), Types::Array(arg, _)) => param == arg,
+        (param, arg) if param == arg => true,
+        (param, arg) => is_spatial(param) && is_spatial(arg),
+    }
+}
+
+/// Whether an argument of type `arg` can be passed to an input parameter of type `param`, with
+/// the implicit int -> float and scalar -> triple promotions.
+pub fn is_promotable(param: &Types, arg: &Types) -> bool {
+    match (param, arg) {
+        (Types::Float, Types::Int) => true,
+        (param, arg) if (is_triple(param) || *param == Types::Matrix) && is_scalar(arg) => true,
+        (param, arg) => is_equivalent(param, arg),
+    }
+}
+
+/// Whether a value of type `from` can be stored in a variable of type `to`. Unsized arrays
+/// accept arrays of any length.
+pub fn is_assignable(to: &Types, from: &Types) -> bool {
+    match (to, from) {
+        (Types::Array(to, to_length), Types::Array(from, from_length)) => {
+            to == from && (to_length.is_none() || to_length == from_length)
+        },
+        (Types::Array(..), _) | (_, Types::Array(..)) => false,
+        (to, from) if to == from => true,
+        (Types::Int, Types::Float) => true,
+        (Types::Float, Types::Int) => true,
+        (to, from) if (is_triple(to) || *to == Types::Matrix) && is_scalar(from) => true,
+        _ => false,
+    }
+}
+
+/// The common type a set of operands is promoted to: int -> float -> triple/matrix.
+pub fn unify_types(types: &[Types], at_least_float: bool) -> Types {
+    let mut unified = if at_least_float { Types::Float } else { Types::Int };
+
+    for t in types {
+        unified = match (&unified, t) {
+            (_, Types::Matrix) => Types::Matrix,
+            (Types::Matrix, _) => Types::Matrix,
+            (u, t) if is_triple(u) && is_triple(t) => u.clone(),
+            (_, t) if is_triple(t) => t.clone(),
+            (u, _) if is_triple(u) => u.clone(),
+            (_, Types::Float) => Types::Float,
+            (u, _) => u.clone(),
+        };
+    }
+
+    unified
+}
+
+/// Folds a parameter default into a constant of type `to`, if it is one.
+pub fn get_constant_value(expr: &Expr, to: &Types) -> Option<Value> {
+    if let (Expr_::InitializerList(values), Types::Array(element, _)) = (&expr.node, to) {
+        let values = values.iter()
+            .map(|value| get_constant_value(value, element))
+            .collect::<Option<Vec<Value>>>()?;
+        return Some(Value::Array(values));
+    }
+
+    if let Expr_::StringLiteral(s) = &expr.node {
+        return match to {
+            Types::String => Some(Value::String(s.clone())),
+            _ => None,
+        };
+    }
+
+    let components: Vec<f32> = constant_components(expr)?.iter().map(|c| *c as f32).collect();
+
+    match (to, components.len()) {
+        (Types::Int, 1) => match expr.node {
+            Expr_::IntLiteral(i) => Some(Value::Int(i as i32)),
+            Expr_::PreUnaryExpression(..) if components[0].fract() == 0.0 => Some(Value::Int(components[0] as i32)),
+            _ => None,
+        },
+        (Types::Float, 1) => Some(Value::Float(components[0])),
+        (t, 1) if is_triple(t) => Some(Value::Triple([components[0]; 3])),
+        (t, 3) if is_triple(t) => Some(Value::Triple([components[0], components[1], components[2]])),
+        (Types::Matrix, 1) => {
+            let mut matrix = [0.0; 16];
+            for i in 0..4 {
+                matrix[i * 5] = components[0];
+            }
+            Some(Value::Matrix(matrix))
+        },
+        (Types::Matrix, 16) => {
+            let mut matrix = [0.0; 16];
+            matrix.copy_from_slice(&components);
+            Some(Value::Matrix(matrix))
+        },
+        _ => None,
+    }
+}
+
+/// Folds a metadata entry into its value. Entries are typed like declarations and every value
+/// has to be a constant of that type.
+pub fn get_metadata(expr: &Expr) -> Result<Metadata, OSLCompilerError> {
+    let (meta_type, name, array, values) = match &expr.node {
+        Expr_::Metadata {meta_type, name, array, values} => (meta_type, name, array, values),
+        _ => return Err(OSLCompilerError::GenericError(Item::new(expr.span, "Expected metadata"))),
+    };
+
+    let name_value = get_ident_value(name).unwrap();
+    let value_type = get_var_type_value(meta_type).unwrap();
+    if !matches!(value_type, Types::Int | Types::Float | Types::String) {
+        return Err(OSLCompilerError::InvalidMetadata {
+            message: format!("Metadata cannot be of type {:?}", value_type),
+            error: Item::new(meta_type.span, "Expected int, float or string"),
+        });
+    }
+
+    let mut folded = Vec::new();
+    for value in values.iter() {
+        match get_constant_value(value, &value_type) {
+            Some(constant) => folded.push(constant),
+            None => return Err(OSLCompilerError::InvalidMetadata {
+                message: format!("The value of metadata {} is not a constant", name_value),
+                error: Item::new(value.span, format!("Expected a constant {:?}", value_type)),
+            }),
+        }
+    }
+
+    let value = match array {
+        None => folded.pop().unwrap(),
+        Some(length) => {
+            match length.node {
+                Expr_::EmptyExpression => {},
+                Expr_::IntLiteral(n) if n == folded.len() as i64 => {},
+                Expr_::IntLiteral(_) => return Err(OSLCompilerError::InvalidMetadata {
+                    message: format!("Metadata {} is given {} values", name_value, folded.len()),
+                    error: Item::new(length.span, "Declared length"),
+                }),
+                _ => return Err(OSLCompilerError::InvalidMetadata {
+                    message: String::from("Metadata array lengths must be int literals"),
+                    error: Item::new(length.span, "Expected an int literal"),
+                }),
+            }
+            Value::Array(folded)
+        },
+    };
+
+    Ok(Metadata {
+        name: name_value,
+        value,
+    })
+}
+
+fn constant_components(expr: &Expr) -> Option<Vec<f64>> {
+    match &expr.node {
+        Expr_::IntLiteral(i) => Some(vec![*i as f64]),
+        Expr_::FloatLiteral(f) => Some(vec![*f]),
+        Expr_::PreUnaryExpression(Operators::Minus, rhs) => {
+            Some(constant_components(rhs)?.iter().map(|c| -c).collect())
+        },
+        Expr_::FunctionCallExpression {name, arguments} => {
+            let mut arguments: &[Expr] = arguments;
+
+            match &name.node {
+                Expr_::VariableType(t) if is_triple(t) || *t == Types::Matrix => {
+                    // Only the default space of each type can be folded
+                    if let Some(Expr_::StringLiteral(space)) = arguments.first().map(|arg| &arg.node) {
+                        match (t, space.as_str()) {
+                            (Types::Color, "rgb") => {},
+                            (Types::Color, _) => return None,
+                            (_, "common") => {},
+                            _ => return None,
+                        }
+                        arguments = &arguments[1..];
+                    }
+                },
+                _ => return None,
+            }
+
+            let mut components = Vec::new();
+            for arg in arguments {
+                let mut value = constant_components(arg)?;
+                if value.len() != 1 {
+                    return None;
+                }
+                components.append(&mut value);
+            }
+            Some(components)
+        },
+        Expr_::PointConstructor {point_type, x, y, z, space} => {
+            let mut arguments = Vec::new();
+            if let Some(space) = space {
+                arguments.push(*space.clone());
+            }
+            arguments.append(&mut vec![*x.clone(), *y.clone(), *z.clone()]);
+            constant_components(&Expr {
+                span: expr.span,
+                node: Expr_::FunctionCallExpression {
+                    name: point_type.clone(),
+                    arguments: Box::new(arguments),
+                },
+            })
+        },
+        _ => None,
+    }
+}
+
 pub fn get_expr_type(expr: &Expr, symbols: &SymbolTable) -> Result<Types, OSLCompilerError> {
     match &expr.node {
         Expr_::AccessExpression {lhs, value, dot} => {
@@ -161,55 +654,25 @@ pub fn get_expr_type(expr: &Expr, symbols: &SymbolTable) -> Result<Types, OSLCom
                         }
                     },
 
-                    _ => Err(OSLCompilerError::GenericError(Item::new(expr.span, "Bad value"))),
-                }
-            } else {
-                let access_value = match value.node.clone() {
-                    Expr_::IntLiteral(i) => i,
-                    _ => -1,
-                };
-
-                match lhs_type {
-                    Types::Color |
-                    Types::Point |
-                    Types::Vector |
-                    Types::Normal => {
-                        match access_value {
-                            0..3 => Ok(Types::Float),
-                            _ => Err(OSLCompilerError::GenericError(Item::new(expr.span, "Bad value"))),
-                        }
-                    },
-
-                    Types::Matrix => {
-                        match access_value {
-                            0..16 => Ok(Types::Float),
-                            _ => Err(OSLCompilerError::GenericError(Item::new(expr.span, "Bad value"))),
-                        }
+                    Types::Struct(struct_name) => {
+                        symbols.get_struct_fields(&struct_name)
+                            .and_then(|fields| fields.iter().find(|(field, _)| *field == access_value))
+                            .map(|(_, field_type)| field_type.clone())
+                            .ok_or_else(|| OSLCompilerError::InvalidStruct {
+                                message: format!("The struct {} has no field named {}", struct_name, access_value),
+                                error: Item::new(value.span, ""),
+                            })
                     },
 
                     _ => Err(OSLCompilerError::GenericError(Item::new(expr.span, "Bad value"))),
                 }
+            } else {
+                get_index_type(lhs_type, value, symbols)
             };
         },
 
-        Expr_::FunctionCallExpression {name, arguments: _} => {
-
-            // let arg_types: Vec<Types> = arguments.iter()
-            //     .map(|expr| {get_expr_type(expr, symbols)?})
-            //     .collect();
-            //
-            // let symbol = symbols.get_reference(expr.span, s.clone());
-            // return match symbol {
-            //     Symbols::Variable{var_type, ..} => Ok(var_type),
-            //     _ => Ok(Types::Void),
-            // }
-
-            //let symbol = symbols.get_reference(name.span, name.n)
-
-            return match &name.node {
-                Expr_::VariableType(t) => Ok(t.clone()),
-                _ => Ok(Types::Void),
-            }
+        Expr_::FunctionCallExpression {name, arguments} => {
+            return get_call_type(name, arguments, symbols);
         }
 
         Expr_::PointConstructor {point_type, x, y, z, space} => {
@@ -270,7 +733,11 @@ pub fn get_expr_type(expr: &Expr, symbols: &SymbolTable) -> Result<Types, OSLCom
         },
 
         Expr_::Assignment(lhs, rhs) => {
+            check_lvalue(lhs)?;
             let lhs_type = get_expr_type(lhs, symbols)?;
+            if let Expr_::InitializerList(values) = &rhs.node {
+                return get_initializer_type(lhs_type, rhs, values, symbols);
+            }
             let rhs_type = get_expr_type(rhs, symbols)?;
 
             // Create error
@@ -281,6 +748,8 @@ pub fn get_expr_type(expr: &Expr, symbols: &SymbolTable) -> Result<Types, OSLCom
 
             return match (lhs_type, rhs_type) {
                 (Types::Int, Types::Int) => Ok(Types::Int),
+                // Narrowing is allowed, the linter warns about it
+                (Types::Int, Types::Float) => Ok(Types::Int),
 
                 (Types::Float, Types::Float) => Ok(Types::Float),
                 (Types::Float, Types::Int) => Ok(Types::Float),
@@ -305,11 +774,18 @@ pub fn get_expr_type(expr: &Expr, symbols: &SymbolTable) -> Result<Types, OSLCom
                 (Types::Matrix, Types::Float) => Ok(Types::Matrix),
                 (Types::Matrix, Types::Int) => Ok(Types::Matrix),
 
+                (Types::String, Types::String) => Ok(Types::String),
+
+                (lhs @ Types::Array(..), rhs) |
+                (lhs @ Types::Struct(_), rhs) |
+                (lhs @ Types::Closure(_), rhs) if is_assignable(&lhs, &rhs) => Ok(lhs),
+
                 _ => Err(error),
             };
         },
 
         Expr_::PostUnaryExpression(op, lhs) => {
+            check_lvalue(lhs)?;
             let lhs_type = get_expr_type(lhs, symbols)?;
 
             // Create error
@@ -327,6 +803,9 @@ pub fn get_expr_type(expr: &Expr, symbols: &SymbolTable) -> Result<Types, OSLCom
         },
 
         Expr_::PreUnaryExpression(op, rhs) => {
+            if let Operators::Increment | Operators::Decrement = op {
+                check_lvalue(rhs)?;
+            }
             let rhs_type = get_expr_type(rhs, symbols)?;
 
             // Create error
@@ -353,6 +832,9 @@ pub fn get_expr_type(expr: &Expr, symbols: &SymbolTable) -> Result<Types, OSLCom
         },
 
         Expr_::BinaryExpression(op, lhs, rhs) => {
+            if is_compound_assignment(op) {
+                check_lvalue(lhs)?;
+            }
             let lhs_type = get_expr_type(lhs, symbols)?;
             let rhs_type = get_expr_type(rhs, symbols)?;
 
@@ -541,23 +1023,25 @@ pub fn get_expr_type(expr: &Expr, symbols: &SymbolTable) -> Result<Types, OSLCom
                 Globals::I => Ok(Types::Vector),
                 Globals::N => Ok(Types::Normal),
                 Globals::Ng => Ok(Types::Normal),
-                Globals::UV => Ok(Types::Float),
+                Globals::U => Ok(Types::Float),
+                Globals::V => Ok(Types::Float),
                 Globals::Dpdu => Ok(Types::Vector),
                 Globals::Dpdv => Ok(Types::Vector),
                 Globals::Ps => Ok(Types::Point),
                 Globals::Time => Ok(Types::Float),
                 Globals::Dtime => Ok(Types::Float),
                 Globals::Dpdtime => Ok(Types::Vector),
-
-                _ => Ok(Types::Void)
+                Globals::Ci => Ok(Types::Closure(Box::new(Types::Color))),
             }
         },
 
         Expr_::Ident(s) => {
-            let symbol = symbols.get_reference(expr.span, s.clone());
+            let symbol = symbols.find_reference(expr.span, s.clone());
             return match symbol {
-                Symbols::Variable{var_type, ..} => Ok(var_type),
-                _ => Ok(Types::Void),
+                Some(Symbols::Variable{var_type, ..}) => Ok(var_type),
+                Some(_) => Ok(Types::Void),
+                // The same error as the access check, which has already reported it
+                None => Err(symbols.check_access(expr.span, s.clone()).unwrap_err()),
             }
         },
 
@@ -575,6 +1059,339 @@ pub fn get_expr_type(expr: &Expr, symbols: &SymbolTable) -> Result<Types, OSLCom
 
         Expr_::EmptyExpression => return Ok(Types::Void),
 
+        Expr_::InitializerList(..) => return Err(OSLCompilerError::InvalidArray {
+            message: String::from("Initializer lists can only be used to initialize arrays"),
+            error: Item::new(expr.span, ""),
+        }),
+
+        Expr_::TernaryExpression {condition, if_true, if_false} => {
+            get_condition_type(condition, symbols)?;
+            let true_type = get_expr_type(if_true, symbols)?;
+            let false_type = get_expr_type(if_false, symbols)?;
+
+            let is_numeric = |t: &Types| is_scalar(t) || is_triple(t) || *t == Types::Matrix;
+            return match (true_type, false_type) {
+                (true_type, false_type) if true_type == false_type => Ok(true_type),
+                // The branches are promoted like the operands of a binary operator
+                (true_type, false_type) if is_numeric(&true_type) && is_numeric(&false_type) => {
+                    Ok(unify_types(&[true_type, false_type], false))
+                },
+                (true_type, false_type) => Err(OSLCompilerError::MismatchedTypesBinary {
+                    lhs: Item::new(if_true.span, format!("{:?}", true_type)),
+                    rhs: Item::new(if_false.span, format!("{:?}", false_type)),
+                }),
+            };
+        },
+
         _ => return Ok(Types::Void),
     }
 }
+
+/// The type of a condition, which has to be an int, float or string.
+pub fn get_condition_type(condition: &Expr, symbols: &SymbolTable) -> Result<Types, OSLCompilerError> {
+    match get_expr_type(condition, symbols)? {
+        condition_type @ (Types::Int | Types::Float | Types::String) => Ok(condition_type),
+        condition_type => Err(OSLCompilerError::InvalidCondition {
+            expr: Item::new(condition.span, format!("{:?}", condition_type)),
+        }),
+    }
+}
+
+/// Assignments, compound assignments and increments need something to store into.
+pub fn check_lvalue(expr: &Expr) -> Result<(), OSLCompilerError> {
+    match &expr.node {
+        Expr_::Ident(_) | Expr_::GlobalVariable(_) => Ok(()),
+        Expr_::AccessExpression {lhs, ..} => check_lvalue(lhs),
+        _ => Err(OSLCompilerError::InvalidAssignment {
+            target: Item::new(expr.span, ""),
+        }),
+    }
+}
+
+pub fn is_compound_assignment(op: &Operators) -> bool {
+    matches!(op,
+        Operators::AddAssign | Operators::SubtractAssign | Operators::MultiplyAssign | Operators::DivideAssign |
+        Operators::BitwiseAndAssign | Operators::BitwiseOrAssign | Operators::BitwiseXorAssign |
+        Operators::ShiftLeftAssign | Operators::ShiftRightAssign)
+}
+
+/// The arithmetic operator behind a compound assignment.
+pub fn compound_operator(op: &Operators) -> Operators {
+    match op {
+        Operators::AddAssign => Operators::Plus,
+        Operators::SubtractAssign => Operators::Minus,
+        Operators::MultiplyAssign => Operators::Multiply,
+        Operators::DivideAssign => Operators::Divide,
+        Operators::BitwiseAndAssign => Operators::BitwiseAnd,
+        Operators::BitwiseOrAssign => Operators::BitwiseOr,
+        Operators::BitwiseXorAssign => Operators::BitwiseXor,
+        Operators::ShiftLeftAssign => Operators::ShiftLeft,
+        Operators::ShiftRightAssign => Operators::ShiftRight,
+        _ => op.clone(),
+    }
+}
+
+/// The type of `lhs[index]`: an element of an array, or a component of a triple or matrix.
+/// Constant indexes are checked against the length.
+fn get_index_type(lhs_type: Types, index: &Expr, symbols: &SymbolTable) -> Result<Types, OSLCompilerError> {
+    let (element, length) = match &lhs_type {
+        Types::Array(element, length) => (*element.clone(), *length),
+        Types::Matrix => (Types::Float, Some(16)),
+        t if is_triple(t) => (Types::Float, Some(3)),
+        _ => return Err(OSLCompilerError::InvalidArray {
+            message: format!("Values of type {:?} cannot be indexed", lhs_type),
+            error: Item::new(index.span, ""),
+        }),
+    };
+
+    let index_type = get_expr_type(index, symbols)?;
+    if index_type != Types::Int {
+        return Err(OSLCompilerError::InvalidArray {
+            message: String::from("Indexes must be of type Int"),
+            error: Item::new(index.span, format!("Type {:?}", index_type)),
+        });
+    }
+
+    if let (Some(Value::Int(i)), Some(length)) = (get_constant_value(index, &Types::Int), length) {
+        if i < 0 || i as usize >= length {
+            return Err(OSLCompilerError::InvalidArray {
+                message: format!("Index {} is out of bounds for type {:?}", i, lhs_type),
+                error: Item::new(index.span, format!("Expected an index from 0 to {}", length - 1)),
+            });
+        }
+    }
+
+    Ok(element)
+}
+
+/// The type of an array initialized with a list, checking each value against the elements.
+fn get_initializer_type(array_type: Types, list: &Expr, values: &[Expr], symbols: &SymbolTable) -> Result<Types, OSLCompilerError> {
+    let (element, length) = match &array_type {
+        Types::Array(element, length) => (element, length),
+        _ => return Err(OSLCompilerError::InvalidArray {
+            message: format!("A value of type {:?} cannot be initialized with a list", array_type),
+            error: Item::new(list.span, ""),
+        }),
+    };
+
+    if let Some(length) = length {
+        if values.len() != *length {
+            return Err(OSLCompilerError::InvalidArray {
+                message: format!("Expected {} values for type {:?}, received {}", length, array_type, values.len()),
+                error: Item::new(list.span, ""),
+            });
+        }
+    }
+
+    for value in values {
+        let value_type = get_expr_type(value, symbols)?;
+        if !is_assignable(element, &value_type) {
+            return Err(OSLCompilerError::MismatchedTypesAssignment {
+                lhs: Item::new(list.span, format!("{:?}", element)),
+                rhs: Item::new(value.span, format!("{:?}", value_type)),
+            });
+        }
+    }
+
+    Ok(array_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{analyze, Backend, CompileOptions};
+
+    fn function(ret_type: Types, params: &[(Types, bool)]) -> Symbols {
+        Symbols::Function {
+            ret_type,
+            name: String::from("f"),
+            params: params.to_vec(),
+            span: Span {lo: 0, hi: 0, line: 0, file: 0},
+            scope: GLOBAL_SCOPE,
+            public: false,
+        }
+    }
+
+    /// Type checks `source`, giving the message of the first error.
+    fn check(source: &str) -> Result<(), String> {
+        analyze(source, &CompileOptions::new(Backend::OSO))
+            .map(|_| ())
+            .map_err(|diagnostics| diagnostics.errors()[0].report().message)
+    }
+
+    /// Resolves the call that starts the shader at the end of the source, giving the signature
+    /// it resolved to or the message of the first error.
+    fn resolve(source: &str) -> Result<String, String> {
+        let analysis = analyze(source, &CompileOptions::new(Backend::OSO))
+            .map_err(|diagnostics| diagnostics.errors()[0].report().message)?;
+
+        if let Stmt_::ShaderDeclaration {body, ..} = &analysis.program.last().unwrap().statement {
+            if let Stmt_::BlockStatement(stmts) = &body.statement {
+                if let Stmt_::ExpressionStatement(Expr {node: Expr_::FunctionCallExpression {name, arguments}, ..}) = &stmts[0].statement {
+                    return resolve_function(name, arguments, &analysis.symbol_table)
+                        .map(get_signature)
+                        .map_err(|error| error.report().message);
+                }
+            }
+        }
+        unreachable!("the shader starts with a call")
+    }
+
+    #[test]
+    fn arguments_rank_by_how_well_they_fit() {
+        let float = function(Types::Float, &[(Types::Float, false)]);
+        assert_eq!(rank_overload(&float, &[Types::Float]), Some(3));
+        assert_eq!(rank_overload(&float, &[Types::Int]), Some(2));
+        assert_eq!(rank_overload(&float, &[Types::Color]), None);
+        assert_eq!(rank_overload(&float, &[Types::Float, Types::Float]), None);
+
+        let color = function(Types::Color, &[(Types::Color, false), (Types::Float, false)]);
+        assert_eq!(rank_overload(&color, &[Types::Float, Types::Int]), Some(3));
+        assert_eq!(rank_overload(&color, &[Types::Point, Types::Float]), None);
+
+        // Points, vectors and normals convert into each other
+        let vector = function(Types::Vector, &[(Types::Vector, false), (Types::Float, false)]);
+        assert_eq!(rank_overload(&vector, &[Types::Point, Types::Float]), Some(4));
+
+        // Outputs take equivalent types only
+        let output = function(Types::Void, &[(Types::Vector, true)]);
+        assert_eq!(rank_overload(&output, &[Types::Vector]), Some(3));
+        assert_eq!(rank_overload(&output, &[Types::Normal]), Some(1));
+        assert_eq!(rank_overload(&output, &[Types::Float]), None);
+    }
+
+    #[test]
+    fn ties_prefer_float_then_int_then_triples() {
+        let ranked = [Types::Float, Types::Int, Types::Color, Types::Vector, Types::Point, Types::Normal, Types::Matrix, Types::String];
+        let ranks: Vec<usize> = ranked.iter().map(|ret_type| return_rank(&function(ret_type.clone(), &[]))).collect();
+        assert!(ranks.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn exact_matches_win() {
+        let overloads = "float f(float x) { return x; }\nint f(int x) { return x; }\n";
+        assert_eq!(resolve(&format!("{}surface s() {{ f(1); }}", overloads)), Ok(String::from("Int f(Int)")));
+        assert_eq!(resolve(&format!("{}surface s() {{ f(1.0); }}", overloads)), Ok(String::from("Float f(Float)")));
+    }
+
+    #[test]
+    fn int_to_float_beats_other_promotions() {
+        let overloads = "color f(color c) { return c; }\nfloat f(float x) { return x; }\n";
+        assert_eq!(resolve(&format!("{}surface s() {{ f(1); }}", overloads)), Ok(String::from("Float f(Float)")));
+    }
+
+    #[test]
+    fn ranks_are_summed_over_arguments() {
+        let overloads = "float f(float a, color b) { return a; }\nfloat f(color a, float b) { return b; }\n";
+        assert_eq!(resolve(&format!("{}surface s() {{ f(1.0, 2); }}", overloads)), Ok(String::from("Float f(Float, Color)")));
+        assert_eq!(resolve(&format!("{}surface s() {{ f(2, 1.0); }}", overloads)), Ok(String::from("Float f(Color, Float)")));
+    }
+
+    #[test]
+    fn ties_are_broken_by_return_type() {
+        let overloads = "vector g(vector d) { return d; }\ncolor g(color c) { return c; }\n";
+        assert_eq!(resolve(&format!("{}surface s() {{ g(1.0); }}", overloads)), Ok(String::from("Color g(Color)")));
+    }
+
+    #[test]
+    fn unresolvable_calls_are_errors() {
+        let ambiguous = "float k(color c) { return 0; }\nfloat k(vector d) { return 1; }\nsurface s() { k(1.0); }";
+        assert_eq!(resolve(ambiguous), Err(String::from("The call to k is ambiguous")));
+
+        let unmatched = "float m(float x) { return x; }\nfloat m(color c) { return 0; }\nsurface s() { m(\"text\"); }";
+        assert!(resolve(unmatched).unwrap_err().starts_with("No overload of m takes arguments"));
+    }
+
+    #[test]
+    fn builtin_overloads() {
+        assert_eq!(resolve("surface s() { abs(-1); }"), Ok(String::from("Int abs(Int)")));
+        assert_eq!(resolve("surface s() { abs(-1.5); }"), Ok(String::from("Float abs(Float)")));
+        assert_eq!(resolve("surface s() { mix(color(1), color(0), 0.5); }"), Ok(String::from("Color mix(Color, Color, Float)")));
+    }
+
+    #[test]
+    fn ci_is_a_closure_color() {
+        assert_eq!(check("surface s() { closure color c = Ci; Ci = c; }"), Ok(()));
+        assert_eq!(check("surface s() { float f = Ci; }"),
+                   Err(String::from("The type Closure(Color) cannot be implicitly cast to type Float.")));
+    }
+
+    #[test]
+    fn metadata_values_are_constants_of_their_type() {
+        assert_eq!(check("shader s [[ string label = \"S\" ]] (float f = 1 [[ float min = 0, int max = 2, float range[2] = {0, 1} ]]) {}"), Ok(()));
+        assert_eq!(check("shader s(float f = 1 [[ color tint = 1 ]]) {}"), Err(String::from("Metadata cannot be of type Color")));
+        assert_eq!(check("shader s(float f = 1 [[ float min = f ]]) {}"), Err(String::from("The value of metadata min is not a constant")));
+        assert_eq!(check("shader s(float f = 1 [[ float range[3] = {0, 1} ]]) {}"), Err(String::from("Metadata range is given 2 values")));
+    }
+
+    #[test]
+    fn array_indexes_are_checked() {
+        assert_eq!(check("surface s(output float o = 0) { float a[3] = {1, 2, 3}; int i = 1; o = a[i] + arraylength(a); }"), Ok(()));
+        assert_eq!(check("surface s(output float o = 0) { float a[3] = {1, 2, 3}; o = a[3]; }"), Err(String::from("Index 3 is out of bounds for type Float[3]")));
+        assert_eq!(check("surface s(output float o = 0) { float a[3] = {1, 2, 3}; o = a[1.5]; }"), Err(String::from("Indexes must be of type Int")));
+        assert_eq!(check("surface s(output float o = 0) { float f = 1; o = f[0]; }"), Err(String::from("Values of type Float cannot be indexed")));
+    }
+
+    #[test]
+    fn array_lengths_are_checked() {
+        assert_eq!(check("float sum(float w[]) { return w[0]; }\nsurface s(float w[2] = {1, 2}, output float o = 0) { o = sum(w); }"), Ok(()));
+        assert_eq!(check("surface s() { float a[2] = {1, 2, 3}; }"), Err(String::from("Expected 2 values for type Float[2], received 3")));
+        assert_eq!(check("surface s() { float a[]; }"), Err(String::from("The array a needs a length or an initializer list")));
+        assert_eq!(check("surface s() { float a[0]; }"), Err(String::from("Arrays must have at least one element")));
+    }
+
+    #[test]
+    fn struct_members_and_constructors_are_checked() {
+        let pair = "struct Pair { float a; color b; };\nstruct Outer { Pair p; int n; };\n";
+        assert_eq!(check(&format!("{}surface s(output float o = 0) {{ Outer x = Outer(Pair(1, color(2)), 2); o = x.p.b.r + x.p.a; }}", pair)), Ok(()));
+        assert_eq!(check(&format!("{}surface s(output float o = 0) {{ Pair p = Pair(1, 2); o = p.c; }}", pair)),
+                   Err(String::from("The struct Pair has no field named c")));
+        assert_eq!(check(&format!("{}surface s() {{ Pair p = Pair(1); }}", pair)),
+                   Err(String::from("The Pair constructor takes 2 values, received 1")));
+        assert_eq!(check(&format!("{}surface s() {{ Pair p = Pair(\"a\", 2); }}", pair)),
+                   Err(String::from("A function argument did not have the correct type.")));
+    }
+
+    #[test]
+    fn ternary_branches_unify() {
+        assert_eq!(check("surface s(float f = 1, output color c = 0) { c = f > 0 ? color(1) : 0.5; }"), Ok(()));
+        // The message names the unified type of the branches
+        assert_eq!(check("surface s(float f = 1) { string t = f > 0 ? 1 : 2.5; }"),
+                   Err(String::from("The type Float cannot be implicitly cast to type String.")));
+        assert_eq!(check("surface s(float f = 1, output color c = 0) { c = f > 0 ? \"a\" : 2; }"),
+                   Err(String::from("This operation is invalid due to mismatched types.")));
+    }
+
+    #[test]
+    fn declarations_can_be_comma_separated() {
+        assert_eq!(check("surface s(output float o = 0) { float a = 1, b, c = 2; o = a + b + c; }"), Ok(()));
+        assert_eq!(check("surface s() { float a = 1, b = \"x\"; }"),
+                   Err(String::from("The type String cannot be implicitly cast to type Float.")));
+        assert_eq!(check("surface s() { float a = 1, a = 2; }"), Err(String::from("Cannot declare variable twice in same scope")));
+    }
+
+    #[test]
+    fn compound_assignments_need_lvalues() {
+        let lvalues = "struct Q { float x; };\nsurface s(output color c = 0) { float a[2] = {1, 2}; Q q = Q(1); c[1] += 2; c.r -= 1; a[0] *= 2; q.x /= 2; ++q.x; a[1]--; }";
+        assert_eq!(check(lvalues), Ok(()));
+        assert_eq!(check("surface s(output color c = 0) { int i = 3; i <<= 1; i &= 1; i |= 2; i ^= 1; i >>= 1; c = i; }"), Ok(()));
+        assert_eq!(check("surface s() { 1 += 2; }"),
+                   Err(String::from("Only variables, array elements, components and struct fields can be assigned to.")));
+        assert_eq!(check("surface s(output color c = 0) { (c * 2) = 1; }"),
+                   Err(String::from("Only variables, array elements, components and struct fields can be assigned to.")));
+    }
+
+    #[test]
+    fn user_calls_are_checked_against_their_signature() {
+        let functions = "float one() { return 1; }\nvoid set(output float x, float value) { x = value; }\nfloat add(float a, float b) { return a + b; }\nvoid g() {}\n";
+        assert_eq!(check(&format!("{}surface s(output float o = 0, output color c = 0) {{ set(o, one()); o = add(1, 2) + add(o, 3); c = add(1, 2); }}", functions)), Ok(()));
+        assert_eq!(check(&format!("{}surface s(output float o = 0) {{ o = add(1); }}", functions)),
+                   Err(String::from("The function add takes 2 arguments, received 1")));
+        assert_eq!(check(&format!("{}surface s(output float o = 0) {{ o = add(\"a\", 2); }}", functions)),
+                   Err(String::from("A function argument did not have the correct type.")));
+        assert_eq!(check(&format!("{}surface s(output float o = 0) {{ set(o * 2, 1); }}", functions)),
+                   Err(String::from("Output arguments of set must be variables, array elements, components or struct fields")));
+        assert_eq!(check(&format!("{}surface s(output float o = 0) {{ o = g(); }}", functions)),
+                   Err(String::from("The type Void cannot be implicitly cast to type Float.")));
+    }
+}
@@ -34,7 +34,7 @@
 //             //module,
 //             tokens,
 //             program,
-//             symbol_table: SymbolTable::new(program_size)?,
+//             symbol_table: SymbolTable::new()?,
 //         })
 //     }
 //
@@ -0,0 +1,289 @@
+use super::*;
+use super::ast::*;
+
+use crate::errors::*;
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+
+use inkwell::context::Context;
+use inkwell::execution_engine::ExecutionEngine;
+
+/// Signature shared by the generated `<shader>` and `<shader>_init` functions.
+type ShaderFunction = unsafe extern "C" fn(*mut ShaderGlobals, *mut u8);
+
+/// The per-point inputs of a shader. The layout matches the `ShaderGlobals` struct the LLVM
+/// backend passes to every generated function.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ShaderGlobals {
+    pub p: [f32; 3],
+    pub i: [f32; 3],
+    pub n: [f32; 3],
+    pub ng: [f32; 3],
+    pub dpdu: [f32; 3],
+    pub dpdv: [f32; 3],
+    pub ps: [f32; 3],
+    pub u: f32,
+    pub v: f32,
+    pub time: f32,
+    pub dtime: f32,
+    pub dpdtime: [f32; 3],
+}
+
+#[derive(Debug, Clone)]
+struct Parameter {
+    param_type: Types,
+    offset: usize,
+    output: bool,
+}
+
+/// A shader JIT-compiled for the host through LLVM.
+///
+/// Parameters that were not bound with `set_param` take their default values, which are
+/// evaluated again on every run since they may depend on the shader globals.
+pub struct ShaderExecutable<'ctx> {
+    engine: ExecutionEngine<'ctx>,
+    name: String,
+    params: HashMap<String, Parameter>,
+    bindings: HashMap<String, Value>,
+    globals: ShaderGlobals,
+
+    // Storage for the `ShaderParams` struct, as u64s so that every field is aligned
+    storage: Vec<u64>,
+    // Bound strings are passed by pointer and must outlive the run
+    strings: HashMap<String, CString>,
+}
+
+impl<'ctx> ShaderExecutable<'ctx> {
+    /// Compiles OSL source and JIT-compiles the result. The backend of `options` is ignored,
+    /// everything else applies as it does to `Compiler::compile`.
+    pub fn new(context: &'ctx Context, contents: String, options: &CompileOptions) -> Result<Self, Diagnostics> {
+        let options = CompileOptions { backend: Backend::LLVM, ..options.clone() };
+        let analysis = analyze(&contents, &options)?;
+        let program = &analysis.program;
+        let module = llvm::build_shader(context, program, &analysis.symbol_table)
+            .map_err(|error| analysis.source.map.remap(error.into()))?;
+        llvm::optimize(&module, options.opt_level);
+
+        options.tracer.dump(Stage::IR, || module.print_to_string().to_string());
+
+        let (name, declared) = program.iter()
+            .find_map(|stmt| match &stmt.statement {
+                Stmt_::ShaderDeclaration {name, params, ..} => Some((get_ident_value(name).unwrap(), params)),
+                _ => None,
+            })
+            .ok_or(OSLCompilerError::MissingShader)?;
+
+        let engine = module.create_jit_execution_engine(llvm::optimization_level(options.opt_level))
+            .map_err(|error| OSLCompilerError::ExecutionError(error.to_string()))?;
+
+        let params_type = module.get_struct_type("ShaderParams")
+            .ok_or_else(|| OSLCompilerError::BackendError(String::from("Missing the ShaderParams struct")))?;
+        let target_data = engine.get_target_data();
+
+        let mut params = HashMap::new();
+        for (index, param) in declared.iter().enumerate() {
+            if let Expr_::Parameter {par_type, name, out, ..} = &param.node {
+                let offset = target_data.offset_of_element(&params_type, index as u32)
+                    .ok_or_else(|| OSLCompilerError::BackendError(format!("Invalid shader parameter index {}", index)))?;
+
+                params.insert(get_ident_value(name).unwrap(), Parameter {
+                    param_type: get_var_type_value(par_type).unwrap(),
+                    offset: offset as usize,
+                    output: *out,
+                });
+            }
+        }
+
+        let size = target_data.get_abi_size(&params_type) as usize;
+
+        Ok(ShaderExecutable {
+            engine,
+            name,
+            params,
+            bindings: HashMap::new(),
+            globals: ShaderGlobals::default(),
+            storage: vec![0; (size + 7) / 8],
+            strings: HashMap::new(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Binds a parameter to a value, replacing its default. Ints are accepted for float
+    /// parameters and floats for every triple component, also as array elements.
+    pub fn set_param(&mut self, name: &str, value: Value) -> Result<(), OSLCompilerError> {
+        let param = self.params.get(name)
+            .ok_or_else(|| OSLCompilerError::ExecutionError(format!("The shader has no parameter named {}", name)))?;
+
+        let value = match (&param.param_type, value) {
+            (Types::String, Value::String(s)) => {
+                let string = CString::new(s.clone())
+                    .map_err(|_| OSLCompilerError::ExecutionError(format!("The value of {} contains a nul byte", name)))?;
+                self.strings.insert(name.to_string(), string);
+                Value::String(s)
+            },
+            (param_type, value) => match convert_value(param_type, &value) {
+                Some(value) => value,
+                None => return Err(OSLCompilerError::ExecutionError(format!("Cannot bind {:?} to the {:?} parameter {}", value, param_type, name))),
+            },
+        };
+
+        self.bindings.insert(name.to_string(), value);
+
+        Ok(())
+    }
+
+    /// Removes a binding so the parameter uses its default again.
+    pub fn clear_param(&mut self, name: &str) {
+        self.bindings.remove(name);
+        self.strings.remove(name);
+    }
+
+    pub fn globals(&self) -> &ShaderGlobals {
+        &self.globals
+    }
+
+    pub fn set_globals(&mut self, globals: ShaderGlobals) {
+        self.globals = globals;
+    }
+
+    /// Runs the shader for the current shader globals.
+    pub fn run(&mut self) -> Result<(), OSLCompilerError> {
+        let (init, shader) = unsafe {
+            let init = self.engine.get_function::<ShaderFunction>(&format!("{}_init", self.name));
+            let shader = self.engine.get_function::<ShaderFunction>(&self.name);
+            match (init, shader) {
+                (Ok(init), Ok(shader)) => (init, shader),
+                _ => return Err(OSLCompilerError::ExecutionError(format!("Could not find the code for shader {}", self.name))),
+            }
+        };
+
+        let storage = self.storage.as_mut_ptr() as *mut u8;
+        unsafe {
+            init.call(&mut self.globals, storage);
+        }
+
+        for (name, value) in &self.bindings {
+            let param = &self.params[name];
+            unsafe {
+                let field = storage.add(param.offset);
+                match value {
+                    Value::String(_) => *(field as *mut *const c_char) = self.strings[name].as_ptr(),
+                    value => write_value(field, value),
+                }
+            }
+        }
+
+        unsafe {
+            shader.call(&mut self.globals, storage);
+        }
+
+        Ok(())
+    }
+
+    /// Reads a parameter as left by the last run. Output parameters hold the shader's results.
+    pub fn get_param(&self, name: &str) -> Option<Value> {
+        let param = self.params.get(name)?;
+        let field = unsafe { (self.storage.as_ptr() as *const u8).add(param.offset) };
+
+        unsafe { read_value(field, &param.param_type) }
+    }
+
+    /// The names of the output parameters.
+    pub fn outputs(&self) -> Vec<&str> {
+        let mut outputs: Vec<(&str, usize)> = self.params.iter()
+            .filter(|(_, param)| param.output)
+            .map(|(name, param)| (name.as_str(), param.offset))
+            .collect();
+        outputs.sort_by_key(|(_, offset)| *offset);
+
+        outputs.into_iter().map(|(name, _)| name).collect()
+    }
+}
+
+/// Converts a bound value to the type of the parameter it is bound to. Strings are bound
+/// separately since they need storage of their own.
+fn convert_value(param_type: &Types, value: &Value) -> Option<Value> {
+    Some(match (param_type, value) {
+        (Types::Int, Value::Int(i)) => Value::Int(*i),
+        (Types::Float, Value::Int(i)) => Value::Float(*i as f32),
+        (Types::Float, Value::Float(f)) => Value::Float(*f),
+        (t, Value::Float(f)) if is_triple(t) => Value::Triple([*f; 3]),
+        (t, Value::Triple(v)) if is_triple(t) => Value::Triple(*v),
+        (Types::Matrix, Value::Matrix(m)) => Value::Matrix(*m),
+        (Types::Array(element, Some(length)), Value::Array(values)) if values.len() == *length => {
+            Value::Array(values.iter().map(|value| convert_value(element, value)).collect::<Option<_>>()?)
+        },
+        _ => return None,
+    })
+}
+
+/// The size of a value of the type in the `ShaderParams` struct.
+fn memory_size(var_type: &Types) -> usize {
+    match var_type {
+        Types::Int | Types::Float => 4,
+        t if is_triple(t) => 12,
+        Types::Matrix => 64,
+        Types::String => std::mem::size_of::<*const c_char>(),
+        Types::Array(element, Some(length)) => memory_size(element) * length,
+        _ => 0,
+    }
+}
+
+unsafe fn write_value(field: *mut u8, value: &Value) {
+    match value {
+        Value::Int(i) => *(field as *mut i32) = *i,
+        Value::Float(f) => *(field as *mut f32) = *f,
+        Value::Triple(v) => *(field as *mut [f32; 3]) = *v,
+        Value::Matrix(m) => *(field as *mut [f32; 16]) = *m,
+        Value::Array(values) => {
+            // Elements are all of one type, so they share one size
+            let mut element = field;
+            for value in values {
+                write_value(element, value);
+                element = element.add(value_size(value));
+            }
+        },
+        Value::String(_) => unreachable!("strings are bound by the caller"),
+    }
+}
+
+fn value_size(value: &Value) -> usize {
+    match value {
+        Value::Int(_) | Value::Float(_) => 4,
+        Value::Triple(_) => 12,
+        Value::Matrix(_) => 64,
+        Value::String(_) => std::mem::size_of::<*const c_char>(),
+        Value::Array(values) => values.iter().map(value_size).sum(),
+    }
+}
+
+unsafe fn read_value(field: *const u8, var_type: &Types) -> Option<Value> {
+    Some(match var_type {
+        Types::Int => Value::Int(*(field as *const i32)),
+        Types::Float => Value::Float(*(field as *const f32)),
+        t if is_triple(t) => Value::Triple(*(field as *const [f32; 3])),
+        Types::Matrix => Value::Matrix(*(field as *const [f32; 16])),
+        Types::String => {
+            let string = *(field as *const *const c_char);
+            if string.is_null() {
+                return None;
+            }
+            Value::String(CStr::from_ptr(string).to_string_lossy().into_owned())
+        },
+        Types::Array(element, Some(length)) => {
+            let size = memory_size(element);
+            let mut values = Vec::new();
+            for index in 0..*length {
+                values.push(read_value(field.add(index * size), element)?);
+            }
+            Value::Array(values)
+        },
+        _ => return None,
+    })
+}
@@ -13,39 +13,16 @@ lexer! {
     r#"/[*](~(.*[*]/.*))[*]/"# => Token::Comment,
     // "C++-style" comments (// ...)
     r#"//[^\n]*"# => Token::Comment,
-    // Meta tags - can't contain "]]"
-    r#"\[\[(~(.*\]\].*))\]\]"# => Token::Meta(text.to_owned()),
-    // Grab string literals
+    // Metadata blocks, closed by two RightSquare tokens
+    r#"\[\["# => Token::MetaBegin,
+    // Grab string literals, the escapes are decoded once the span is known
     r#"\"(\\.|[^\\"\n])*\""# => Token::Str(text.to_owned()),
     // Int literals
-    r#"[0-9]+"# => {
-        if let Ok(i) = text.parse() {
-            Token::Integer(i)
-        } else {
-            panic!("integer {} is out of range", text)
-        }
-    }
-    r#"0[xX][0-9a-fA-F]+"# => {
-        if let Ok(i) = text.parse() {
-            Token::HexInteger(i)
-        } else {
-            panic!("integer {} is out of range", text)
-        }
-    }
-    // Match floats, including scientific notation
-    // r#"([0-9]+[eE][-+]?[0-9]+|\
-    // [0-9]+\.[0-9]*([eE][-+]?[0-9]+)?|\
-    // [0-9]*\.[0-9]+([eE][-+]?[0-9]+)?|\
-    // [0-9]*\.[0-9]+)"# => {
-    r#"[0-9]*\.[0-9]+"# => {
-        if let Ok(f) = text.parse() {
-            Token::Float(f)
-        } else {
-            panic!("float {} is out of range", text)
-        }
-    }
-
-    r#"else if"# => Token::ElseIf,
+    r#"[0-9]+"# => int_literal(text, text, 10),
+    r#"0[xX][0-9a-fA-F]+"# => int_literal(text, &text[2..], 16),
+    // Float literals: a fraction, an exponent or both, with an optional f suffix
+    r#"([0-9]+\.[0-9]*|[0-9]*\.[0-9]+)([eE][-+]?[0-9]+)?[fF]?"# => float_literal(text),
+    r#"[0-9]+[eE][-+]?[0-9]+[fF]?"# => float_literal(text),
 
     // Keywords
     r#"and"# => Token::KWAnd,
@@ -101,6 +78,7 @@ lexer! {
     r#"\]"# => Token::RightSquare,
     r#";"# => Token::Semicolon,
     r#":"# => Token::Colon,
+    r#"\?"# => Token::Question,
     r#"\."# => Token::Period,
     r#","# => Token::Comma,
 
@@ -109,11 +87,11 @@ lexer! {
     r#"I"# => Token::Global(Globals::I),
     r#"N"# => Token::Global(Globals::N),
     r#"Ng"# => Token::Global(Globals::Ng),
-    r#"dPdU"# => Token::Global(Globals::Dpdu),
-    r#"dPdV"# => Token::Global(Globals::Dpdv),
+    r#"dPdu"# => Token::Global(Globals::Dpdu),
+    r#"dPdv"# => Token::Global(Globals::Dpdv),
     r#"Ps"# => Token::Global(Globals::Ps),
-    r#"I"# => Token::Global(Globals::I),
-    r#"u[ \t\r]*,[ \t\r]*v"# => Token::Global(Globals::UV),
+    r#"u"# => Token::Global(Globals::U),
+    r#"v"# => Token::Global(Globals::V),
     r#"time"# => Token::Global(Globals::Time),
     r#"dtime"# => Token::Global(Globals::Dtime),
     r#"dPdtime"# => Token::Global(Globals::Dpdtime),
@@ -162,11 +140,111 @@ lexer! {
     },
 }
 
+/// Ints are 32 bits. Like oslc, literals up to 0xFFFFFFFF are accepted and wrap around, so that
+/// e.g. `-2147483648` and `0xFFFFFFFF` can be written.
+fn int_literal(text: &str, digits: &str, radix: u32) -> Token {
+    match u64::from_str_radix(digits, radix) {
+        Ok(i) if i <= u32::MAX as u64 => Token::Integer(i as u32 as i32 as i64),
+        _ => Token::Error {
+            message: format!("Integer literal {} is out of range", text),
+            content: String::new(),
+        },
+    }
+}
+
+fn float_literal(text: &str) -> Token {
+    let digits = text.trim_end_matches(|c| c == 'f' || c == 'F');
+    match digits.parse::<f64>() {
+        Ok(f) if f.abs() <= f32::MAX as f64 => Token::Float(f),
+        _ => Token::Error {
+            message: format!("Float literal {} is out of range", text),
+            content: String::new(),
+        },
+    }
+}
+
+/// Decodes the escapes of a string literal and drops its quotes. A bad escape is reported at
+/// just the escape rather than the whole literal.
+fn string_literal(text: &str, span: Span) -> (Token, Span) {
+    let inner = &text[1..text.len() - 1];
+    let mut bytes = Vec::new();
+    let mut chars = inner.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+
+        // The regex only matches a backslash followed by another character
+        let (_, escape) = chars.next().unwrap();
+        let value = match escape {
+            'n' => Some(b'\n' as u32),
+            't' => Some(b'\t' as u32),
+            'r' => Some(b'\r' as u32),
+            'v' => Some(0x0b),
+            'f' => Some(0x0c),
+            'a' => Some(0x07),
+            'b' => Some(0x08),
+            '\\' | '\'' | '"' | '?' => Some(escape as u32),
+            '0'..='7' => {
+                let mut value = escape.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match chars.peek().and_then(|(_, c)| c.to_digit(8)) {
+                        Some(digit) => {
+                            value = value * 8 + digit;
+                            chars.next();
+                        },
+                        None => break,
+                    }
+                }
+                Some(value)
+            },
+            'x' => {
+                let mut value: Option<u32> = None;
+                while let Some(digit) = chars.peek().and_then(|(_, c)| c.to_digit(16)) {
+                    value = Some(value.unwrap_or(0).saturating_mul(16).saturating_add(digit));
+                    chars.next();
+                }
+                value
+            },
+            _ => None,
+        };
+
+        let end = chars.peek().map_or(inner.len(), |(index, _)| *index);
+        let escape_text = &inner[start..end];
+        let message = match value {
+            None if escape == 'x' => format!("Missing hex digits in the escape sequence {}", escape_text),
+            None => format!("Unknown escape sequence {}", escape_text),
+            Some(0) => String::from("Strings cannot contain a null character"),
+            Some(value) if value > 0xff => format!("The escape sequence {} is out of range", escape_text),
+            Some(value) => {
+                bytes.push(value as u8);
+                continue;
+            },
+        };
+
+        let lo = span.lo + 1 + start;
+        let span = Span { lo, hi: lo + escape_text.len(), ..span };
+        return (Token::Error { message, content: String::new() }, span);
+    }
+
+    match String::from_utf8(bytes) {
+        Ok(value) => (Token::Str(value), span),
+        Err(_) => (Token::Error {
+            message: String::from("The escape sequences of this string do not form valid UTF-8"),
+            content: String::new(),
+        }, span),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Lexer<'a> {
     original: &'a str,
     remaining: &'a str,
     cur_line: usize,
+    lines: Option<&'a [(usize, usize)]>,
 }
 
 impl<'a> Lexer<'a> {
@@ -175,6 +253,22 @@ impl<'a> Lexer<'a> {
             original: s,
             remaining: s,
             cur_line: 1,
+            lines: None,
+        }
+    }
+
+    /// Reports files and line numbers through a table from lines of the text to lines of the
+    /// original files, as built by the preprocessor.
+    pub fn with_lines(mut self, lines: &'a [(usize, usize)]) -> Lexer<'a> {
+        self.lines = Some(lines);
+        self
+    }
+
+    /// The file and line of the current position.
+    fn location(&self) -> (usize, usize) {
+        match self.lines {
+            Some(lines) => lines.get(self.cur_line - 1).copied().unwrap_or((0, 0)),
+            None => (0, self.cur_line),
         }
     }
 }
@@ -187,7 +281,8 @@ impl<'a> Iterator for Lexer<'a> {
                 let lo = self.original.len() - self.remaining.len();
                 let hi = self.original.len() - new_remaining.len();
                 self.remaining = new_remaining;
-                (tok, Span { lo, hi, line: self.cur_line })
+                let (file, line) = self.location();
+                (tok, Span { lo, hi, line, file })
             } else {
                 self.cur_line = 0;
                 return None;
@@ -204,6 +299,9 @@ impl<'a> Iterator for Lexer<'a> {
                     self.cur_line += 1;
                     continue;
                 }
+                Token::Str(text) => {
+                    return Some(string_literal(&text, span));
+                }
                 tok => {
                     return Some((tok, span));
                 }
@@ -211,3 +309,73 @@ impl<'a> Iterator for Lexer<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lexes text holding a single token.
+    fn lex(text: &str) -> (Token, Span) {
+        let mut tokens: Vec<(Token, Span)> = Lexer::new(text).collect();
+        assert_eq!(tokens.len(), 1, "{} lexed as {:?}", text, tokens);
+        tokens.remove(0)
+    }
+
+    fn error_message(text: &str) -> String {
+        match lex(text).0 {
+            Token::Error {message, ..} => message,
+            token => panic!("{} lexed as {:?}", text, token),
+        }
+    }
+
+    #[test]
+    fn int_literals() {
+        let cases = [("42", 42), ("0x1F", 31), ("0XfF", 255), ("2147483648", -2147483648), ("0xFFFFFFFF", -1)];
+        for (text, expected) in cases {
+            assert!(matches!(lex(text).0, Token::Integer(value) if value == expected), "{}", text);
+        }
+
+        assert!(error_message("4294967296").contains("out of range"));
+        assert!(error_message("0x100000000").contains("out of range"));
+    }
+
+    #[test]
+    fn float_literals() {
+        let cases = [("1.5", 1.5), ("1.", 1.0), (".25", 0.25), ("1e3", 1000.0), ("2.5E-2", 0.025), ("3e0f", 3.0), ("1.0f", 1.0)];
+        for (text, expected) in cases {
+            assert!(matches!(lex(text).0, Token::Float(value) if value == expected), "{}", text);
+        }
+
+        assert!(error_message("1e39").contains("out of range"));
+    }
+
+    fn string_value(text: &str) -> String {
+        match lex(text).0 {
+            Token::Str(value) => value,
+            token => panic!("{} lexed as {:?}", text, token),
+        }
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(string_value(r#""plain""#), "plain");
+        assert_eq!(string_value(r#""a\tb\n""#), "a\tb\n");
+        assert_eq!(string_value(r#""\"quoted\" \\ \? \'""#), "\"quoted\" \\ ? '");
+        assert_eq!(string_value(r#""\a\b\f\v\r""#), "\x07\x08\x0c\x0b\r");
+        assert_eq!(string_value(r#""\101\0101""#), "A\x081");
+        assert_eq!(string_value(r#""\x41\x4a""#), "AJ");
+        assert_eq!(string_value(r#""caf\xc3\xa9""#), "café");
+    }
+
+    #[test]
+    fn invalid_escapes_are_reported_at_the_escape() {
+        let (token, span) = lex(r#""ok \q""#);
+        assert!(matches!(token, Token::Error {message, ..} if message.contains("Unknown escape sequence \\q")));
+        assert_eq!((span.lo, span.hi), (4, 6));
+
+        assert!(error_message(r#""\x""#).contains("Missing hex digits"));
+        assert!(error_message(r#""\x100""#).contains("out of range"));
+        assert!(error_message(r#""a\0b""#).contains("null character"));
+        assert!(error_message(r#""\xff""#).contains("UTF-8"));
+    }
+}
@@ -0,0 +1,324 @@
+use super::*;
+use super::ast::*;
+use super::symtab::*;
+
+use crate::errors::*;
+
+use std::collections::HashSet;
+
+/// Variables are keyed by name and the start of their declaration, like in the backends.
+type VariableKey = (String, usize);
+
+#[derive(Debug, Clone, PartialEq)]
+enum DeclarationKind {
+    Variable,
+    Parameter,
+    Output,
+}
+
+#[derive(Debug, Clone)]
+struct Declaration {
+    key: VariableKey,
+    name: Expr,
+    kind: DeclarationKind,
+}
+
+/// Looks for code that compiles but is most likely a mistake. Only run on programs without
+/// errors, since it relies on every name resolving.
+pub fn lint(program: &Vec<Stmt>, symbol_table: &SymbolTable, diagnostics: &mut Diagnostics) {
+    let mut linter = Linter::new(symbol_table);
+    linter.lint_statements(program);
+
+    for warning in linter.finish() {
+        diagnostics.warn(warning);
+    }
+}
+
+struct Linter<'a> {
+    symbol_table: &'a SymbolTable,
+
+    declarations: Vec<Declaration>,
+    reads: HashSet<VariableKey>,
+    writes: HashSet<VariableKey>,
+    warnings: Vec<(usize, OSLCompilerWarning)>,
+}
+
+impl<'a> Linter<'a> {
+    fn new(symbol_table: &'a SymbolTable) -> Self {
+        Linter {
+            symbol_table,
+            declarations: Vec::new(),
+            reads: HashSet::new(),
+            writes: HashSet::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// The warnings for everything that was never read or written, along with the ones found
+    /// while walking the program, in source order.
+    fn finish(mut self) -> Vec<OSLCompilerWarning> {
+        for declaration in &self.declarations {
+            let name = get_ident_value(&declaration.name).unwrap();
+            let item = Item::new(declaration.name.span, name);
+
+            let warning = match declaration.kind {
+                DeclarationKind::Variable if !self.reads.contains(&declaration.key) => {
+                    OSLCompilerWarning::UnusedVariable {var: item}
+                },
+                DeclarationKind::Parameter if !self.reads.contains(&declaration.key) => {
+                    OSLCompilerWarning::UnusedParameter {param: item}
+                },
+                DeclarationKind::Output if !self.writes.contains(&declaration.key) => {
+                    OSLCompilerWarning::UnwrittenOutput {param: item}
+                },
+                _ => continue,
+            };
+
+            self.warnings.push((declaration.name.span.lo, warning));
+        }
+
+        self.warnings.sort_by_key(|(lo, _)| *lo);
+        self.warnings.into_iter().map(|(_, warning)| warning).collect()
+    }
+
+    fn warn(&mut self, span: Span, warning: OSLCompilerWarning) {
+        self.warnings.push((span.lo, warning));
+    }
+
+    fn lint_statements(&mut self, stmts: &[Stmt]) {
+        let mut exit: Option<&Stmt> = None;
+        let mut reported = false;
+
+        for stmt in stmts {
+            if let (Some(exit), false) = (exit, reported) {
+                if !matches!(stmt.statement, Stmt_::EmptyStatement) {
+                    // One warning per block is enough
+                    self.warn(stmt.span, OSLCompilerWarning::UnreachableCode {
+                        code: Item::new(stmt.span, ""),
+                        exit: Item::new(exit.span, match exit.statement {
+                            Stmt_::BreakStatement => "break",
+                            Stmt_::ContinueStatement => "continue",
+                            _ => "return",
+                        }),
+                    });
+                    reported = true;
+                }
+            }
+
+            self.lint_statement(stmt);
+
+            if let (None, Stmt_::ReturnStatement(_) | Stmt_::BreakStatement | Stmt_::ContinueStatement) = (exit, &stmt.statement) {
+                exit = Some(stmt);
+            }
+        }
+    }
+
+    fn lint_statement(&mut self, stmt: &Stmt) {
+        match &stmt.statement {
+            Stmt_::ExpressionStatement(expr) |
+            Stmt_::ReturnStatement(expr) => self.lint_expression(expr),
+
+            Stmt_::VariableDeclaration {var_type, name, value} => {
+                self.declare(stmt.span, name, DeclarationKind::Variable);
+
+                if let Expr_::EmptyExpression = value.node {
+                    return;
+                }
+
+                self.check_narrowing(get_var_type_value(var_type), value);
+                self.lint_expression(value);
+            },
+
+            Stmt_::ShaderDeclaration {params, body, ..} |
+            Stmt_::FunctionDeclaration {params, body, ..} => {
+                for param in params {
+                    if let Expr_::Parameter {par_type, name, out, value, ..} = &param.node {
+                        let kind = if *out { DeclarationKind::Output } else { DeclarationKind::Parameter };
+                        self.declare(param.span, name, kind);
+
+                        if let Expr_::EmptyExpression = value.node {
+                            continue;
+                        }
+
+                        self.check_narrowing(get_var_type_value(par_type), value);
+                        self.lint_expression(value);
+                    }
+                }
+
+                self.lint_statement(body);
+            },
+
+            Stmt_::BlockStatement(stmts) => self.lint_statements(stmts),
+
+            Stmt_::IfStatement {condition, body, else_body} => {
+                self.lint_expression(condition);
+                self.lint_statement(body);
+                if let Some(else_body) = else_body {
+                    self.lint_statement(else_body);
+                }
+            },
+
+            Stmt_::WhileStatement {condition, body} |
+            Stmt_::DoWhileStatement {condition, body} => {
+                self.lint_expression(condition);
+                self.lint_statement(body);
+            },
+
+            Stmt_::ForStatement {initialization, condition, iteration, body} => {
+                self.lint_statements(initialization);
+                self.lint_expression(condition);
+                for expr in iteration {
+                    self.lint_expression(expr);
+                }
+                self.lint_statement(body);
+            },
+
+            _ => {},
+        }
+    }
+
+    fn lint_expression(&mut self, expr: &Expr) {
+        match &expr.node {
+            Expr_::Ident(name) => {
+                if let Some(key) = self.variable_key(expr, name) {
+                    self.reads.insert(key);
+                }
+            },
+
+            Expr_::Assignment(lhs, rhs) => {
+                let lhs_type = get_expr_type(lhs, self.symbol_table).ok();
+                self.check_narrowing(lhs_type, rhs);
+                self.write(lhs);
+                self.lint_expression(rhs);
+            },
+
+            Expr_::BinaryExpression(op, lhs, rhs) => {
+                if is_compound_assignment(op) {
+                    self.write(lhs);
+                }
+                self.lint_expression(lhs);
+                self.lint_expression(rhs);
+            },
+
+            Expr_::PreUnaryExpression(op, operand) |
+            Expr_::PostUnaryExpression(op, operand) => {
+                if let Operators::Increment | Operators::Decrement = op {
+                    self.write(operand);
+                }
+                self.lint_expression(operand);
+            },
+
+            // The value of a dot access is a component name, not a variable
+            Expr_::AccessExpression {lhs, value, dot} => {
+                self.lint_expression(lhs);
+                if !dot {
+                    self.lint_expression(value);
+                }
+            },
+
+            Expr_::InitializerList(values) => {
+                for value in values.iter() {
+                    self.lint_expression(value);
+                }
+            },
+
+            Expr_::TernaryExpression {condition, if_true, if_false} => {
+                self.lint_expression(condition);
+                self.lint_expression(if_true);
+                self.lint_expression(if_false);
+            },
+
+            Expr_::ExplicitCast {cast_expr, ..} => self.lint_expression(cast_expr),
+
+            Expr_::PointConstructor {x, y, z, space, ..} => {
+                if let Some(space) = space {
+                    self.lint_expression(space);
+                }
+                self.lint_expression(x);
+                self.lint_expression(y);
+                self.lint_expression(z);
+            },
+
+            Expr_::FunctionCallExpression {name, arguments} => {
+                let params = match &name.node {
+                    Expr_::Ident(function_name) if function_name != "arraylength" => match resolve_function(name, arguments, self.symbol_table) {
+                        Ok(Symbols::Function {params, ..}) => params.as_slice(),
+                        _ => &[],
+                    },
+                    _ => &[],
+                };
+
+                for (index, argument) in arguments.iter().enumerate() {
+                    match params.get(index) {
+                        Some((_, true)) => self.write(argument),
+                        _ => self.lint_expression(argument),
+                    }
+                }
+            },
+
+            _ => {},
+        }
+    }
+
+    /// Marks the variable behind an l-value as written.
+    fn write(&mut self, expr: &Expr) {
+        match &expr.node {
+            Expr_::Ident(name) => {
+                if let Some(key) = self.variable_key(expr, name) {
+                    self.writes.insert(key);
+                }
+            },
+            Expr_::AccessExpression {lhs, value, dot} => {
+                self.write(lhs);
+                if !dot {
+                    self.lint_expression(value);
+                }
+            },
+            _ => self.lint_expression(expr),
+        }
+    }
+
+    fn variable_key(&self, expr: &Expr, name: &String) -> Option<VariableKey> {
+        match self.symbol_table.find_reference(expr.span, name.clone())? {
+            Symbols::Variable {span, ..} => Some((name.clone(), span.lo)),
+            _ => None,
+        }
+    }
+
+    /// Records a declaration and warns if it hides a variable of an enclosing scope.
+    fn declare(&mut self, span: Span, name: &Expr, kind: DeclarationKind) {
+        let ident = get_ident_value(name).unwrap();
+
+        if let Some(shadowed) = self.symbol_table.find_shadowed(name.span, ident.clone()) {
+            self.warn(name.span, OSLCompilerWarning::ShadowedDeclaration {
+                existing: Item::new(shadowed.get_span(), ident.clone()),
+                new: Item::new(name.span, ident.clone()),
+            });
+        }
+
+        self.declarations.push(Declaration {
+            key: (ident, span.lo),
+            name: name.clone(),
+            kind,
+        });
+    }
+
+    fn check_narrowing(&mut self, to: Option<Types>, value: &Expr) {
+        if let (Some(Types::Array(element, _)), Expr_::InitializerList(values)) = (&to, &value.node) {
+            for value in values.iter() {
+                self.check_narrowing(Some(*element.clone()), value);
+            }
+            return;
+        }
+
+        if to != Some(Types::Int) {
+            return;
+        }
+
+        if let Ok(Types::Float) = get_expr_type(value, self.symbol_table) {
+            self.warn(value.span, OSLCompilerWarning::NarrowingConversion {
+                expr: Item::new(value.span, ""),
+            });
+        }
+    }
+}
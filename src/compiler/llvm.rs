@@ -1,186 +1,1801 @@
 use super::*;
 use super::ast::*;
-use super::symtab::SymbolTable;
+use super::symtab::{SymbolTable, Symbols};
 
 use crate::errors::*;
 
-use inkwell::AddressSpace;
+use std::collections::HashMap;
+
+use inkwell::{AddressSpace, FloatPredicate, IntPredicate, OptimizationLevel};
+use inkwell::basic_block::BasicBlock;
+use inkwell::builder::Builder;
 use inkwell::context::Context;
 use inkwell::module::Module;
-use inkwell::builder::Builder;
-use inkwell::types::BasicMetadataTypeEnum;
-use inkwell::values::BasicValueEnum;
+use inkwell::passes::{PassManager, PassManagerBuilder};
+use inkwell::types::{BasicMetadataTypeEnum, BasicType, BasicTypeEnum, StructType, VectorType};
+use inkwell::values::{ArrayValue, BasicMetadataValueEnum, BasicValue, BasicValueEnum, FloatMathValue,
+                      FloatValue, FunctionValue, IntMathValue, IntValue, PointerValue, VectorValue};
 
-pub fn compile(program: &Vec<Stmt>, symbol_table: &SymbolTable) -> Result<Vec<u8>, OSLCompilerError> {
+/// Fields of the `ShaderGlobals` struct every generated function receives, in layout order.
+pub const SHADER_GLOBALS: [(&str, Types); 12] = [
+    ("P", Types::Point),
+    ("I", Types::Vector),
+    ("N", Types::Normal),
+    ("Ng", Types::Normal),
+    ("dPdu", Types::Vector),
+    ("dPdv", Types::Vector),
+    ("Ps", Types::Point),
+    ("u", Types::Float),
+    ("v", Types::Float),
+    ("time", Types::Float),
+    ("dtime", Types::Float),
+    ("dPdtime", Types::Vector),
+];
 
+pub fn compile(program: &Vec<Stmt>, symbol_table: &SymbolTable, opt_level: OptLevel) -> Result<ShaderOutput, OSLCompilerError> {
     let context = Context::create();
-    let builder = context.create_builder();
+    let module = build_shader(&context, program, symbol_table)?;
+    optimize(&module, opt_level);
+
+    Ok(ShaderOutput::LLVM {
+        bitcode: module.write_bitcode_to_memory().as_slice().to_vec(),
+        ir: module.print_to_string().to_string(),
+    })
+}
+
+/// Runs LLVM's module passes for `opt_level` over the module.
+pub fn optimize(module: &Module, opt_level: OptLevel) {
+    if opt_level != OptLevel::None {
+        let pass_manager_builder = PassManagerBuilder::create();
+        pass_manager_builder.set_optimization_level(optimization_level(opt_level));
+
+        let pass_manager = PassManager::create(());
+        pass_manager_builder.populate_module_pass_manager(&pass_manager);
+        pass_manager.run_on(module);
+    }
+}
+
+pub fn optimization_level(opt_level: OptLevel) -> OptimizationLevel {
+    match opt_level {
+        OptLevel::None => OptimizationLevel::None,
+        OptLevel::Less => OptimizationLevel::Less,
+        OptLevel::Default => OptimizationLevel::Default,
+        OptLevel::Aggressive => OptimizationLevel::Aggressive,
+    }
+}
 
-    if let Some(module) = build_shader(&context, &builder, &symbol_table, program) {
-        println!("{}", module.to_string());
+/// Lowers the checked program into a verified LLVM module.
+///
+/// The shader becomes `void @name(%ShaderGlobals*, %ShaderParams*)`, which runs the shader body,
+/// and `void @name_init(%ShaderGlobals*, %ShaderParams*)`, which stores the parameter defaults.
+/// User functions take the shader globals followed by a pointer to each of their parameters.
+pub fn build_shader<'ctx>(context: &'ctx Context, program: &Vec<Stmt>, symbol_table: &SymbolTable) -> Result<Module<'ctx>, OSLCompilerError> {
+    let shader_name = program.iter()
+        .find_map(|stmt| match &stmt.statement {
+            Stmt_::ShaderDeclaration {name, ..} => get_ident_value(name),
+            _ => None,
+        })
+        .ok_or(OSLCompilerError::MissingShader)?;
+
+    let mut shader = ShaderBuilder::new(context, symbol_table, &shader_name)?;
+
+    // Functions are declared up front so calls can reference them before they are built
+    for stmt in program {
+        if let Stmt_::FunctionDeclaration {name, ret_type, params, ..} = &stmt.statement {
+            shader.declare_function(stmt.span, name, ret_type, params)?;
+        }
+    }
+
+    for stmt in program {
+        if let Stmt_::FunctionDeclaration {name, params, body, ..} = &stmt.statement {
+            shader.build_function(stmt.span, name, params, body)?;
+        }
     }
 
+    for stmt in program {
+        if let Stmt_::ShaderDeclaration {name, params, body, ..} = &stmt.statement {
+            shader.build_entry_point(name, params, body)?;
+        }
+    }
+
+    shader.module.verify().map_err(|error| OSLCompilerError::BackendError(error.to_string()))?;
+
+    Ok(shader.module)
+}
+
+#[derive(Debug, Clone)]
+struct Function<'ctx> {
+    value: FunctionValue<'ctx>,
+    ret_type: Types,
+    params: Vec<(Types, bool)>,
+}
+
+struct ShaderBuilder<'ctx, 'a> {
+    context: &'ctx Context,
+    module: Module<'ctx>,
+    builder: Builder<'ctx>,
+    symbol_table: &'a SymbolTable,
+
+    globals_type: StructType<'ctx>,
+    params_type: StructType<'ctx>,
 
-    Ok(vec![])
+    // Variables and functions are keyed by name and the span of their declaration so that
+    // shadowed names resolve to the symbol the symbol table picked.
+    variables: HashMap<(String, usize), PointerValue<'ctx>>,
+    functions: HashMap<(String, usize), Function<'ctx>>,
+    // Lengths of the unsized array parameters of the function being built
+    array_lengths: HashMap<(String, usize), IntValue<'ctx>>,
+
+    // State of the function being built
+    function: Option<FunctionValue<'ctx>>,
+    shader_globals: Option<PointerValue<'ctx>>,
+    ret_type: Types,
+
+    // (break, continue) targets of the enclosing loops
+    loops: Vec<(BasicBlock<'ctx>, BasicBlock<'ctx>)>,
 }
 
-fn build_shader<'ctx> (context: &'ctx Context, builder: &Builder, symbol_table: &SymbolTable, program: &Vec<Stmt>) -> Option<Module<'ctx>> {
-    match &program[0].statement {
-        Stmt_::ShaderDeclaration{name, shader_type: _, params, body} => {
-            let shader_name = match &name.node {
-                Expr_::Ident(s) => s,
-                _ => {return None;}
+impl<'ctx, 'a> ShaderBuilder<'ctx, 'a> {
+    fn new(context: &'ctx Context, symbol_table: &'a SymbolTable, shader_name: &str) -> Result<Self, OSLCompilerError> {
+        let module = context.create_module(shader_name);
+        let globals_type = context.opaque_struct_type("ShaderGlobals");
+        let params_type = context.opaque_struct_type("ShaderParams");
+
+        let shader = ShaderBuilder {
+            context,
+            module,
+            builder: context.create_builder(),
+            symbol_table,
+            globals_type,
+            params_type,
+            variables: HashMap::new(),
+            functions: HashMap::new(),
+            array_lengths: HashMap::new(),
+            function: None,
+            shader_globals: None,
+            ret_type: Types::Void,
+            loops: Vec::new(),
+        };
+
+        let mut fields = Vec::new();
+        for (_, global_type) in SHADER_GLOBALS.iter() {
+            fields.push(shader.memory_type(global_type, Span {lo: 0, hi: 0, line: 0, file: 0})?);
+        }
+        shader.globals_type.set_body(&fields, false);
+
+        Ok(shader)
+    }
+
+    //===============
+    // Declarations
+    //===============
+
+    fn declare_function(&mut self, span: Span, name: &Expr, ret_type: &Expr, params: &Vec<Expr>) -> Result<(), OSLCompilerError> {
+        let function_name = get_ident_value(name).unwrap();
+        let ret_type = get_var_type_value(ret_type).unwrap();
+
+        let mut param_types = Vec::new();
+        let mut pointer_types: Vec<BasicMetadataTypeEnum> = vec![self.globals_type.ptr_type(AddressSpace::Generic).into()];
+        for param in params {
+            if let Expr_::Parameter {par_type, out, ..} = &param.node {
+                let param_type = get_var_type_value(par_type).unwrap();
+                match &param_type {
+                    // Unsized arrays are passed as a pointer to their first element and their length
+                    Types::Array(element, None) => {
+                        pointer_types.push(self.memory_type(element, param.span)?.ptr_type(AddressSpace::Generic).into());
+                        pointer_types.push(self.context.i32_type().into());
+                    },
+                    _ => pointer_types.push(self.memory_type(&param_type, param.span)?.ptr_type(AddressSpace::Generic).into()),
+                }
+                param_types.push((param_type, *out));
+            }
+        }
+
+        let function_type = match ret_type {
+            Types::Void => self.context.void_type().fn_type(&pointer_types, false),
+            _ => self.value_type(&ret_type, span)?.fn_type(&pointer_types, false),
+        };
+        let value = self.module.add_function(&function_name, function_type, None);
+
+        self.functions.insert((function_name, span.lo), Function {
+            value,
+            ret_type,
+            params: param_types,
+        });
+
+        Ok(())
+    }
+
+    fn build_function(&mut self, span: Span, name: &Expr, params: &Vec<Expr>, body: &Stmt) -> Result<(), OSLCompilerError> {
+        let function = self.functions[&(get_ident_value(name).unwrap(), span.lo)].clone();
+        self.begin_function(function.value, function.ret_type.clone());
+
+        // Parameters are passed by reference so that output parameters can be written
+        let mut position = 1;
+        for (param, (param_type, _)) in params.iter().zip(&function.params) {
+            if let Expr_::Parameter {name, ..} = &param.node {
+                let param_name = get_ident_value(name).unwrap();
+                let pointer = function.value.get_nth_param(position).unwrap().into_pointer_value();
+                pointer.set_name(&param_name);
+                position += 1;
+
+                if let Types::Array(_, None) = param_type {
+                    let length = function.value.get_nth_param(position).unwrap().into_int_value();
+                    length.set_name(&format!("{}_length", param_name));
+                    self.array_lengths.insert((param_name.clone(), param.span.lo), length);
+                    position += 1;
+                }
+
+                self.variables.insert((param_name, param.span.lo), pointer);
+            }
+        }
+
+        self.build_statement(body)?;
+        self.end_function(span)
+    }
+
+    fn build_entry_point(&mut self, name: &Expr, params: &Vec<Expr>, body: &Stmt) -> Result<(), OSLCompilerError> {
+        let shader_name = get_ident_value(name).unwrap();
+
+        let mut fields = Vec::new();
+        for param in params {
+            if let Expr_::Parameter {par_type, ..} = &param.node {
+                fields.push(self.memory_type(&get_var_type_value(par_type).unwrap(), param.span)?);
+            }
+        }
+        self.params_type.set_body(&fields, false);
+
+        let function_type = self.context.void_type().fn_type(&[
+            self.globals_type.ptr_type(AddressSpace::Generic).into(),
+            self.params_type.ptr_type(AddressSpace::Generic).into()], false);
+
+        // Defaults are evaluated in declaration order, so they may refer to earlier parameters
+        let init = self.module.add_function(&format!("{}_init", shader_name), function_type, None);
+        self.begin_function(init, Types::Void);
+        self.bind_parameters(init, params)?;
+        for (index, param) in params.iter().enumerate() {
+            if let Expr_::Parameter {par_type, value, ..} = &param.node {
+                let param_type = get_var_type_value(par_type).unwrap();
+                let pointer = self.param_pointer(init, index)?;
+                let default = match value.node {
+                    Expr_::EmptyExpression => self.zero_value(&param_type, param.span)?,
+                    _ => self.build_initializer(value, &param_type)?,
+                };
+                self.store(pointer, default, &param_type);
+            }
+        }
+        self.end_function(name.span)?;
+
+        let function = self.module.add_function(&shader_name, function_type, None);
+        self.begin_function(function, Types::Void);
+        self.bind_parameters(function, params)?;
+        self.build_statement(body)?;
+        self.end_function(name.span)
+    }
+
+    /// Makes the fields of the parameter struct visible as the shader's parameters.
+    fn bind_parameters(&mut self, function: FunctionValue<'ctx>, params: &Vec<Expr>) -> Result<(), OSLCompilerError> {
+        for (index, param) in params.iter().enumerate() {
+            if let Expr_::Parameter {name, ..} = &param.node {
+                let param_name = get_ident_value(name).unwrap();
+                let pointer = self.param_pointer(function, index)?;
+                self.variables.insert((param_name, param.span.lo), pointer);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn param_pointer(&self, function: FunctionValue<'ctx>, index: usize) -> Result<PointerValue<'ctx>, OSLCompilerError> {
+        let params = function.get_nth_param(1).unwrap().into_pointer_value();
+        self.builder.build_struct_gep(params, index as u32, "")
+            .map_err(|_| OSLCompilerError::BackendError(format!("Invalid shader parameter index {}", index)))
+    }
+
+    fn begin_function(&mut self, function: FunctionValue<'ctx>, ret_type: Types) {
+        let entry = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(entry);
+
+        let shader_globals = function.get_nth_param(0).unwrap().into_pointer_value();
+        shader_globals.set_name("sg");
+
+        self.function = Some(function);
+        self.shader_globals = Some(shader_globals);
+        self.ret_type = ret_type;
+    }
+
+    fn end_function(&mut self, span: Span) -> Result<(), OSLCompilerError> {
+        // Falling off the end of a function returns a zero value
+        if !self.is_terminated() {
+            match self.ret_type.clone() {
+                Types::Void => self.builder.build_return(None),
+                ret_type => {
+                    let zero = self.zero_value(&ret_type, span)?;
+                    self.builder.build_return(Some(&zero))
+                },
             };
+        }
 
-            let module = context.create_module(shader_name);
-            // let mut types: HashMap<&str, Box<dyn AnyType>> = HashMap::new();
-            
-            let f32_type = context.f32_type();
-            let v3f32_type = f32_type.vec_type(3);
-            let i32_type = context.i32_type();
-            let void_type = context.void_type();
-
-            let mut param_types: Vec<BasicMetadataTypeEnum> = Vec::new();
-
-
-            for param in params {
-                match &param.node {
-                    Expr_::Parameter{par_type, name, out: _, value: _} => {
-                        let param_ident = match &name.node {
-                            Expr_::Ident(n) => n,
-                            _ => {return None;}
-                        };
-
-                        match &par_type.node {
-                            Expr_::VariableType(Types::Float) => {
-                                module.add_global(f32_type, Some(AddressSpace::Global), &param_ident.to_owned());
-                                param_types.push(f32_type.ptr_type(AddressSpace::Global).into());
-                            },
-                            Expr_::VariableType(Types::Int) => {
-                                module.add_global(i32_type, Some(AddressSpace::Global), &param_ident.to_owned());
-                                param_types.push(i32_type.ptr_type(AddressSpace::Global).into());
-                            },
-                            // // Expr_::VariableType(Types::String) => ,
-                            // Expr_::VariableType(Types::Void) => void_type,
-                            Expr_::VariableType(Types::Color) |
-                            Expr_::VariableType(Types::Normal) |
-                            Expr_::VariableType(Types::Point) => {
-                                module.add_global(v3f32_type, Some(AddressSpace::Global), &param_ident.to_owned());
-                                param_types.push(v3f32_type.ptr_type(AddressSpace::Global).into());
-                            },
-                            _ => {return None;}
-                        };
-                    }
-                    _ => {return None;}
+        self.function = None;
+        self.shader_globals = None;
+
+        Ok(())
+    }
+
+    /// Whether the block being built already ended, e.g. with a return.
+    fn is_terminated(&self) -> bool {
+        self.builder.get_insert_block()
+            .and_then(|block| block.get_terminator())
+            .is_some()
+    }
+
+    fn append_block(&self, name: &str) -> BasicBlock<'ctx> {
+        self.context.append_basic_block(self.function.unwrap(), name)
+    }
+
+    /// Branches to `target` unless the current block already ended.
+    fn branch_to(&self, target: BasicBlock<'ctx>) {
+        if !self.is_terminated() {
+            self.builder.build_unconditional_branch(target);
+        }
+    }
+
+    /// Allocates a local variable. Allocas all live at the top of the entry block.
+    fn build_local_variable(&self, var_type: &Types, name: &str, span: Span) -> Result<PointerValue<'ctx>, OSLCompilerError> {
+        let memory_type = self.memory_type(var_type, span)?;
+        let current_block = self.builder.get_insert_block().unwrap();
+        let entry = self.function.unwrap().get_first_basic_block().unwrap();
+
+        match entry.get_first_instruction() {
+            Some(instruction) => self.builder.position_before(&instruction),
+            None => self.builder.position_at_end(entry),
+        }
+        let pointer = self.builder.build_alloca(memory_type, name);
+        self.builder.position_at_end(current_block);
+
+        Ok(pointer)
+    }
+
+    fn build_global(&self, global: &Globals, span: Span) -> Result<(PointerValue<'ctx>, Types), OSLCompilerError> {
+        let name = match global {
+            Globals::P => "P",
+            Globals::I => "I",
+            Globals::N => "N",
+            Globals::Ng => "Ng",
+            Globals::Dpdu => "dPdu",
+            Globals::Dpdv => "dPdv",
+            Globals::Ps => "Ps",
+            Globals::U => "u",
+            Globals::V => "v",
+            Globals::Time => "time",
+            Globals::Dtime => "dtime",
+            Globals::Dpdtime => "dPdtime",
+            _ => return Err(self.unsupported(span, format!("The global {:?} is not supported by the LLVM backend", global))),
+        };
+
+        let index = SHADER_GLOBALS.iter().position(|(global_name, _)| *global_name == name).unwrap();
+        let pointer = self.builder.build_struct_gep(self.shader_globals.unwrap(), index as u32, name)
+            .map_err(|_| OSLCompilerError::BackendError(format!("Invalid shader global {}", name)))?;
+
+        Ok((pointer, SHADER_GLOBALS[index].1.clone()))
+    }
+
+    //===============
+    // Types and values
+    //===============
+
+    /// The type values are kept in while being computed on.
+    fn value_type(&self, var_type: &Types, span: Span) -> Result<BasicTypeEnum<'ctx>, OSLCompilerError> {
+        let float_type = self.context.f32_type();
+
+        Ok(match var_type {
+            Types::Int => self.context.i32_type().into(),
+            Types::Float => float_type.into(),
+            Types::Color |
+            Types::Point |
+            Types::Vector |
+            Types::Normal => float_type.vec_type(3).into(),
+            Types::Matrix => float_type.vec_type(16).into(),
+            Types::String => self.context.i8_type().ptr_type(AddressSpace::Generic).into(),
+            // Arrays and structs are only ever copied as a whole, so they stay in memory form
+            Types::Array(_, Some(_)) |
+            Types::Struct(_) => self.memory_type(var_type, span)?,
+            _ => return Err(self.unsupported(span, format!("The type {:?} is not supported by the LLVM backend", var_type))),
+        })
+    }
+
+    /// The type values are stored as. Triples and matrices use plain float arrays so that the
+    /// parameter and globals structs have a C compatible layout.
+    fn memory_type(&self, var_type: &Types, span: Span) -> Result<BasicTypeEnum<'ctx>, OSLCompilerError> {
+        let float_type = self.context.f32_type();
+
+        Ok(match var_type {
+            Types::Color |
+            Types::Point |
+            Types::Vector |
+            Types::Normal => float_type.array_type(3).into(),
+            Types::Matrix => float_type.array_type(16).into(),
+            Types::Array(element, Some(length)) => self.memory_type(element, span)?.array_type(*length as u32).into(),
+            Types::Struct(name) => {
+                let mut fields = Vec::new();
+                for (_, field_type) in self.struct_fields(name, span)? {
+                    fields.push(self.memory_type(field_type, span)?);
                 }
+                self.context.struct_type(&fields, false).into()
+            },
+            _ => self.value_type(var_type, span)?,
+        })
+    }
+
+    fn struct_fields(&self, name: &str, span: Span) -> Result<&'a [(String, Types)], OSLCompilerError> {
+        self.symbol_table.get_struct_fields(name)
+            .ok_or_else(|| self.unsupported(span, format!("Could not find the struct {}", name)))
+    }
+
+    /// The position and type of a struct field.
+    fn struct_field(&self, name: &str, field: &Expr) -> Result<(u32, Types), OSLCompilerError> {
+        let field_name = get_ident_value(field).unwrap_or_default();
+        self.struct_fields(name, field.span)?.iter()
+            .enumerate()
+            .find(|(_, (existing, _))| *existing == field_name)
+            .map(|(index, (_, field_type))| (index as u32, field_type.clone()))
+            .ok_or_else(|| self.unsupported(field.span, format!("The struct {} has no field named {}", name, field_name)))
+    }
+
+    fn load(&self, pointer: PointerValue<'ctx>, var_type: &Types) -> BasicValueEnum<'ctx> {
+        let value = self.builder.build_load(pointer, "");
+        self.from_memory(value, var_type)
+    }
+
+    fn store(&self, pointer: PointerValue<'ctx>, value: BasicValueEnum<'ctx>, var_type: &Types) {
+        self.builder.build_store(pointer, self.to_memory(value, var_type));
+    }
+
+    /// Converts a loaded value from its memory type to its value type.
+    fn from_memory(&self, value: BasicValueEnum<'ctx>, var_type: &Types) -> BasicValueEnum<'ctx> {
+        match var_type {
+            t if is_triple(t) || *t == Types::Matrix => self.array_to_vector(value.into_array_value()).into(),
+            _ => value,
+        }
+    }
+
+    /// Converts a value from its value type to its memory type.
+    fn to_memory(&self, value: BasicValueEnum<'ctx>, var_type: &Types) -> BasicValueEnum<'ctx> {
+        match var_type {
+            t if is_triple(t) || *t == Types::Matrix => self.vector_to_array(value.into_vector_value()).into(),
+            _ => value,
+        }
+    }
+
+    fn array_to_vector(&self, array: ArrayValue<'ctx>) -> VectorValue<'ctx> {
+        let size = array.get_type().len();
+        let mut vector = self.context.f32_type().vec_type(size).get_undef();
+        for index in 0..size {
+            let element = self.builder.build_extract_value(array, index, "").unwrap();
+            vector = self.builder.build_insert_element(vector, element, self.const_int(index as i32), "");
+        }
+
+        vector
+    }
+
+    fn vector_to_array(&self, vector: VectorValue<'ctx>) -> ArrayValue<'ctx> {
+        let size = vector.get_type().get_size();
+        let mut array = self.context.f32_type().array_type(size).get_undef();
+        for index in 0..size {
+            let element = self.builder.build_extract_element(vector, self.const_int(index as i32), "");
+            array = self.builder.build_insert_value(array, element, index, "").unwrap().into_array_value();
+        }
+
+        array
+    }
+
+    fn const_int(&self, value: i32) -> IntValue<'ctx> {
+        self.context.i32_type().const_int(value as u64, true)
+    }
+
+    fn const_float(&self, value: f32) -> FloatValue<'ctx> {
+        self.context.f32_type().const_float(value as f64)
+    }
+
+    fn zero_value(&self, var_type: &Types, span: Span) -> Result<BasicValueEnum<'ctx>, OSLCompilerError> {
+        // Every element is zeroed separately, since strings are never null
+        if let Types::Array(element, Some(length)) = var_type {
+            let zero = self.zero_value(element, span)?;
+            let zero = self.to_memory(zero, element);
+            let mut array = self.memory_type(var_type, span)?.into_array_type().get_undef();
+            for index in 0..*length {
+                array = self.builder.build_insert_value(array, zero, index as u32, "").unwrap().into_array_value();
+            }
+            return Ok(array.into());
+        }
+
+        if let Types::Struct(name) = var_type {
+            let mut value = self.memory_type(var_type, span)?.into_struct_type().get_undef();
+            for (index, (_, field_type)) in self.struct_fields(name, span)?.iter().enumerate() {
+                let zero = self.zero_value(field_type, span)?;
+                let zero = self.to_memory(zero, field_type);
+                value = self.builder.build_insert_value(value, zero, index as u32, "").unwrap().into_struct_value();
+            }
+            return Ok(value.into());
+        }
+
+        Ok(match self.value_type(var_type, span)? {
+            BasicTypeEnum::IntType(t) => t.const_zero().into(),
+            BasicTypeEnum::FloatType(t) => t.const_zero().into(),
+            BasicTypeEnum::VectorType(t) => t.const_zero().into(),
+            BasicTypeEnum::PointerType(_) => self.builder.build_global_string_ptr("", "").as_pointer_value().into(),
+            _ => return Err(self.unsupported(span, format!("The type {:?} has no zero value", var_type))),
+        })
+    }
+
+    /// Repeats a float in every component of a triple or matrix.
+    fn splat(&self, value: FloatValue<'ctx>, size: u32) -> VectorValue<'ctx> {
+        let mut vector = self.context.f32_type().vec_type(size).get_undef();
+        for index in 0..size {
+            vector = self.builder.build_insert_element(vector, value, self.const_int(index as i32), "");
+        }
+
+        vector
+    }
+
+    fn unsupported(&self, span: Span, message: String) -> OSLCompilerError {
+        OSLCompilerError::CodegenError {
+            message,
+            error: Item::new(span, "Used here"),
+        }
+    }
+
+    //===============
+    // Statements
+    //===============
+
+    fn build_statement(&mut self, stmt: &Stmt) -> Result<(), OSLCompilerError> {
+        self.build_statements(std::slice::from_ref(stmt))
+    }
+
+    fn build_statements(&mut self, stmts: &[Stmt]) -> Result<(), OSLCompilerError> {
+        for stmt in stmts {
+            // Anything after a return in the same block can never run
+            if self.is_terminated() {
+                break;
             }
 
+            match &stmt.statement {
+                Stmt_::ExpressionStatement(expr) => {
+                    if let Expr_::EmptyExpression = expr.node {} else {
+                        self.build_expression(expr)?;
+                    }
+                },
+
+                Stmt_::EmptyStatement => {},
 
-            let entry_function_type = void_type.fn_type(&param_types, false);
+                Stmt_::BlockStatement(block_stmts) => {
+                    self.build_statements(block_stmts)?;
+                },
 
-            let function = module.add_function(shader_name, entry_function_type, None);
+                Stmt_::VariableDeclaration {var_type, name, value} => {
+                    let var_type = get_var_type_value(var_type).unwrap();
+                    let var_name = get_ident_value(name).unwrap();
+                    let pointer = self.build_local_variable(&var_type, &var_name, stmt.span)?;
+                    self.variables.insert((var_name, stmt.span.lo), pointer);
 
-            let _entry_block = context.append_basic_block(function, "entry");
+                    if let Expr_::EmptyExpression = value.node {} else {
+                        let initial = self.build_initializer(value, &var_type)?;
+                        self.store(pointer, initial, &var_type);
+                    }
+                },
 
-            if let Stmt_::BlockStatement(statements) = &body.statement {
-                for statement in statements.clone().into_iter() {
-                    match statement.statement {
-                        Stmt_::ExpressionStatement(s) => {
-                            build_expression(&context, &builder, &module, &symbol_table, &s.node);
-                        }
-                        _ => {}
+                Stmt_::ReturnStatement(expr) => {
+                    match (&expr.node, self.ret_type.clone()) {
+                        (Expr_::EmptyExpression, _) |
+                        (_, Types::Void) => {
+                            if let Expr_::EmptyExpression = expr.node {} else {
+                                self.build_expression(expr)?;
+                            }
+                            self.builder.build_return(None);
+                        },
+                        (_, ret_type) => {
+                            let value = self.build_coerced(expr, &ret_type)?;
+                            self.builder.build_return(Some(&value));
+                        },
                     }
+                },
+
+                Stmt_::BreakStatement |
+                Stmt_::ContinueStatement => {
+                    // The type checker made sure that there is an enclosing loop
+                    let (break_block, continue_block) = *self.loops.last().unwrap();
+                    let target = if let Stmt_::BreakStatement = stmt.statement { break_block } else { continue_block };
+                    self.builder.build_unconditional_branch(target);
+                },
+
+                Stmt_::IfStatement {..} => {
+                    let (branches, else_body) = get_conditional_chain(stmt);
+                    self.build_conditional(&branches, else_body)?;
+                },
+
+                Stmt_::WhileStatement {condition, body} => {
+                    self.build_loop(&[], condition, &[], body)?;
+                },
+
+                Stmt_::DoWhileStatement {condition, body} => {
+                    self.build_do_while(condition, body)?;
+                },
+
+                Stmt_::ForStatement {initialization, condition, iteration, body} => {
+                    self.build_loop(initialization, condition, iteration, body)?;
+                },
+
+                Stmt_::FunctionDeclaration {..} |
+                Stmt_::StructDeclaration {..} |
+                Stmt_::ShaderDeclaration {..} => {
+                    return Err(self.unsupported(stmt.span, String::from("Nested declarations are not supported by the LLVM backend")));
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    fn build_conditional(&mut self, branches: &[(&Expr, &Stmt)], else_body: Option<&Stmt>) -> Result<(), OSLCompilerError> {
+        let merge_block = self.append_block("endif");
+
+        for (condition, body) in branches {
+            let condition = self.build_bool(condition)?;
+            let then_block = self.append_block("then");
+            let else_block = self.append_block("else");
+            self.builder.build_conditional_branch(condition, then_block, else_block);
+
+            self.builder.position_at_end(then_block);
+            self.build_statement(body)?;
+            self.branch_to(merge_block);
+
+            self.builder.position_at_end(else_block);
+        }
+
+        if let Some(else_body) = else_body {
+            self.build_statement(else_body)?;
+        }
+        self.branch_to(merge_block);
+
+        // Keep the blocks in source order
+        merge_block.move_after(self.builder.get_insert_block().unwrap()).unwrap();
+        self.builder.position_at_end(merge_block);
+
+        Ok(())
+    }
+
+    fn build_loop(&mut self, initialization: &[Stmt], condition: &Expr, iteration: &[Expr], body: &Stmt) -> Result<(), OSLCompilerError> {
+        self.build_statements(initialization)?;
+
+        let condition_block = self.append_block("loop_cond");
+        let body_block = self.append_block("loop_body");
+        let step_block = self.append_block("loop_step");
+        let end_block = self.append_block("loop_end");
+
+        self.builder.build_unconditional_branch(condition_block);
+        self.builder.position_at_end(condition_block);
+        if let Expr_::EmptyExpression = condition.node {
+            self.builder.build_unconditional_branch(body_block);
+        } else {
+            let condition = self.build_bool(condition)?;
+            self.builder.build_conditional_branch(condition, body_block, end_block);
+        }
+
+        self.builder.position_at_end(body_block);
+        self.loops.push((end_block, step_block));
+        self.build_statement(body)?;
+        self.loops.pop();
+        self.branch_to(step_block);
+
+        step_block.move_after(self.builder.get_insert_block().unwrap()).unwrap();
+        self.builder.position_at_end(step_block);
+        for expr in iteration {
+            self.build_expression(expr)?;
+        }
+        self.builder.build_unconditional_branch(condition_block);
+
+        end_block.move_after(self.builder.get_insert_block().unwrap()).unwrap();
+        self.builder.position_at_end(end_block);
+
+        Ok(())
+    }
+
+    fn build_do_while(&mut self, condition: &Expr, body: &Stmt) -> Result<(), OSLCompilerError> {
+        let body_block = self.append_block("do_body");
+        let condition_block = self.append_block("do_cond");
+        let end_block = self.append_block("do_end");
+
+        self.builder.build_unconditional_branch(body_block);
+        self.builder.position_at_end(body_block);
+        self.loops.push((end_block, condition_block));
+        self.build_statement(body)?;
+        self.loops.pop();
+        self.branch_to(condition_block);
+
+        condition_block.move_after(self.builder.get_insert_block().unwrap()).unwrap();
+        self.builder.position_at_end(condition_block);
+        let condition = self.build_bool(condition)?;
+        self.builder.build_conditional_branch(condition, body_block, end_block);
+
+        end_block.move_after(self.builder.get_insert_block().unwrap()).unwrap();
+        self.builder.position_at_end(end_block);
+
+        Ok(())
+    }
+
+    //===============
+    // Expressions
+    //===============
+
+    fn expr_type(&self, expr: &Expr) -> Result<Types, OSLCompilerError> {
+        get_expr_type(expr, self.symbol_table)
+    }
+
+    /// The user function a call resolves to, or None for builtins.
+    fn get_function(&self, name: &Expr, arguments: &[Expr]) -> Result<Option<&Function<'ctx>>, OSLCompilerError> {
+        let function_name = get_ident_value(name).unwrap();
+        Ok(match resolve_function(name, arguments, self.symbol_table)? {
+            Symbols::Function {span, public: false, ..} => self.functions.get(&(function_name, span.lo)),
+            _ => None,
+        })
+    }
+
+    fn get_variable(&self, expr: &Expr, name: &String) -> Result<PointerValue<'ctx>, OSLCompilerError> {
+        let symbol = self.symbol_table.get_reference(expr.span, name.clone());
+        self.variables.get(&(name.clone(), symbol.get_span().lo))
+            .copied()
+            .ok_or_else(|| self.unsupported(expr.span, format!("Could not find storage for {}", name)))
+    }
+
+    /// Builds an expression and converts the result to `to`.
+    fn build_coerced(&mut self, expr: &Expr, to: &Types) -> Result<BasicValueEnum<'ctx>, OSLCompilerError> {
+        let from = self.expr_type(expr)?;
+        let value = self.build_expression(expr)?;
+        self.coerce(value, &from, to, expr.span)
+    }
+
+    /// Builds the initial value of a declaration, which may be an initializer list.
+    fn build_initializer(&mut self, value: &Expr, var_type: &Types) -> Result<BasicValueEnum<'ctx>, OSLCompilerError> {
+        let (values, element) = match (&value.node, var_type) {
+            (Expr_::InitializerList(values), Types::Array(element, _)) => (values, element),
+            _ => return self.build_coerced(value, var_type),
+        };
+
+        let mut array = self.memory_type(var_type, value.span)?.into_array_type().get_undef();
+        for (index, value) in values.iter().enumerate() {
+            let element_value = self.build_coerced(value, element)?;
+            let element_value = self.to_memory(element_value, element);
+            array = self.builder.build_insert_value(array, element_value, index as u32, "").unwrap().into_array_value();
+        }
+
+        Ok(array.into())
+    }
+
+    /// Converts `value` from one type to another following OSL's implicit and explicit casts.
+    fn coerce(&self, value: BasicValueEnum<'ctx>, from: &Types, to: &Types, span: Span) -> Result<BasicValueEnum<'ctx>, OSLCompilerError> {
+        if from == to || (is_triple(from) && is_triple(to)) {
+            return Ok(value);
+        }
+
+        match (from, to) {
+            (Types::Int, Types::Float) => {
+                Ok(self.builder.build_signed_int_to_float(value.into_int_value(), self.context.f32_type(), "").into())
+            },
+            (Types::Float, Types::Int) => {
+                Ok(self.builder.build_float_to_signed_int(value.into_float_value(), self.context.i32_type(), "").into())
+            },
+            (Types::Int, _) |
+            (Types::Float, _) if is_triple(to) => {
+                let value = self.coerce(value, from, &Types::Float, span)?;
+                Ok(self.splat(value.into_float_value(), 3).into())
+            },
+            (Types::Int, Types::Matrix) |
+            (Types::Float, Types::Matrix) => {
+                // A scalar becomes a uniform scale matrix
+                let value = self.coerce(value, from, &Types::Float, span)?;
+                let mut matrix = self.context.f32_type().vec_type(16).const_zero();
+                for index in [0, 5, 10, 15] {
+                    matrix = self.builder.build_insert_element(matrix, value, self.const_int(index), "");
+                }
+                Ok(matrix.into())
+            },
+            _ => Err(self.unsupported(span, format!("Cannot convert {:?} to {:?}", from, to))),
+        }
+    }
+
+    fn build_expression(&mut self, expr: &Expr) -> Result<BasicValueEnum<'ctx>, OSLCompilerError> {
+        match &expr.node {
+            Expr_::IntLiteral(i) => Ok(self.const_int(*i as i32).into()),
+
+            Expr_::FloatLiteral(f) => Ok(self.const_float(*f as f32).into()),
+
+            Expr_::StringLiteral(s) => {
+                let string = self.builder.build_global_string_ptr(s, "str");
+                Ok(string.as_pointer_value().into())
+            },
+
+            Expr_::Ident(s) => {
+                let pointer = self.get_variable(expr, s)?;
+                let value_type = self.expr_type(expr)?;
+                Ok(self.load(pointer, &value_type))
+            },
+
+            Expr_::GlobalVariable(g) => {
+                let (pointer, global_type) = self.build_global(g, expr.span)?;
+                Ok(self.load(pointer, &global_type))
+            },
+
+            Expr_::Assignment(lhs, rhs) => {
+                let lhs_type = self.expr_type(lhs)?;
+                let value = self.build_coerced(rhs, &lhs_type)?;
+                self.store_lvalue(lhs, value, &lhs_type)?;
+                Ok(value)
+            },
+
+            Expr_::ExplicitCast {cast_type, cast_expr} => {
+                let to = get_var_type_value(cast_type).unwrap();
+                self.build_coerced(cast_expr, &to)
+            },
+
+            Expr_::AccessExpression {lhs, value, ..} => {
+                // Arrays and structs are indexed in memory, triples and matrices as values
+                match self.expr_type(lhs)? {
+                    // Struct values that aren't stored anywhere, like call results, are taken apart
+                    Types::Struct(name) if !is_addressable(lhs) => {
+                        let (index, field_type) = self.struct_field(&name, value)?;
+                        let lhs_value = self.build_expression(lhs)?.into_struct_value();
+                        let field = self.builder.build_extract_value(lhs_value, index, "").unwrap();
+                        return Ok(self.from_memory(field, &field_type));
+                    },
+                    Types::Array(..) |
+                    Types::Struct(_) => {
+                        let (pointer, element_type) = self.build_lvalue(expr)?;
+                        return Ok(self.load(pointer, &element_type));
+                    },
+                    _ => {},
+                }
+
+                let lhs_value = self.build_expression(lhs)?.into_vector_value();
+                let index = self.access_index(expr)?;
+                Ok(self.builder.build_extract_element(lhs_value, index, ""))
+            },
+
+            Expr_::BinaryExpression(op, lhs, rhs) => {
+                match op {
+                    Operators::LessThan |
+                    Operators::LessThanEqual |
+                    Operators::GreaterThan |
+                    Operators::GreaterThanEqual |
+                    Operators::Equals |
+                    Operators::NotEqual |
+                    Operators::LogicalAnd |
+                    Operators::LogicalOr => {
+                        let value = self.build_bool(expr)?;
+                        Ok(self.bool_to_int(value).into())
+                    },
+
+                    Operators::AddAssign |
+                    Operators::SubtractAssign |
+                    Operators::MultiplyAssign |
+                    Operators::DivideAssign |
+                    Operators::BitwiseAndAssign |
+                    Operators::BitwiseOrAssign |
+                    Operators::BitwiseXorAssign |
+                    Operators::ShiftLeftAssign |
+                    Operators::ShiftRightAssign => {
+                        // The target is only evaluated once, `a[i++] += 1` bumps `i` a single time
+                        let (pointer, lhs_type) = self.build_lvalue(lhs)?;
+                        let lhs_value = self.load(pointer, &lhs_type);
+                        let rhs_type = self.expr_type(rhs)?;
+                        let rhs_value = self.build_expression(rhs)?;
+                        let value = self.build_binary_values(expr, &compound_operator(op), (lhs_value, &lhs_type), (rhs_value, &rhs_type))?;
+                        let value = self.coerce(value, &self.expr_type(expr)?, &lhs_type, expr.span)?;
+                        self.store(pointer, value, &lhs_type);
+                        Ok(value)
+                    },
+
+                    _ => self.build_binary(expr, op, lhs, rhs),
+                }
+            },
+
+            Expr_::PreUnaryExpression(op, rhs) => {
+                match op {
+                    Operators::Minus => {
+                        let value = self.build_expression(rhs)?;
+                        Ok(match value {
+                            BasicValueEnum::IntValue(v) => self.builder.build_int_neg(v, "").into(),
+                            BasicValueEnum::FloatValue(v) => self.builder.build_float_neg(v, "").into(),
+                            BasicValueEnum::VectorValue(v) => self.builder.build_float_neg(v, "").into(),
+                            _ => return Err(self.unsupported(expr.span, String::from("Cannot negate this value"))),
+                        })
+                    },
+                    Operators::BitwiseCompliment => {
+                        let value = self.build_expression(rhs)?.into_int_value();
+                        Ok(self.builder.build_not(value, "").into())
+                    },
+                    Operators::Not => {
+                        let value = self.build_bool(expr)?;
+                        Ok(self.bool_to_int(value).into())
+                    },
+                    Operators::Increment |
+                    Operators::Decrement => {
+                        let (_, new_value) = self.build_increment(op, rhs)?;
+                        Ok(new_value)
+                    },
+                    _ => Err(self.unsupported(expr.span, format!("Unsupported unary operator {:?}", op))),
+                }
+            },
+
+            Expr_::PostUnaryExpression(op, lhs) => {
+                let (old_value, _) = self.build_increment(op, lhs)?;
+                Ok(old_value)
+            },
+
+            Expr_::FunctionCallExpression {name, arguments} => {
+                self.build_call(expr, name, arguments)
+            },
+
+            Expr_::TernaryExpression {condition, if_true, if_false} => {
+                self.build_ternary(expr, condition, if_true, if_false)
+            },
+
+            Expr_::PointConstructor {point_type, x, y, z, space} => {
+                let mut arguments = Vec::new();
+                if let Some(space) = space {
+                    arguments.push(*space.clone());
+                }
+                arguments.extend([*x.clone(), *y.clone(), *z.clone()]);
+                self.build_call(expr, point_type, &arguments)
+            },
+
+            _ => Err(self.unsupported(expr.span, String::from("Unsupported expression"))),
+        }
+    }
+
+    /// Only the branch that was picked is evaluated, so both get a block of their own.
+    fn build_ternary(&mut self, expr: &Expr, condition: &Expr, if_true: &Expr, if_false: &Expr) -> Result<BasicValueEnum<'ctx>, OSLCompilerError> {
+        let result_type = self.expr_type(expr)?;
+        let condition = self.build_bool(condition)?;
+        let true_block = self.append_block("ternary_true");
+        let false_block = self.append_block("ternary_false");
+        let end_block = self.append_block("ternary_end");
+        self.builder.build_conditional_branch(condition, true_block, false_block);
+
+        let mut incoming = Vec::new();
+        for (block, value) in [(true_block, if_true), (false_block, if_false)] {
+            block.move_after(self.builder.get_insert_block().unwrap()).unwrap();
+            self.builder.position_at_end(block);
+            let value = self.build_coerced(value, &result_type)?;
+            incoming.push((value, self.builder.get_insert_block().unwrap()));
+            self.builder.build_unconditional_branch(end_block);
+        }
+
+        end_block.move_after(self.builder.get_insert_block().unwrap()).unwrap();
+        self.builder.position_at_end(end_block);
+        let phi = self.builder.build_phi(self.value_type(&result_type, expr.span)?, "");
+        for (value, block) in &incoming {
+            phi.add_incoming(&[(value as &dyn BasicValue, *block)]);
+        }
+
+        Ok(phi.as_basic_value())
+    }
+
+    /// Builds a pointer to the storage an assignable expression refers to, along with the type
+    /// stored there.
+    fn build_lvalue(&mut self, expr: &Expr) -> Result<(PointerValue<'ctx>, Types), OSLCompilerError> {
+        match &expr.node {
+            Expr_::Ident(s) => Ok((self.get_variable(expr, s)?, self.expr_type(expr)?)),
+
+            Expr_::GlobalVariable(g) => self.build_global(g, expr.span),
+
+            Expr_::AccessExpression {lhs, value, ..} => {
+                let (base, lhs_type) = self.build_lvalue(lhs)?;
+                if let Types::Struct(name) = &lhs_type {
+                    let (index, field_type) = self.struct_field(name, value)?;
+                    let pointer = self.builder.build_struct_gep(base, index, "")
+                        .map_err(|_| OSLCompilerError::BackendError(format!("Invalid field index {} of {}", index, name)))?;
+                    return Ok((pointer, field_type));
+                }
+
+                let index = self.access_index(expr)?;
+                let (indexes, element_type) = match lhs_type {
+                    // Unsized arrays already point at their first element
+                    Types::Array(element, None) => (vec![index], *element),
+                    Types::Array(element, Some(_)) => (vec![self.const_int(0), index], *element),
+                    _ => (vec![self.const_int(0), index], Types::Float),
+                };
+                let pointer = unsafe {
+                    self.builder.build_in_bounds_gep(base, &indexes, "")
+                };
+                Ok((pointer, element_type))
+            },
+
+            _ => Err(self.unsupported(expr.span, String::from("This expression cannot be assigned to"))),
+        }
+    }
+
+    fn store_lvalue(&mut self, expr: &Expr, value: BasicValueEnum<'ctx>, value_type: &Types) -> Result<(), OSLCompilerError> {
+        let (pointer, pointer_type) = self.build_lvalue(expr)?;
+        let value = self.coerce(value, value_type, &pointer_type, expr.span)?;
+        self.store(pointer, value, &pointer_type);
+
+        Ok(())
+    }
+
+    /// Element or component index for `a[i]`, `a.x` and `m[i]` style accesses. Constant indexes
+    /// were bounds checked by the type checker.
+    fn access_index(&mut self, expr: &Expr) -> Result<IntValue<'ctx>, OSLCompilerError> {
+        match &expr.node {
+            Expr_::AccessExpression {value, dot: false, ..} => Ok(self.build_coerced(value, &Types::Int)?.into_int_value()),
+            Expr_::AccessExpression {value, dot: true, ..} => match get_ident_value(value).as_deref() {
+                Some("x") | Some("r") => Ok(self.const_int(0)),
+                Some("y") | Some("g") => Ok(self.const_int(1)),
+                Some("z") | Some("b") => Ok(self.const_int(2)),
+                _ => Err(self.unsupported(value.span, String::from("Unknown component"))),
+            },
+            _ => Err(self.unsupported(expr.span, String::from("Not a component access"))),
+        }
+    }
+
+    fn build_increment(&mut self, op: &Operators, expr: &Expr) -> Result<(BasicValueEnum<'ctx>, BasicValueEnum<'ctx>), OSLCompilerError> {
+        let (pointer, value_type) = self.build_lvalue(expr)?;
+        let old_value = self.load(pointer, &value_type);
+
+        let new_value: BasicValueEnum = match (old_value, op) {
+            (BasicValueEnum::IntValue(v), Operators::Increment) => self.builder.build_int_add(v, self.const_int(1), "").into(),
+            (BasicValueEnum::IntValue(v), Operators::Decrement) => self.builder.build_int_sub(v, self.const_int(1), "").into(),
+            (BasicValueEnum::FloatValue(v), Operators::Increment) => self.builder.build_float_add(v, self.const_float(1.0), "").into(),
+            (BasicValueEnum::FloatValue(v), Operators::Decrement) => self.builder.build_float_sub(v, self.const_float(1.0), "").into(),
+            _ => return Err(self.unsupported(expr.span, format!("Cannot apply {:?} to type {:?}", op, value_type))),
+        };
+
+        self.store(pointer, new_value, &value_type);
+
+        Ok((old_value, new_value))
+    }
+
+    fn bool_to_int(&self, value: IntValue<'ctx>) -> IntValue<'ctx> {
+        self.builder.build_int_z_extend(value, self.context.i32_type(), "")
+    }
+
+    /// Builds an expression as an `i1`, e.g. for use as a condition.
+    fn build_bool(&mut self, expr: &Expr) -> Result<IntValue<'ctx>, OSLCompilerError> {
+        match &expr.node {
+            Expr_::BinaryExpression(op @ (Operators::LogicalAnd | Operators::LogicalOr), lhs, rhs) => {
+                // The right hand side is only evaluated when it decides the result
+                let lhs_value = self.build_bool(lhs)?;
+                let lhs_block = self.builder.get_insert_block().unwrap();
+                let rhs_block = self.append_block("rhs");
+                let merge_block = self.append_block("logic");
+
+                match op {
+                    Operators::LogicalAnd => self.builder.build_conditional_branch(lhs_value, rhs_block, merge_block),
+                    _ => self.builder.build_conditional_branch(lhs_value, merge_block, rhs_block),
+                };
+
+                self.builder.position_at_end(rhs_block);
+                let rhs_value = self.build_bool(rhs)?;
+                let rhs_end_block = self.builder.get_insert_block().unwrap();
+                self.builder.build_unconditional_branch(merge_block);
+
+                merge_block.move_after(rhs_end_block).unwrap();
+                self.builder.position_at_end(merge_block);
+                let short_circuit = self.context.bool_type().const_int(matches!(op, Operators::LogicalOr) as u64, false);
+                let phi = self.builder.build_phi(self.context.bool_type(), "");
+                phi.add_incoming(&[(&short_circuit, lhs_block), (&rhs_value, rhs_end_block)]);
+
+                return Ok(phi.as_basic_value().into_int_value());
+            },
+
+            Expr_::BinaryExpression(op @ (Operators::LessThan |
+                                          Operators::LessThanEqual |
+                                          Operators::GreaterThan |
+                                          Operators::GreaterThanEqual |
+                                          Operators::Equals |
+                                          Operators::NotEqual), lhs, rhs) => {
+                return self.build_comparison(expr, op, lhs, rhs);
+            },
+
+            Expr_::PreUnaryExpression(Operators::Not, rhs) => {
+                let value = self.build_bool(rhs)?;
+                return Ok(self.builder.build_not(value, ""));
+            },
+
+            _ => {},
+        }
+
+        // Any other value is true when it is non-zero
+        let value_type = self.expr_type(expr)?;
+        let value = self.build_expression(expr)?;
+        match value {
+            BasicValueEnum::IntValue(v) => {
+                Ok(self.builder.build_int_compare(IntPredicate::NE, v, self.const_int(0), ""))
+            },
+            BasicValueEnum::FloatValue(v) => {
+                Ok(self.builder.build_float_compare(FloatPredicate::UNE, v, self.const_float(0.0), ""))
+            },
+            _ => Err(OSLCompilerError::InvalidCondition {
+                expr: Item::new(expr.span, format!("{:?}", value_type)),
+            }),
+        }
+    }
+
+    fn build_comparison(&mut self, expr: &Expr, op: &Operators, lhs: &Expr, rhs: &Expr) -> Result<IntValue<'ctx>, OSLCompilerError> {
+        let lhs_type = self.expr_type(lhs)?;
+        let rhs_type = self.expr_type(rhs)?;
+        let operand_type = match (&lhs_type, &rhs_type) {
+            (Types::String, Types::String) => Types::String,
+            _ => unify_types(&[lhs_type.clone(), rhs_type.clone()], false),
+        };
+
+        let lhs_value = self.build_coerced(lhs, &operand_type)?;
+        let rhs_value = self.build_coerced(rhs, &operand_type)?;
+
+        let float_predicate = match op {
+            Operators::LessThan => FloatPredicate::OLT,
+            Operators::LessThanEqual => FloatPredicate::OLE,
+            Operators::GreaterThan => FloatPredicate::OGT,
+            Operators::GreaterThanEqual => FloatPredicate::OGE,
+            Operators::Equals => FloatPredicate::OEQ,
+            _ => FloatPredicate::UNE,
+        };
+        let int_predicate = match op {
+            Operators::LessThan => IntPredicate::SLT,
+            Operators::LessThanEqual => IntPredicate::SLE,
+            Operators::GreaterThan => IntPredicate::SGT,
+            Operators::GreaterThanEqual => IntPredicate::SGE,
+            Operators::Equals => IntPredicate::EQ,
+            _ => IntPredicate::NE,
+        };
+
+        Ok(match (lhs_value, rhs_value) {
+            (BasicValueEnum::IntValue(l), BasicValueEnum::IntValue(r)) => {
+                self.builder.build_int_compare(int_predicate, l, r, "")
+            },
+            (BasicValueEnum::FloatValue(l), BasicValueEnum::FloatValue(r)) => {
+                self.builder.build_float_compare(float_predicate, l, r, "")
+            },
+            (BasicValueEnum::VectorValue(l), BasicValueEnum::VectorValue(r)) => {
+                // Triples and matrices are equal when all of their components are
+                let components = self.builder.build_float_compare(float_predicate, l, r, "");
+                let size = components.get_type().get_size();
+                let mut result = self.builder.build_extract_element(components, self.const_int(0), "").into_int_value();
+                for index in 1..size {
+                    let component = self.builder.build_extract_element(components, self.const_int(index as i32), "").into_int_value();
+                    result = match op {
+                        Operators::Equals => self.builder.build_and(result, component, ""),
+                        _ => self.builder.build_or(result, component, ""),
+                    };
+                }
+                result
+            },
+            (BasicValueEnum::PointerValue(l), BasicValueEnum::PointerValue(r)) => {
+                let strcmp = self.libc_function("strcmp", self.context.i32_type().into(), &[l.get_type().into(), r.get_type().into()]);
+                let order = self.builder.build_call(strcmp, &[l.into(), r.into()], "")
+                    .try_as_basic_value().left().unwrap().into_int_value();
+                self.builder.build_int_compare(int_predicate, order, self.const_int(0), "")
+            },
+            _ => return Err(self.unsupported(expr.span, format!("Cannot compare values of type {:?}", operand_type))),
+        })
+    }
+
+    fn build_binary(&mut self, expr: &Expr, op: &Operators, lhs: &Expr, rhs: &Expr) -> Result<BasicValueEnum<'ctx>, OSLCompilerError> {
+        let lhs_type = self.expr_type(lhs)?;
+        let rhs_type = self.expr_type(rhs)?;
+        let lhs_value = self.build_expression(lhs)?;
+        let rhs_value = self.build_expression(rhs)?;
+        self.build_binary_values(expr, op, (lhs_value, &lhs_type), (rhs_value, &rhs_type))
+    }
+
+    fn build_binary_values(&mut self, expr: &Expr, op: &Operators,
+                           (lhs_value, lhs_type): (BasicValueEnum<'ctx>, &Types),
+                           (rhs_value, rhs_type): (BasicValueEnum<'ctx>, &Types)) -> Result<BasicValueEnum<'ctx>, OSLCompilerError> {
+        let result_type = self.expr_type(expr)?;
+        if result_type == Types::Matrix {
+            return self.build_matrix_binary(expr, op, (lhs_value, lhs_type), (rhs_value, rhs_type));
+        }
+
+        let lhs_value = self.coerce(lhs_value, lhs_type, &result_type, expr.span)?;
+        let rhs_value = self.coerce(rhs_value, rhs_type, &result_type, expr.span)?;
+
+        let value = match (lhs_value, rhs_value) {
+            (BasicValueEnum::IntValue(l), BasicValueEnum::IntValue(r)) => self.int_binary(op, l, r).map(|v| v.into()),
+            (BasicValueEnum::FloatValue(l), BasicValueEnum::FloatValue(r)) => self.float_binary(op, l, r).map(|v| v.into()),
+            (BasicValueEnum::VectorValue(l), BasicValueEnum::VectorValue(r)) => self.float_binary(op, l, r).map(|v| v.into()),
+            _ => None,
+        };
+
+        value.ok_or_else(|| self.unsupported(expr.span, format!("Unsupported binary operator {:?} for type {:?}", op, result_type)))
+    }
+
+    fn int_binary<T: IntMathValue<'ctx>>(&self, op: &Operators, lhs: T, rhs: T) -> Option<T> {
+        Some(match op {
+            Operators::Plus => self.builder.build_int_add(lhs, rhs, ""),
+            Operators::Minus => self.builder.build_int_sub(lhs, rhs, ""),
+            Operators::Multiply => self.builder.build_int_mul(lhs, rhs, ""),
+            Operators::Divide => self.builder.build_int_signed_div(lhs, rhs, ""),
+            Operators::Mod => self.builder.build_int_signed_rem(lhs, rhs, ""),
+            Operators::BitwiseAnd => self.builder.build_and(lhs, rhs, ""),
+            Operators::BitwiseOr => self.builder.build_or(lhs, rhs, ""),
+            Operators::BitwiseXor => self.builder.build_xor(lhs, rhs, ""),
+            Operators::ShiftLeft => self.builder.build_left_shift(lhs, rhs, ""),
+            Operators::ShiftRight => self.builder.build_right_shift(lhs, rhs, true, ""),
+            _ => return None,
+        })
+    }
+
+    fn float_binary<T: FloatMathValue<'ctx>>(&self, op: &Operators, lhs: T, rhs: T) -> Option<T> {
+        Some(match op {
+            Operators::Plus => self.builder.build_float_add(lhs, rhs, ""),
+            Operators::Minus => self.builder.build_float_sub(lhs, rhs, ""),
+            Operators::Multiply => self.builder.build_float_mul(lhs, rhs, ""),
+            Operators::Divide => self.builder.build_float_div(lhs, rhs, ""),
+            Operators::Mod => self.builder.build_float_rem(lhs, rhs, ""),
+            _ => return None,
+        })
+    }
+
+    fn build_matrix_binary(&mut self, expr: &Expr, op: &Operators,
+                           (lhs, lhs_type): (BasicValueEnum<'ctx>, &Types),
+                           (rhs, rhs_type): (BasicValueEnum<'ctx>, &Types)) -> Result<BasicValueEnum<'ctx>, OSLCompilerError> {
+        // Scaling by a scalar is done per component
+        let scalar = |value: BasicValueEnum<'ctx>, value_type: &Types| -> Result<VectorValue<'ctx>, OSLCompilerError> {
+            let value = self.coerce(value, value_type, &Types::Float, expr.span)?;
+            Ok(self.splat(value.into_float_value(), 16))
+        };
+
+        let value = match (op, is_scalar(lhs_type), is_scalar(rhs_type)) {
+            (Operators::Multiply, false, true) => {
+                let rhs = scalar(rhs, rhs_type)?;
+                self.builder.build_float_mul(lhs.into_vector_value(), rhs, "")
+            },
+            (Operators::Multiply, true, false) => {
+                let lhs = scalar(lhs, lhs_type)?;
+                self.builder.build_float_mul(lhs, rhs.into_vector_value(), "")
+            },
+            (Operators::Multiply, false, false) => {
+                self.build_matrix_product(lhs.into_vector_value(), rhs.into_vector_value())
+            },
+            (Operators::Divide, false, true) => {
+                let rhs = scalar(rhs, rhs_type)?;
+                self.builder.build_float_div(lhs.into_vector_value(), rhs, "")
+            },
+            (Operators::Divide, true, false) => {
+                let lhs = scalar(lhs, lhs_type)?;
+                let inverse = self.build_matrix_inverse(rhs.into_vector_value());
+                self.builder.build_float_mul(lhs, inverse, "")
+            },
+            (Operators::Divide, false, false) => {
+                let inverse = self.build_matrix_inverse(rhs.into_vector_value());
+                self.build_matrix_product(lhs.into_vector_value(), inverse)
+            },
+            _ => return Err(self.unsupported(expr.span, format!("Unsupported binary operator {:?} for matrices", op))),
+        };
+
+        Ok(value.into())
+    }
+
+    fn matrix_elements(&self, matrix: VectorValue<'ctx>) -> Vec<FloatValue<'ctx>> {
+        (0..16)
+            .map(|index| self.builder.build_extract_element(matrix, self.const_int(index), "").into_float_value())
+            .collect()
+    }
+
+    fn matrix_from_elements(&self, elements: &[FloatValue<'ctx>]) -> VectorValue<'ctx> {
+        let mut matrix = self.context.f32_type().vec_type(16).get_undef();
+        for (index, element) in elements.iter().enumerate() {
+            matrix = self.builder.build_insert_element(matrix, *element, self.const_int(index as i32), "");
+        }
+
+        matrix
+    }
+
+    /// Row-major matrix product.
+    fn build_matrix_product(&self, lhs: VectorValue<'ctx>, rhs: VectorValue<'ctx>) -> VectorValue<'ctx> {
+        let a = self.matrix_elements(lhs);
+        let b = self.matrix_elements(rhs);
+
+        let mut elements = Vec::new();
+        for row in 0..4 {
+            for column in 0..4 {
+                let mut sum = self.builder.build_float_mul(a[row * 4], b[column], "");
+                for k in 1..4 {
+                    let product = self.builder.build_float_mul(a[row * 4 + k], b[k * 4 + column], "");
+                    sum = self.builder.build_float_add(sum, product, "");
                 }
+                elements.push(sum);
+            }
+        }
+
+        self.matrix_from_elements(&elements)
+    }
+
+    /// Inverts a matrix through its cofactors.
+    fn build_matrix_inverse(&self, matrix: VectorValue<'ctx>) -> VectorValue<'ctx> {
+        let m = self.matrix_elements(matrix);
+
+        let minor = |skip_row: usize, skip_column: usize| -> FloatValue<'ctx> {
+            let rows: Vec<usize> = (0..4).filter(|r| *r != skip_row).collect();
+            let columns: Vec<usize> = (0..4).filter(|c| *c != skip_column).collect();
+            let at = |r: usize, c: usize| m[rows[r] * 4 + columns[c]];
+
+            let mut determinant: Option<FloatValue<'ctx>> = None;
+            for c in 0..3 {
+                let (c1, c2) = ((c + 1) % 3, (c + 2) % 3);
+                let first = self.builder.build_float_mul(at(1, c1), at(2, c2), "");
+                let second = self.builder.build_float_mul(at(1, c2), at(2, c1), "");
+                let cofactor = self.builder.build_float_sub(first, second, "");
+                let term = self.builder.build_float_mul(at(0, c), cofactor, "");
+                determinant = Some(match determinant {
+                    None => term,
+                    Some(sum) => self.builder.build_float_add(sum, term, ""),
+                });
             }
+            determinant.unwrap()
+        };
 
-            builder.build_return(None);
+        let mut cofactors = Vec::new();
+        for row in 0..4 {
+            for column in 0..4 {
+                let value = minor(row, column);
+                cofactors.push(match (row + column) % 2 {
+                    0 => value,
+                    _ => self.builder.build_float_neg(value, ""),
+                });
+            }
+        }
 
-            return Some(module);
+        let mut determinant = self.builder.build_float_mul(m[0], cofactors[0], "");
+        for column in 1..4 {
+            let term = self.builder.build_float_mul(m[column], cofactors[column], "");
+            determinant = self.builder.build_float_add(determinant, term, "");
+        }
 
+        // The inverse is the transposed cofactor matrix divided by the determinant
+        let mut elements = Vec::new();
+        for row in 0..4 {
+            for column in 0..4 {
+                elements.push(self.builder.build_float_div(cofactors[column * 4 + row], determinant, ""));
+            }
+        }
 
-        },
-        _ => {}
+        self.matrix_from_elements(&elements)
     }
 
-    None
+    //===============
+    // Calls
+    //===============
 
-}
+    fn build_call(&mut self, expr: &Expr, name: &Expr, arguments: &Vec<Expr>) -> Result<BasicValueEnum<'ctx>, OSLCompilerError> {
+        if let Expr_::VariableType(t) = &name.node {
+            return self.build_constructor(expr, t, arguments);
+        }
 
-fn build_expression<'ctx> (context: &'ctx Context,
-                           builder: &'ctx Builder,
-                           module: &'ctx Module,
-                           symbol_table: &'ctx SymbolTable, 
-                           expr: &'ctx Expr_
-                           ) -> Option<BasicValueEnum<'ctx>> {
-    return match expr {
-        Expr_::Ident(s) => {
-            println!("Fetching {}", s);
-            let global_val = module.get_global(s)?;
-            println!("{:?}", builder.build_load(global_val.as_pointer_value(), "In"));
-            let val = builder.build_load(global_val.as_pointer_value(), "");
-            println!("Done");
-            Some(val)
-
-        },
-        Expr_::FloatLiteral(f) => {
-            Some(context.f32_type().const_float(*f).into())
-        },
-        Expr_::IntLiteral(i) => {
-            Some(context.i32_type().const_int(*i as u64, true).into())
-        },
-        Expr_::BinaryExpression(op, lhs, rhs) => {
-            match op {
-                Operators::Multiply => {
-                    match (get_expr_type(&lhs, symbol_table).expect("Placeholder"),
-                           get_expr_type(&rhs, symbol_table).expect("Placeholder")) {
-                        (Types::Float, Types::Float) => {
-                            let lhs_value = build_expression(context, builder, module, symbol_table, &lhs.node)?.into_float_value();
-                            let rhs_value = build_expression(context, builder, module, symbol_table, &rhs.node)?.into_float_value();
-                            Some(builder.build_float_mul(
-                                    lhs_value,
-                                    rhs_value,
-                                    "").into())
-                        },
-                        (Types::Color, Types::Float) |
-                        (Types::Normal, Types::Float) => {
-                            let lhs_value = build_expression(context, builder, module, symbol_table, &lhs.node)?.into_vector_value();
-                            let rhs_value = build_expression(context, builder, module, symbol_table, &rhs.node)?.into_float_value();
+        let function_name = match get_ident_value(name) {
+            Some(s) => s,
+            None => return Err(self.unsupported(name.span, String::from("Invalid function call"))),
+        };
+
+        if let Some(Symbols::Struct {..}) = self.symbol_table.find_reference(name.span, function_name.clone()) {
+            return self.build_struct_constructor(expr, &function_name, arguments);
+        }
 
-                            let i32_type = context.i32_type();
+        if function_name == "arraylength" {
+            return Ok(self.build_array_length(&arguments[0])?.into());
+        }
 
-                            let x = builder.build_extract_element(lhs_value, i32_type.const_int(0, false), "");
-                            let y = builder.build_extract_element(lhs_value, i32_type.const_int(1, false), "");
-                            let z = builder.build_extract_element(lhs_value, i32_type.const_int(2, false), "");
+        if let Some(function) = self.get_function(name, arguments)?.cloned() {
+            return self.build_user_call(expr, &function, arguments);
+        }
 
-                            let x = builder.build_float_mul(x.into_float_value(), rhs_value, "");
-                            let y = builder.build_float_mul(y.into_float_value(), rhs_value, "");
-                            let z = builder.build_float_mul(z.into_float_value(), rhs_value, "");
+        let result_type = self.expr_type(expr)?;
 
-                            let lhs_value = builder.build_insert_element(lhs_value, x, i32_type.const_int(0, false), "");
-                            let lhs_value = builder.build_insert_element(lhs_value, y, i32_type.const_int(1, false), "");
-                            let lhs_value = builder.build_insert_element(lhs_value, z, i32_type.const_int(2, false), "");
-                            
-                            Some(lhs_value.into())
-                        }
+        // Arguments share one type, except for the scalar results of geometric functions
+        let operand_type = match function_name.as_str() {
+            "length" | "distance" | "dot" => Types::Vector,
+            _ => result_type.clone(),
+        };
+        let mut values = Vec::new();
+        for arg in arguments {
+            values.push(self.build_coerced(arg, &operand_type)?);
+        }
 
-                        _ => {None}
+        self.build_builtin(name, &function_name, &result_type, &values)
+    }
 
-                    }
+    fn build_array_length(&self, array: &Expr) -> Result<IntValue<'ctx>, OSLCompilerError> {
+        match (self.expr_type(array)?, &array.node) {
+            (Types::Array(_, Some(length)), _) => Ok(self.const_int(length as i32)),
+            // Only parameters can be unsized, and their length is passed along with them
+            (Types::Array(_, None), Expr_::Ident(name)) => {
+                let symbol = self.symbol_table.get_reference(array.span, name.clone());
+                self.array_lengths.get(&(name.clone(), symbol.get_span().lo))
+                    .copied()
+                    .ok_or_else(|| self.unsupported(array.span, format!("Could not find the length of {}", name)))
+            },
+            (array_type, _) => Err(self.unsupported(array.span, format!("Values of type {:?} have no length", array_type))),
+        }
+    }
+
+    fn build_builtin(&mut self, name: &Expr, function_name: &str, result_type: &Types, values: &[BasicValueEnum<'ctx>]) -> Result<BasicValueEnum<'ctx>, OSLCompilerError> {
+        let is_int = *result_type == Types::Int;
+        let one = self.float_like(values.first().copied(), 1.0);
+        let zero = self.float_like(values.first().copied(), 0.0);
+
+        Ok(match (function_name, values.len()) {
+            ("abs", 1) | ("fabs", 1) if is_int => {
+                let is_poison = self.context.bool_type().const_zero();
+                self.call_intrinsic("llvm.abs", &[values[0], is_poison.into()])
+            },
+            ("abs", 1) | ("fabs", 1) => self.call_intrinsic("llvm.fabs", values),
+            ("sign", 1) if is_int => {
+                let value = values[0].into_int_value();
+                let positive = self.builder.build_int_compare(IntPredicate::SGT, value, self.const_int(0), "");
+                let negative = self.builder.build_int_compare(IntPredicate::SLT, value, self.const_int(0), "");
+                let positive = self.bool_to_int(positive);
+                let negative = self.bool_to_int(negative);
+                self.builder.build_int_sub(positive, negative, "").into()
+            },
+            ("sign", 1) => {
+                let positive = self.float_select(FloatPredicate::OGT, values[0], zero, one, zero);
+                let minus_one = self.float_like(Some(values[0]), -1.0);
+                self.float_select(FloatPredicate::OLT, values[0], zero, minus_one, positive)
+            },
+            ("min", 2) if is_int => self.call_intrinsic("llvm.smin", values),
+            ("max", 2) if is_int => self.call_intrinsic("llvm.smax", values),
+            ("clamp", 3) if is_int => {
+                let value = self.call_intrinsic("llvm.smax", &values[0..2]);
+                self.call_intrinsic("llvm.smin", &[value, values[2]])
+            },
+            ("mod", 2) if is_int => {
+                // The result takes the sign of the divisor
+                let (a, b) = (values[0].into_int_value(), values[1].into_int_value());
+                let remainder = self.builder.build_int_signed_rem(a, b, "");
+                let nonzero = self.builder.build_int_compare(IntPredicate::NE, remainder, self.const_int(0), "");
+                let signs = self.builder.build_xor(remainder, b, "");
+                let differ = self.builder.build_int_compare(IntPredicate::SLT, signs, self.const_int(0), "");
+                let adjust = self.builder.build_and(nonzero, differ, "");
+                let adjusted = self.builder.build_int_add(remainder, b, "");
+                self.builder.build_select(adjust, adjusted, remainder, "")
+            },
+            ("min", 2) => self.call_intrinsic("llvm.minnum", values),
+            ("max", 2) => self.call_intrinsic("llvm.maxnum", values),
+            ("clamp", 3) => {
+                let value = self.call_intrinsic("llvm.maxnum", &values[0..2]);
+                self.call_intrinsic("llvm.minnum", &[value, values[2]])
+            },
+            ("mod", 2) => {
+                let quotient = self.float_op(Operators::Divide, values[0], values[1]);
+                let quotient = self.call_intrinsic("llvm.floor", &[quotient]);
+                let product = self.float_op(Operators::Multiply, values[1], quotient);
+                self.float_op(Operators::Minus, values[0], product)
+            },
+            ("fmod", 2) => self.float_op(Operators::Mod, values[0], values[1]),
+            ("round", 1) => self.call_intrinsic("llvm.round", values),
+            ("trunc", 1) => self.call_intrinsic("llvm.trunc", values),
+            ("floor", 1) => self.call_intrinsic("llvm.floor", values),
+            ("ceil", 1) => self.call_intrinsic("llvm.ceil", values),
+            ("radians", 1) => {
+                let factor = self.float_like(Some(values[0]), std::f32::consts::PI / 180.0);
+                self.float_op(Operators::Multiply, values[0], factor)
+            },
+            ("degrees", 1) => {
+                let factor = self.float_like(Some(values[0]), 180.0 / std::f32::consts::PI);
+                self.float_op(Operators::Multiply, values[0], factor)
+            },
+            ("sin", 1) => self.call_intrinsic("llvm.sin", values),
+            ("cos", 1) => self.call_intrinsic("llvm.cos", values),
+            ("tan", 1) => self.call_libm("tanf", values),
+            ("asin", 1) => self.call_libm("asinf", values),
+            ("acos", 1) => self.call_libm("acosf", values),
+            ("atan", 1) => self.call_libm("atanf", values),
+            ("atan2", 2) => self.call_libm("atan2f", values),
+            ("sinh", 1) => self.call_libm("sinhf", values),
+            ("cosh", 1) => self.call_libm("coshf", values),
+            ("tanh", 1) => self.call_libm("tanhf", values),
+            ("pow", 2) => self.call_intrinsic("llvm.pow", values),
+            ("exp", 1) => self.call_intrinsic("llvm.exp", values),
+            ("exp2", 1) => self.call_intrinsic("llvm.exp2", values),
+            ("log", 1) => self.call_intrinsic("llvm.log", values),
+            ("log2", 1) => self.call_intrinsic("llvm.log2", values),
+            ("sqrt", 1) => self.call_intrinsic("llvm.sqrt", values),
+            ("inversesqrt", 1) => {
+                let root = self.call_intrinsic("llvm.sqrt", values);
+                self.float_op(Operators::Divide, one, root)
+            },
+            ("mix", 3) => {
+                let inverse = self.float_op(Operators::Minus, one, values[2]);
+                let a = self.float_op(Operators::Multiply, values[0], inverse);
+                let b = self.float_op(Operators::Multiply, values[1], values[2]);
+                self.float_op(Operators::Plus, a, b)
+            },
+            ("step", 2) => self.float_select(FloatPredicate::OLT, values[1], values[0], zero, one),
+            ("smoothstep", 3) => {
+                let range = self.float_op(Operators::Minus, values[1], values[0]);
+                let offset = self.float_op(Operators::Minus, values[2], values[0]);
+                let t = self.float_op(Operators::Divide, offset, range);
+                let t = self.call_intrinsic("llvm.maxnum", &[t, zero]);
+                let t = self.call_intrinsic("llvm.minnum", &[t, one]);
+                let two = self.float_like(Some(t), 2.0);
+                let three = self.float_like(Some(t), 3.0);
+                let twice = self.float_op(Operators::Multiply, two, t);
+                let weight = self.float_op(Operators::Minus, three, twice);
+                let square = self.float_op(Operators::Multiply, t, t);
+                self.float_op(Operators::Multiply, square, weight)
+            },
+            ("dot", 2) => self.build_dot(values[0], values[1]).into(),
+            ("length", 1) => {
+                let square = self.build_dot(values[0], values[0]);
+                self.call_intrinsic("llvm.sqrt", &[square.into()])
+            },
+            ("distance", 2) => {
+                let difference = self.float_op(Operators::Minus, values[0], values[1]);
+                let square = self.build_dot(difference, difference);
+                self.call_intrinsic("llvm.sqrt", &[square.into()])
+            },
+            ("cross", 2) => {
+                let (a, b) = (values[0].into_vector_value(), values[1].into_vector_value());
+                let a = self.vector_elements(a);
+                let b = self.vector_elements(b);
+                let mut components = Vec::new();
+                for index in 0..3 {
+                    let (i, j) = ((index + 1) % 3, (index + 2) % 3);
+                    let first = self.builder.build_float_mul(a[i], b[j], "");
+                    let second = self.builder.build_float_mul(a[j], b[i], "");
+                    components.push(self.builder.build_float_sub(first, second, ""));
+                }
+                self.vector_from_elements(&components).into()
+            },
+            ("normalize", 1) => {
+                // The zero vector normalizes to itself
+                let square = self.build_dot(values[0], values[0]);
+                let length = self.call_intrinsic("llvm.sqrt", &[square.into()]).into_float_value();
+                let length = self.splat(length, 3);
+                let normalized = self.float_op(Operators::Divide, values[0], length.into());
+                self.float_select(FloatPredicate::OGT, length.into(), zero, normalized, values[0])
+            },
+            ("reflect", 2) => {
+                // I - 2 * dot(N, I) * N
+                let dot = self.build_dot(values[1], values[0]);
+                let dot = self.builder.build_float_mul(dot, self.const_float(2.0), "");
+                let scaled = self.float_op(Operators::Multiply, self.splat(dot, 3).into(), values[1]);
+                self.float_op(Operators::Minus, values[0], scaled)
+            },
+            ("faceforward", 3) => {
+                // dot(I, Nref) > 0 ? -N : N
+                let dot = self.build_dot(values[1], values[2]);
+                let facing = self.builder.build_float_compare(FloatPredicate::OGT, dot, self.const_float(0.0), "");
+                let flipped = self.builder.build_float_neg(values[0].into_vector_value(), "");
+                self.builder.build_select(facing, flipped.into(), values[0], "")
+            },
+            _ => return Err(self.unsupported(name.span, format!("The function {} is not supported by the LLVM backend", function_name))),
+        })
+    }
+
+    /// A float constant of the same shape as `like`: a scalar, triple or matrix.
+    fn float_like(&self, like: Option<BasicValueEnum<'ctx>>, value: f32) -> BasicValueEnum<'ctx> {
+        let value = self.const_float(value);
+        match like {
+            Some(BasicValueEnum::VectorValue(v)) => {
+                let size = v.get_type().get_size();
+                VectorType::const_vector(&vec![value; size as usize]).into()
+            },
+            _ => value.into(),
+        }
+    }
+
+    fn float_op(&self, op: Operators, lhs: BasicValueEnum<'ctx>, rhs: BasicValueEnum<'ctx>) -> BasicValueEnum<'ctx> {
+        match (lhs, rhs) {
+            (BasicValueEnum::VectorValue(l), BasicValueEnum::VectorValue(r)) => self.float_binary(&op, l, r).unwrap().into(),
+            (l, r) => self.float_binary(&op, l.into_float_value(), r.into_float_value()).unwrap().into(),
+        }
+    }
+
+    /// Picks `then` or `otherwise` per component, depending on how `lhs` compares to `rhs`.
+    fn float_select(&self, predicate: FloatPredicate, lhs: BasicValueEnum<'ctx>, rhs: BasicValueEnum<'ctx>,
+                    then: BasicValueEnum<'ctx>, otherwise: BasicValueEnum<'ctx>) -> BasicValueEnum<'ctx> {
+        match (lhs, rhs) {
+            (BasicValueEnum::VectorValue(l), BasicValueEnum::VectorValue(r)) => {
+                let condition = self.builder.build_float_compare(predicate, l, r, "");
+                self.builder.build_select(condition, then, otherwise, "")
+            },
+            (l, r) => {
+                let condition = self.builder.build_float_compare(predicate, l.into_float_value(), r.into_float_value(), "");
+                self.builder.build_select(condition, then, otherwise, "")
+            },
+        }
+    }
+
+    fn vector_elements(&self, vector: VectorValue<'ctx>) -> Vec<FloatValue<'ctx>> {
+        (0..vector.get_type().get_size())
+            .map(|index| self.builder.build_extract_element(vector, self.const_int(index as i32), "").into_float_value())
+            .collect()
+    }
+
+    fn vector_from_elements(&self, elements: &[FloatValue<'ctx>]) -> VectorValue<'ctx> {
+        let mut vector = self.context.f32_type().vec_type(elements.len() as u32).get_undef();
+        for (index, element) in elements.iter().enumerate() {
+            vector = self.builder.build_insert_element(vector, *element, self.const_int(index as i32), "");
+        }
+
+        vector
+    }
+
+    fn build_dot(&self, lhs: BasicValueEnum<'ctx>, rhs: BasicValueEnum<'ctx>) -> FloatValue<'ctx> {
+        let product = self.builder.build_float_mul(lhs.into_vector_value(), rhs.into_vector_value(), "");
+        let components = self.vector_elements(product);
+        let sum = self.builder.build_float_add(components[0], components[1], "");
+        self.builder.build_float_add(sum, components[2], "")
+    }
+
+    /// Calls an overloaded LLVM intrinsic such as `llvm.sin`, picking the variant for the type
+    /// of the first argument.
+    fn call_intrinsic(&self, name: &str, args: &[BasicValueEnum<'ctx>]) -> BasicValueEnum<'ctx> {
+        let value_type = args[0].get_type();
+        let suffix = match value_type {
+            BasicTypeEnum::VectorType(t) => format!("v{}f32", t.get_size()),
+            BasicTypeEnum::IntType(_) => String::from("i32"),
+            _ => String::from("f32"),
+        };
+        let name = format!("{}.{}", name, suffix);
+
+        let function = match self.module.get_function(&name) {
+            Some(function) => function,
+            None => {
+                let param_types: Vec<BasicMetadataTypeEnum> = args.iter().map(|arg| arg.get_type().into()).collect();
+                self.module.add_function(&name, value_type.fn_type(&param_types, false), None)
+            },
+        };
+
+        let args: Vec<BasicMetadataValueEnum> = args.iter().map(|arg| (*arg).into()).collect();
+        self.builder.build_call(function, &args, "").try_as_basic_value().left().unwrap()
+    }
+
+    /// Calls a single precision C math function, once per component for triples.
+    fn call_libm(&self, name: &str, args: &[BasicValueEnum<'ctx>]) -> BasicValueEnum<'ctx> {
+        let float_type = self.context.f32_type();
+        let param_types = vec![float_type.into(); args.len()];
+        let function = self.libc_function(name, float_type.into(), &param_types);
+
+        let call = |args: Vec<BasicMetadataValueEnum<'ctx>>| -> FloatValue<'ctx> {
+            self.builder.build_call(function, &args, "").try_as_basic_value().left().unwrap().into_float_value()
+        };
+
+        match args[0] {
+            BasicValueEnum::VectorValue(v) => {
+                let mut components = Vec::new();
+                for index in 0..v.get_type().get_size() {
+                    let index = self.const_int(index as i32);
+                    let component_args = args.iter()
+                        .map(|arg| self.builder.build_extract_element(arg.into_vector_value(), index, "").into())
+                        .collect();
+                    components.push(call(component_args));
                 }
-                
-                _ => {None}
+                self.vector_from_elements(&components).into()
+            },
+            _ => call(args.iter().map(|arg| (*arg).into()).collect()).into(),
+        }
+    }
+
+    fn libc_function(&self, name: &str, ret_type: BasicTypeEnum<'ctx>, param_types: &[BasicTypeEnum<'ctx>]) -> FunctionValue<'ctx> {
+        self.module.get_function(name).unwrap_or_else(|| {
+            let param_types: Vec<BasicMetadataTypeEnum> = param_types.iter().map(|t| (*t).into()).collect();
+            self.module.add_function(name, ret_type.fn_type(&param_types, false), None)
+        })
+    }
+
+    fn build_user_call(&mut self, expr: &Expr, function: &Function<'ctx>, arguments: &Vec<Expr>) -> Result<BasicValueEnum<'ctx>, OSLCompilerError> {
+        if arguments.len() != function.params.len() {
+            return Err(self.unsupported(expr.span, format!("Expected {} arguments, received {}", function.params.len(), arguments.len())));
+        }
+
+        // Every argument goes through a temporary so it can be passed by reference
+        let mut args: Vec<BasicMetadataValueEnum> = vec![self.shader_globals.unwrap().into()];
+        let mut temporaries = Vec::new();
+        for (arg, (param_type, _)) in arguments.iter().zip(&function.params) {
+            // Unsized array parameters take the argument as it is, followed by its length
+            let arg_type = match param_type {
+                Types::Array(_, None) => self.expr_type(arg)?,
+                _ => param_type.clone(),
+            };
+
+            if let Types::Array(_, None) = arg_type {
+                let (pointer, _) = self.build_lvalue(arg)?;
+                args.push(pointer.into());
+                args.push(self.build_array_length(arg)?.into());
+                temporaries.push(None);
+                continue;
+            }
+
+            let value = self.build_coerced(arg, &arg_type)?;
+            let temporary = self.build_local_variable(&arg_type, "", arg.span)?;
+            self.store(temporary, value, &arg_type);
+            match param_type {
+                Types::Array(_, None) => {
+                    let first = unsafe {
+                        self.builder.build_in_bounds_gep(temporary, &[self.const_int(0), self.const_int(0)], "")
+                    };
+                    args.push(first.into());
+                    args.push(self.build_array_length(arg)?.into());
+                },
+                _ => args.push(temporary.into()),
+            }
+            temporaries.push(Some((temporary, arg_type)));
+        }
+
+        let call = self.builder.build_call(function.value, &args, "");
+
+        // Copy output parameters back to the caller's variables
+        for ((arg, (_, out)), temporary) in arguments.iter().zip(&function.params).zip(temporaries) {
+            if let (true, Some((temporary, arg_type))) = (*out, temporary) {
+                let value = self.load(temporary, &arg_type);
+                self.store_lvalue(arg, value, &arg_type)?;
             }
         }
-        Expr_::Assignment(lhs, rhs) => {
-            let val = build_expression(context, builder, module, symbol_table, &rhs.node)?;
-            if let Expr_::Ident(name) = &lhs.node {
-               let global_val = module.get_global(name)?;
-               builder.build_store(global_val.as_pointer_value(), val);
+
+        match call.try_as_basic_value().left() {
+            Some(value) => Ok(value),
+            // Void results are never used, the type checker made sure of that
+            None => Ok(self.const_int(0).into()),
+        }
+    }
+
+    fn build_constructor(&mut self, expr: &Expr, constructed: &Types, arguments: &Vec<Expr>) -> Result<BasicValueEnum<'ctx>, OSLCompilerError> {
+        let mut arguments: &[Expr] = arguments;
+
+        // An optional leading string names the space the components are given in
+        if let Some(Expr_::StringLiteral(space)) = arguments.first().map(|arg| &arg.node) {
+            match (constructed, space.as_str()) {
+                (Types::Color, "rgb") => {},
+                (Types::Point, "common") |
+                (Types::Vector, "common") |
+                (Types::Normal, "common") |
+                (Types::Matrix, "common") => {},
+                _ => return Err(self.unsupported(arguments[0].span, format!("The space \"{}\" is not supported by the LLVM backend", space))),
             }
+            arguments = &arguments[1..];
+        }
+
+        match (constructed, arguments.len()) {
+            (_, 1) => self.build_coerced(&arguments[0], constructed),
+            (t, 3) if is_triple(t) => {
+                let mut components = Vec::new();
+                for arg in arguments {
+                    components.push(self.build_coerced(arg, &Types::Float)?.into_float_value());
+                }
+                Ok(self.vector_from_elements(&components).into())
+            },
+            (Types::Matrix, 16) => {
+                let mut components = Vec::new();
+                for arg in arguments {
+                    components.push(self.build_coerced(arg, &Types::Float)?.into_float_value());
+                }
+                Ok(self.matrix_from_elements(&components).into())
+            },
+            _ => Err(self.unsupported(expr.span, format!("Invalid number of arguments to the {:?} constructor", constructed))),
+        }
+    }
+
+    /// Builds a struct from one value per field, in declaration order.
+    fn build_struct_constructor(&mut self, expr: &Expr, name: &str, arguments: &Vec<Expr>) -> Result<BasicValueEnum<'ctx>, OSLCompilerError> {
+        let struct_type = Types::Struct(name.to_owned());
+        let mut value = self.memory_type(&struct_type, expr.span)?.into_struct_type().get_undef();
+        for (index, (arg, (_, field_type))) in arguments.iter().zip(self.struct_fields(name, expr.span)?).enumerate() {
+            let field = self.build_coerced(arg, field_type)?;
+            let field = self.to_memory(field, field_type);
+            value = self.builder.build_insert_value(value, field, index as u32, "").unwrap().into_struct_value();
+        }
 
-            None
-        },
+        Ok(value.into())
+    }
+}
 
-        _ => {None},
+/// Whether an expression refers to storage that can be indexed through a pointer.
+fn is_addressable(expr: &Expr) -> bool {
+    match &expr.node {
+        Expr_::Ident(_) | Expr_::GlobalVariable(_) => true,
+        Expr_::AccessExpression {lhs, ..} => is_addressable(lhs),
+        _ => false,
     }
 }
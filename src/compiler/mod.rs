@@ -1,22 +1,37 @@
+mod preprocessor;
 mod lexer;
 mod ast;
 mod parser;
 pub mod symtab;
 mod spirv;
+mod oso;
 mod llvm;
+mod artifact;
+mod trace;
+mod options;
+mod lint;
+pub mod jit;
 
 
+use preprocessor::{preprocess, Preprocessed};
 use lexer::Lexer;
 use parser::parse;
 use symtab::SymbolTable;
 use ast::Stmt;
 use super::errors::*;
 
+pub use artifact::{CompiledShader, ShaderOutput, ShaderParameter, ParameterDefault, Metadata, Value};
+pub use preprocessor::{SourceFile, SourceMap};
+pub use trace::{Stage, Tracer};
+pub use options::{CompileOptions, Compiler, OptLevel};
+
 #[derive(Debug, Clone, Copy)]
 pub struct Span {
     pub lo: usize,
     pub hi: usize,
     pub line: usize,
+    /// The file the span came from, as numbered by the preprocessor. The main file is 0.
+    pub file: usize,
 }
 
 
@@ -171,7 +186,7 @@ pub enum GeometricSpaces {
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq)]
 pub enum Types {
     Int,
     Float,
@@ -183,6 +198,31 @@ pub enum Types {
     Matrix,
     Void,
     Closure(Box<Types>),
+    /// A fixed-size array. Only function parameters may leave out the length.
+    Array(Box<Types>, Option<usize>),
+    /// A user-defined struct, named as declared. Its fields live in the symbol table.
+    Struct(String),
+}
+
+// Arrays read as `Float[4]` and structs by their name in messages rather than as nested variants
+impl std::fmt::Debug for Types {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Types::Int => write!(f, "Int"),
+            Types::Float => write!(f, "Float"),
+            Types::String => write!(f, "String"),
+            Types::Color => write!(f, "Color"),
+            Types::Point => write!(f, "Point"),
+            Types::Vector => write!(f, "Vector"),
+            Types::Normal => write!(f, "Normal"),
+            Types::Matrix => write!(f, "Matrix"),
+            Types::Void => write!(f, "Void"),
+            Types::Closure(t) => write!(f, "Closure({:?})", t),
+            Types::Array(t, Some(length)) => write!(f, "{:?}[{}]", t, length),
+            Types::Array(t, None) => write!(f, "{:?}[]", t),
+            Types::Struct(name) => write!(f, "{}", name),
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -195,7 +235,8 @@ pub enum Globals {
     Dpdu,
     Dpdv,
     Ps,
-    UV,
+    U,
+    V,
     Time,
     Dtime,
     Dpdtime,
@@ -238,8 +279,6 @@ pub enum Token {
     KWVolume,
     KWWhile,
 
-    ElseIf,
-
     ReservedKeyword(ReservedKeywords),
     Error{
         message: String,
@@ -252,7 +291,6 @@ pub enum Token {
     Shader(ShaderTypes),
 
     Integer(i64),
-    HexInteger(i64),
     Float(f64),
     Str(String),
 
@@ -297,16 +335,17 @@ pub enum Token {
     RightSquare,
     Semicolon,
     Colon,
+    Question,
     Period,
     Comma,
 
     Whitespace,
     Newline,
     Comment,
-    Meta(String),
+    MetaBegin,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Backend {
     LLVM,
     SPIRV,
@@ -314,16 +353,57 @@ pub enum Backend {
 }
 
 
-pub fn compile(contents: String, backend: Backend) -> Result<Vec<u8>, OSLCompilerError> {
+pub fn compile(contents: String, backend: Backend) -> Result<CompiledShader, Diagnostics> {
+    Compiler::new(CompileOptions::new(backend)).compile(&contents)
+}
 
+/// A program that passed every check, ready for a backend.
+struct Analysis {
+    program: Vec<Stmt>,
+    symbol_table: SymbolTable,
+    /// The preprocessed source. Spans in the program point into its text.
+    source: Preprocessed,
+    warnings: Vec<OSLCompilerWarning>,
+}
 
-    println!("Lexing tokens...");
-    let tokens = Lexer::new(contents.as_str());
+/// Runs the front end: preprocesses, lexes, parses, type checks and lints the source. Errors
+/// point into the original source. Every lexer error is reported, as are every symbol and
+/// type error of a program that parsed. The parser does not recover, so parse errors are
+/// reported one at a time and stop the compile.
+fn analyze(contents: &str, options: &CompileOptions) -> Result<Analysis, Diagnostics> {
+    let tracer = &options.tracer;
+    let mut diagnostics = Diagnostics::new()
+        .silence_warnings(options.silence_warnings)
+        .warnings_as_errors(options.warnings_as_errors);
+    for warning in &options.allowed_warnings {
+        diagnostics = diagnostics.allow_warning(warning.clone());
+    }
+
+    let source = preprocess(contents, options)?;
+
+    tracer.dump(Stage::Preprocessed, || source.text.clone());
+
+    let (program, symbol_table) = check_source(&source, tracer, &mut diagnostics)
+        .map_err(|diagnostics| source.map.remap(diagnostics))?;
+
+    let warnings = source.map.remap(diagnostics).take_warnings();
+
+    Ok(Analysis {
+        program,
+        symbol_table,
+        source,
+        warnings,
+    })
+}
+
+fn check_source(source: &Preprocessed, tracer: &Tracer, diagnostics: &mut Diagnostics) -> Result<(Vec<Stmt>, SymbolTable), Diagnostics> {
+    let contents = &source.text;
+    let tokens = Lexer::new(contents.as_str()).with_lines(source.map.lines());
 
     for tok in tokens.clone() {
         match tok.0 {
             Token::Error{message, content} => {
-                return Err(OSLCompilerError::LexerError {
+                diagnostics.push(OSLCompilerError::LexerError {
                     message,
                     error: Item::new(tok.1, content),
                 });
@@ -332,61 +412,87 @@ pub fn compile(contents: String, backend: Backend) -> Result<Vec<u8>, OSLCompile
         }
     }
 
-    for tok in tokens.clone() {
-        println!{"{:?}", tok};
-    }
+    diagnostics.check()?;
+
+    tracer.dump(Stage::Tokens, || {
+        tokens.clone()
+            .map(|tok| format!("{:?}", tok))
+            .collect::<Vec<String>>()
+            .join("\n")
+    });
 
-    println!("Parsing AST...");
     let program = match parse(tokens.clone()) {
         Err(error) => {
-            let (_token, span) = error.0.unwrap();
+            // An else that no if can claim is only an unexpected token to the parser
+            let (span, message) = match error.0 {
+                Some((Token::KWElse, span)) => (span, "This else does not follow an if statement"),
+                Some((_, span)) => (span, error.1),
+                None => (Span {lo: contents.len(), hi: contents.len(), line: 0, file: 0}, "Unexpected end of file"),
+            };
             return Err(OSLCompilerError::ParserError {
-                error: Item::new(span, error.1)
-            });
+                error: Item::new(span, message)
+            }.into());
         }
         Ok(stmts) => stmts
     };
 
-    println!("{:#?}", program);
+    tracer.dump(Stage::Ast, || format!("{:#?}", program));
 
-    println!("Building symbol table...");
-    let mut symbol_table = SymbolTable::new(contents.len())?;
-    symbol_table.build_symbols(&program)?;
+    let mut symbol_table = SymbolTable::new()?;
+    symbol_table.build_symbols(&program, diagnostics);
 
-    println!("{:#?}", symbol_table);
+    tracer.dump(Stage::Symbols, || format!("{:#?}", symbol_table));
 
-    println!("Checking symantics...");
-    check_semantics(&symbol_table, &tokens, &program)?;
+    check_semantics(&symbol_table, &program, diagnostics);
+    diagnostics.check()?;
 
-    // let mut comp = compiler::Compiler::new(tokens.clone().collect(), &statements, contents.len())?;
-    // comp.compile()?;
-    //
-    llvm::compile(&program, &symbol_table)?;
+    lint::lint(&program, &symbol_table, diagnostics);
+    diagnostics.check()?;
 
-    Ok(vec![])
+    Ok((program, symbol_table))
 }
 
-fn check_semantics(symbol_table: &SymbolTable, tokens: &Lexer, program: &Vec<Stmt>) -> Result<(), OSLCompilerError> {
+fn check_semantics(symbol_table: &SymbolTable, program: &Vec<Stmt>, diagnostics: &mut Diagnostics) {
     // Make sure that the program has one and only one shader function
     if symbol_table.n_shaders == 0 {
-        return Err(OSLCompilerError::MissingShader);
+        diagnostics.push(OSLCompilerError::MissingShader);
     }
     else if symbol_table.n_shaders > 1 {
-        return Err(OSLCompilerError::MultipleShaders);
+        diagnostics.push(OSLCompilerError::MultipleShaders);
     }
 
-    // Cycle through tokens and make sure that any ident token references a symbol in the
-    // symbol table and has access to it.
-    for (token, span) in tokens.clone() {
-        match token {
-            Token::Ident(s) => {
-                symbol_table.check_access(span, s.clone())?;
-            },
-            _ => {}
-        }
+    // Make sure that every name used in an expression refers to a symbol that is visible
+    // from where it is used. Declared struct types are resolved by the type checker instead.
+    for reference in symbol_table.get_references() {
+        diagnostics.record(symbol_table.check_access(reference.span, reference.name.clone()));
+    }
+
+    symbol_table.check_types(program, diagnostics);
+    symbol_table.check_control_flow(program, diagnostics);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+
+    use super::*;
+
+    /// The message and range of the first error in `source`.
+    fn first_error(source: &str) -> (String, Range<usize>) {
+        let diagnostics = analyze(source, &CompileOptions::new(Backend::OSO)).err().unwrap();
+        let report = diagnostics.errors()[0].report();
+        (report.labels[0].message.clone(), report.labels[0].range.clone())
     }
 
-    symbol_table.check_types(program)?;
+    #[test]
+    fn truncated_source_reports_end_of_file() {
+        let source = "shader s() {";
+        assert_eq!(first_error(source), (String::from("Unexpected end of file"), source.len()..source.len()));
+    }
 
-    Ok(())
+    #[test]
+    fn stray_else_is_reported_at_the_else() {
+        let source = "shader s() { else {} }";
+        assert_eq!(first_error(source), (String::from("This else does not follow an if statement"), 13..17));
+    }
 }
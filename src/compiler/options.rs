@@ -0,0 +1,126 @@
+use super::*;
+
+use std::path::PathBuf;
+
+/// How hard the backend should try to optimize the generated code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptLevel {
+    None,
+    Less,
+    Default,
+    Aggressive,
+}
+
+/// Settings for a compile, built with chained setters starting from `CompileOptions::new`.
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    pub backend: Backend,
+    /// Name of the source file, used in diagnostics, `__FILE__` and the OSO `%filename` hints.
+    /// Quoted includes are looked up next to it.
+    pub file_name: Option<String>,
+    pub include_paths: Vec<PathBuf>,
+    /// Preprocessor macros as `(name, value)`. A define without a value expands to `1`.
+    pub defines: Vec<(String, Option<String>)>,
+    pub opt_level: OptLevel,
+    /// Drops every warning.
+    pub silence_warnings: bool,
+    /// Codes or names of the warnings to drop, e.g. `W0001` or `unused-variable`.
+    pub allowed_warnings: Vec<String>,
+    pub warnings_as_errors: bool,
+    pub tracer: Tracer,
+}
+
+impl CompileOptions {
+    pub fn new(backend: Backend) -> Self {
+        CompileOptions {
+            backend,
+            file_name: None,
+            include_paths: Vec::new(),
+            defines: Vec::new(),
+            opt_level: OptLevel::None,
+            silence_warnings: false,
+            allowed_warnings: Vec::new(),
+            warnings_as_errors: false,
+            tracer: Tracer::default(),
+        }
+    }
+
+    pub fn file_name(mut self, file_name: impl Into<String>) -> Self {
+        self.file_name = Some(file_name.into());
+        self
+    }
+
+    pub fn include_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.include_paths.push(path.into());
+        self
+    }
+
+    pub fn define(mut self, name: impl Into<String>, value: Option<&str>) -> Self {
+        self.defines.push((name.into(), value.map(String::from)));
+        self
+    }
+
+    pub fn opt_level(mut self, opt_level: OptLevel) -> Self {
+        self.opt_level = opt_level;
+        self
+    }
+
+    pub fn silence_warnings(mut self, silence_warnings: bool) -> Self {
+        self.silence_warnings = silence_warnings;
+        self
+    }
+
+    pub fn allow_warning(mut self, warning: impl Into<String>) -> Self {
+        self.allowed_warnings.push(warning.into());
+        self
+    }
+
+    pub fn warnings_as_errors(mut self, warnings_as_errors: bool) -> Self {
+        self.warnings_as_errors = warnings_as_errors;
+        self
+    }
+
+    pub fn tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = tracer;
+        self
+    }
+}
+
+/// Compiles shaders with a fixed set of options. A compiler holds no state between compiles,
+/// so one can be shared for any number of shaders.
+#[derive(Debug, Clone)]
+pub struct Compiler {
+    options: CompileOptions,
+}
+
+impl Compiler {
+    pub fn new(options: CompileOptions) -> Self {
+        Compiler { options }
+    }
+
+    pub fn options(&self) -> &CompileOptions {
+        &self.options
+    }
+
+    pub fn compile(&self, contents: &str) -> Result<CompiledShader, Diagnostics> {
+        let options = &self.options;
+        let analysis = analyze(contents, options)?;
+        let (program, symbol_table) = (&analysis.program, &analysis.symbol_table);
+
+        let output = match options.backend {
+            Backend::SPIRV => spirv::build_shader(program, symbol_table).map(ShaderOutput::SPIRV),
+            Backend::OSO => oso::build_shader(program, symbol_table, analysis.source.map.files(), options.file_name.as_deref()).map(ShaderOutput::OSO),
+            Backend::LLVM => llvm::compile(program, symbol_table, options.opt_level),
+        };
+        let output = output.map_err(|error| analysis.source.map.remap(error.into()))?;
+
+        options.tracer.dump(Stage::IR, || output.disassemble());
+
+        let mut shader = artifact::build_artifact(program, &analysis.source.text, output)
+            .map_err(|error| analysis.source.map.remap(error.into()))?;
+        shader.warnings = analysis.warnings;
+        shader.sources = analysis.source.map.files().to_vec();
+
+        Ok(shader)
+    }
+}
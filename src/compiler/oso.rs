@@ -0,0 +1,1272 @@
+use super::*;
+use super::ast::*;
+use super::symtab::{SymbolTable, Symbols};
+
+use crate::errors::*;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Lowers the checked program into OpenShadingLanguage 1.00 (.oso) text.
+/// `filename` overrides the name of the main file in the `%filename` hints.
+pub fn build_shader(program: &Vec<Stmt>, symbol_table: &SymbolTable, sources: &[SourceFile], filename: Option<&str>) -> Result<String, OSLCompilerError> {
+    let mut writer = OsoWriter::new(symbol_table, sources);
+
+    for stmt in program {
+        match &stmt.statement {
+            Stmt_::FunctionDeclaration {name, ..} => {
+                writer.functions.insert((get_ident_value(name).unwrap(), stmt.span.lo), stmt);
+            },
+            // oslc flattens structs into one symbol per field, which isn't done here yet
+            Stmt_::StructDeclaration {..} => {
+                return Err(OSLCompilerError::CodegenError {
+                    message: String::from("Structs are not supported by the OSO backend"),
+                    error: Item::new(stmt.span, ""),
+                });
+            },
+            _ => {},
+        }
+    }
+
+    for stmt in program {
+        if let Stmt_::ShaderDeclaration {name, shader_type, params, body, metadata} = &stmt.statement {
+            let shader_name = get_ident_value(name).unwrap();
+            writer.filename = match filename {
+                Some(filename) => filename.to_owned(),
+                None => format!("{}.osl", shader_name),
+            };
+
+            writer.build_shader(params, body)?;
+            let metadata = metadata.iter().map(get_metadata).collect::<Result<Vec<_>, _>>()?;
+            return Ok(writer.write(&get_shader_type_value(shader_type).unwrap(), &shader_name, &metadata));
+        }
+    }
+
+    Err(OSLCompilerError::MissingShader)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SymbolKind {
+    Param,
+    OutputParam,
+    Global,
+    Local,
+    Const,
+    Temp,
+}
+
+#[derive(Debug, Clone)]
+struct OsoSymbol {
+    kind: SymbolKind,
+    var_type: Types,
+    name: String,
+    value: String,
+    initexpr: bool,
+    metadata: Vec<Metadata>,
+}
+
+#[derive(Debug, Clone)]
+struct Op {
+    opname: String,
+    args: Vec<usize>,
+    argrw: String,
+    jumps: Vec<usize>,
+    // The file and line the op was generated for
+    location: (usize, usize),
+}
+
+/// Where an assignment stores its value. Elements and components are written through the
+/// container they were read from, which is written back to its own container in turn.
+enum LValue {
+    Variable(usize),
+    Element {
+        container: usize,
+        parent: Box<LValue>,
+        element_type: Types,
+        opname: &'static str,
+        indexes: Vec<usize>,
+        argrw: String,
+    },
+}
+
+/// The inlined function call currently being generated.
+struct CallFrame {
+    function: (String, usize),
+    result: Option<usize>,
+}
+
+struct OsoWriter<'a> {
+    symbol_table: &'a SymbolTable,
+    sources: &'a [SourceFile],
+    filename: String,
+
+    // Keyed by name and declaration span, like the symbol table resolves them
+    functions: HashMap<(String, usize), &'a Stmt>,
+    variables: HashMap<(String, usize), usize>,
+    globals: HashMap<String, usize>,
+    constants: HashMap<(String, String), usize>,
+
+    symbols: Vec<OsoSymbol>,
+    ops: Vec<Op>,
+    sections: Vec<(String, usize)>,
+    location: (usize, usize),
+    n_consts: usize,
+    n_temps: usize,
+
+    calls: Vec<CallFrame>,
+}
+
+impl<'a> OsoWriter<'a> {
+    fn new(symbol_table: &'a SymbolTable, sources: &'a [SourceFile]) -> Self {
+        OsoWriter {
+            symbol_table,
+            sources,
+            filename: String::new(),
+            functions: HashMap::new(),
+            variables: HashMap::new(),
+            globals: HashMap::new(),
+            constants: HashMap::new(),
+            symbols: Vec::new(),
+            ops: Vec::new(),
+            sections: Vec::new(),
+            location: (0, 0),
+            n_consts: 0,
+            n_temps: 0,
+            calls: Vec::new(),
+        }
+    }
+
+    fn build_shader(&mut self, params: &Vec<Expr>, body: &Stmt) -> Result<(), OSLCompilerError> {
+        let mut init_exprs = Vec::new();
+
+        for param in params {
+            if let Expr_::Parameter {par_type, name, out, value, metadata} = &param.node {
+                let param_type = get_var_type_value(par_type).unwrap();
+                let param_name = get_ident_value(name).unwrap();
+                let kind = if *out { SymbolKind::OutputParam } else { SymbolKind::Param };
+
+                // Defaults that aren't constant are computed by an init expression
+                let (default, initexpr) = match value.node {
+                    Expr_::EmptyExpression => (zero_value(&param_type), false),
+                    _ => match constant_value(value, &param_type) {
+                        Some(default) => (default, false),
+                        None => (zero_value(&param_type), true),
+                    }
+                };
+
+                let symbol = self.add_symbol(kind, param_type, param_name.clone(), default);
+                self.symbols[symbol].initexpr = initexpr;
+                self.symbols[symbol].metadata = metadata.iter().map(get_metadata).collect::<Result<_, _>>()?;
+                self.variables.insert((param_name, param.span.lo), symbol);
+
+                if initexpr {
+                    init_exprs.push((symbol, value));
+                }
+            }
+        }
+
+        for (symbol, value) in init_exprs {
+            self.sections.push((self.symbols[symbol].name.clone(), self.ops.len()));
+            self.location = (value.span.file, value.span.line);
+            self.build_assignment(symbol, value)?;
+        }
+
+        self.sections.push((String::from("___main___"), self.ops.len()));
+        self.build_statement(body)?;
+        self.emit("end", vec![], "");
+
+        Ok(())
+    }
+
+    //===============
+    // Symbols
+    //===============
+
+    fn add_symbol(&mut self, kind: SymbolKind, var_type: Types, name: String, value: String) -> usize {
+        self.symbols.push(OsoSymbol {
+            kind,
+            var_type,
+            name,
+            value,
+            initexpr: false,
+            metadata: Vec::new(),
+        });
+
+        self.symbols.len() - 1
+    }
+
+    fn new_temp(&mut self, var_type: &Types) -> usize {
+        self.n_temps += 1;
+        let name = format!("$tmp{}", self.n_temps);
+        self.add_symbol(SymbolKind::Temp, var_type.clone(), name, String::new())
+    }
+
+    /// The symbol an operation producing `var_type` should write to: the requested
+    /// destination when the types agree, otherwise a new temporary.
+    fn result_symbol(&mut self, var_type: &Types, dest: Option<usize>) -> usize {
+        match dest {
+            Some(dest) if self.symbols[dest].var_type == *var_type => dest,
+            _ => self.new_temp(var_type),
+        }
+    }
+
+    fn new_local(&mut self, var_type: Types, name: String, span: Span) -> usize {
+        // Names that are already taken by another scope get mangled the way oslc does it
+        let taken = self.symbols.iter().any(|symbol| symbol.name == name);
+        let oso_name = match taken {
+            true => format!("___{}_{}", self.variables.len(), name),
+            false => name.clone(),
+        };
+
+        let symbol = self.add_symbol(SymbolKind::Local, var_type, oso_name, String::new());
+        self.variables.insert((name, span.lo), symbol);
+
+        symbol
+    }
+
+    fn constant(&mut self, var_type: Types, value: String) -> usize {
+        let key = (type_name(&var_type), value.clone());
+        if let Some(symbol) = self.constants.get(&key) {
+            return *symbol;
+        }
+
+        self.n_consts += 1;
+        let name = format!("$const{}", self.n_consts);
+        let symbol = self.add_symbol(SymbolKind::Const, var_type, name, value);
+        self.constants.insert(key, symbol);
+
+        symbol
+    }
+
+    fn constant_int(&mut self, value: i64) -> usize {
+        self.constant(Types::Int, value.to_string())
+    }
+
+    fn constant_float(&mut self, value: f64) -> usize {
+        self.constant(Types::Float, format_float(value))
+    }
+
+    fn global(&mut self, global: &Globals) -> usize {
+        let (name, global_type) = match global {
+            Globals::P => ("P", Types::Point),
+            Globals::I => ("I", Types::Vector),
+            Globals::N => ("N", Types::Normal),
+            Globals::Ng => ("Ng", Types::Normal),
+            Globals::Dpdu => ("dPdu", Types::Vector),
+            Globals::Dpdv => ("dPdv", Types::Vector),
+            Globals::Ps => ("Ps", Types::Point),
+            Globals::U => ("u", Types::Float),
+            Globals::V => ("v", Types::Float),
+            Globals::Time => ("time", Types::Float),
+            Globals::Dtime => ("dtime", Types::Float),
+            Globals::Dpdtime => ("dPdtime", Types::Vector),
+            Globals::Ci => ("Ci", Types::Closure(Box::new(Types::Color))),
+        };
+
+        if let Some(symbol) = self.globals.get(name) {
+            return *symbol;
+        }
+
+        let symbol = self.add_symbol(SymbolKind::Global, global_type, name.to_owned(), String::new());
+        self.globals.insert(name.to_owned(), symbol);
+
+        symbol
+    }
+
+    fn get_variable(&self, expr: &Expr, name: &String) -> Result<usize, OSLCompilerError> {
+        let symbol = self.symbol_table.get_reference(expr.span, name.clone());
+        self.variables.get(&(name.clone(), symbol.get_span().lo))
+            .copied()
+            .ok_or_else(|| OSLCompilerError::CodegenError {
+                message: format!("Could not find storage for {}", name),
+                error: Item::new(expr.span, "Used here"),
+            })
+    }
+
+    //===============
+    // Ops
+    //===============
+
+    /// Attributes the following ops to a span. Spans without a line, e.g. of code that came
+    /// from a macro, keep the current location.
+    fn locate(&mut self, span: Span) {
+        if span.line > 0 {
+            self.location = (span.file, span.line);
+        }
+    }
+
+    fn file_name(&self, file: usize) -> &str {
+        match file {
+            0 => &self.filename,
+            _ => &self.sources[file].name,
+        }
+    }
+
+    fn emit(&mut self, opname: &str, args: Vec<usize>, argrw: &str) -> usize {
+        self.ops.push(Op {
+            opname: opname.to_owned(),
+            args,
+            argrw: argrw.to_owned(),
+            jumps: Vec::new(),
+            location: self.location,
+        });
+
+        self.ops.len() - 1
+    }
+
+    /// Makes `symbol` usable where a value of type `to` is expected. Ops take care of
+    /// most conversions themselves, so only integers feeding float math need converting.
+    fn coerce(&mut self, symbol: usize, to: &Types) -> usize {
+        let from = self.symbols[symbol].var_type.clone();
+        if from != Types::Int || *to == Types::Int || *to == Types::String {
+            return symbol;
+        }
+
+        if self.symbols[symbol].kind == SymbolKind::Const {
+            let value: f64 = self.symbols[symbol].value.parse().unwrap();
+            return self.constant_float(value);
+        }
+
+        let temp = self.new_temp(&Types::Float);
+        self.emit("assign", vec![temp, symbol], "wr");
+
+        temp
+    }
+
+    /// Stores the value of `expr` into `symbol`, writing it in place when possible.
+    fn build_assignment(&mut self, symbol: usize, expr: &Expr) -> Result<(), OSLCompilerError> {
+        if let Expr_::InitializerList(values) = &expr.node {
+            return self.build_initializer(symbol, values);
+        }
+
+        let value = self.build_expression(expr, Some(symbol))?;
+        if value != symbol {
+            // `assign` converts on its own, only constants are worth folding
+            let value = match self.symbols[value].kind {
+                SymbolKind::Const => {
+                    let var_type = self.symbols[symbol].var_type.clone();
+                    self.coerce(value, &var_type)
+                },
+                _ => value,
+            };
+            self.emit("assign", vec![symbol, value], "wr");
+        }
+
+        Ok(())
+    }
+
+    /// Stores each value of an initializer list into its element of the array `symbol`.
+    fn build_initializer(&mut self, symbol: usize, values: &[Expr]) -> Result<(), OSLCompilerError> {
+        let element_type = match &self.symbols[symbol].var_type {
+            Types::Array(element_type, _) => *element_type.clone(),
+            var_type => return Err(OSLCompilerError::CodegenError {
+                message: format!("A value of type {:?} cannot be initialized with a list", var_type),
+                error: Item::new(values[0].span, ""),
+            }),
+        };
+
+        for (index, value) in values.iter().enumerate() {
+            let value = self.build_expression(value, None)?;
+            let value = self.coerce(value, &element_type);
+            let index = self.constant_int(index as i64);
+            self.emit("aassign", vec![symbol, index, value], "wrr");
+        }
+
+        Ok(())
+    }
+
+    //===============
+    // Statements
+    //===============
+
+    fn build_statement(&mut self, stmt: &Stmt) -> Result<(), OSLCompilerError> {
+        self.build_statements(std::slice::from_ref(stmt))
+    }
+
+    fn build_statements(&mut self, stmts: &[Stmt]) -> Result<(), OSLCompilerError> {
+        for stmt in stmts {
+            self.locate(stmt.span);
+
+            match &stmt.statement {
+                Stmt_::ExpressionStatement(expr) => {
+                    self.build_discarded(expr)?;
+                },
+
+                Stmt_::EmptyStatement => {},
+
+                Stmt_::BlockStatement(block_stmts) => {
+                    self.build_statements(block_stmts)?;
+                },
+
+                Stmt_::VariableDeclaration {var_type, name, value} => {
+                    let var_type = get_var_type_value(var_type).unwrap();
+                    let var_name = get_ident_value(name).unwrap();
+                    let symbol = match self.variables.get(&(var_name.clone(), stmt.span.lo)) {
+                        Some(symbol) => *symbol,
+                        None => self.new_local(var_type, var_name, stmt.span),
+                    };
+
+                    if let Expr_::EmptyExpression = value.node {} else {
+                        self.build_assignment(symbol, value)?;
+                    }
+                },
+
+                Stmt_::ReturnStatement(expr) => {
+                    match self.calls.last() {
+                        Some(frame) => {
+                            if let (Some(result), Expr_::EmptyExpression) = (frame.result, &expr.node) {
+                                return Err(OSLCompilerError::CodegenError {
+                                    message: format!("Expected a return value of type {:?}", self.symbols[result].var_type),
+                                    error: Item::new(stmt.span, ""),
+                                });
+                            }
+                            if let Some(result) = frame.result {
+                                self.build_assignment(result, expr)?;
+                            }
+                            self.emit("return", vec![], "");
+                        },
+                        None => {
+                            self.emit("exit", vec![], "");
+                        },
+                    }
+                },
+
+                // The op leaves the innermost loop op on its own
+                Stmt_::BreakStatement => {
+                    self.emit("break", vec![], "");
+                },
+
+                Stmt_::ContinueStatement => {
+                    self.emit("continue", vec![], "");
+                },
+
+                Stmt_::IfStatement {..} => {
+                    let (branches, else_body) = get_conditional_chain(stmt);
+                    self.build_conditional(&branches, else_body)?;
+                },
+
+                Stmt_::WhileStatement {condition, body} => {
+                    self.build_loop("while", &[], condition, &[], body, stmt.span)?;
+                },
+
+                Stmt_::DoWhileStatement {condition, body} => {
+                    self.build_loop("dowhile", &[], condition, &[], body, stmt.span)?;
+                },
+
+                Stmt_::ForStatement {initialization, condition, iteration, body} => {
+                    self.build_loop("for", initialization, condition, iteration, body, stmt.span)?;
+                },
+
+                Stmt_::FunctionDeclaration {..} |
+                Stmt_::StructDeclaration {..} |
+                Stmt_::ShaderDeclaration {..} => {
+                    return Err(OSLCompilerError::CodegenError {
+                        message: String::from("Nested declarations are not supported"),
+                        error: Item::new(stmt.span, ""),
+                    });
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    fn build_conditional(&mut self, branches: &[(&Expr, &Stmt)], else_body: Option<&Stmt>) -> Result<(), OSLCompilerError> {
+        let (condition, body) = branches[0];
+
+        self.locate(condition.span);
+        let condition = self.build_condition(condition)?;
+        let if_op = self.emit("if", vec![condition], "r");
+
+        self.build_statement(body)?;
+        let else_label = self.ops.len();
+
+        if branches.len() > 1 {
+            self.build_conditional(&branches[1..], else_body)?;
+        } else if let Some(else_body) = else_body {
+            self.build_statement(else_body)?;
+        }
+
+        self.ops[if_op].jumps = vec![else_label, self.ops.len()];
+
+        Ok(())
+    }
+
+    /// All loops share one layout: the loop op, then the init, condition, body and step ops.
+    fn build_loop(&mut self, opname: &str, initialization: &[Stmt], condition: &Expr, iteration: &[Expr], body: &Stmt, span: Span) -> Result<(), OSLCompilerError> {
+        let loop_op = self.emit(opname, vec![], "r");
+
+        self.build_statements(initialization)?;
+
+        let condition_label = self.ops.len();
+        let condition = match condition.node {
+            Expr_::EmptyExpression => self.constant_int(1),
+            _ => self.build_condition(condition)?,
+        };
+
+        let body_label = self.ops.len();
+        self.build_statement(body)?;
+
+        let step_label = self.ops.len();
+        self.location = (span.file, span.line);
+        for expr in iteration {
+            self.build_discarded(expr)?;
+        }
+
+        self.ops[loop_op].args = vec![condition];
+        self.ops[loop_op].jumps = vec![condition_label, body_label, step_label, self.ops.len()];
+
+        Ok(())
+    }
+
+    //===============
+    // Expressions
+    //===============
+
+    /// Builds an expression whose value is never used.
+    fn build_discarded(&mut self, expr: &Expr) -> Result<(), OSLCompilerError> {
+        match &expr.node {
+            Expr_::EmptyExpression => {},
+
+            // Without a reader for the old value, `i++` is the same as `++i`
+            Expr_::PostUnaryExpression(op, lhs) => {
+                self.build_increment(op, lhs, false)?;
+            },
+
+            _ => {
+                self.build_expression(expr, None)?;
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Builds a condition as an int, which is what the control flow ops test.
+    fn build_condition(&mut self, expr: &Expr) -> Result<usize, OSLCompilerError> {
+        let value = self.build_expression(expr, None)?;
+        self.build_truth(value)
+    }
+
+    fn build_truth(&mut self, value: usize) -> Result<usize, OSLCompilerError> {
+        let value_type = self.symbols[value].var_type.clone();
+        if value_type == Types::Int {
+            return Ok(value);
+        }
+
+        let zero = match value_type {
+            Types::Float => self.constant_float(0.0),
+            Types::String => self.constant(Types::String, String::from("\"\"")),
+            _ => self.constant(value_type.clone(), zero_value(&value_type)),
+        };
+        let result = self.new_temp(&Types::Int);
+        self.emit("neq", vec![result, value, zero], "wrr");
+
+        Ok(result)
+    }
+
+    fn build_expression(&mut self, expr: &Expr, dest: Option<usize>) -> Result<usize, OSLCompilerError> {
+        match &expr.node {
+            Expr_::IntLiteral(i) => Ok(self.constant_int(*i)),
+
+            Expr_::FloatLiteral(f) => Ok(self.constant_float(*f)),
+
+            Expr_::StringLiteral(s) => Ok(self.constant(Types::String, quote(s))),
+
+            Expr_::Ident(s) => self.get_variable(expr, s),
+
+            Expr_::GlobalVariable(g) => Ok(self.global(g)),
+
+            Expr_::Assignment(lhs, rhs) => {
+                match self.build_lvalue(lhs)? {
+                    // Variables are written in place
+                    LValue::Variable(symbol) => {
+                        self.build_assignment(symbol, rhs)?;
+                        Ok(symbol)
+                    },
+                    lvalue @ LValue::Element {..} => {
+                        let element_type = get_expr_type(lhs, self.symbol_table)?;
+                        let value = self.build_expression(rhs, None)?;
+                        let value = self.coerce(value, &element_type);
+                        self.store_lvalue(&lvalue, value);
+                        Ok(value)
+                    },
+                }
+            },
+
+            Expr_::ExplicitCast {cast_type, cast_expr} => {
+                let cast_type = get_var_type_value(cast_type).unwrap();
+                let value = self.build_expression(cast_expr, None)?;
+                if self.symbols[value].var_type == cast_type {
+                    return Ok(value);
+                }
+                let result = self.result_symbol(&cast_type, dest);
+                self.emit("assign", vec![result, value], "wr");
+                Ok(result)
+            },
+
+            Expr_::AccessExpression {lhs, ..} => {
+                let element_type = get_expr_type(expr, self.symbol_table)?;
+                let base = self.build_expression(lhs, None)?;
+                let (opname, mut indexes, argrw) = self.component_indexes(expr, base)?;
+                let result = self.result_symbol(&element_type, dest);
+                let mut args = vec![result, base];
+                args.append(&mut indexes);
+                self.emit(&format!("{}ref", opname), args, &argrw);
+                Ok(result)
+            },
+
+            // The current value is updated in place, then written back to where it came from
+            Expr_::BinaryExpression(op, lhs, rhs) if is_compound_assignment(op) => {
+                let lvalue = self.build_lvalue(lhs)?;
+                let lhs_value = self.load_lvalue(&lvalue);
+                let rhs_value = self.build_expression(rhs, None)?;
+                let result = self.build_binary(expr, &compound_operator(op), lhs_value, rhs_value, Some(lhs_value))?;
+                self.store_lvalue(&lvalue, result);
+                Ok(result)
+            },
+
+            Expr_::BinaryExpression(op @ (Operators::LogicalAnd | Operators::LogicalOr), lhs, rhs) => {
+                self.build_logic(op, lhs, rhs, dest)
+            },
+
+            Expr_::BinaryExpression(op, lhs, rhs) => {
+                let lhs_value = self.build_expression(lhs, None)?;
+                let rhs_value = self.build_expression(rhs, None)?;
+                self.build_binary(expr, op, lhs_value, rhs_value, dest)
+            },
+
+            Expr_::PreUnaryExpression(op, rhs) => {
+                match op {
+                    Operators::Increment |
+                    Operators::Decrement => self.build_increment(op, rhs, false),
+                    Operators::Minus |
+                    Operators::BitwiseCompliment => {
+                        let value = self.build_expression(rhs, None)?;
+                        let value_type = self.symbols[value].var_type.clone();
+                        let result = self.result_symbol(&value_type, dest);
+                        let opname = if let Operators::Minus = op { "neg" } else { "compl" };
+                        self.emit(opname, vec![result, value], "wr");
+                        Ok(result)
+                    },
+                    Operators::Not => {
+                        let value = self.build_expression(rhs, None)?;
+                        let value = self.build_truth(value)?;
+                        let zero = self.constant_int(0);
+                        let result = self.result_symbol(&Types::Int, dest);
+                        self.emit("eq", vec![result, value, zero], "wrr");
+                        Ok(result)
+                    },
+                    _ => Err(OSLCompilerError::CodegenError {
+                        message: format!("Unsupported unary operator {:?}", op),
+                        error: Item::new(expr.span, ""),
+                    }),
+                }
+            },
+
+            Expr_::PostUnaryExpression(op, lhs) => self.build_increment(op, lhs, true),
+
+            Expr_::FunctionCallExpression {name, arguments} => {
+                self.build_call(expr, name, arguments, dest)
+            },
+
+            // Laid out like an if statement whose branches both assign the result
+            Expr_::TernaryExpression {condition, if_true, if_false} => {
+                let result_type = get_expr_type(expr, self.symbol_table)?;
+                let result = self.result_symbol(&result_type, dest);
+                let condition = self.build_condition(condition)?;
+                let if_op = self.emit("if", vec![condition], "r");
+
+                self.build_assignment(result, if_true)?;
+                let else_label = self.ops.len();
+                self.build_assignment(result, if_false)?;
+                self.ops[if_op].jumps = vec![else_label, self.ops.len()];
+
+                Ok(result)
+            },
+
+            Expr_::PointConstructor {point_type, x, y, z, space} => {
+                let mut arguments = Vec::new();
+                if let Some(space) = space {
+                    arguments.push(*space.clone());
+                }
+                arguments.append(&mut vec![*x.clone(), *y.clone(), *z.clone()]);
+                self.build_call(expr, point_type, &arguments, dest)
+            },
+
+            _ => Err(OSLCompilerError::CodegenError {
+                message: String::from("Unsupported expression"),
+                error: Item::new(expr.span, ""),
+            }),
+        }
+    }
+
+    /// Laid out like oslc does: the right hand side is built inside an `if` on the left one, so
+    /// it only runs when it decides the result.
+    fn build_logic(&mut self, op: &Operators, lhs: &Expr, rhs: &Expr, dest: Option<usize>) -> Result<usize, OSLCompilerError> {
+        let result = self.result_symbol(&Types::Int, dest);
+        let lhs_value = self.build_condition(lhs)?;
+        let if_op = self.emit("if", vec![lhs_value], "r");
+
+        // `and` only runs when the left hand side is true, `or` when it is false
+        let else_label = match op {
+            Operators::LogicalAnd => {
+                let rhs_value = self.build_condition(rhs)?;
+                self.emit("and", vec![result, lhs_value, rhs_value], "wrr");
+                let else_label = self.ops.len();
+                let zero = self.constant_int(0);
+                self.emit("assign", vec![result, zero], "wr");
+                else_label
+            },
+            _ => {
+                let one = self.constant_int(1);
+                self.emit("assign", vec![result, one], "wr");
+                let else_label = self.ops.len();
+                let rhs_value = self.build_condition(rhs)?;
+                self.emit("or", vec![result, lhs_value, rhs_value], "wrr");
+                else_label
+            },
+        };
+        self.ops[if_op].jumps = vec![else_label, self.ops.len()];
+
+        Ok(result)
+    }
+
+    /// Applies a binary operator to two built operands.
+    fn build_binary(&mut self, expr: &Expr, op: &Operators, lhs_value: usize, rhs_value: usize, dest: Option<usize>) -> Result<usize, OSLCompilerError> {
+        let result_type = get_expr_type(expr, self.symbol_table)?;
+        let opname = match op {
+            Operators::Plus => "add",
+            Operators::Minus => "sub",
+            Operators::Multiply => "mul",
+            Operators::Divide => "div",
+            Operators::Mod => "mod",
+            Operators::Equals => "eq",
+            Operators::NotEqual => "neq",
+            Operators::LessThan => "lt",
+            Operators::LessThanEqual => "le",
+            Operators::GreaterThan => "gt",
+            Operators::GreaterThanEqual => "ge",
+            Operators::BitwiseAnd => "bitand",
+            Operators::BitwiseOr => "bitor",
+            Operators::BitwiseXor => "xor",
+            Operators::ShiftLeft => "shl",
+            Operators::ShiftRight => "shr",
+            _ => return Err(OSLCompilerError::CodegenError {
+                message: format!("Unsupported binary operator {:?}", op),
+                error: Item::new(expr.span, ""),
+            }),
+        };
+
+        // Operands are promoted to float when mixed with floats
+        let lhs_type = self.symbols[lhs_value].var_type.clone();
+        let rhs_type = self.symbols[rhs_value].var_type.clone();
+        let operand_type = unify_types(&[lhs_type, rhs_type], false);
+        let (lhs_value, rhs_value) = (self.coerce(lhs_value, &operand_type), self.coerce(rhs_value, &operand_type));
+
+        let result = self.result_symbol(&result_type, dest);
+        self.emit(opname, vec![result, lhs_value, rhs_value], "wrr");
+        Ok(result)
+    }
+
+    /// Adds or subtracts one from `target`. Returns the updated value, or a copy of the old
+    /// one for `postfix` increments.
+    fn build_increment(&mut self, op: &Operators, target: &Expr, postfix: bool) -> Result<usize, OSLCompilerError> {
+        let lvalue = self.build_lvalue(target)?;
+        let symbol = self.load_lvalue(&lvalue);
+        let value_type = self.symbols[symbol].var_type.clone();
+
+        let result = match postfix {
+            true => {
+                let old_value = self.new_temp(&value_type);
+                self.emit("assign", vec![old_value, symbol], "wr");
+                old_value
+            },
+            false => symbol,
+        };
+
+        let one = match value_type {
+            Types::Int => self.constant_int(1),
+            _ => self.constant_float(1.0),
+        };
+        let opname = if let Operators::Increment = op { "add" } else { "sub" };
+        self.emit(opname, vec![symbol, symbol, one], "wrr");
+        self.store_lvalue(&lvalue, symbol);
+
+        Ok(result)
+    }
+
+    /// Resolves an assignable expression. Containers of elements are read, indexes are
+    /// evaluated once.
+    fn build_lvalue(&mut self, expr: &Expr) -> Result<LValue, OSLCompilerError> {
+        match &expr.node {
+            Expr_::Ident(s) => Ok(LValue::Variable(self.get_variable(expr, s)?)),
+
+            Expr_::GlobalVariable(g) => Ok(LValue::Variable(self.global(g))),
+
+            Expr_::AccessExpression {lhs, ..} => {
+                let parent = self.build_lvalue(lhs)?;
+                let container = self.load_lvalue(&parent);
+                let (opname, indexes, argrw) = self.component_indexes(expr, container)?;
+                Ok(LValue::Element {
+                    container,
+                    parent: Box::new(parent),
+                    element_type: get_expr_type(expr, self.symbol_table)?,
+                    opname,
+                    indexes,
+                    argrw,
+                })
+            },
+
+            _ => Err(OSLCompilerError::CodegenError {
+                message: String::from("This expression cannot be assigned to"),
+                error: Item::new(expr.span, ""),
+            }),
+        }
+    }
+
+    /// The current value of an l-value. Elements are copied into a temporary.
+    fn load_lvalue(&mut self, lvalue: &LValue) -> usize {
+        match lvalue {
+            LValue::Variable(symbol) => *symbol,
+            LValue::Element {container, element_type, opname, indexes, argrw, ..} => {
+                let result = self.new_temp(element_type);
+                let mut args = vec![result, *container];
+                args.extend(indexes);
+                self.emit(&format!("{}ref", opname), args, argrw);
+                result
+            },
+        }
+    }
+
+    fn store_lvalue(&mut self, lvalue: &LValue, value: usize) {
+        match lvalue {
+            LValue::Variable(symbol) => {
+                if *symbol != value {
+                    self.emit("assign", vec![*symbol, value], "wr");
+                }
+            },
+            LValue::Element {container, parent, opname, indexes, argrw, ..} => {
+                let mut args = vec![*container];
+                args.extend(indexes);
+                args.push(value);
+                self.emit(&format!("{}assign", opname), args, argrw);
+                self.store_lvalue(parent, *container);
+            },
+        }
+    }
+
+    /// The op prefix, index arguments and argrw flags of an element or component access.
+    fn component_indexes(&mut self, expr: &Expr, base: usize) -> Result<(&'static str, Vec<usize>, String), OSLCompilerError> {
+        let index = match &expr.node {
+            Expr_::AccessExpression {value, dot: false, ..} => match value.node {
+                Expr_::IntLiteral(i) => self.constant_int(i),
+                _ => self.build_expression(value, None)?,
+            },
+            Expr_::AccessExpression {value, dot: true, ..} => match get_ident_value(value).as_deref() {
+                Some("x") | Some("r") => self.constant_int(0),
+                Some("y") | Some("g") => self.constant_int(1),
+                Some("z") | Some("b") => self.constant_int(2),
+                _ => return Err(OSLCompilerError::CodegenError {
+                    message: String::from("Invalid component access"),
+                    error: Item::new(expr.span, ""),
+                }),
+            },
+            _ => return Err(OSLCompilerError::CodegenError {
+                message: String::from("Invalid component access"),
+                error: Item::new(expr.span, ""),
+            }),
+        };
+
+        Ok(match self.symbols[base].var_type {
+            Types::Array(..) => ("a", vec![index], String::from("wrr")),
+            Types::Matrix => {
+                let (row, column) = match self.symbols[index].kind {
+                    SymbolKind::Const => {
+                        let index: i64 = self.symbols[index].value.parse().unwrap();
+                        (self.constant_int(index / 4), self.constant_int(index % 4))
+                    },
+                    _ => {
+                        let four = self.constant_int(4);
+                        let row = self.new_temp(&Types::Int);
+                        let column = self.new_temp(&Types::Int);
+                        self.emit("div", vec![row, index, four], "wrr");
+                        self.emit("mod", vec![column, index, four], "wrr");
+                        (row, column)
+                    },
+                };
+                ("mxcomp", vec![row, column], String::from("wrrr"))
+            },
+            _ => ("comp", vec![index], String::from("wrr")),
+        })
+    }
+
+    fn build_call(&mut self, expr: &Expr, name: &Expr, arguments: &Vec<Expr>, dest: Option<usize>) -> Result<usize, OSLCompilerError> {
+        let result_type = get_expr_type(expr, self.symbol_table)?;
+
+        // Constructors are ops named after the type they build
+        if let Expr_::VariableType(t) = &name.node {
+            let mut values = Vec::new();
+            for arg in arguments {
+                let value = self.build_expression(arg, None)?;
+                values.push(self.coerce(value, &Types::Float));
+            }
+
+            let result = self.result_symbol(t, dest);
+            if values.len() == 1 {
+                self.emit("assign", vec![result, values[0]], "wr");
+            } else {
+                let argrw = format!("w{}", "r".repeat(values.len()));
+                let mut args = vec![result];
+                args.append(&mut values);
+                self.emit(&type_name(t), args, &argrw);
+            }
+            return Ok(result);
+        }
+
+        // arraylength takes an array of any type, so it has no overloads to resolve
+        let function_name = get_ident_value(name).unwrap();
+        if function_name != "arraylength" {
+            if let Symbols::Function {span, public: false, ..} = resolve_function(name, arguments, self.symbol_table)? {
+                return self.build_user_call(expr, (function_name, span.lo), arguments);
+            }
+        }
+
+        // Builtins take their arguments in the type of the call
+        let operand_type = match function_name.as_str() {
+            "length" | "distance" | "dot" => Types::Vector,
+            _ => result_type.clone(),
+        };
+        let mut values = Vec::new();
+        for arg in arguments {
+            let value = self.build_expression(arg, None)?;
+            values.push(self.coerce(value, &operand_type));
+        }
+
+        let result = self.result_symbol(&result_type, dest);
+        let argrw = format!("w{}", "r".repeat(values.len()));
+        let mut args = vec![result];
+        args.append(&mut values);
+        self.emit(&function_name, args, &argrw);
+
+        Ok(result)
+    }
+
+    /// User functions are inlined at the call site, bracketed by a `functioncall` op.
+    fn build_user_call(&mut self, expr: &Expr, function: (String, usize), arguments: &Vec<Expr>) -> Result<usize, OSLCompilerError> {
+        if self.calls.iter().any(|frame| frame.function == function) {
+            return Err(OSLCompilerError::CodegenError {
+                message: format!("The function {} is called recursively", function.0),
+                error: Item::new(expr.span, "Called here"),
+            });
+        }
+
+        let declaration = self.functions[&function];
+        let (ret_type, params, body) = match &declaration.statement {
+            Stmt_::FunctionDeclaration {ret_type, params, body, ..} => (get_var_type_value(ret_type).unwrap(), params, body),
+            _ => unreachable!(),
+        };
+
+        if params.len() != arguments.len() {
+            return Err(OSLCompilerError::CodegenError {
+                message: format!("Expected {} arguments, received {}", params.len(), arguments.len()),
+                error: Item::new(expr.span, ""),
+            });
+        }
+
+        // Arguments are copied into the function's parameters
+        let mut outputs = Vec::new();
+        for (param, arg) in params.iter().zip(arguments.iter()) {
+            if let Expr_::Parameter {par_type, name, out, ..} = &param.node {
+                let param_name = get_ident_value(name).unwrap();
+                let arg_value = self.build_expression(arg, None)?;
+                let symbol = match (get_var_type_value(par_type).unwrap(), self.variables.get(&(param_name.clone(), param.span.lo))) {
+                    // Unsized arrays take on the length of each argument they are passed
+                    (Types::Array(_, None), _) => {
+                        let arg_type = self.symbols[arg_value].var_type.clone();
+                        self.new_local(arg_type, param_name, param.span)
+                    },
+                    (_, Some(symbol)) => *symbol,
+                    (param_type, None) => self.new_local(param_type, param_name, param.span),
+                };
+                let param_type = self.symbols[symbol].var_type.clone();
+                let value = self.coerce(arg_value, &param_type);
+                self.emit("assign", vec![symbol, value], "wr");
+
+                if *out {
+                    outputs.push((symbol, arg_value));
+                }
+            }
+        }
+
+        let result = match ret_type {
+            Types::Void => None,
+            _ => Some(self.new_temp(&ret_type)),
+        };
+
+        let name = self.constant(Types::String, quote(&function.0));
+        let call_op = self.emit("functioncall", vec![name], "r");
+
+        let location = self.location;
+        self.calls.push(CallFrame {function, result});
+        self.build_statement(body)?;
+        self.calls.pop();
+        self.location = location;
+
+        self.ops[call_op].jumps = vec![self.ops.len()];
+
+        for (symbol, value) in outputs {
+            if self.symbols[value].kind == SymbolKind::Const || self.symbols[value].kind == SymbolKind::Temp {
+                return Err(OSLCompilerError::CodegenError {
+                    message: String::from("Output parameters must be passed a variable"),
+                    error: Item::new(expr.span, ""),
+                });
+            }
+            self.emit("assign", vec![value, symbol], "wr");
+        }
+
+        // Void functions have no value, the name keeps the result a valid symbol
+        Ok(result.unwrap_or(name))
+    }
+
+    //===============
+    // Output
+    //===============
+
+    fn write(&self, shader_type: &ShaderTypes, shader_name: &str, metadata: &[Metadata]) -> String {
+        let mut oso = String::new();
+
+        writeln!(oso, "OpenShadingLanguage 1.00").unwrap();
+        writeln!(oso, "# Compiled by osl.rs {}", env!("CARGO_PKG_VERSION")).unwrap();
+        writeln!(oso, "# options: ").unwrap();
+        write!(oso, "{} {}", shader_type_name(shader_type), shader_name).unwrap();
+        for entry in metadata {
+            write!(oso, "\t{}", meta_hint(entry)).unwrap();
+        }
+        writeln!(oso).unwrap();
+
+        // First and last op each symbol is read and written by
+        let mut reads = vec![(i32::MAX as i64, -1i64); self.symbols.len()];
+        let mut writes = vec![(i32::MAX as i64, -1i64); self.symbols.len()];
+        for (index, op) in self.ops.iter().enumerate() {
+            let index = index as i64;
+            for (arg, rw) in op.args.iter().zip(op.argrw.chars()) {
+                let range = match rw {
+                    'w' => &mut writes[*arg],
+                    _ => &mut reads[*arg],
+                };
+                range.0 = range.0.min(index);
+                range.1 = range.1.max(index);
+            }
+        }
+
+        // Parameters are listed first, then everything else in the order it was created
+        let params = self.symbols.iter().enumerate()
+            .filter(|(_, symbol)| matches!(symbol.kind, SymbolKind::Param | SymbolKind::OutputParam));
+        let globals = self.symbols.iter().enumerate()
+            .filter(|(_, symbol)| symbol.kind == SymbolKind::Global);
+        let others = self.symbols.iter().enumerate()
+            .filter(|(_, symbol)| matches!(symbol.kind, SymbolKind::Local | SymbolKind::Const | SymbolKind::Temp));
+
+        for (index, symbol) in params.chain(globals).chain(others) {
+            // Constants that were folded into others are left out
+            if symbol.kind == SymbolKind::Const && reads[index].1 < 0 {
+                continue;
+            }
+
+            let keyword = match symbol.kind {
+                SymbolKind::Param => "param",
+                SymbolKind::OutputParam => "oparam",
+                SymbolKind::Global => "global",
+                SymbolKind::Local => "local",
+                SymbolKind::Const => "const",
+                SymbolKind::Temp => "temp",
+            };
+            write!(oso, "{}\t{}\t{}\t", keyword, type_name(&symbol.var_type), symbol.name).unwrap();
+            if matches!(symbol.kind, SymbolKind::Param | SymbolKind::OutputParam | SymbolKind::Const) {
+                write!(oso, "{}\t\t", symbol.value).unwrap();
+            }
+            for entry in &symbol.metadata {
+                write!(oso, "{} ", meta_hint(entry)).unwrap();
+            }
+            write!(oso, "%read{{{},{}}} %write{{{},{}}}", reads[index].0, reads[index].1, writes[index].0, writes[index].1).unwrap();
+            if symbol.initexpr {
+                write!(oso, " %initexpr").unwrap();
+            }
+            writeln!(oso).unwrap();
+        }
+
+        let lines: Vec<Vec<&str>> = self.sources.iter().map(|source| source.contents.lines().collect()).collect();
+        for (section, (label, start)) in self.sections.iter().enumerate() {
+            writeln!(oso, "code {}", label).unwrap();
+
+            let end = match self.sections.get(section + 1) {
+                Some((_, next)) => *next,
+                None => self.ops.len(),
+            };
+
+            let mut last_file = None;
+            let mut last_line = None;
+            for op in &self.ops[*start..end] {
+                let mut hints = Vec::new();
+                let (file, line) = op.location;
+                if last_file.is_none() || (line > 0 && last_file != Some(file)) {
+                    hints.push(format!("%filename{{\"{}\"}}", self.file_name(file)));
+                    last_file = Some(file);
+                    last_line = None;
+                }
+                if last_line != Some(line) && line > 0 {
+                    let text = lines.get(file).and_then(|lines| lines.get(line - 1)).unwrap_or(&"");
+                    writeln!(oso, "# {}:{}", self.file_name(file), line).unwrap();
+                    writeln!(oso, "# {}", text).unwrap();
+                    hints.push(format!("%line{{{}}}", line));
+                    last_line = Some(line);
+                }
+                if !op.args.is_empty() {
+                    hints.push(format!("%argrw{{\"{}\"}}", op.argrw));
+                }
+
+                write!(oso, "\t{}", op.opname).unwrap();
+                if !op.args.is_empty() || !op.jumps.is_empty() {
+                    write!(oso, "{}", if op.opname.len() < 8 { "\t\t" } else { "\t" }).unwrap();
+                }
+                for arg in &op.args {
+                    write!(oso, "{} ", self.symbols[*arg].name).unwrap();
+                }
+                for jump in &op.jumps {
+                    write!(oso, "{} ", jump).unwrap();
+                }
+                if !hints.is_empty() {
+                    write!(oso, "\t{}", hints.join(" ")).unwrap();
+                }
+                writeln!(oso).unwrap();
+            }
+        }
+
+        oso
+    }
+}
+
+fn type_name(t: &Types) -> String {
+    match t {
+        Types::Int => String::from("int"),
+        Types::Float => String::from("float"),
+        Types::String => String::from("string"),
+        Types::Color => String::from("color"),
+        Types::Point => String::from("point"),
+        Types::Vector => String::from("vector"),
+        Types::Normal => String::from("normal"),
+        Types::Matrix => String::from("matrix"),
+        Types::Void => String::from("void"),
+        Types::Closure(t) => format!("closure {}", type_name(t)),
+        Types::Array(t, Some(length)) => format!("{}[{}]", type_name(t), length),
+        Types::Array(t, None) => format!("{}[]", type_name(t)),
+        Types::Struct(name) => format!("struct {}", name),
+    }
+}
+
+fn shader_type_name(t: &ShaderTypes) -> &'static str {
+    match t {
+        ShaderTypes::Surface => "surface",
+        ShaderTypes::Displacement => "displacement",
+        ShaderTypes::Volume => "volume",
+        ShaderTypes::Light => "light",
+        ShaderTypes::Shader => "shader",
+    }
+}
+
+/// Floats are written like printf's `%.9g`, which round trips every f32.
+fn format_float(value: f64) -> String {
+    let value = value as f32 as f64;
+    if value == 0.0 {
+        return String::from("0");
+    }
+
+    let scientific = format!("{:.8e}", value);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+
+    if !(-4..9).contains(&exponent) {
+        let mantissa = mantissa.trim_end_matches('0').trim_end_matches('.');
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+    } else {
+        let fixed = format!("{:.*}", (8 - exponent) as usize, value);
+        match fixed.contains('.') {
+            true => fixed.trim_end_matches('0').trim_end_matches('.').to_owned(),
+            false => fixed,
+        }
+    }
+}
+
+/// Quotes a string value, escaping what the OSO reader would otherwise misread.
+fn quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+/// The default written for parameters that start out as zero.
+fn zero_value(t: &Types) -> String {
+    match t {
+        Types::Int | Types::Float => String::from("0"),
+        Types::String => String::from("\"\""),
+        Types::Color | Types::Point | Types::Vector | Types::Normal => String::from("0 0 0"),
+        Types::Matrix => vec!["0"; 16].join(" "),
+        Types::Array(t, Some(length)) => vec![zero_value(t); *length].join(" "),
+        _ => String::new(),
+    }
+}
+
+/// Folds a parameter default into the text of a constant value, if it is one.
+fn constant_value(expr: &Expr, to: &Types) -> Option<String> {
+    Some(match get_constant_value(expr, to)? {
+        // Array defaults list their elements one after the other
+        Value::Array(values) => values.iter().map(value_text).collect::<Vec<String>>().join(" "),
+        value => value_text(&value),
+    })
+}
+
+fn value_text(value: &Value) -> String {
+    let format = |components: &[f32]| components.iter()
+        .map(|c| format_float(*c as f64))
+        .collect::<Vec<String>>()
+        .join(" ");
+
+    match value {
+        Value::Int(i) => i.to_string(),
+        Value::Float(f) => format_float(*f as f64),
+        Value::Triple(v) => format(v),
+        Value::Matrix(m) => format(m),
+        Value::String(s) => quote(s),
+        Value::Array(values) => format!("{{{}}}", values.iter().map(value_text).collect::<Vec<String>>().join(",")),
+    }
+}
+
+/// A `%meta{type,name,value}` hint. Metadata is always an int, float or string, or an array
+/// of them.
+fn meta_hint(metadata: &Metadata) -> String {
+    let element_type = |value: &Value| match value {
+        Value::Int(_) => "int",
+        Value::Float(_) => "float",
+        Value::String(_) => "string",
+        _ => unreachable!("metadata is checked to be an int, float or string"),
+    };
+
+    let meta_type = match &metadata.value {
+        Value::Array(values) => format!("{}[{}]", element_type(&values[0]), values.len()),
+        value => element_type(value).to_owned(),
+    };
+
+    format!("%meta{{{},{},{}}}", meta_type, metadata.name, value_text(&metadata.value))
+}
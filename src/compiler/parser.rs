@@ -12,6 +12,7 @@ parser! {
             lo: a.lo,
             hi: b.hi,
             line: a.line,
+            file: a.file,
         }
     }
 
@@ -23,10 +24,15 @@ parser! {
     // Recursive NT for a list of statements
     StatementList: Vec<Stmt> {
         Statement[s] => vec![s],
+        VariableDeclaration[decls] => decls,
         StatementList[mut stmts] Statement[s] => {
             stmts.push(s);
             stmts
-        }
+        },
+        StatementList[mut stmts] VariableDeclaration[decls] => {
+            stmts.extend(decls);
+            stmts
+        },
     }
 
     OptStatementList: Vec<Stmt> {
@@ -34,21 +40,55 @@ parser! {
         => vec![],
     }
 
-    // Breaks into the different statement types
+    // Statements are split by whether they end in an `if` without an `else`, which is how an
+    // `else` is tied to the closest `if` without grammar conflicts
     Statement: Stmt {
+        MatchedStatement[s] => s,
+        UnmatchedStatement[s] => s,
+    }
+
+    // Breaks into the different statement types
+    MatchedStatement: Stmt {
         ExpressionStatement[s] => s,
         BlockStatement[s] => s,
-        VariableDeclaration[s] => s,
         FunctionDeclaration[s] => s,
         ReturnStatement[s] => s,
+        BreakStatement[s] => s,
+        ContinueStatement[s] => s,
         StructDeclaration[s] => s,
         ShaderDeclaration[s] => s,
-        IfStatement[s] => s,
-        ElseIfStatement[s] => s,
-        ElseStatement[s] => s,
-        WhileStatement[s] => s,
         DoWhileStatement[s] => s,
-        ForStatement[s] => s,
+        IfCondition[x] MatchedStatement[body] KWElse MatchedStatement[else_body] => Stmt {
+            span: span!(),
+            statement: Stmt_::IfStatement {
+                condition: x,
+                body: Box::new(body),
+                else_body: Some(Box::new(else_body)),
+            }
+        },
+        WhileCondition[x] MatchedStatement[body] => while_statement(x, body, span!()),
+        ForClauses[clauses] MatchedStatement[body] => for_statement(clauses, body, span!()),
+    }
+
+    UnmatchedStatement: Stmt {
+        IfCondition[x] Statement[body] => Stmt {
+            span: span!(),
+            statement: Stmt_::IfStatement {
+                condition: x,
+                body: Box::new(body),
+                else_body: None,
+            }
+        },
+        IfCondition[x] MatchedStatement[body] KWElse UnmatchedStatement[else_body] => Stmt {
+            span: span!(),
+            statement: Stmt_::IfStatement {
+                condition: x,
+                body: Box::new(body),
+                else_body: Some(Box::new(else_body)),
+            }
+        },
+        WhileCondition[x] UnmatchedStatement[body] => while_statement(x, body, span!()),
+        ForClauses[clauses] UnmatchedStatement[body] => for_statement(clauses, body, span!()),
     }
 
     // An expression ending in a semicolon
@@ -66,20 +106,49 @@ parser! {
         }
     }
 
-    VariableDeclaration: Stmt {
-        VariableType[var_type] Identifier[name] OptAssignment[value] Semicolon=> Stmt {
-            span: span!(),
-            statement: Stmt_::VariableDeclaration {
-                var_type,
-                name,
-                value,
-            }
+    // `float a = 1, b[2], c;` declares each name as a statement of its own
+    VariableDeclaration: Vec<Stmt> {
+        DeclarationType[var_type] DeclaratorList[declarators] Semicolon => {
+            declarators.into_iter()
+                .enumerate()
+                .map(|(index, (name, length, value, span))| {
+                    // The first declaration spans the type as well
+                    let lo = if index == 0 { var_type.span.lo } else { span.lo };
+                    let var_type = match length {
+                        Some(length) => array_type(var_type.clone(), length, &value),
+                        None => var_type.clone(),
+                    };
+
+                    Stmt {
+                        span: Span {lo, ..span},
+                        statement: Stmt_::VariableDeclaration {
+                            var_type,
+                            name,
+                            value,
+                        }
+                    }
+                })
+                .collect()
         },
     }
 
+    DeclaratorList: Vec<(Expr, Option<Expr>, Expr, Span)> {
+        Declarator[d] => vec![d],
+        DeclaratorList[mut declarators] Comma Declarator[d] => {
+            declarators.push(d);
+            declarators
+        },
+    }
+
+    // A declared name with its array length and initial value
+    Declarator: (Expr, Option<Expr>, Expr, Span) {
+        Identifier[name] OptAssignment[value] => (name, None, value, span!()),
+        Identifier[name] ArrayLength[length] OptArrayAssignment[value] => (name, Some(length), value, span!()),
+    }
+
 
     FunctionDeclaration: Stmt {
-        VariableType[ret_type] Identifier[name] LeftParen OptFormalParameterList[params] RightParen BlockStatement[s] => Stmt {
+        DeclarationType[ret_type] Identifier[name] LeftParen OptFormalParameterList[params] RightParen BlockStatement[s] => Stmt {
             span: span!(),
             statement: Stmt_::FunctionDeclaration {
                 name,
@@ -91,13 +160,14 @@ parser! {
     }
 
     ShaderDeclaration: Stmt {
-        ShaderType[shader_type] Identifier[name] LeftParen OptFormalParameterList[params] RightParen BlockStatement[s] => Stmt {
+        ShaderType[shader_type] Identifier[name] OptMetadata[metadata] LeftParen OptFormalParameterList[params] RightParen BlockStatement[s] => Stmt {
             span: span!(),
             statement: Stmt_::ShaderDeclaration {
                 name,
                 shader_type,
                 params,
                 body: Box::new(s),
+                metadata,
             }
         }
     }
@@ -109,86 +179,91 @@ parser! {
         }
     }
 
-    StructDeclaration: Stmt {
-        KWStruct Identifier[name] BlockStatement[block] => Stmt {
+    BreakStatement: Stmt {
+        KWBreak Semicolon => Stmt {
             span: span!(),
-            statement: Stmt_::StructDeclaration {
-                name,
-                body: Box::new(block),
-            }
+            statement: Stmt_::BreakStatement,
         }
     }
 
-    IfStatement: Stmt {
-        KWIf LeftParen Expression[x] RightParen BlockStatement[block] => Stmt {
+    ContinueStatement: Stmt {
+        KWContinue Semicolon => Stmt {
             span: span!(),
-            statement: Stmt_::IfStatement {
-                condition: x,
-                body: Box::new(block),
-            }
+            statement: Stmt_::ContinueStatement,
         }
     }
 
-    ElseIfStatement: Stmt {
-        ElseIf LeftParen Expression[x] RightParen BlockStatement[block] => Stmt {
+    StructDeclaration: Stmt {
+        KWStruct Identifier[name] BlockStatement[block] => Stmt {
             span: span!(),
-            statement: Stmt_::ElseIfStatement {
-                condition: x,
+            statement: Stmt_::StructDeclaration {
+                name,
                 body: Box::new(block),
             }
         }
     }
 
-    ElseStatement: Stmt {
-        KWElse BlockStatement[block] => Stmt {
-            span: span!(),
-            statement: Stmt_::ElseStatement {
-                body: Box::new(block),
-            }
-        }
+    IfCondition: Expr {
+        KWIf LeftParen Expression[x] RightParen => x,
     }
 
-    WhileStatement: Stmt {
-        KWWhile LeftParen Expression[x] RightParen BlockStatement[block] => Stmt {
-            span: span!(),
-            statement: Stmt_::WhileStatement {
-                condition: x,
-                body: Box::new(block),
-            }
-        }
+    WhileCondition: Expr {
+        KWWhile LeftParen Expression[x] RightParen => x,
     }
 
     DoWhileStatement: Stmt {
-        KWDo BlockStatement[block] KWWhile LeftParen Expression[x] RightParen => Stmt {
+        KWDo Statement[body] KWWhile LeftParen Expression[x] RightParen => Stmt {
             span: span!(),
             statement: Stmt_::DoWhileStatement {
                 condition: x,
-                body: Box::new(block),
+                body: Box::new(body),
             }
         }
     }
 
-    ForStatement: Stmt {
-        KWFor LeftParen OptAssignment[init] Semicolon Expression[cond] Semicolon Expression[iter] RightParen BlockStatement[block] => Stmt {
-            span: span!(),
-            statement: Stmt_::ForStatement {
-                initialization: init,
-                condition: cond,
-                iteration: iter,
-                body: Box::new(block),
-            }
-        }
+    // The initialization, condition and iteration of a for loop
+    ForClauses: (Vec<Stmt>, Expr, Vec<Expr>) {
+        KWFor LeftParen ForInitialization[init] OptExpression[cond] Semicolon OptExpressionList[iter] RightParen => (init, cond, iter),
+    }
+
+    // A declaration brings its own semicolon
+    ForInitialization: Vec<Stmt> {
+        VariableDeclaration[decls] => decls,
+        OptExpressionList[exprs] Semicolon => exprs.into_iter()
+            .map(|x| Stmt {
+                span: x.span,
+                statement: Stmt_::ExpressionStatement(x),
+            })
+            .collect(),
     }
 
     //===============
     // Expressions
     //===============
 
+    // Any unary expression may appear on the left, the type checker rejects the ones that
+    // aren't l-values
     VariableAssignment: Expr {
-        Identifier[s] Assignment[x] => Expr {
+        PreUnaryExpression[lhs] Assignment[x] => Expr {
             span: span!(),
-            node: Expr_::Assignment(Box::new(s), Box::new(x)),
-        }
+            node: Expr_::Assignment(Box::new(lhs), Box::new(x)),
+        },
+        PreUnaryExpression[lhs] CompoundOperator[op] Expression[rhs] => Expr {
+            span: span!(),
+            node: Expr_::BinaryExpression(op, Box::new(lhs), Box::new(rhs)),
+        },
+    }
+
+    CompoundOperator: Operators {
+        OPAddAssign => Operators::AddAssign,
+        OPSubtractAssign => Operators::SubtractAssign,
+        OPMultiplyAssign => Operators::MultiplyAssign,
+        OPDivideAssign => Operators::DivideAssign,
+        OPBitwiseAndAssign => Operators::BitwiseAndAssign,
+        OPBitwiseOrAssign => Operators::BitwiseOrAssign,
+        OPBitwiseXorAssign => Operators::BitwiseXorAssign,
+        OPShiftLeftAssign => Operators::ShiftLeftAssign,
+        OPShiftRightAssign => Operators::ShiftRightAssign,
     }
 
     Assignment: Expr {
@@ -198,8 +273,32 @@ parser! {
     OptAssignment: Expr {
         Assignment[x] => x,
         => Expr {
-            span: Span{hi: 0, lo: 0, line: 0},
+            span: Span{hi: 0, lo: 0, line: 0, file: 0},
+            node: Expr_::EmptyExpression,
+        }
+    }
+
+    // The `[4]` or `[]` after the name of an array
+    ArrayLength: Expr {
+        LeftSquare IntLiteral[x] RightSquare => Expr {
+            span: span!(),
+            node: x.node,
+        },
+        LeftSquare RightSquare => Expr {
+            span: span!(),
             node: Expr_::EmptyExpression,
+        },
+    }
+
+    OptArrayAssignment: Expr {
+        OPAssign InitializerList[x] => x,
+        OptAssignment[x] => x,
+    }
+
+    InitializerList: Expr {
+        LeftCurly ExpressionList[values] RightCurly => Expr {
+            span: span!(),
+            node: Expr_::InitializerList(Box::new(values)),
         }
     }
 
@@ -210,6 +309,15 @@ parser! {
         },
     }
 
+    // A built-in type or the name of a struct
+    DeclarationType: Expr {
+        VariableType[t] => t,
+        Ident(s) => Expr {
+            span: span!(),
+            node: Expr_::VariableType(Types::Struct(s)),
+        },
+    }
+
     VariableType: Expr {
         KWClosure Type(s) => Expr {
             span: span!(),
@@ -229,26 +337,82 @@ parser! {
     }
 
     Parameter: Expr {
-        VariableType[par_type] Identifier[name] OptAssignment[value] => Expr {
+        DeclarationType[par_type] Identifier[name] OptAssignment[value] OptMetadata[metadata] => Expr {
             span: span!(),
             node: Expr_::Parameter {
                 par_type: Box::new(par_type),
                 name: Box::new(name),
                 out: false,
                 value: Box::new(value),
+                metadata,
             }
         },
-        KWOutput VariableType[par_type] Identifier[name] OptAssignment[value] => Expr {
+        KWOutput DeclarationType[par_type] Identifier[name] OptAssignment[value] OptMetadata[metadata] => Expr {
             span: span!(),
             node: Expr_::Parameter {
                 par_type: Box::new(par_type),
                 name: Box::new(name),
                 out: true,
                 value: Box::new(value),
+                metadata,
+            }
+        },
+        DeclarationType[element] Identifier[name] ArrayLength[length] OptArrayAssignment[value] OptMetadata[metadata] => Expr {
+            span: span!(),
+            node: Expr_::Parameter {
+                par_type: Box::new(array_type(element, length, &value)),
+                name: Box::new(name),
+                out: false,
+                value: Box::new(value),
+                metadata,
+            }
+        },
+        KWOutput DeclarationType[element] Identifier[name] ArrayLength[length] OptArrayAssignment[value] OptMetadata[metadata] => Expr {
+            span: span!(),
+            node: Expr_::Parameter {
+                par_type: Box::new(array_type(element, length, &value)),
+                name: Box::new(name),
+                out: true,
+                value: Box::new(value),
+                metadata,
             }
+        },
+    }
+
+    OptMetadata: Vec<Expr> {
+        MetaBegin MetadataList[x] RightSquare RightSquare => x,
+        => vec![],
+    }
+
+    MetadataList: Vec<Expr> {
+        Metadata[x] => vec![x],
+        MetadataList[mut a] Comma Metadata[x] => {
+            a.push(x);
+            a
         }
     }
 
+    Metadata: Expr {
+        VariableType[meta_type] Identifier[name] Assignment[x] => Expr {
+            span: span!(),
+            node: Expr_::Metadata {
+                meta_type: Box::new(meta_type),
+                name: Box::new(name),
+                array: None,
+                values: Box::new(vec![x]),
+            }
+        },
+        VariableType[meta_type] Identifier[name] LeftSquare OptExpression[length] RightSquare OPAssign LeftCurly ExpressionList[values] RightCurly => Expr {
+            span: span!(),
+            node: Expr_::Metadata {
+                meta_type: Box::new(meta_type),
+                name: Box::new(name),
+                array: Some(Box::new(length)),
+                values: Box::new(values),
+            }
+        },
+    }
+
     FormalParameterList: Vec<Expr> {
         Parameter[x] => vec![x],
         FormalParameterList[mut p] Comma Parameter[x] => {
@@ -278,7 +442,7 @@ parser! {
     Expression: Expr {
         VariableAssignment[x] => x,
         ExplicitCastExpression[x] => x,
-        LogicalOrExpression[x] => x,
+        ConditionalExpression[x] => x,
         // FunctionCall[x] => x,
         // PointConstructorExpression[x] => x,
     }
@@ -286,7 +450,7 @@ parser! {
     OptExpression: Expr {
         Expression[x] => x,
         => Expr {
-            span: Span{hi: 0, lo: 0, line: 0},
+            span: Span{hi: 0, lo: 0, line: 0, file: 0},
             node: Expr_::EmptyExpression,
         }
     }
@@ -302,7 +466,7 @@ parser! {
     }
 
     FunctionCall: Expr {
-        Identifier[name] LeftParen ExpressionList[arguments] RightParen => Expr {
+        Identifier[name] LeftParen OptExpressionList[arguments] RightParen => Expr {
             span: span!(),
             node: Expr_::FunctionCallExpression {
                 name: Box::new(name),
@@ -342,6 +506,18 @@ parser! {
     // }
 
 
+    ConditionalExpression: Expr {
+        LogicalOrExpression[condition] Question Expression[if_true] Colon ConditionalExpression[if_false] => Expr {
+            span: span!(),
+            node: Expr_::TernaryExpression {
+                condition: Box::new(condition),
+                if_true: Box::new(if_true),
+                if_false: Box::new(if_false),
+            }
+        },
+        LogicalOrExpression[x] => x,
+    }
+
     LogicalOrExpression: Expr {
         LogicalOrExpression[lhs] OPLogicalOr LogicalAndExpression[rhs] => Expr {
             span: span!(),
@@ -491,7 +667,7 @@ parser! {
     }
 
     AccessExpression: Expr {
-        PrimaryExpression[lhs] LeftSquare IntLiteral[val] RightSquare => Expr {
+        AccessExpression[lhs] LeftSquare Expression[val] RightSquare => Expr {
             span: span!(),
             node: Expr_::AccessExpression {
                 lhs: Box::new(lhs),
@@ -499,7 +675,7 @@ parser! {
                 dot: false,
             }
         },
-        PrimaryExpression[lhs] Period Identifier[val] => Expr {
+        AccessExpression[lhs] Period Identifier[val] => Expr {
             span: span!(),
             node: Expr_::AccessExpression {
                 lhs: Box::new(lhs),
@@ -549,7 +725,15 @@ parser! {
         Str(i) => Expr {
             span: span!(),
             node: Expr_::StringLiteral(i),
-        }
+        },
+        // Adjacent literals are joined like in C
+        StringLiteral[x] Str(i) => Expr {
+            span: span!(),
+            node: match x.node {
+                Expr_::StringLiteral(s) => Expr_::StringLiteral(s + &i),
+                _ => unreachable!(),
+            },
+        },
     }
 
     ParenthesizedExpression: Expr {
@@ -557,6 +741,42 @@ parser! {
     }
 }
 
+/// Turns the element type of an array declaration into the array type, spanning up to the
+/// closing bracket.
+fn array_type(element: Expr, length: Expr, value: &Expr) -> Expr {
+    Expr {
+        span: Span {
+            lo: element.span.lo,
+            hi: length.span.hi,
+            line: element.span.line,
+            file: element.span.file,
+        },
+        node: Expr_::VariableType(get_array_type(&element, &length, value)),
+    }
+}
+
+fn while_statement(condition: Expr, body: Stmt, span: Span) -> Stmt {
+    Stmt {
+        span,
+        statement: Stmt_::WhileStatement {
+            condition,
+            body: Box::new(body),
+        }
+    }
+}
+
+fn for_statement((initialization, condition, iteration): (Vec<Stmt>, Expr, Vec<Expr>), body: Stmt, span: Span) -> Stmt {
+    Stmt {
+        span,
+        statement: Stmt_::ForStatement {
+            initialization,
+            condition,
+            iteration,
+            body: Box::new(body),
+        }
+    }
+}
+
 pub fn parse<I: Iterator<Item = (Token, Span)>>(
     i: I,
 ) -> Result<Vec<Stmt>, (Option<(Token, Span)>, &'static str)> {
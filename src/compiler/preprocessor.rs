@@ -0,0 +1,1153 @@
+use super::*;
+
+use crate::errors::*;
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Includes nested deeper than this are assumed to be recursive.
+const MAX_INCLUDE_DEPTH: usize = 64;
+
+/// Headers whose declarations are built into the compiler. Including them has no effect unless
+/// a file of the same name is found on the include paths.
+const BUILTIN_HEADERS: &[&str] = &["stdosl.h"];
+
+/// Binary operators of `#if` expressions, from the lowest precedence to the highest.
+const BINARY_OPERATORS: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", ">", "<=", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+/// The source after preprocessing, along with where each part of it came from.
+#[derive(Debug, Clone)]
+pub struct Preprocessed {
+    pub text: String,
+    pub map: SourceMap,
+}
+
+/// A file that took part in preprocessing. The main file is always the first one.
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub name: String,
+    pub contents: String,
+    /// The file and range of the `#include` directive that pulled this file in.
+    pub included_from: Option<(usize, Range<usize>)>,
+    path: Option<PathBuf>,
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    fn new(name: String, contents: String, path: Option<PathBuf>, included_from: Option<(usize, Range<usize>)>) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(contents.match_indices('\n').map(|(index, _)| index + 1))
+            .collect();
+
+        SourceFile {
+            name,
+            contents,
+            included_from,
+            path,
+            line_starts,
+        }
+    }
+
+    /// The 1-based line of an offset in the file.
+    pub fn line(&self, offset: usize) -> usize {
+        self.line_starts.partition_point(|start| *start <= offset)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Segment {
+    output: Range<usize>,
+    file: usize,
+    source: Range<usize>,
+    // Macro expansions do not line up with their source, any position in one maps to the
+    // whole invocation
+    expanded: bool,
+}
+
+/// Maps positions in the preprocessed text back to the files they came from.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+    segments: Vec<Segment>,
+    lines: Vec<(usize, usize)>,
+}
+
+impl SourceMap {
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    /// The file and line that each line of the output came from.
+    pub fn lines(&self) -> &[(usize, usize)] {
+        &self.lines
+    }
+
+    /// The file and offset a position of the output came from. `end` resolves a position
+    /// between two segments to the one before it, as needed for the end of a range.
+    pub fn locate(&self, offset: usize, end: bool) -> (usize, usize) {
+        let index = match end && offset > 0 {
+            true => self.segments.partition_point(|segment| segment.output.start < offset),
+            false => self.segments.partition_point(|segment| segment.output.start <= offset),
+        };
+
+        let segment = match index {
+            0 => match self.segments.first() {
+                Some(segment) => segment,
+                None => return (0, offset),
+            },
+            index => &self.segments[index - 1],
+        };
+
+        let position = match (segment.expanded, end) {
+            (true, false) => segment.source.start,
+            (true, true) => segment.source.end,
+            (false, _) => (segment.source.start + offset.saturating_sub(segment.output.start)).min(segment.source.end),
+        };
+
+        (segment.file, position)
+    }
+
+    /// Maps a range of the output back to the file it came from. A range that starts and ends
+    /// in different files maps to the innermost file that contains both ends, where it covers
+    /// the `#include` directives in between.
+    pub fn original_location(&self, range: Range<usize>) -> (usize, Range<usize>) {
+        let (lo_file, lo) = self.locate(range.start, false);
+        let (hi_file, hi) = self.locate(range.end.max(range.start), true);
+
+        let hi_chain = self.include_chain(hi_file, hi..hi);
+        for (file, lo_range) in self.include_chain(lo_file, lo..lo) {
+            if let Some((_, hi_range)) = hi_chain.iter().find(|(hi_file, _)| *hi_file == file) {
+                let lo = lo_range.start;
+                return (file, lo..hi_range.end.max(lo));
+            }
+        }
+
+        (0, range)
+    }
+
+    /// Points every diagnostic back to the files they came from.
+    pub fn remap(&self, diagnostics: Diagnostics) -> Diagnostics {
+        diagnostics
+            .map_locations(&|range| self.original_location(range))
+            .with_sources(self.files.clone())
+    }
+
+    /// A range in a file followed by the `#include` directives that led to it, up to the main
+    /// file.
+    fn include_chain(&self, mut file: usize, mut range: Range<usize>) -> Vec<(usize, Range<usize>)> {
+        let mut chain = vec![(file, range.clone())];
+        while let Some((parent, directive)) = &self.files[file].included_from {
+            file = *parent;
+            range = directive.clone();
+            chain.push((file, range.clone()));
+        }
+
+        chain
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    /// `None` for object-like macros.
+    params: Option<Vec<String>>,
+    body: String,
+}
+
+#[derive(Debug, Clone)]
+struct Conditional {
+    active: bool,
+    /// Whether a branch of the conditional has been taken already.
+    taken: bool,
+    seen_else: bool,
+    range: Range<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Ident,
+    Number,
+    Str,
+    Comment,
+    Space,
+    Newline,
+    Punct,
+}
+
+#[derive(Debug, Clone)]
+struct PPToken {
+    kind: Kind,
+    range: Range<usize>,
+}
+
+/// Runs the C preprocessor over the source: includes files, expands macros and drops the
+/// regions excluded by conditionals.
+pub fn preprocess(contents: &str, options: &CompileOptions) -> Result<Preprocessed, Diagnostics> {
+    let mut preprocessor = Preprocessor::new(options);
+
+    let name = options.file_name.clone().unwrap_or_else(|| String::from("<source>"));
+    let path = options.file_name.as_ref().map(PathBuf::from);
+    preprocessor.files.push(SourceFile::new(name, contents.to_owned(), path, None));
+
+    preprocessor.process_file(0, 0);
+    if let Err(diagnostics) = preprocessor.diagnostics.check() {
+        return Err(diagnostics.with_sources(preprocessor.files));
+    }
+
+    Ok(preprocessor.finish())
+}
+
+struct Preprocessor<'a> {
+    options: &'a CompileOptions,
+    macros: HashMap<String, Macro>,
+    files: Vec<SourceFile>,
+    // Files with `#pragma once`
+    once: HashSet<PathBuf>,
+
+    output: String,
+    segments: Vec<Segment>,
+    diagnostics: Diagnostics,
+}
+
+impl<'a> Preprocessor<'a> {
+    fn new(options: &'a CompileOptions) -> Self {
+        let mut macros = HashMap::new();
+        for (name, value) in &options.defines {
+            macros.insert(name.clone(), Macro {
+                params: None,
+                body: value.clone().unwrap_or_else(|| String::from("1")),
+            });
+        }
+
+        Preprocessor {
+            options,
+            macros,
+            files: Vec::new(),
+            once: HashSet::new(),
+            output: String::new(),
+            segments: Vec::new(),
+            diagnostics: Diagnostics::new(),
+        }
+    }
+
+    fn finish(self) -> Preprocessed {
+        let mut map = SourceMap {
+            files: self.files,
+            segments: self.segments,
+            lines: Vec::new(),
+        };
+
+        let line_starts = std::iter::once(0).chain(self.output.match_indices('\n').map(|(index, _)| index + 1));
+        let lines = line_starts
+            .map(|start| {
+                let (file, offset) = map.locate(start, false);
+                (file, map.files[file].line(offset))
+            })
+            .collect();
+        map.lines = lines;
+
+        Preprocessed {
+            text: self.output,
+            map,
+        }
+    }
+
+    /// Records an error at a range of a file.
+    fn error(&mut self, file: usize, range: Range<usize>, message: impl Into<String>, label: impl Into<String>) {
+        let line = self.files[file].line(range.start);
+        self.diagnostics.push(OSLCompilerError::PreprocessorError {
+            message: message.into(),
+            error: Item::new(Span {lo: range.start, hi: range.end, line, file}, label),
+        });
+    }
+
+    fn emit(&mut self, text: &str, file: usize, source: Range<usize>, expanded: bool) {
+        if text.is_empty() {
+            return;
+        }
+
+        let start = self.output.len();
+        self.output.push_str(text);
+        let output = start..self.output.len();
+
+        if let Some(last) = self.segments.last_mut() {
+            if !expanded && !last.expanded && last.file == file && last.output.end == start && last.source.end == source.start {
+                last.output.end = output.end;
+                last.source.end = source.end;
+                return;
+            }
+        }
+
+        self.segments.push(Segment {output, file, source, expanded});
+    }
+
+    /// Emits just the line breaks of a range, so that line numbers stay in step.
+    fn emit_newlines(&mut self, file: usize, range: Range<usize>) {
+        let breaks: Vec<usize> = self.files[file].contents[range.clone()]
+            .match_indices('\n')
+            .map(|(index, _)| range.start + index)
+            .collect();
+
+        for position in breaks {
+            self.emit("\n", file, position..position + 1, false);
+        }
+    }
+
+    fn process_file(&mut self, file: usize, depth: usize) {
+        let contents = self.files[file].contents.clone();
+        let lines: Vec<Range<usize>> = self.files[file].line_starts.iter()
+            .map(|start| *start..contents[*start..].find('\n').map_or(contents.len(), |end| start + end + 1))
+            .filter(|line| !line.is_empty())
+            .collect();
+
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        let mut in_comment = false;
+        let mut chunk: Option<(usize, bool)> = None;
+        let mut index = 0;
+
+        while index < lines.len() {
+            let line = &contents[lines[index].clone()];
+
+            if in_comment || !line.trim_start().starts_with('#') {
+                if chunk.is_none() {
+                    chunk = Some((lines[index].start, in_comment));
+                }
+                in_comment = pp_tokens(line, in_comment).1;
+                index += 1;
+                continue;
+            }
+
+            if let Some((start, chunk_comment)) = chunk.take() {
+                self.process_chunk(file, start..lines[index].start, chunk_comment, &conditionals);
+            }
+
+            // Directives continue on the next line after a backslash
+            let mut last = index;
+            while last + 1 < lines.len() && contents[lines[last].clone()].trim_end().ends_with('\\') {
+                last += 1;
+            }
+
+            let range = lines[index].start..lines[last].end;
+            let text = contents[range.clone()].replace("\\\r\n", " ").replace("\\\n", " ");
+            let (tokens, comment) = pp_tokens(&text, false);
+            in_comment = comment;
+
+            // Comments count as whitespace
+            let text: String = tokens.iter()
+                .map(|token| match token.kind {
+                    Kind::Comment | Kind::Newline => " ",
+                    _ => &text[token.range.clone()],
+                })
+                .collect();
+
+            let trimmed = contents[range.clone()].trim_end();
+            let directive = range.start..range.start + trimmed.len();
+            self.directive(file, text.trim(), directive, &mut conditionals, depth);
+            self.emit_newlines(file, range);
+
+            index = last + 1;
+        }
+
+        if let Some((start, chunk_comment)) = chunk.take() {
+            self.process_chunk(file, start..contents.len(), chunk_comment, &conditionals);
+        }
+
+        for conditional in conditionals {
+            self.error(file, conditional.range, "Unterminated conditional directive", "This conditional has no matching #endif");
+        }
+    }
+
+    /// Expands the macros in a run of lines without directives, or drops it if a conditional
+    /// excludes it.
+    fn process_chunk(&mut self, file: usize, range: Range<usize>, in_comment: bool, conditionals: &[Conditional]) {
+        if !conditionals.iter().all(|conditional| conditional.active) {
+            self.emit_newlines(file, range);
+            return;
+        }
+
+        let text = self.files[file].contents[range.clone()].to_owned();
+        let base = range.start;
+        let (tokens, _) = pp_tokens(&text, in_comment);
+
+        let mut verbatim = 0;
+        let mut index = 0;
+        while index < tokens.len() {
+            let token = &tokens[index];
+            if token.kind != Kind::Ident {
+                index += 1;
+                continue;
+            }
+
+            let location = (file, base + token.range.start);
+            match self.invocation(&text, &tokens, index, &HashSet::new(), location) {
+                Ok(Some((expansion, next))) => {
+                    let start = token.range.start;
+                    let end = tokens[next - 1].range.end;
+
+                    self.emit(&text[verbatim..start], file, base + verbatim..base + start, false);
+                    self.emit(&expansion, file, base + start..base + end, true);
+                    // Arguments can span several lines
+                    self.emit_newlines(file, base + start..base + end);
+
+                    verbatim = end;
+                    index = next;
+                },
+                Ok(None) => index += 1,
+                Err(message) => {
+                    let range = base + token.range.start..base + token.range.end;
+                    self.error(file, range, message, "In this macro invocation");
+                    index += 1;
+                },
+            }
+        }
+
+        self.emit(&text[verbatim..], file, base + verbatim..range.end, false);
+    }
+
+    fn directive(&mut self, file: usize, text: &str, range: Range<usize>, conditionals: &mut Vec<Conditional>, depth: usize) {
+        let text = text[1..].trim_start();
+        let name_length = text.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(text.len());
+        let (name, rest) = text.split_at(name_length);
+        let rest = rest.trim();
+
+        let active = conditionals.iter().all(|conditional| conditional.active);
+        let location = (file, range.start);
+
+        match name {
+            "if" | "ifdef" | "ifndef" => {
+                let condition = match (active, name) {
+                    (false, _) => false,
+                    (true, "ifdef") => self.macros.contains_key(macro_name(rest)),
+                    (true, "ifndef") => !self.macros.contains_key(macro_name(rest)),
+                    (true, _) => self.condition(rest, location, range.clone()),
+                };
+
+                conditionals.push(Conditional {
+                    active: condition,
+                    // An excluded region never takes any of its branches
+                    taken: condition || !active,
+                    seen_else: false,
+                    range,
+                });
+            },
+
+            "elif" => {
+                let conditional = match conditionals.last() {
+                    Some(conditional) if !conditional.seen_else => conditional.clone(),
+                    Some(_) => return self.error(file, range, "#elif after #else", "Unexpected #elif"),
+                    None => return self.error(file, range, "#elif without #if", "Unexpected #elif"),
+                };
+
+                let condition = !conditional.taken && self.condition(rest, location, range);
+                let conditional = conditionals.last_mut().unwrap();
+                conditional.active = condition;
+                conditional.taken |= condition;
+            },
+
+            "else" => {
+                match conditionals.last_mut() {
+                    Some(conditional) if !conditional.seen_else => {
+                        conditional.active = !conditional.taken;
+                        conditional.taken = true;
+                        conditional.seen_else = true;
+                    },
+                    Some(_) => self.error(file, range, "#else after #else", "Unexpected #else"),
+                    None => self.error(file, range, "#else without #if", "Unexpected #else"),
+                }
+            },
+
+            "endif" => {
+                if conditionals.pop().is_none() {
+                    self.error(file, range, "#endif without #if", "Unexpected #endif");
+                }
+            },
+
+            // Everything else is ignored in excluded regions
+            _ if !active => {},
+
+            "" => {},
+
+            "define" => self.define(file, rest, range),
+
+            "undef" => {
+                self.macros.remove(macro_name(rest));
+            },
+
+            "include" => self.include(file, rest, range, depth),
+
+            "error" => self.error(file, range, format!("#error {}", rest), "Raised here"),
+
+            "pragma" => {
+                if rest == "once" {
+                    if let Some(path) = self.files[file].path.as_ref().and_then(|path| path.canonicalize().ok()) {
+                        self.once.insert(path);
+                    }
+                }
+            },
+
+            _ => self.error(file, range, format!("Unknown preprocessor directive #{}", name), "Unknown directive"),
+        }
+    }
+
+    fn define(&mut self, file: usize, text: &str, range: Range<usize>) {
+        let name = macro_name(text);
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            return self.error(file, range, "Expected a macro name after #define", "Missing macro name");
+        }
+
+        let rest = &text[name.len()..];
+
+        // A parenthesis right after the name makes a function-like macro
+        let (params, body) = match rest.strip_prefix('(') {
+            Some(rest) => {
+                let close = match rest.find(')') {
+                    Some(close) => close,
+                    None => return self.error(file, range, "Unterminated macro parameter list", "Missing )"),
+                };
+
+                let params: Vec<String> = rest[..close].split(',')
+                    .map(|param| param.trim().to_owned())
+                    .filter(|param| !param.is_empty())
+                    .collect();
+
+                if let Some(param) = params.iter().find(|param| macro_name(param).len() != param.len()) {
+                    let message = format!("Invalid macro parameter {}", param);
+                    return self.error(file, range, message, "In this definition");
+                }
+
+                (Some(params), &rest[close + 1..])
+            },
+            None => (None, rest),
+        };
+
+        self.macros.insert(name.to_owned(), Macro {
+            params,
+            body: body.trim().to_owned(),
+        });
+    }
+
+    fn include(&mut self, file: usize, text: &str, range: Range<usize>, depth: usize) {
+        let mut text = text.to_owned();
+        if !text.starts_with('"') && !text.starts_with('<') {
+            text = self.expand_string(&text, &HashSet::new(), (file, range.start)).unwrap_or_default().trim().to_owned();
+        }
+
+        let (name, quoted) = match (text.chars().next(), text.get(1..)) {
+            (Some('"'), Some(rest)) if rest.contains('"') => (rest[..rest.find('"').unwrap()].to_owned(), true),
+            (Some('<'), Some(rest)) if rest.contains('>') => (rest[..rest.find('>').unwrap()].to_owned(), false),
+            _ => return self.error(file, range, "Expected \"file\" or <file> after #include", "Invalid #include"),
+        };
+
+        if depth >= MAX_INCLUDE_DEPTH {
+            return self.error(file, range, "#include nested too deeply", "Most likely a file that includes itself");
+        }
+
+        let path = match self.find_include(file, &name, quoted) {
+            Some(path) => path,
+            None if BUILTIN_HEADERS.contains(&name.as_str()) => return,
+            None => return self.error(file, range, format!("Cannot find the include file {}", name), "Included here"),
+        };
+
+        if path.canonicalize().map_or(false, |path| self.once.contains(&path)) {
+            return;
+        }
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(error) => return self.error(file, range, format!("Cannot read the include file {}: {}", name, error), "Included here"),
+        };
+
+        self.files.push(SourceFile::new(path.display().to_string(), contents, Some(path), Some((file, range))));
+        self.process_file(self.files.len() - 1, depth + 1);
+    }
+
+    /// Quoted includes are looked up next to the including file first, then on the include
+    /// paths like angle bracket includes.
+    fn find_include(&self, file: usize, name: &str, quoted: bool) -> Option<PathBuf> {
+        if Path::new(name).is_absolute() {
+            return Some(PathBuf::from(name)).filter(|path| path.is_file());
+        }
+
+        let mut directories: Vec<PathBuf> = Vec::new();
+        if quoted {
+            let parent = self.files[file].path.as_ref().and_then(|path| path.parent());
+            directories.push(parent.map_or_else(PathBuf::new, Path::to_path_buf));
+        }
+        directories.extend(self.options.include_paths.iter().cloned());
+
+        directories.into_iter()
+            .map(|directory| directory.join(name))
+            .find(|path| path.is_file())
+    }
+
+    /// If the identifier at `index` invokes a macro, returns its expansion and the index of the
+    /// first token after the invocation.
+    fn invocation(&self, text: &str, tokens: &[PPToken], index: usize, disabled: &HashSet<String>,
+                  location: (usize, usize)) -> Result<Option<(String, usize)>, String> {
+        let name = &text[tokens[index].range.clone()];
+        let (file, offset) = location;
+
+        match name {
+            "__LINE__" => return Ok(Some((self.files[file].line(offset).to_string(), index + 1))),
+            "__FILE__" => return Ok(Some((string_literal(&self.files[file].name), index + 1))),
+            _ => {},
+        }
+
+        if disabled.contains(name) {
+            return Ok(None);
+        }
+
+        let definition = match self.macros.get(name) {
+            Some(definition) => definition,
+            None => return Ok(None),
+        };
+
+        let params = match &definition.params {
+            Some(params) => params,
+            None => return Ok(Some((self.substitute(name, definition, &[], disabled, location)?, index + 1))),
+        };
+
+        // Function-like macros are only invoked when followed by arguments
+        let mut next = index + 1;
+        while next < tokens.len() && matches!(tokens[next].kind, Kind::Space | Kind::Newline | Kind::Comment) {
+            next += 1;
+        }
+        if next == tokens.len() || &text[tokens[next].range.clone()] != "(" {
+            return Ok(None);
+        }
+
+        let mut arguments = vec![String::new()];
+        let mut nesting = 0;
+        for (position, token) in tokens.iter().enumerate().skip(next + 1) {
+            let token_text = match token.kind {
+                Kind::Comment | Kind::Newline => " ",
+                _ => &text[token.range.clone()],
+            };
+
+            match token_text {
+                ")" if nesting == 0 => {
+                    if arguments.len() == 1 && arguments[0].trim().is_empty() && params.is_empty() {
+                        arguments.clear();
+                    }
+
+                    if arguments.len() != params.len() {
+                        return Err(format!("Macro {} takes {} arguments but {} were given", name, params.len(), arguments.len()));
+                    }
+
+                    let arguments: Vec<String> = arguments.iter().map(|argument| argument.trim().to_owned()).collect();
+                    return Ok(Some((self.substitute(name, definition, &arguments, disabled, location)?, position + 1)));
+                },
+                "," if nesting == 0 => arguments.push(String::new()),
+                _ => {
+                    match token_text {
+                        "(" => nesting += 1,
+                        ")" => nesting -= 1,
+                        _ => {},
+                    }
+                    arguments.last_mut().unwrap().push_str(token_text);
+                },
+            }
+        }
+
+        Err(format!("Unterminated invocation of macro {}", name))
+    }
+
+    /// Replaces the parameters in the body of a macro and expands the result again, with the
+    /// macro itself disabled so that it cannot recurse.
+    fn substitute(&self, name: &str, definition: &Macro, arguments: &[String], disabled: &HashSet<String>,
+                  location: (usize, usize)) -> Result<String, String> {
+        let body = &definition.body;
+        let (tokens, _) = pp_tokens(body, false);
+        let params = definition.params.clone().unwrap_or_default();
+
+        let argument = |token: &PPToken| -> Option<&String> {
+            match token.kind {
+                Kind::Ident => params.iter().position(|param| *param == body[token.range.clone()]).map(|index| &arguments[index]),
+                _ => None,
+            }
+        };
+
+        // Tokens next to ## are pasted, and parameters there are not expanded
+        let significant: Vec<usize> = (0..tokens.len()).filter(|index| tokens[*index].kind != Kind::Space).collect();
+        let pasted = |index: usize| -> bool {
+            let position = significant.iter().position(|significant| *significant == index).unwrap();
+            let before = position.checked_sub(1).map(|before| &body[tokens[significant[before]].range.clone()]);
+            let after = significant.get(position + 1).map(|after| &body[tokens[*after].range.clone()]);
+            before == Some("##") || after == Some("##")
+        };
+
+        let mut substituted = String::new();
+        let mut index = 0;
+        while index < tokens.len() {
+            let token = &tokens[index];
+            let token_text = &body[token.range.clone()];
+
+            match (token_text, token.kind) {
+                ("##", _) => {
+                    substituted.truncate(substituted.trim_end().len());
+                    index += 1;
+                    while index < tokens.len() && tokens[index].kind == Kind::Space {
+                        index += 1;
+                    }
+                    continue;
+                },
+                ("#", _) if definition.params.is_some() => {
+                    let mut next = index + 1;
+                    while next < tokens.len() && tokens[next].kind == Kind::Space {
+                        next += 1;
+                    }
+                    match tokens.get(next).and_then(|token| argument(token)) {
+                        Some(value) => {
+                            substituted.push_str(&string_literal(value));
+                            index = next + 1;
+                            continue;
+                        },
+                        None => return Err(format!("# in macro {} must be followed by a parameter", name)),
+                    }
+                },
+                (_, Kind::Ident) => match argument(token) {
+                    Some(value) if pasted(index) => substituted.push_str(value),
+                    Some(value) => substituted.push_str(&self.expand_string(value, disabled, location)?),
+                    None => substituted.push_str(token_text),
+                },
+                _ => substituted.push_str(token_text),
+            }
+
+            index += 1;
+        }
+
+        let mut disabled = disabled.clone();
+        disabled.insert(name.to_owned());
+        self.expand_string(&substituted, &disabled, location)
+    }
+
+    /// Expands every macro in a piece of text that is not part of a file, such as a macro body.
+    fn expand_string(&self, text: &str, disabled: &HashSet<String>, location: (usize, usize)) -> Result<String, String> {
+        let (tokens, _) = pp_tokens(text, false);
+
+        let mut expanded = String::new();
+        let mut index = 0;
+        while index < tokens.len() {
+            if tokens[index].kind == Kind::Ident {
+                if let Some((expansion, next)) = self.invocation(text, &tokens, index, disabled, location)? {
+                    expanded.push_str(&expansion);
+                    index = next;
+                    continue;
+                }
+            }
+
+            expanded.push_str(&text[tokens[index].range.clone()]);
+            index += 1;
+        }
+
+        Ok(expanded)
+    }
+
+    /// Evaluates the condition of an `#if` or `#elif`, recording an error if it is invalid.
+    fn condition(&mut self, text: &str, location: (usize, usize), range: Range<usize>) -> bool {
+        match self.evaluate(text, location) {
+            Ok(value) => value != 0,
+            Err(message) => {
+                self.error(location.0, range, message, "In this condition");
+                false
+            },
+        }
+    }
+
+    fn evaluate(&self, text: &str, location: (usize, usize)) -> Result<i64, String> {
+        let (tokens, _) = pp_tokens(text, false);
+        let significant: Vec<&str> = tokens.iter()
+            .filter(|token| token.kind != Kind::Space)
+            .map(|token| &text[token.range.clone()])
+            .collect();
+
+        // `defined` has to be resolved before the macros it names are expanded
+        let mut resolved = Vec::new();
+        let mut index = 0;
+        while index < significant.len() {
+            if significant[index] != "defined" {
+                resolved.push(significant[index].to_owned());
+                index += 1;
+                continue;
+            }
+
+            let name = match significant.get(index + 1) {
+                Some(&"(") if significant.get(index + 3) == Some(&")") => {
+                    index += 4;
+                    significant[index - 2]
+                },
+                Some(name) if *name != "(" => {
+                    index += 2;
+                    *name
+                },
+                _ => return Err(String::from("Expected a macro name after defined")),
+            };
+
+            resolved.push(String::from(if self.macros.contains_key(name) { "1" } else { "0" }));
+        }
+
+        let expanded = self.expand_string(&resolved.join(" "), &HashSet::new(), location)?;
+
+        let mut expression = Expression {
+            tokens: expression_tokens(&expanded),
+            position: 0,
+            evaluated: true,
+        };
+        let value = expression.ternary()?;
+
+        match expression.tokens.get(expression.position) {
+            None => Ok(value),
+            Some(token) => Err(format!("Unexpected {} in preprocessor expression", token)),
+        }
+    }
+}
+
+/// The identifier at the start of the text.
+fn macro_name(text: &str) -> &str {
+    let text = text.trim_start();
+    let length = text.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(text.len());
+    &text[..length]
+}
+
+fn string_literal(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Splits text into preprocessing tokens. `in_comment` tells whether the text starts inside a
+/// block comment, and the returned flag whether it ends inside one.
+fn pp_tokens(text: &str, in_comment: bool) -> (Vec<PPToken>, bool) {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let offset = |index: usize| chars.get(index).map_or(text.len(), |(offset, _)| *offset);
+    let at = |index: usize| chars.get(index).map(|(_, c)| *c);
+
+    let mut tokens = Vec::new();
+    let mut in_comment = in_comment;
+    let mut index = 0;
+
+    while index < chars.len() {
+        let start = index;
+        let c = chars[index].1;
+
+        let kind = if in_comment || (c == '/' && at(index + 1) == Some('*')) {
+            if !in_comment {
+                index += 2;
+            }
+            in_comment = true;
+            while index < chars.len() {
+                if at(index) == Some('*') && at(index + 1) == Some('/') {
+                    index += 2;
+                    in_comment = false;
+                    break;
+                }
+                index += 1;
+            }
+            Kind::Comment
+        }
+        else if c == '/' && at(index + 1) == Some('/') {
+            while index < chars.len() && at(index) != Some('\n') {
+                index += 1;
+            }
+            Kind::Comment
+        }
+        else if c == '"' {
+            index += 1;
+            while let Some(c) = at(index) {
+                index += 1;
+                match c {
+                    '\\' if at(index).map_or(false, |c| c != '\n') => index += 1,
+                    '"' => break,
+                    '\n' => {
+                        index -= 1;
+                        break;
+                    },
+                    _ => {},
+                }
+            }
+            Kind::Str
+        }
+        else if c == '\n' {
+            index += 1;
+            Kind::Newline
+        }
+        else if c.is_whitespace() {
+            while at(index).map_or(false, |c| c.is_whitespace() && c != '\n') {
+                index += 1;
+            }
+            Kind::Space
+        }
+        else if c.is_ascii_alphabetic() || c == '_' {
+            while at(index).map_or(false, |c| c.is_ascii_alphanumeric() || c == '_') {
+                index += 1;
+            }
+            Kind::Ident
+        }
+        else if c.is_ascii_digit() || (c == '.' && at(index + 1).map_or(false, |c| c.is_ascii_digit())) {
+            index += 1;
+            while let Some(c) = at(index) {
+                let exponent = matches!(chars[index - 1].1, 'e' | 'E') && matches!(c, '+' | '-');
+                if !(c.is_ascii_alphanumeric() || c == '_' || c == '.' || exponent) {
+                    break;
+                }
+                index += 1;
+            }
+            Kind::Number
+        }
+        else if c == '#' && at(index + 1) == Some('#') {
+            index += 2;
+            Kind::Punct
+        }
+        else {
+            index += 1;
+            Kind::Punct
+        };
+
+        tokens.push(PPToken {
+            kind,
+            range: offset(start)..offset(index),
+        });
+    }
+
+    (tokens, in_comment)
+}
+
+fn expression_tokens(text: &str) -> Vec<String> {
+    const OPERATORS: &[&str] = &["||", "&&", "==", "!=", "<=", ">=", "<<", ">>"];
+
+    let (tokens, _) = pp_tokens(text, false);
+    let mut result: Vec<String> = Vec::new();
+
+    for token in tokens {
+        let token_text = &text[token.range];
+        match token.kind {
+            Kind::Space | Kind::Newline | Kind::Comment => {},
+            Kind::Punct => {
+                // Join two character operators that were split into single characters
+                let joined = result.last().map(|last| format!("{}{}", last, token_text));
+                match joined {
+                    Some(joined) if OPERATORS.contains(&joined.as_str()) => *result.last_mut().unwrap() = joined,
+                    _ => result.push(token_text.to_owned()),
+                }
+            },
+            _ => result.push(token_text.to_owned()),
+        }
+    }
+
+    result
+}
+
+/// A constant integer expression of an `#if`. Identifiers left after macro expansion are 0.
+struct Expression {
+    tokens: Vec<String>,
+    position: usize,
+    /// False while parsing an operand whose value is not used, like the right side of
+    /// `0 && x`. Such operands cannot fail to evaluate.
+    evaluated: bool,
+}
+
+impl Expression {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(|token| token.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.peek() {
+            Some(token) if token == expected => {
+                self.position += 1;
+                Ok(())
+            },
+            Some(token) => Err(format!("Expected {} but found {} in preprocessor expression", expected, token)),
+            None => Err(format!("Expected {} at the end of preprocessor expression", expected)),
+        }
+    }
+
+    /// Parses an operand with `parse`, only evaluating it if `used`.
+    fn operand(&mut self, used: bool, parse: impl FnOnce(&mut Self) -> Result<i64, String>) -> Result<i64, String> {
+        let evaluated = self.evaluated;
+        self.evaluated = evaluated && used;
+        let value = parse(self);
+        self.evaluated = evaluated;
+        value
+    }
+
+    fn ternary(&mut self) -> Result<i64, String> {
+        let condition = self.binary(0)?;
+        if self.peek() != Some("?") {
+            return Ok(condition);
+        }
+
+        self.position += 1;
+        let if_true = self.operand(condition != 0, Self::ternary)?;
+        self.expect(":")?;
+        let if_false = self.operand(condition == 0, Self::ternary)?;
+
+        Ok(if condition != 0 { if_true } else { if_false })
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, String> {
+        if level == BINARY_OPERATORS.len() {
+            return self.unary();
+        }
+
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(op) = self.peek().map(String::from).filter(|op| BINARY_OPERATORS[level].contains(&op.as_str())) {
+            self.position += 1;
+            // The right side of && and || is only evaluated if the left side does not decide
+            let used = match op.as_str() {
+                "&&" => lhs != 0,
+                "||" => lhs == 0,
+                _ => true,
+            };
+            let rhs = self.operand(used, |expression| expression.binary(level + 1))?;
+
+            lhs = match op.as_str() {
+                "||" => (lhs != 0 || rhs != 0) as i64,
+                "&&" => (lhs != 0 && rhs != 0) as i64,
+                "|" => lhs | rhs,
+                "^" => lhs ^ rhs,
+                "&" => lhs & rhs,
+                "==" => (lhs == rhs) as i64,
+                "!=" => (lhs != rhs) as i64,
+                "<" => (lhs < rhs) as i64,
+                ">" => (lhs > rhs) as i64,
+                "<=" => (lhs <= rhs) as i64,
+                ">=" => (lhs >= rhs) as i64,
+                "<<" => lhs.wrapping_shl(rhs as u32),
+                ">>" => lhs.wrapping_shr(rhs as u32),
+                "+" => lhs.wrapping_add(rhs),
+                "-" => lhs.wrapping_sub(rhs),
+                "*" => lhs.wrapping_mul(rhs),
+                "/" | "%" if rhs == 0 && !self.evaluated => 0,
+                "/" | "%" if rhs == 0 => return Err(String::from("Division by zero in preprocessor expression")),
+                "/" => lhs.wrapping_div(rhs),
+                _ => lhs.wrapping_rem(rhs),
+            };
+        }
+
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        let op = self.peek().map(String::from);
+        match op.as_deref() {
+            Some("!") | Some("~") | Some("-") | Some("+") => {
+                self.position += 1;
+                let value = self.unary()?;
+                Ok(match op.as_deref() {
+                    Some("!") => (value == 0) as i64,
+                    Some("~") => !value,
+                    Some("-") => value.wrapping_neg(),
+                    _ => value,
+                })
+            },
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, String> {
+        let token = match self.peek() {
+            Some(token) => token.to_owned(),
+            None => return Err(String::from("Unexpected end of preprocessor expression")),
+        };
+        self.position += 1;
+
+        if token == "(" {
+            let value = self.ternary()?;
+            self.expect(")")?;
+            return Ok(value);
+        }
+
+        if token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            return Ok(0);
+        }
+
+        let digits = token.trim_end_matches(|c| matches!(c, 'u' | 'U' | 'l' | 'L'));
+        let value = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+            Some(hex) => i64::from_str_radix(hex, 16),
+            None => digits.parse::<i64>(),
+        };
+
+        value.map_err(|_| format!("Invalid {} in preprocessor expression", token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluates an `#if` condition with the given macros defined.
+    fn evaluate(condition: &str, defines: &[(&str, &str)]) -> Result<i64, String> {
+        let options = defines.iter()
+            .fold(CompileOptions::new(Backend::OSO), |options, (name, value)| options.define(*name, Some(*value)));
+        Preprocessor::new(&options).evaluate(condition, (0, 0))
+    }
+
+    #[test]
+    fn operators_follow_c_precedence() {
+        assert_eq!(evaluate("1 + 2 * 3", &[]), Ok(7));
+        assert_eq!(evaluate("(1 + 2) * 3", &[]), Ok(9));
+        assert_eq!(evaluate("1 << 2 + 1", &[]), Ok(8));
+        assert_eq!(evaluate("7 % 4 == 3 && 10 / 3 == 3", &[]), Ok(1));
+        assert_eq!(evaluate("1 | 2 ^ 3 & 6", &[]), Ok(1 | (2 ^ (3 & 6))));
+        assert_eq!(evaluate("-2 + ~0 + !0", &[]), Ok(-2));
+        assert_eq!(evaluate("0x10 + 2L", &[]), Ok(18));
+    }
+
+    #[test]
+    fn ternary_only_picks_one_branch() {
+        assert_eq!(evaluate("1 ? 2 : 3", &[]), Ok(2));
+        assert_eq!(evaluate("0 ? 2 : 0 ? 3 : 4", &[]), Ok(4));
+        assert_eq!(evaluate("1 ? 2 : 1 / 0", &[]), Ok(2));
+        assert_eq!(evaluate("0 ? 1 % 0 : 5", &[]), Ok(5));
+    }
+
+    #[test]
+    fn macros_are_expanded_and_unknown_names_are_zero() {
+        assert_eq!(evaluate("N * 2", &[("N", "4")]), Ok(8));
+        assert_eq!(evaluate("UNKNOWN + 1", &[]), Ok(1));
+        assert_eq!(evaluate("defined(N) && defined N", &[("N", "0")]), Ok(1));
+        assert_eq!(evaluate("defined(N) || defined M", &[]), Ok(0));
+    }
+
+    #[test]
+    fn logical_operators_short_circuit() {
+        assert_eq!(evaluate("0 && (1 / 0)", &[]), Ok(0));
+        assert_eq!(evaluate("1 || 1 % 0", &[]), Ok(1));
+        assert_eq!(evaluate("defined(N) && 10 / N", &[]), Ok(0));
+        assert_eq!(evaluate("defined(N) && 10 / N", &[("N", "5")]), Ok(1));
+        assert_eq!(evaluate("0 && (1 / 0 || 2 / 0)", &[]), Ok(0));
+    }
+
+    #[test]
+    fn invalid_expressions_are_errors() {
+        assert!(evaluate("1 && 1 / 0", &[]).unwrap_err().contains("Division by zero"));
+        assert!(evaluate("0 || 1 % 0", &[]).is_err());
+        assert!(evaluate("(1 + 2", &[]).is_err());
+        assert!(evaluate("1 2", &[]).is_err());
+        assert!(evaluate("defined(", &[]).is_err());
+        assert!(evaluate("", &[]).is_err());
+    }
+
+    #[test]
+    fn conditional_directives_select_lines() {
+        let source = "#if 0 && (1 / 0)\nfirst\n#elif defined(N) && 10 / N\nsecond\n#else\nthird\n#endif\n";
+        let options = CompileOptions::new(Backend::OSO);
+        let text = preprocess(source, &options).unwrap().text;
+        assert!(!text.contains("first") && !text.contains("second") && text.contains("third"));
+
+        let options = CompileOptions::new(Backend::OSO).define("N", Some("2"));
+        let text = preprocess(source, &options).unwrap().text;
+        assert!(!text.contains("first") && text.contains("second") && !text.contains("third"));
+    }
+}
@@ -1,7 +1,1378 @@
-// use rspirv::binary::Assemble;
-// use rspirv::binary::Disassemble;
-// use rspirv::dr::Builder;
-//
-// pub fn build_shader(builder: &Builder) {
-//     
-// }
+use super::*;
+use super::ast::*;
+use super::symtab::{SymbolTable, Symbols};
+
+use crate::errors::*;
+
+use std::collections::HashMap;
+
+use rspirv::binary::Assemble;
+use rspirv::dr::{Builder, InsertPoint, Instruction, Operand};
+use ::spirv::{Word, StorageClass, GLOp};
+
+impl From<rspirv::dr::Error> for OSLCompilerError {
+    fn from(error: rspirv::dr::Error) -> Self {
+        OSLCompilerError::BackendError(error.to_string())
+    }
+}
+
+/// Lowers the checked program into a SPIR-V module and returns its words.
+pub fn build_shader(program: &Vec<Stmt>, symbol_table: &SymbolTable) -> Result<Vec<Word>, OSLCompilerError> {
+    let mut shader = ShaderBuilder::new(symbol_table);
+
+    // Functions are declared up front so calls can reference them before they are built
+    for stmt in program {
+        if let Stmt_::FunctionDeclaration {name, ret_type, params, ..} = &stmt.statement {
+            shader.declare_function(stmt.span, name, ret_type, params)?;
+        }
+    }
+
+    for stmt in program {
+        if let Stmt_::FunctionDeclaration {name, params, body, ..} = &stmt.statement {
+            shader.build_function(stmt.span, name, params, body)?;
+        }
+    }
+
+    for stmt in program {
+        if let Stmt_::ShaderDeclaration {name, params, body, ..} = &stmt.statement {
+            shader.build_entry_point(name, params, body)?;
+        }
+    }
+
+    Ok(shader.builder.module().assemble())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Variable {
+    pointer: Word,
+    storage: StorageClass,
+}
+
+#[derive(Debug, Clone)]
+struct Function {
+    id: Word,
+    ret_type: Types,
+    params: Vec<(Types, bool)>,
+}
+
+struct ShaderBuilder<'a> {
+    builder: Builder,
+    symbol_table: &'a SymbolTable,
+    glsl: Word,
+
+    // Variables and functions are keyed by name and the span of their declaration so that
+    // shadowed names resolve to the symbol the symbol table picked.
+    variables: HashMap<(String, usize), Variable>,
+    functions: HashMap<(String, usize), Function>,
+    globals: HashMap<String, Word>,
+    constants: HashMap<(Word, u32), Word>,
+
+    interface: Vec<Word>,
+    input_location: u32,
+    output_location: u32,
+
+    // (merge, continue) targets of the enclosing loops
+    loops: Vec<(Word, Word)>,
+    ret_type: Types,
+}
+
+impl<'a> ShaderBuilder<'a> {
+    fn new(symbol_table: &'a SymbolTable) -> Self {
+        let mut builder = Builder::new();
+        builder.capability(::spirv::Capability::Shader);
+        let glsl = builder.ext_inst_import("GLSL.std.450");
+        builder.memory_model(::spirv::AddressingModel::Logical, ::spirv::MemoryModel::GLSL450);
+
+        ShaderBuilder {
+            builder,
+            symbol_table,
+            glsl,
+            variables: HashMap::new(),
+            functions: HashMap::new(),
+            globals: HashMap::new(),
+            constants: HashMap::new(),
+            interface: Vec::new(),
+            input_location: 0,
+            output_location: 0,
+            loops: Vec::new(),
+            ret_type: Types::Void,
+        }
+    }
+
+    //===============
+    // Declarations
+    //===============
+
+    fn declare_function(&mut self, span: Span, name: &Expr, ret_type: &Expr, params: &Vec<Expr>) -> Result<(), OSLCompilerError> {
+        let mut param_types = Vec::new();
+        for param in params {
+            if let Expr_::Parameter {par_type, out, ..} = &param.node {
+                param_types.push((get_var_type_value(par_type).unwrap(), *out));
+            }
+        }
+
+        let id = self.builder.id();
+        self.functions.insert((get_ident_value(name).unwrap(), span.lo), Function {
+            id,
+            ret_type: get_var_type_value(ret_type).unwrap(),
+            params: param_types,
+        });
+
+        Ok(())
+    }
+
+    fn build_function(&mut self, span: Span, name: &Expr, params: &Vec<Expr>, body: &Stmt) -> Result<(), OSLCompilerError> {
+        let function = self.functions[&(get_ident_value(name).unwrap(), span.lo)].clone();
+
+        let ret_type = self.build_type(&function.ret_type, span)?;
+        let mut pointer_types = Vec::new();
+        for (param_type, _) in &function.params {
+            let param_type = self.build_type(param_type, span)?;
+            pointer_types.push(self.builder.type_pointer(None, StorageClass::Function, param_type));
+        }
+
+        let function_type = self.builder.type_function(ret_type, pointer_types.clone());
+        self.builder.begin_function(ret_type, Some(function.id), ::spirv::FunctionControl::NONE, function_type)?;
+        self.builder.name(function.id, get_ident_value(name).unwrap());
+
+        // Parameters are passed by reference so that output parameters can be written
+        for (param, pointer_type) in params.iter().zip(pointer_types) {
+            if let Expr_::Parameter {name, ..} = &param.node {
+                let param_name = get_ident_value(name).unwrap();
+                let pointer = self.builder.function_parameter(pointer_type)?;
+                self.builder.name(pointer, param_name.clone());
+                self.variables.insert((param_name, param.span.lo), Variable {
+                    pointer,
+                    storage: StorageClass::Function,
+                });
+            }
+        }
+
+        self.builder.begin_block(None)?;
+        self.ret_type = function.ret_type.clone();
+        self.build_statement(body)?;
+
+        if self.builder.selected_block().is_some() {
+            match function.ret_type {
+                Types::Void => self.builder.ret()?,
+                _ => self.builder.unreachable()?,
+            }
+        }
+
+        self.builder.end_function()?;
+
+        Ok(())
+    }
+
+    fn build_entry_point(&mut self, name: &Expr, params: &Vec<Expr>, body: &Stmt) -> Result<(), OSLCompilerError> {
+        let shader_name = get_ident_value(name).unwrap();
+
+        // Shader parameters become interface variables
+        let mut parameters = Vec::new();
+        for param in params {
+            if let Expr_::Parameter {par_type, name, out, value, ..} = &param.node {
+                let param_type = get_var_type_value(par_type).unwrap();
+                let param_name = get_ident_value(name).unwrap();
+                let storage = if *out { StorageClass::Output } else { StorageClass::Input };
+                let pointer = self.build_interface_variable(&param_type, storage, &param_name, param.span)?;
+                parameters.push((param, param_type, param_name, pointer, *out, value));
+            }
+        }
+
+        let void_type = self.builder.type_void();
+        let function_type = self.builder.type_function(void_type, vec![]);
+        let function = self.builder.begin_function(void_type, None, ::spirv::FunctionControl::NONE, function_type)?;
+        self.builder.name(function, shader_name.clone());
+        self.builder.begin_block(None)?;
+        self.ret_type = Types::Void;
+
+        for (param, param_type, param_name, pointer, out, value) in parameters {
+            if out {
+                // Outputs start out holding their default value
+                self.variables.insert((param_name, param.span.lo), Variable {
+                    pointer,
+                    storage: StorageClass::Output,
+                });
+                if let Expr_::EmptyExpression = value.node {} else {
+                    let default = self.build_initializer(value, &param_type)?;
+                    self.builder.store(pointer, default, None, vec![])?;
+                }
+            } else {
+                // Inputs are read-only in SPIR-V, so the shader works on a local copy
+                let local = self.build_local_variable(&param_type, &param_name, param.span)?;
+                let input_type = self.build_type(&param_type, param.span)?;
+                let input = self.builder.load(input_type, None, pointer, None, vec![])?;
+                self.builder.store(local, input, None, vec![])?;
+                self.variables.insert((param_name, param.span.lo), Variable {
+                    pointer: local,
+                    storage: StorageClass::Function,
+                });
+            }
+        }
+
+        self.build_statement(body)?;
+
+        if self.builder.selected_block().is_some() {
+            self.builder.ret()?;
+        }
+        self.builder.end_function()?;
+
+        // Parameters and globals are per-point inputs and outputs, like those of a fragment
+        self.builder.entry_point(::spirv::ExecutionModel::Fragment, function, shader_name, self.interface.clone());
+        self.builder.execution_mode(function, ::spirv::ExecutionMode::OriginUpperLeft, []);
+
+        Ok(())
+    }
+
+    /// Creates a module level Input/Output variable and adds it to the entry point interface.
+    fn build_interface_variable(&mut self, var_type: &Types, storage: StorageClass, name: &str, span: Span) -> Result<Word, OSLCompilerError> {
+        let mut element = var_type;
+        while let Types::Array(inner, _) = element {
+            element = inner;
+        }
+
+        // Fragment shaders may only output scalars and vectors
+        if storage == StorageClass::Output && matches!(element, Types::Matrix) {
+            return Err(self.unsupported(span, format!("The output {} is a matrix, which the SPIR-V backend cannot output", name)));
+        }
+
+        let value_type = self.build_type(var_type, span)?;
+        let pointer_type = self.builder.type_pointer(None, storage, value_type);
+        let pointer = self.builder.id();
+
+        // Module level variables must not end up in the block being built
+        self.builder.insert_types_global_values(InsertPoint::End, Instruction::new(
+            ::spirv::Op::Variable,
+            Some(pointer_type),
+            Some(pointer),
+            vec![Operand::StorageClass(storage)]));
+
+        let location = match storage {
+            StorageClass::Input => &mut self.input_location,
+            _ => &mut self.output_location,
+        };
+        self.builder.decorate(pointer, ::spirv::Decoration::Location, vec![Operand::LiteralInt32(*location)]);
+        *location += location_count(var_type);
+
+        // Integer inputs cannot be interpolated across the primitive
+        if storage == StorageClass::Input && matches!(element, Types::Int) {
+            self.builder.decorate(pointer, ::spirv::Decoration::Flat, vec![]);
+        }
+
+        self.builder.name(pointer, name);
+        self.interface.push(pointer);
+
+        Ok(pointer)
+    }
+
+    /// Creates a Function storage variable. These must all live at the top of the first block.
+    fn build_local_variable(&mut self, var_type: &Types, name: &str, span: Span) -> Result<Word, OSLCompilerError> {
+        let value_type = self.build_type(var_type, span)?;
+        let pointer_type = self.builder.type_pointer(None, StorageClass::Function, value_type);
+        let pointer = self.builder.id();
+
+        let current_block = self.builder.selected_block();
+        self.builder.select_block(Some(0))?;
+        self.builder.insert_into_block(InsertPoint::Begin, Instruction::new(
+            ::spirv::Op::Variable,
+            Some(pointer_type),
+            Some(pointer),
+            vec![Operand::StorageClass(StorageClass::Function)]))?;
+        self.builder.select_block(current_block)?;
+
+        if !name.is_empty() {
+            self.builder.name(pointer, name);
+        }
+
+        Ok(pointer)
+    }
+
+    fn build_global(&mut self, global: &Globals, span: Span) -> Result<Word, OSLCompilerError> {
+        let (name, global_type) = match global {
+            Globals::P => ("P", Types::Point),
+            Globals::I => ("I", Types::Vector),
+            Globals::N => ("N", Types::Normal),
+            Globals::Ng => ("Ng", Types::Normal),
+            Globals::Dpdu => ("dPdu", Types::Vector),
+            Globals::Dpdv => ("dPdv", Types::Vector),
+            Globals::Ps => ("Ps", Types::Point),
+            Globals::U => ("u", Types::Float),
+            Globals::V => ("v", Types::Float),
+            Globals::Time => ("time", Types::Float),
+            Globals::Dtime => ("dtime", Types::Float),
+            Globals::Dpdtime => ("dPdtime", Types::Vector),
+            _ => return Err(self.unsupported(span, format!("The global {:?} is not supported by the SPIR-V backend", global))),
+        };
+
+        if let Some(pointer) = self.globals.get(name) {
+            return Ok(*pointer);
+        }
+
+        let pointer = self.build_interface_variable(&global_type, StorageClass::Input, name, span)?;
+        self.globals.insert(name.to_owned(), pointer);
+
+        Ok(pointer)
+    }
+
+    //===============
+    // Types and constants
+    //===============
+
+    fn build_type(&mut self, var_type: &Types, span: Span) -> Result<Word, OSLCompilerError> {
+        Ok(match var_type {
+            Types::Int => self.builder.type_int(32, 1),
+            Types::Float => self.builder.type_float(32),
+            Types::Color |
+            Types::Point |
+            Types::Vector |
+            Types::Normal => {
+                let float_type = self.builder.type_float(32);
+                self.builder.type_vector(float_type, 3)
+            },
+            Types::Matrix => {
+                let float_type = self.builder.type_float(32);
+                let column_type = self.builder.type_vector(float_type, 4);
+                self.builder.type_matrix(column_type, 4)
+            },
+            Types::Void => self.builder.type_void(),
+            Types::Array(element, Some(length)) => {
+                let element_type = self.build_type(element, span)?;
+                let length = self.constant_int(*length as i32);
+                self.builder.type_array(element_type, length)
+            },
+            _ => return Err(self.unsupported(span, format!("The type {:?} is not supported by the SPIR-V backend", var_type))),
+        })
+    }
+
+    fn constant_int(&mut self, value: i32) -> Word {
+        let int_type = self.builder.type_int(32, 1);
+        let builder = &mut self.builder;
+        *self.constants.entry((int_type, value as u32))
+            .or_insert_with(|| builder.constant_u32(int_type, value as u32))
+    }
+
+    fn constant_float(&mut self, value: f32) -> Word {
+        let float_type = self.builder.type_float(32);
+        let builder = &mut self.builder;
+        *self.constants.entry((float_type, value.to_bits()))
+            .or_insert_with(|| builder.constant_f32(float_type, value))
+    }
+
+    fn unsupported(&self, span: Span, message: String) -> OSLCompilerError {
+        OSLCompilerError::CodegenError {
+            message,
+            error: Item::new(span, "Used here"),
+        }
+    }
+
+    //===============
+    // Statements
+    //===============
+
+    fn build_statement(&mut self, stmt: &Stmt) -> Result<(), OSLCompilerError> {
+        self.build_statements(std::slice::from_ref(stmt))
+    }
+
+    fn build_statements(&mut self, stmts: &[Stmt]) -> Result<(), OSLCompilerError> {
+        for stmt in stmts {
+            // Anything after a return in the same block can never run
+            if self.builder.selected_block().is_none() {
+                break;
+            }
+
+            match &stmt.statement {
+                Stmt_::ExpressionStatement(expr) => {
+                    if let Expr_::EmptyExpression = expr.node {} else {
+                        self.build_expression(expr)?;
+                    }
+                },
+
+                Stmt_::EmptyStatement => {},
+
+                Stmt_::BlockStatement(block_stmts) => {
+                    self.build_statements(block_stmts)?;
+                },
+
+                Stmt_::VariableDeclaration {var_type, name, value} => {
+                    let var_type = get_var_type_value(var_type).unwrap();
+                    let var_name = get_ident_value(name).unwrap();
+                    let pointer = self.build_local_variable(&var_type, &var_name, stmt.span)?;
+                    self.variables.insert((var_name, stmt.span.lo), Variable {
+                        pointer,
+                        storage: StorageClass::Function,
+                    });
+
+                    if let Expr_::EmptyExpression = value.node {} else {
+                        let initial = self.build_initializer(value, &var_type)?;
+                        self.builder.store(pointer, initial, None, vec![])?;
+                    }
+                },
+
+                Stmt_::ReturnStatement(expr) => {
+                    match expr.node {
+                        Expr_::EmptyExpression => self.builder.ret()?,
+                        _ => {
+                            let value_type = self.expr_type(expr)?;
+                            let value = self.build_expression(expr)?;
+                            let value = self.coerce(value, &value_type, &self.ret_type.clone(), expr.span)?;
+                            self.builder.ret_value(value)?;
+                        }
+                    }
+                },
+
+                Stmt_::BreakStatement |
+                Stmt_::ContinueStatement => {
+                    let (merge_label, continue_label) = *self.loops.last().unwrap();
+                    let target = if let Stmt_::BreakStatement = stmt.statement { merge_label } else { continue_label };
+                    self.builder.branch(target)?;
+                },
+
+                Stmt_::IfStatement {..} => {
+                    let (branches, else_body) = get_conditional_chain(stmt);
+                    self.build_conditional(&branches, else_body)?;
+                },
+
+                Stmt_::WhileStatement {condition, body} => {
+                    self.build_loop(&[], condition, &[], body)?;
+                },
+
+                Stmt_::DoWhileStatement {condition, body} => {
+                    self.build_do_while(condition, body)?;
+                },
+
+                Stmt_::ForStatement {initialization, condition, iteration, body} => {
+                    self.build_loop(initialization, condition, iteration, body)?;
+                },
+
+                Stmt_::FunctionDeclaration {..} |
+                Stmt_::StructDeclaration {..} |
+                Stmt_::ShaderDeclaration {..} => {
+                    return Err(self.unsupported(stmt.span, String::from("Nested declarations are not supported by the SPIR-V backend")));
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Appends an OpSelectionMerge/OpLoopMerge. The builder's own helpers for these treat them
+    /// as terminators, but the branch that follows is what actually ends the block.
+    fn merge_instruction(&mut self, op: ::spirv::Op, operands: Vec<Operand>) -> Result<(), OSLCompilerError> {
+        self.builder.insert_into_block(InsertPoint::End, Instruction::new(op, None, None, operands))?;
+
+        Ok(())
+    }
+
+    /// The label of the block that instructions are currently appended to.
+    fn current_label(&self) -> Word {
+        let module = self.builder.module_ref();
+        let function = &module.functions[self.builder.selected_function().unwrap()];
+        function.blocks[self.builder.selected_block().unwrap()].label_id().unwrap()
+    }
+
+    /// Branches to `target` unless the current block already ended (e.g. with a return).
+    fn branch_to(&mut self, target: Word) -> Result<(), OSLCompilerError> {
+        if self.builder.selected_block().is_some() {
+            self.builder.branch(target)?;
+        }
+
+        Ok(())
+    }
+
+    fn build_conditional(&mut self, branches: &[(&Expr, &Stmt)], else_body: Option<&Stmt>) -> Result<(), OSLCompilerError> {
+        let (condition, body) = branches[0];
+
+        let condition = self.build_bool(condition)?;
+        let then_label = self.builder.id();
+        let merge_label = self.builder.id();
+        let else_label = if branches.len() > 1 || else_body.is_some() {
+            self.builder.id()
+        } else {
+            merge_label
+        };
+
+        self.merge_instruction(::spirv::Op::SelectionMerge, vec![
+            Operand::IdRef(merge_label),
+            Operand::SelectionControl(::spirv::SelectionControl::NONE)])?;
+        self.builder.branch_conditional(condition, then_label, else_label, vec![])?;
+
+        self.builder.begin_block(Some(then_label))?;
+        self.build_statement(body)?;
+        self.branch_to(merge_label)?;
+
+        if else_label != merge_label {
+            self.builder.begin_block(Some(else_label))?;
+            if branches.len() > 1 {
+                self.build_conditional(&branches[1..], else_body)?;
+            } else if let Some(else_body) = else_body {
+                self.build_statement(else_body)?;
+            }
+            self.branch_to(merge_label)?;
+        }
+
+        self.builder.begin_block(Some(merge_label))?;
+
+        Ok(())
+    }
+
+    fn build_loop(&mut self, initialization: &[Stmt], condition: &Expr, iteration: &[Expr], body: &Stmt) -> Result<(), OSLCompilerError> {
+        self.build_statements(initialization)?;
+
+        let header_label = self.builder.id();
+        let condition_label = self.builder.id();
+        let body_label = self.builder.id();
+        let continue_label = self.builder.id();
+        let merge_label = self.builder.id();
+
+        self.builder.branch(header_label)?;
+        self.builder.begin_block(Some(header_label))?;
+        self.merge_instruction(::spirv::Op::LoopMerge, vec![
+            Operand::IdRef(merge_label),
+            Operand::IdRef(continue_label),
+            Operand::LoopControl(::spirv::LoopControl::NONE)])?;
+        self.builder.branch(condition_label)?;
+
+        self.builder.begin_block(Some(condition_label))?;
+        if let Expr_::EmptyExpression = condition.node {
+            self.builder.branch(body_label)?;
+        } else {
+            let condition = self.build_bool(condition)?;
+            self.builder.branch_conditional(condition, body_label, merge_label, vec![])?;
+        }
+
+        self.builder.begin_block(Some(body_label))?;
+        self.loops.push((merge_label, continue_label));
+        self.build_statement(body)?;
+        self.loops.pop();
+        self.branch_to(continue_label)?;
+
+        self.builder.begin_block(Some(continue_label))?;
+        for expr in iteration {
+            self.build_expression(expr)?;
+        }
+        self.builder.branch(header_label)?;
+
+        self.builder.begin_block(Some(merge_label))?;
+
+        Ok(())
+    }
+
+    fn build_do_while(&mut self, condition: &Expr, body: &Stmt) -> Result<(), OSLCompilerError> {
+        let header_label = self.builder.id();
+        let body_label = self.builder.id();
+        let continue_label = self.builder.id();
+        let merge_label = self.builder.id();
+
+        self.builder.branch(header_label)?;
+        self.builder.begin_block(Some(header_label))?;
+        self.merge_instruction(::spirv::Op::LoopMerge, vec![
+            Operand::IdRef(merge_label),
+            Operand::IdRef(continue_label),
+            Operand::LoopControl(::spirv::LoopControl::NONE)])?;
+        self.builder.branch(body_label)?;
+
+        self.builder.begin_block(Some(body_label))?;
+        self.loops.push((merge_label, continue_label));
+        self.build_statement(body)?;
+        self.loops.pop();
+        self.branch_to(continue_label)?;
+
+        self.builder.begin_block(Some(continue_label))?;
+        let condition = self.build_bool(condition)?;
+        self.builder.branch_conditional(condition, header_label, merge_label, vec![])?;
+
+        self.builder.begin_block(Some(merge_label))?;
+
+        Ok(())
+    }
+
+    //===============
+    // Expressions
+    //===============
+
+    fn expr_type(&self, expr: &Expr) -> Result<Types, OSLCompilerError> {
+        get_expr_type(expr, self.symbol_table)
+    }
+
+    /// The user function a call resolves to, or None for builtins.
+    fn get_function(&self, name: &Expr, arguments: &[Expr]) -> Result<Option<&Function>, OSLCompilerError> {
+        let function_name = get_ident_value(name).unwrap();
+        Ok(match resolve_function(name, arguments, self.symbol_table)? {
+            Symbols::Function {span, public: false, ..} => self.functions.get(&(function_name, span.lo)),
+            _ => None,
+        })
+    }
+
+    fn get_variable(&self, expr: &Expr, name: &String) -> Result<Variable, OSLCompilerError> {
+        let symbol = self.symbol_table.get_reference(expr.span, name.clone());
+        self.variables.get(&(name.clone(), symbol.get_span().lo))
+            .copied()
+            .ok_or_else(|| self.unsupported(expr.span, format!("Could not find storage for {}", name)))
+    }
+
+    /// Builds the initial value of a declaration, which may be an initializer list.
+    fn build_initializer(&mut self, value: &Expr, var_type: &Types) -> Result<Word, OSLCompilerError> {
+        if let (Expr_::InitializerList(values), Types::Array(element, _)) = (&value.node, var_type) {
+            let mut elements = Vec::new();
+            for value in values.iter() {
+                let value_type = self.expr_type(value)?;
+                let element_value = self.build_expression(value)?;
+                elements.push(self.coerce(element_value, &value_type, element, value.span)?);
+            }
+            let type_id = self.build_type(var_type, value.span)?;
+            return Ok(self.builder.composite_construct(type_id, None, elements)?);
+        }
+
+        let value_type = self.expr_type(value)?;
+        let initial = self.build_expression(value)?;
+        self.coerce(initial, &value_type, var_type, value.span)
+    }
+
+    /// Only the branch that was picked is evaluated. Each branch stores its value in a local
+    /// that is read back after the selection merges.
+    fn build_ternary(&mut self, expr: &Expr, condition: &Expr, if_true: &Expr, if_false: &Expr) -> Result<Word, OSLCompilerError> {
+        let result_type = self.expr_type(expr)?;
+        let result = self.build_local_variable(&result_type, "", expr.span)?;
+
+        let condition = self.build_bool(condition)?;
+        let true_label = self.builder.id();
+        let false_label = self.builder.id();
+        let merge_label = self.builder.id();
+        self.merge_instruction(::spirv::Op::SelectionMerge, vec![
+            Operand::IdRef(merge_label),
+            Operand::SelectionControl(::spirv::SelectionControl::NONE)])?;
+        self.builder.branch_conditional(condition, true_label, false_label, vec![])?;
+
+        for (label, value) in [(true_label, if_true), (false_label, if_false)] {
+            self.builder.begin_block(Some(label))?;
+            let value = self.build_initializer(value, &result_type)?;
+            self.builder.store(result, value, None, vec![])?;
+            self.builder.branch(merge_label)?;
+        }
+
+        self.builder.begin_block(Some(merge_label))?;
+        let value_type = self.build_type(&result_type, expr.span)?;
+        Ok(self.builder.load(value_type, None, result, None, vec![])?)
+    }
+
+    /// Converts `value` from one type to another following OSL's implicit and explicit casts.
+    fn coerce(&mut self, value: Word, from: &Types, to: &Types, span: Span) -> Result<Word, OSLCompilerError> {
+        if from == to || (is_triple(from) && is_triple(to)) {
+            return Ok(value);
+        }
+
+        let float_type = self.build_type(&Types::Float, span)?;
+
+        match (from, to) {
+            (Types::Int, Types::Float) => Ok(self.builder.convert_s_to_f(float_type, None, value)?),
+            (Types::Float, Types::Int) => {
+                let int_type = self.build_type(&Types::Int, span)?;
+                Ok(self.builder.convert_f_to_s(int_type, None, value)?)
+            },
+            (Types::Int, _) |
+            (Types::Float, _) if is_triple(to) => {
+                let value = self.coerce(value, from, &Types::Float, span)?;
+                let triple_type = self.build_type(to, span)?;
+                Ok(self.builder.composite_construct(triple_type, None, vec![value, value, value])?)
+            },
+            (Types::Int, Types::Matrix) |
+            (Types::Float, Types::Matrix) => {
+                // A scalar becomes a uniform scale matrix
+                let value = self.coerce(value, from, &Types::Float, span)?;
+                let zero = self.constant_float(0.0);
+                let column_type = self.builder.type_vector(float_type, 4);
+                let mut columns = Vec::new();
+                for column in 0..4 {
+                    let mut components = vec![zero; 4];
+                    components[column] = value;
+                    columns.push(self.builder.composite_construct(column_type, None, components)?);
+                }
+                let matrix_type = self.build_type(&Types::Matrix, span)?;
+                Ok(self.builder.composite_construct(matrix_type, None, columns)?)
+            },
+            _ => Err(self.unsupported(span, format!("Cannot convert {:?} to {:?}", from, to))),
+        }
+    }
+
+    fn build_expression(&mut self, expr: &Expr) -> Result<Word, OSLCompilerError> {
+        match &expr.node {
+            Expr_::IntLiteral(i) => Ok(self.constant_int(*i as i32)),
+
+            Expr_::FloatLiteral(f) => Ok(self.constant_float(*f as f32)),
+
+            Expr_::Ident(s) => {
+                let variable = self.get_variable(expr, s)?;
+                let value_type = self.expr_type(expr)?;
+                let value_type = self.build_type(&value_type, expr.span)?;
+                Ok(self.builder.load(value_type, None, variable.pointer, None, vec![])?)
+            },
+
+            Expr_::GlobalVariable(g) => {
+                let pointer = self.build_global(g, expr.span)?;
+                let value_type = self.expr_type(expr)?;
+                let value_type = self.build_type(&value_type, expr.span)?;
+                Ok(self.builder.load(value_type, None, pointer, None, vec![])?)
+            },
+
+            Expr_::Assignment(lhs, rhs) => {
+                let lhs_type = self.expr_type(lhs)?;
+                let rhs_type = self.expr_type(rhs)?;
+                let value = self.build_expression(rhs)?;
+                let value = self.coerce(value, &rhs_type, &lhs_type, rhs.span)?;
+                let pointer = self.build_lvalue(lhs)?;
+                self.builder.store(pointer, value, None, vec![])?;
+                Ok(value)
+            },
+
+            Expr_::ExplicitCast {cast_type, cast_expr} => {
+                let from = self.expr_type(cast_expr)?;
+                let to = get_var_type_value(cast_type).unwrap();
+                let value = self.build_expression(cast_expr)?;
+                self.coerce(value, &from, &to, expr.span)
+            },
+
+            Expr_::AccessExpression {lhs, ..} => {
+                let element_type = self.expr_type(expr)?;
+                let element_type = self.build_type(&element_type, expr.span)?;
+
+                // Variables are indexed through a pointer, so that the index may be dynamic
+                if is_addressable(lhs) {
+                    let pointer = self.build_pointer(expr)?.pointer;
+                    return Ok(self.builder.load(element_type, None, pointer, None, vec![])?);
+                }
+
+                let lhs_value = self.build_expression(lhs)?;
+                let lhs_type = self.expr_type(lhs)?;
+                let indexes = self.access_indexes(expr, &lhs_type)?;
+                Ok(self.builder.composite_extract(element_type, None, lhs_value, indexes)?)
+            },
+
+            Expr_::BinaryExpression(op, lhs, rhs) => {
+                match op {
+                    Operators::LessThan |
+                    Operators::LessThanEqual |
+                    Operators::GreaterThan |
+                    Operators::GreaterThanEqual |
+                    Operators::Equals |
+                    Operators::NotEqual |
+                    Operators::LogicalAnd |
+                    Operators::LogicalOr => {
+                        let value = self.build_bool(expr)?;
+                        self.bool_to_int(value, expr.span)
+                    },
+
+                    Operators::AddAssign |
+                    Operators::SubtractAssign |
+                    Operators::MultiplyAssign |
+                    Operators::DivideAssign |
+                    Operators::BitwiseAndAssign |
+                    Operators::BitwiseOrAssign |
+                    Operators::BitwiseXorAssign |
+                    Operators::ShiftLeftAssign |
+                    Operators::ShiftRightAssign => {
+                        let lhs_type = self.expr_type(lhs)?;
+                        let rhs_type = self.expr_type(rhs)?;
+                        let type_id = self.build_type(&lhs_type, lhs.span)?;
+                        let pointer = self.build_lvalue(lhs)?;
+                        let lhs_value = self.builder.load(type_id, None, pointer, None, vec![])?;
+                        let rhs_value = self.build_expression(rhs)?;
+                        let value = self.build_binary_values(expr, &compound_operator(op), (lhs_value, &lhs_type, lhs.span), (rhs_value, &rhs_type, rhs.span))?;
+                        let value = self.coerce(value, &self.expr_type(expr)?, &lhs_type, expr.span)?;
+                        self.builder.store(pointer, value, None, vec![])?;
+                        Ok(value)
+                    },
+                    _ => self.build_binary(expr, op, lhs, rhs),
+                }
+            },
+
+            Expr_::PreUnaryExpression(op, rhs) => {
+                let rhs_type = self.expr_type(rhs)?;
+                let value_type = self.build_type(&rhs_type, expr.span)?;
+
+                match op {
+                    Operators::Minus => {
+                        let value = self.build_expression(rhs)?;
+                        Ok(match rhs_type {
+                            Types::Int => self.builder.s_negate(value_type, None, value)?,
+                            Types::Matrix => {
+                                let minus_one = self.constant_float(-1.0);
+                                self.builder.matrix_times_scalar(value_type, None, value, minus_one)?
+                            },
+                            _ => self.builder.f_negate(value_type, None, value)?,
+                        })
+                    },
+                    Operators::BitwiseCompliment => {
+                        let value = self.build_expression(rhs)?;
+                        Ok(self.builder.not(value_type, None, value)?)
+                    },
+                    Operators::Not => {
+                        let value = self.build_bool(expr)?;
+                        self.bool_to_int(value, expr.span)
+                    },
+                    Operators::Increment |
+                    Operators::Decrement => {
+                        let (_, new_value) = self.build_increment(op, rhs)?;
+                        Ok(new_value)
+                    },
+                    _ => Err(self.unsupported(expr.span, format!("Unsupported unary operator {:?}", op))),
+                }
+            },
+
+            Expr_::PostUnaryExpression(op, lhs) => {
+                let (old_value, _) = self.build_increment(op, lhs)?;
+                Ok(old_value)
+            },
+
+            Expr_::FunctionCallExpression {name, arguments} => {
+                self.build_call(expr, name, arguments)
+            },
+
+            Expr_::TernaryExpression {condition, if_true, if_false} => {
+                self.build_ternary(expr, condition, if_true, if_false)
+            },
+
+            Expr_::PointConstructor {point_type, x, y, z, ..} => {
+                let arguments = vec![*x.clone(), *y.clone(), *z.clone()];
+                self.build_call(expr, point_type, &arguments)
+            },
+
+            Expr_::StringLiteral(..) => {
+                Err(self.unsupported(expr.span, String::from("Strings are not supported by the SPIR-V backend")))
+            },
+
+            _ => Err(self.unsupported(expr.span, String::from("Unsupported expression"))),
+        }
+    }
+
+    /// Builds a pointer to the storage an assignable expression refers to.
+    fn build_lvalue(&mut self, expr: &Expr) -> Result<Word, OSLCompilerError> {
+        match self.build_pointer(expr)? {
+            Variable {storage: StorageClass::Input, ..} => Err(self.unsupported(expr.span, String::from("This expression cannot be assigned to"))),
+            variable => Ok(variable.pointer),
+        }
+    }
+
+    /// Builds a pointer to a variable, global, or an element or component of one.
+    fn build_pointer(&mut self, expr: &Expr) -> Result<Variable, OSLCompilerError> {
+        match &expr.node {
+            Expr_::Ident(s) => self.get_variable(expr, s),
+
+            Expr_::GlobalVariable(g) => Ok(Variable {
+                pointer: self.build_global(g, expr.span)?,
+                storage: StorageClass::Input,
+            }),
+
+            Expr_::AccessExpression {lhs, ..} => {
+                let base = self.build_pointer(lhs)?;
+                let lhs_type = self.expr_type(lhs)?;
+                let indexes = self.access_chain_indexes(expr, &lhs_type)?;
+                let element_type = self.expr_type(expr)?;
+                let element_type = self.build_type(&element_type, expr.span)?;
+                let pointer_type = self.builder.type_pointer(None, base.storage, element_type);
+                Ok(Variable {
+                    pointer: self.builder.access_chain(pointer_type, None, base.pointer, indexes)?,
+                    storage: base.storage,
+                })
+            },
+
+            _ => Err(self.unsupported(expr.span, String::from("This expression cannot be assigned to"))),
+        }
+    }
+
+    /// Index operands of an access chain for `a[i]`, `a.x` and `m[i]` style accesses.
+    fn access_chain_indexes(&mut self, expr: &Expr, lhs_type: &Types) -> Result<Vec<Word>, OSLCompilerError> {
+        let value = match &expr.node {
+            Expr_::AccessExpression {value, dot: false, ..} => value,
+            _ => {
+                let indexes = self.access_indexes(expr, lhs_type)?;
+                return Ok(indexes.into_iter().map(|i| self.constant_int(i as i32)).collect());
+            },
+        };
+
+        if let Expr_::IntLiteral(_) = value.node {
+            let indexes = self.access_indexes(expr, lhs_type)?;
+            return Ok(indexes.into_iter().map(|i| self.constant_int(i as i32)).collect());
+        }
+
+        let index_type = self.expr_type(value)?;
+        let index = self.build_expression(value)?;
+        let index = self.coerce(index, &index_type, &Types::Int, value.span)?;
+
+        Ok(match lhs_type {
+            // Matrix rows are stored as SPIR-V columns
+            Types::Matrix => {
+                let int_type = self.build_type(&Types::Int, value.span)?;
+                let four = self.constant_int(4);
+                let row = self.builder.s_div(int_type, None, index, four)?;
+                let column = self.builder.s_mod(int_type, None, index, four)?;
+                vec![row, column]
+            },
+            _ => vec![index],
+        })
+    }
+
+    /// Component indexes for `a[i]`, `a.x` and `m[i]` style accesses.
+    fn access_indexes(&self, expr: &Expr, lhs_type: &Types) -> Result<Vec<u32>, OSLCompilerError> {
+        let index = match &expr.node {
+            Expr_::AccessExpression {value, dot: false, ..} => match value.node {
+                Expr_::IntLiteral(i) => i as u32,
+                _ => return Err(self.unsupported(value.span, String::from("Only variables can be indexed with a dynamic index"))),
+            },
+            Expr_::AccessExpression {value, dot: true, ..} => match get_ident_value(value).as_deref() {
+                Some("x") | Some("r") => 0,
+                Some("y") | Some("g") => 1,
+                Some("z") | Some("b") => 2,
+                _ => return Err(self.unsupported(value.span, String::from("Unknown component"))),
+            },
+            _ => return Err(self.unsupported(expr.span, String::from("Not a component access"))),
+        };
+
+        Ok(match lhs_type {
+            // Matrix rows are stored as SPIR-V columns
+            Types::Matrix => vec![index / 4, index % 4],
+            _ => vec![index],
+        })
+    }
+
+    fn build_increment(&mut self, op: &Operators, expr: &Expr) -> Result<(Word, Word), OSLCompilerError> {
+        let value_type = self.expr_type(expr)?;
+        let type_id = self.build_type(&value_type, expr.span)?;
+        let pointer = self.build_lvalue(expr)?;
+        let old_value = self.builder.load(type_id, None, pointer, None, vec![])?;
+
+        let new_value = match (&value_type, op) {
+            (Types::Int, Operators::Increment) => {
+                let one = self.constant_int(1);
+                self.builder.i_add(type_id, None, old_value, one)?
+            },
+            (Types::Int, Operators::Decrement) => {
+                let one = self.constant_int(1);
+                self.builder.i_sub(type_id, None, old_value, one)?
+            },
+            (Types::Float, Operators::Increment) => {
+                let one = self.constant_float(1.0);
+                self.builder.f_add(type_id, None, old_value, one)?
+            },
+            (Types::Float, Operators::Decrement) => {
+                let one = self.constant_float(1.0);
+                self.builder.f_sub(type_id, None, old_value, one)?
+            },
+            _ => return Err(self.unsupported(expr.span, format!("Cannot apply {:?} to type {:?}", op, value_type))),
+        };
+
+        self.builder.store(pointer, new_value, None, vec![])?;
+
+        Ok((old_value, new_value))
+    }
+
+    fn bool_to_int(&mut self, value: Word, span: Span) -> Result<Word, OSLCompilerError> {
+        let int_type = self.build_type(&Types::Int, span)?;
+        let one = self.constant_int(1);
+        let zero = self.constant_int(0);
+        Ok(self.builder.select(int_type, None, value, one, zero)?)
+    }
+
+    /// Builds an expression as a SPIR-V boolean, e.g. for use as a condition.
+    fn build_bool(&mut self, expr: &Expr) -> Result<Word, OSLCompilerError> {
+        let bool_type = self.builder.type_bool();
+
+        match &expr.node {
+            Expr_::BinaryExpression(op @ (Operators::LogicalAnd | Operators::LogicalOr), lhs, rhs) => {
+                // The right hand side is only evaluated when it decides the result
+                let lhs_value = self.build_bool(lhs)?;
+                let lhs_label = self.current_label();
+                let rhs_label = self.builder.id();
+                let merge_label = self.builder.id();
+
+                self.merge_instruction(::spirv::Op::SelectionMerge, vec![
+                    Operand::IdRef(merge_label),
+                    Operand::SelectionControl(::spirv::SelectionControl::NONE)])?;
+                match op {
+                    Operators::LogicalAnd => self.builder.branch_conditional(lhs_value, rhs_label, merge_label, vec![])?,
+                    _ => self.builder.branch_conditional(lhs_value, merge_label, rhs_label, vec![])?,
+                };
+
+                self.builder.begin_block(Some(rhs_label))?;
+                let rhs_value = self.build_bool(rhs)?;
+                let rhs_end_label = self.current_label();
+                self.builder.branch(merge_label)?;
+
+                self.builder.begin_block(Some(merge_label))?;
+                let short_circuit = match op {
+                    Operators::LogicalAnd => self.builder.constant_false(bool_type),
+                    _ => self.builder.constant_true(bool_type),
+                };
+                return Ok(self.builder.phi(bool_type, None, vec![(short_circuit, lhs_label), (rhs_value, rhs_end_label)])?);
+            },
+
+            Expr_::BinaryExpression(op @ (Operators::LessThan |
+                                          Operators::LessThanEqual |
+                                          Operators::GreaterThan |
+                                          Operators::GreaterThanEqual |
+                                          Operators::Equals |
+                                          Operators::NotEqual), lhs, rhs) => {
+                return self.build_comparison(expr, op, lhs, rhs);
+            },
+
+            Expr_::PreUnaryExpression(Operators::Not, rhs) => {
+                let value = self.build_bool(rhs)?;
+                return Ok(self.builder.logical_not(bool_type, None, value)?);
+            },
+
+            _ => {},
+        }
+
+        // Any other value is true when it is non-zero
+        let value_type = self.expr_type(expr)?;
+        let value = self.build_expression(expr)?;
+        match value_type {
+            Types::Int => {
+                let zero = self.constant_int(0);
+                Ok(self.builder.i_not_equal(bool_type, None, value, zero)?)
+            },
+            Types::Float => {
+                let zero = self.constant_float(0.0);
+                Ok(self.builder.f_unord_not_equal(bool_type, None, value, zero)?)
+            },
+            _ => Err(OSLCompilerError::InvalidCondition {
+                expr: Item::new(expr.span, format!("{:?}", value_type)),
+            }),
+        }
+    }
+
+    fn build_comparison(&mut self, expr: &Expr, op: &Operators, lhs: &Expr, rhs: &Expr) -> Result<Word, OSLCompilerError> {
+        let bool_type = self.builder.type_bool();
+        let lhs_type = self.expr_type(lhs)?;
+        let rhs_type = self.expr_type(rhs)?;
+        let operand_type = unify_types(&[lhs_type.clone(), rhs_type.clone()], false);
+
+        let lhs_value = self.build_expression(lhs)?;
+        let lhs_value = self.coerce(lhs_value, &lhs_type, &operand_type, lhs.span)?;
+        let rhs_value = self.build_expression(rhs)?;
+        let rhs_value = self.coerce(rhs_value, &rhs_type, &operand_type, rhs.span)?;
+
+        let b = &mut self.builder;
+        Ok(match (&operand_type, op) {
+            (Types::Int, Operators::LessThan) => b.s_less_than(bool_type, None, lhs_value, rhs_value)?,
+            (Types::Int, Operators::LessThanEqual) => b.s_less_than_equal(bool_type, None, lhs_value, rhs_value)?,
+            (Types::Int, Operators::GreaterThan) => b.s_greater_than(bool_type, None, lhs_value, rhs_value)?,
+            (Types::Int, Operators::GreaterThanEqual) => b.s_greater_than_equal(bool_type, None, lhs_value, rhs_value)?,
+            (Types::Int, Operators::Equals) => b.i_equal(bool_type, None, lhs_value, rhs_value)?,
+            (Types::Int, Operators::NotEqual) => b.i_not_equal(bool_type, None, lhs_value, rhs_value)?,
+
+            (Types::Float, Operators::LessThan) => b.f_ord_less_than(bool_type, None, lhs_value, rhs_value)?,
+            (Types::Float, Operators::LessThanEqual) => b.f_ord_less_than_equal(bool_type, None, lhs_value, rhs_value)?,
+            (Types::Float, Operators::GreaterThan) => b.f_ord_greater_than(bool_type, None, lhs_value, rhs_value)?,
+            (Types::Float, Operators::GreaterThanEqual) => b.f_ord_greater_than_equal(bool_type, None, lhs_value, rhs_value)?,
+            (Types::Float, Operators::Equals) => b.f_ord_equal(bool_type, None, lhs_value, rhs_value)?,
+            (Types::Float, Operators::NotEqual) => b.f_unord_not_equal(bool_type, None, lhs_value, rhs_value)?,
+
+            (t, Operators::Equals) |
+            (t, Operators::NotEqual) if is_triple(t) => {
+                let bool_vector_type = b.type_vector(bool_type, 3);
+                match op {
+                    Operators::Equals => {
+                        let components = b.f_ord_equal(bool_vector_type, None, lhs_value, rhs_value)?;
+                        b.all(bool_type, None, components)?
+                    },
+                    _ => {
+                        let components = b.f_unord_not_equal(bool_vector_type, None, lhs_value, rhs_value)?;
+                        b.any(bool_type, None, components)?
+                    },
+                }
+            },
+
+            (Types::Matrix, Operators::Equals) |
+            (Types::Matrix, Operators::NotEqual) => {
+                let float_type = b.type_float(32);
+                let column_type = b.type_vector(float_type, 4);
+                let bool_vector_type = b.type_vector(bool_type, 4);
+                let mut result = None;
+                for column in 0..4 {
+                    let lhs_column = b.composite_extract(column_type, None, lhs_value, vec![column])?;
+                    let rhs_column = b.composite_extract(column_type, None, rhs_value, vec![column])?;
+                    let components = b.f_ord_equal(bool_vector_type, None, lhs_column, rhs_column)?;
+                    let equal = b.all(bool_type, None, components)?;
+                    result = Some(match result {
+                        None => equal,
+                        Some(previous) => b.logical_and(bool_type, None, previous, equal)?,
+                    });
+                }
+                match op {
+                    Operators::Equals => result.unwrap(),
+                    _ => b.logical_not(bool_type, None, result.unwrap())?,
+                }
+            },
+
+            _ => return Err(self.unsupported(expr.span, format!("Cannot compare values of type {:?}", operand_type))),
+        })
+    }
+
+    fn build_binary(&mut self, expr: &Expr, op: &Operators, lhs: &Expr, rhs: &Expr) -> Result<Word, OSLCompilerError> {
+        let lhs_type = self.expr_type(lhs)?;
+        let rhs_type = self.expr_type(rhs)?;
+        let lhs_value = self.build_expression(lhs)?;
+        let rhs_value = self.build_expression(rhs)?;
+        self.build_binary_values(expr, op, (lhs_value, &lhs_type, lhs.span), (rhs_value, &rhs_type, rhs.span))
+    }
+
+    fn build_binary_values(&mut self, expr: &Expr, op: &Operators,
+                           (lhs_value, lhs_type, lhs_span): (Word, &Types, Span),
+                           (rhs_value, rhs_type, rhs_span): (Word, &Types, Span)) -> Result<Word, OSLCompilerError> {
+        let result_type = self.expr_type(expr)?;
+        let type_id = self.build_type(&result_type, expr.span)?;
+
+        // Scaling a triple or a matrix doesn't need the scalar to be widened first
+        if let Operators::Multiply = op {
+            if is_triple(&result_type) || result_type == Types::Matrix {
+                let scaled = if is_scalar(rhs_type) {
+                    Some((lhs_value, self.coerce(rhs_value, rhs_type, &Types::Float, rhs_span)?))
+                } else if is_scalar(lhs_type) {
+                    Some((rhs_value, self.coerce(lhs_value, lhs_type, &Types::Float, lhs_span)?))
+                } else {
+                    None
+                };
+
+                if let Some((value, scalar)) = scaled {
+                    return Ok(match result_type {
+                        Types::Matrix => self.builder.matrix_times_scalar(type_id, None, value, scalar)?,
+                        _ => self.builder.vector_times_scalar(type_id, None, value, scalar)?,
+                    });
+                }
+            }
+
+            if result_type == Types::Matrix {
+                // OSL matrices are row-major and stored transposed, so the operands swap
+                return Ok(self.builder.matrix_times_matrix(type_id, None, rhs_value, lhs_value)?);
+            }
+        }
+
+        if let (Types::Matrix, Operators::Divide) = (&result_type, op) {
+            let rhs_value = self.coerce(rhs_value, rhs_type, &Types::Matrix, rhs_span)?;
+            let inverse = self.builder.ext_inst(type_id, None, self.glsl, GLOp::MatrixInverse as u32, vec![Operand::IdRef(rhs_value)])?;
+            let lhs_value = self.coerce(lhs_value, lhs_type, &Types::Matrix, lhs_span)?;
+            return Ok(self.builder.matrix_times_matrix(type_id, None, inverse, lhs_value)?);
+        }
+
+        let lhs_value = self.coerce(lhs_value, lhs_type, &result_type, lhs_span)?;
+        let rhs_value = self.coerce(rhs_value, rhs_type, &result_type, rhs_span)?;
+
+        let b = &mut self.builder;
+        Ok(match (&result_type, op) {
+            (Types::Int, Operators::Plus) => b.i_add(type_id, None, lhs_value, rhs_value)?,
+            (Types::Int, Operators::Minus) => b.i_sub(type_id, None, lhs_value, rhs_value)?,
+            (Types::Int, Operators::Multiply) => b.i_mul(type_id, None, lhs_value, rhs_value)?,
+            (Types::Int, Operators::Divide) => b.s_div(type_id, None, lhs_value, rhs_value)?,
+            (Types::Int, Operators::Mod) => b.s_rem(type_id, None, lhs_value, rhs_value)?,
+            (Types::Int, Operators::BitwiseAnd) => b.bitwise_and(type_id, None, lhs_value, rhs_value)?,
+            (Types::Int, Operators::BitwiseOr) => b.bitwise_or(type_id, None, lhs_value, rhs_value)?,
+            (Types::Int, Operators::BitwiseXor) => b.bitwise_xor(type_id, None, lhs_value, rhs_value)?,
+            (Types::Int, Operators::ShiftLeft) => b.shift_left_logical(type_id, None, lhs_value, rhs_value)?,
+            (Types::Int, Operators::ShiftRight) => b.shift_right_arithmetic(type_id, None, lhs_value, rhs_value)?,
+
+            (_, Operators::Plus) => b.f_add(type_id, None, lhs_value, rhs_value)?,
+            (_, Operators::Minus) => b.f_sub(type_id, None, lhs_value, rhs_value)?,
+            (_, Operators::Multiply) => b.f_mul(type_id, None, lhs_value, rhs_value)?,
+            (_, Operators::Divide) => b.f_div(type_id, None, lhs_value, rhs_value)?,
+            (_, Operators::Mod) => b.f_rem(type_id, None, lhs_value, rhs_value)?,
+
+            _ => return Err(self.unsupported(expr.span, format!("Unsupported binary operator {:?} for type {:?}", op, result_type))),
+        })
+    }
+
+    fn build_call(&mut self, expr: &Expr, name: &Expr, arguments: &Vec<Expr>) -> Result<Word, OSLCompilerError> {
+        if let Expr_::VariableType(t) = &name.node {
+            return self.build_constructor(expr, t, arguments);
+        }
+
+        let function_name = match get_ident_value(name) {
+            Some(s) => s,
+            None => return Err(self.unsupported(name.span, String::from("Invalid function call"))),
+        };
+
+        if function_name == "arraylength" {
+            return match self.expr_type(&arguments[0])? {
+                Types::Array(_, Some(length)) => Ok(self.constant_int(length as i32)),
+                array_type => Err(self.unsupported(arguments[0].span, format!("The length of {:?} is not known to the SPIR-V backend", array_type))),
+            };
+        }
+
+        if let Some(function) = self.get_function(name, arguments)?.cloned() {
+            return self.build_user_call(expr, &function, arguments);
+        }
+
+        let result_type = self.expr_type(expr)?;
+        let type_id = self.build_type(&result_type, expr.span)?;
+
+        // Arguments share one type, except for the scalar results of geometric functions
+        let operand_type = match function_name.as_str() {
+            "length" | "distance" | "dot" => Types::Vector,
+            _ => result_type.clone(),
+        };
+        let mut values = Vec::new();
+        for arg in arguments {
+            let arg_type = self.expr_type(arg)?;
+            let value = self.build_expression(arg)?;
+            values.push(self.coerce(value, &arg_type, &operand_type, arg.span)?);
+        }
+
+        let is_int = result_type == Types::Int;
+        let glsl_op = match (function_name.as_str(), values.len()) {
+            ("mod", 2) => {
+                return Ok(match is_int {
+                    true => self.builder.s_mod(type_id, None, values[0], values[1])?,
+                    false => self.builder.f_mod(type_id, None, values[0], values[1])?,
+                });
+            },
+            ("fmod", 2) => return Ok(self.builder.f_rem(type_id, None, values[0], values[1])?),
+            ("dot", 2) => return Ok(self.builder.dot(type_id, None, values[0], values[1])?),
+
+            ("abs", 1) | ("fabs", 1) => if is_int { GLOp::SAbs } else { GLOp::FAbs },
+            ("sign", 1) => if is_int { GLOp::SSign } else { GLOp::FSign },
+            ("min", 2) => if is_int { GLOp::SMin } else { GLOp::FMin },
+            ("max", 2) => if is_int { GLOp::SMax } else { GLOp::FMax },
+            ("clamp", 3) => if is_int { GLOp::SClamp } else { GLOp::FClamp },
+            ("round", 1) => GLOp::Round,
+            ("trunc", 1) => GLOp::Trunc,
+            ("floor", 1) => GLOp::Floor,
+            ("ceil", 1) => GLOp::Ceil,
+            ("radians", 1) => GLOp::Radians,
+            ("degrees", 1) => GLOp::Degrees,
+            ("sin", 1) => GLOp::Sin,
+            ("cos", 1) => GLOp::Cos,
+            ("tan", 1) => GLOp::Tan,
+            ("asin", 1) => GLOp::Asin,
+            ("acos", 1) => GLOp::Acos,
+            ("atan", 1) => GLOp::Atan,
+            ("atan2", 2) => GLOp::Atan2,
+            ("sinh", 1) => GLOp::Sinh,
+            ("cosh", 1) => GLOp::Cosh,
+            ("tanh", 1) => GLOp::Tanh,
+            ("pow", 2) => GLOp::Pow,
+            ("exp", 1) => GLOp::Exp,
+            ("exp2", 1) => GLOp::Exp2,
+            ("log", 1) => GLOp::Log,
+            ("log2", 1) => GLOp::Log2,
+            ("sqrt", 1) => GLOp::Sqrt,
+            ("inversesqrt", 1) => GLOp::InverseSqrt,
+            ("mix", 3) => GLOp::FMix,
+            ("step", 2) => GLOp::Step,
+            ("smoothstep", 3) => GLOp::SmoothStep,
+            ("length", 1) => GLOp::Length,
+            ("distance", 2) => GLOp::Distance,
+            ("cross", 2) => GLOp::Cross,
+            ("normalize", 1) => GLOp::Normalize,
+            ("reflect", 2) => GLOp::Reflect,
+            ("faceforward", 3) => GLOp::FaceForward,
+            _ => return Err(self.unsupported(name.span, format!("The function {} is not supported by the SPIR-V backend", function_name))),
+        };
+
+        let operands: Vec<Operand> = values.into_iter().map(Operand::IdRef).collect();
+        Ok(self.builder.ext_inst(type_id, None, self.glsl, glsl_op as u32, operands)?)
+    }
+
+    fn build_user_call(&mut self, expr: &Expr, function: &Function, arguments: &Vec<Expr>) -> Result<Word, OSLCompilerError> {
+        if arguments.len() != function.params.len() {
+            return Err(self.unsupported(expr.span, format!("Expected {} arguments, received {}", function.params.len(), arguments.len())));
+        }
+
+        // Every argument goes through a temporary so it can be passed by reference
+        let mut temporaries = Vec::new();
+        for (arg, (param_type, _)) in arguments.iter().zip(&function.params) {
+            let arg_type = self.expr_type(arg)?;
+            let value = self.build_expression(arg)?;
+            let value = self.coerce(value, &arg_type, param_type, arg.span)?;
+            let temporary = self.build_local_variable(param_type, "", arg.span)?;
+            self.builder.store(temporary, value, None, vec![])?;
+            temporaries.push(temporary);
+        }
+
+        let ret_type = self.build_type(&function.ret_type, expr.span)?;
+        let result = self.builder.function_call(ret_type, None, function.id, temporaries.clone())?;
+
+        // Copy output parameters back to the caller's variables
+        for ((arg, (param_type, out)), temporary) in arguments.iter().zip(&function.params).zip(temporaries) {
+            if *out {
+                let arg_type = self.expr_type(arg)?;
+                let type_id = self.build_type(param_type, arg.span)?;
+                let value = self.builder.load(type_id, None, temporary, None, vec![])?;
+                let value = self.coerce(value, param_type, &arg_type, arg.span)?;
+                let pointer = self.build_lvalue(arg)?;
+                self.builder.store(pointer, value, None, vec![])?;
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn build_constructor(&mut self, expr: &Expr, constructed: &Types, arguments: &Vec<Expr>) -> Result<Word, OSLCompilerError> {
+        let mut arguments: &[Expr] = arguments;
+
+        // An optional leading string names the space the components are given in
+        if let Some(Expr_::StringLiteral(space)) = arguments.first().map(|arg| &arg.node) {
+            match (constructed, space.as_str()) {
+                (Types::Color, "rgb") => {},
+                (Types::Point, "common") |
+                (Types::Vector, "common") |
+                (Types::Normal, "common") |
+                (Types::Matrix, "common") => {},
+                _ => return Err(self.unsupported(arguments[0].span, format!("The space \"{}\" is not supported by the SPIR-V backend", space))),
+            }
+            arguments = &arguments[1..];
+        }
+
+        let mut values = Vec::new();
+        for arg in arguments {
+            let arg_type = self.expr_type(arg)?;
+            let value = self.build_expression(arg)?;
+            values.push((value, arg_type, arg.span));
+        }
+
+        let type_id = self.build_type(constructed, expr.span)?;
+        match (constructed, values.len()) {
+            (_, 1) => {
+                let (value, arg_type, span) = values.remove(0);
+                self.coerce(value, &arg_type, constructed, span)
+            },
+            (t, 3) if is_triple(t) => {
+                let mut components = Vec::new();
+                for (value, arg_type, span) in values {
+                    components.push(self.coerce(value, &arg_type, &Types::Float, span)?);
+                }
+                Ok(self.builder.composite_construct(type_id, None, components)?)
+            },
+            (Types::Matrix, 16) => {
+                let float_type = self.build_type(&Types::Float, expr.span)?;
+                let column_type = self.builder.type_vector(float_type, 4);
+                let mut components = Vec::new();
+                for (value, arg_type, span) in values {
+                    components.push(self.coerce(value, &arg_type, &Types::Float, span)?);
+                }
+                let mut columns = Vec::new();
+                for row in components.chunks(4) {
+                    columns.push(self.builder.composite_construct(column_type, None, row.to_vec())?);
+                }
+                Ok(self.builder.composite_construct(type_id, None, columns)?)
+            },
+            _ => Err(self.unsupported(expr.span, format!("Invalid number of arguments to the {:?} constructor", constructed))),
+        }
+    }
+}
+
+/// Whether an expression refers to storage that can be indexed through a pointer.
+fn is_addressable(expr: &Expr) -> bool {
+    match &expr.node {
+        Expr_::Ident(_) | Expr_::GlobalVariable(_) => true,
+        Expr_::AccessExpression {lhs, ..} => is_addressable(lhs),
+        _ => false,
+    }
+}
+
+/// The number of interface locations a variable of the type takes up.
+fn location_count(var_type: &Types) -> u32 {
+    match var_type {
+        Types::Matrix => 4,
+        Types::Array(element, Some(length)) => location_count(element) * *length as u32,
+        _ => 1,
+    }
+}
@@ -3,7 +3,7 @@ use super::ast::*;
 use crate::errors::*;
 use crate::stdosl;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Debug, Clone)]
 pub enum Symbols {
@@ -11,22 +11,32 @@ pub enum Symbols {
         var_type: Types,
         name: String,
         span: Span,
-        scope: u64,
+        scope: ScopeId,
         output: bool,
+        /// How many variables were declared before this one.
+        order: usize,
     },
+    /// A function, with the type of each parameter and whether it is an output.
     Function {
         ret_type: Types,
         name: String,
-        arg_types: Vec<Types>,
+        params: Vec<(Types, bool)>,
         span: Span,
-        scope: u64,
+        scope: ScopeId,
         public: bool,
     },
     Shader {
         shader_type: ShaderTypes,
         name: String,
         span: Span,
-        scope: u64,
+        scope: ScopeId,
+    },
+    /// A struct type, with its fields in declaration order.
+    Struct {
+        name: String,
+        fields: Vec<(String, Types)>,
+        span: Span,
+        scope: ScopeId,
     },
     Closure,
 }
@@ -37,6 +47,7 @@ impl Symbols {
             Symbols::Variable {..} => String::from("Variable"),
             Symbols::Function {..} => String::from("Function"),
             Symbols::Shader {..} => String::from("Shader"),
+            Symbols::Struct {..} => String::from("Struct"),
             _ => String::new(),
         }
     }
@@ -45,6 +56,7 @@ impl Symbols {
             Symbols::Variable {var_type, ..} => format!("{:?}", var_type.clone()),
             Symbols::Function {ret_type, ..} => format!("{:?}", ret_type.clone()),
             Symbols::Shader {shader_type, ..} => format!("{:?}", shader_type.clone()),
+            Symbols::Struct {name, ..} => name.clone(),
             _ => String::new(),
         }
     }
@@ -53,6 +65,7 @@ impl Symbols {
         match self {
             Symbols::Variable {name, ..} => name.clone(),
             Symbols::Function {name, ..} => name.clone(),
+            Symbols::Struct {name, ..} => name.clone(),
             _ => String::new(),
         }
     }
@@ -61,27 +74,51 @@ impl Symbols {
         match self {
             Symbols::Variable {span, ..} => span.clone(),
             Symbols::Function {span, ..} => span.clone(),
-            _ => Span{lo: 0, hi: 0, line: 0},
+            Symbols::Struct {span, ..} => span.clone(),
+            _ => Span{lo: 0, hi: 0, line: 0, file: 0},
         }
     }
 
-    pub fn get_scope(&self) -> u64 {
+    pub fn get_scope(&self) -> ScopeId {
         match self {
             Symbols::Variable {scope, ..} => *scope,
             Symbols::Function {scope, ..} => *scope,
-            _ => 1,
+            Symbols::Struct {scope, ..} => *scope,
+            _ => GLOBAL_SCOPE,
         }
     }
 
 }
 
+/// Scopes are numbered in the order they are opened.
+pub type ScopeId = usize;
+
+/// Functions, structs, shaders and the builtins live in the global scope.
+pub const GLOBAL_SCOPE: ScopeId = 0;
+
+/// A block of source that declarations are local to.
+#[derive(Debug, Clone)]
+struct Scope {
+    parent: Option<ScopeId>,
+}
+
+/// A name used in an expression, with the scope it was found in and how many variables had
+/// been declared by then.
+#[derive(Debug, Clone)]
+pub struct Reference {
+    pub name: String,
+    pub span: Span,
+    pub scope: ScopeId,
+    pub declared: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct SymbolTable {
     symbols: HashMap<String, Vec<Symbols>>,
-    pub cur_scope: u64,
-    next_scope: u64,
-    scope_stack: Vec<u64>,
-    scopes: Vec<u64>,
+    pub cur_scope: ScopeId,
+    scopes: Vec<Scope>,
+    /// The references of the program, by the position of their identifier node.
+    references: BTreeMap<usize, Reference>,
 
     pub n_variables: usize,
     pub n_functions: usize,
@@ -89,13 +126,12 @@ pub struct SymbolTable {
 }
 
 impl SymbolTable {
-    pub fn new(program_size: usize) -> Result<Self, OSLCompilerError> {
+    pub fn new() -> Result<Self, OSLCompilerError> {
         let mut symbol_table = SymbolTable {
             symbols: HashMap::new(),
-            cur_scope: 1,
-            next_scope: 2,
-            scope_stack: Vec::new(),
-            scopes: vec![1;program_size],
+            cur_scope: GLOBAL_SCOPE,
+            scopes: vec![Scope {parent: None}],
+            references: BTreeMap::new(),
             n_variables: 0,
             n_functions: 0,
             n_shaders: 0,
@@ -108,7 +144,7 @@ impl SymbolTable {
     }
 
     pub fn add_variable(&mut self, var_type: Types, name: String, span: Span, output: bool) -> Result<(), OSLCompilerError> {
-        if self.cur_scope == 1 {
+        if self.cur_scope == GLOBAL_SCOPE {
             return Err(OSLCompilerError::GlobalScopeVariable{
                 var : Item::new(span, name)
             });
@@ -119,6 +155,7 @@ impl SymbolTable {
             span,
             scope: self.cur_scope,
             output,
+            order: self.n_variables,
         };
 
         if self.symbols.contains_key(name.as_str()) {
@@ -148,39 +185,33 @@ impl SymbolTable {
         Ok(())
     }
 
-    pub fn add_function(&mut self, ret_type: Types, name: String, arg_types: Vec<Types>, span: Span, public: bool) -> Result<(), OSLCompilerError> {
+    /// Functions may be overloaded, as long as their parameter types differ.
+    pub fn add_function(&mut self, ret_type: Types, name: String, params: Vec<(Types, bool)>, span: Span, public: bool) -> Result<(), OSLCompilerError> {
+        let same_signature = |symbol: &Symbols| match symbol {
+            Symbols::Function {params: existing, ..} => existing.iter().map(|(t, _)| t).eq(params.iter().map(|(t, _)| t)),
+            _ => true,
+        };
+
+        if let Some(existing) = self.symbols.get(name.as_str()).and_then(|s| s.iter().find(|s| s.get_scope() == self.cur_scope && same_signature(s))) {
+            // Duplicate symbol error
+            return Err(OSLCompilerError::ExistingVariable {
+                existing: Item::new(existing.get_span(), existing.get_name()),
+                new: Item::new(span, name),
+            });
+        }
+
         let func = Symbols::Function {
             ret_type,
             name: name.clone(),
-            arg_types,
+            params,
             span,
             scope: self.cur_scope,
             public,
         };
-
-        if self.symbols.contains_key(name.as_str()) {
-            for symbol in self.symbols.get(name.as_str()).unwrap() {
-                if symbol.get_scope() == self.cur_scope {
-                    // Duplicate symbol error
-                    return Err(OSLCompilerError::ExistingVariable {
-                        existing: Item::new(symbol.get_span(), symbol.get_name()),
-                        new: Item::new(span, name),
-                    });
-                }
-            }
-
-            self.symbols.get_mut(name.as_str())
-                .unwrap()
-                .push(func);
-        }
-
-        else {
-            self.symbols.insert(name.clone(), vec![func]);
-        }
+        self.symbols.entry(name).or_insert_with(Vec::new).push(func);
 
         self.n_functions += 1;
 
-
         Ok(())
     }
 
@@ -219,110 +250,314 @@ impl SymbolTable {
         Ok(())
     }
 
-    pub fn up_scope(&mut self, span: Span) {
+    pub fn add_struct(&mut self, name: String, fields: Vec<(String, Types)>, span: Span) -> Result<(), OSLCompilerError> {
+        let declared = Symbols::Struct {
+            name: name.clone(),
+            fields,
+            span,
+            scope: self.cur_scope,
+        };
 
+        if let Some(existing) = self.symbols.get(name.as_str()).and_then(|s| s.iter().find(|s| s.get_scope() == self.cur_scope)) {
+            // Duplicate symbol error
+            return Err(OSLCompilerError::ExistingVariable {
+                existing: Item::new(existing.get_span(), existing.get_name()),
+                new: Item::new(span, name),
+            });
+        }
 
-        self.scope_stack.push(self.cur_scope);
-        self.cur_scope |= self.next_scope;
-        self.next_scope <<= 1;
+        self.symbols.entry(name).or_insert_with(Vec::new).push(declared);
 
-        for i in span.lo..span.hi {
-            self.scopes[i] = self.cur_scope;
-        }
+        Ok(())
+    }
+
+    /// Opens a scope nested in the current one.
+    pub fn up_scope(&mut self) {
+        self.scopes.push(Scope {parent: Some(self.cur_scope)});
+        self.cur_scope = self.scopes.len() - 1;
     }
 
     pub fn down_scope(&mut self) {
-        self.cur_scope = self.scope_stack.pop().unwrap();
+        self.cur_scope = self.scopes[self.cur_scope].parent.unwrap();
     }
 
-    pub fn get_scope(&self, loc: usize) -> u64 {
-        self.scopes[loc]
+    /// The scope an identifier node was found in while building the symbols. None if the
+    /// span is not the one of a reference.
+    pub fn get_reference_scope(&self, span: Span) -> Option<ScopeId> {
+        self.references.get(&span.lo).map(|reference| reference.scope)
     }
 
-    pub fn check_access(&self, origin_span: Span, dest_ident: String) -> Result<(), OSLCompilerError>{
+    /// Every reference of the program, in source order.
+    pub fn get_references(&self) -> impl Iterator<Item = &Reference> {
+        self.references.values()
+    }
 
-        let mut items: Vec<Item> = Vec::new();
-        let scope = self.scopes[origin_span.lo];
+    /// The scope and the scopes it is nested in, innermost first.
+    fn enclosing_scopes(&self, scope: ScopeId) -> impl Iterator<Item = ScopeId> + '_ {
+        std::iter::successors(Some(scope), move |scope| self.scopes[*scope].parent)
+    }
 
-        if let Some(s) = self.symbols.get(dest_ident.as_str()) {
-            for symbol in s {
-                if scope | symbol.get_scope() == scope {
-                    return Ok(());
-                }
+    /// Whether the symbols of `outer` are visible from `inner`.
+    fn encloses(&self, outer: ScopeId, inner: ScopeId) -> bool {
+        self.enclosing_scopes(inner).any(|scope| scope == outer)
+    }
 
-                items.push(Item::new(symbol.get_span(), ""));
-            }
+    pub fn check_access(&self, origin_span: Span, dest_ident: String) -> Result<(), OSLCompilerError>{
+        let candidates = self.get_symbols(&dest_ident);
+        if candidates.is_empty() {
+            return Err(OSLCompilerError::NonExistentIdent {
+                ident: Item::new(origin_span, dest_ident),
+            });
+        }
+
+        if self.find_reference(origin_span, dest_ident.clone()).is_some() {
+            return Ok(());
         }
 
         Err(OSLCompilerError::OutOfScopeIdent {
             origin: Item::new(origin_span, ""),
-            options: items,
+            options: candidates.iter().map(|symbol| Item::new(symbol.get_span(), "")).collect(),
         })
     }
 
-    pub fn get_reference(&self, span: Span, dest_ident: String) -> Symbols {
-        let scope = self.scopes[span.lo];
-        let mut closest: Symbols = self.symbols.get(dest_ident.as_str()).unwrap()[0].clone();
-        let mut closest_distance: isize = isize::MAX;
-
-        for symbol in self.symbols.get(dest_ident.as_str()).unwrap() {
-            let distance = self.distance(scope, symbol.get_scope());
-            if distance < closest_distance {
-                closest = symbol.clone();
-                closest_distance = distance;
+    /// The fields of the struct type with the given name. Struct names share one namespace
+    /// regardless of where they were declared.
+    pub fn get_struct_fields(&self, name: &str) -> Option<&[(String, Types)]> {
+        self.get_symbols(name).iter().find_map(|symbol| match symbol {
+            Symbols::Struct {fields, ..} => Some(fields.as_slice()),
+            _ => None,
+        })
+    }
+
+    /// Checks that every struct a declared type refers to exists.
+    pub fn check_declared_type(&self, var_type: &Expr) -> Result<(), OSLCompilerError> {
+        let mut declared = get_var_type_value(var_type).unwrap();
+        while let Types::Array(element, _) = declared {
+            declared = *element;
+        }
+
+        match declared {
+            Types::Struct(name) if self.get_struct_fields(&name).is_none() => Err(OSLCompilerError::InvalidStruct {
+                message: format!("There is no struct named {}", name),
+                error: Item::new(var_type.span, "Used as a type here"),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Collects the fields of a struct declaration, which may only declare variables.
+    fn get_struct_declaration_fields(&self, body: &Stmt, diagnostics: &mut Diagnostics) -> Vec<(String, Types)> {
+        let stmts = match &body.statement {
+            Stmt_::BlockStatement(stmts) => stmts.as_slice(),
+            _ => std::slice::from_ref(body),
+        };
+
+        let mut fields: Vec<(String, Types)> = Vec::new();
+        for stmt in stmts {
+            match &stmt.statement {
+                Stmt_::VariableDeclaration {var_type, name, value} => {
+                    let field_name = get_ident_value(name).unwrap();
+                    if let Expr_::EmptyExpression = value.node {} else {
+                        diagnostics.push(OSLCompilerError::InvalidStruct {
+                            message: String::from("Struct fields cannot have an initial value"),
+                            error: Item::new(value.span, ""),
+                        });
+                        continue;
+                    }
+
+                    if fields.iter().any(|(existing, _)| *existing == field_name) {
+                        diagnostics.push(OSLCompilerError::InvalidStruct {
+                            message: format!("The field {} is already declared", field_name),
+                            error: Item::new(name.span, ""),
+                        });
+                        continue;
+                    }
+
+                    // Fields may only use structs declared before this one
+                    if diagnostics.record(self.check_declared_type(var_type)).is_some() {
+                        diagnostics.record(check_array_type(var_type, name, false));
+                        fields.push((field_name, get_var_type_value(var_type).unwrap()));
+                    }
+                },
+                Stmt_::EmptyStatement |
+                Stmt_::ExpressionStatement(Expr {node: Expr_::EmptyExpression, ..}) => {},
+                _ => diagnostics.push(OSLCompilerError::InvalidStruct {
+                    message: String::from("Structs may only contain field declarations"),
+                    error: Item::new(stmt.span, ""),
+                }),
             }
         }
 
-        closest
+        fields
+    }
+
+    /// Every function with the name that is visible from the reference, i.e. its overload set.
+    pub fn find_functions(&self, span: Span, name: &str) -> Vec<&Symbols> {
+        let scope = match self.get_reference_scope(span) {
+            Some(scope) => scope,
+            None => return Vec::new(),
+        };
+        self.get_symbols(name).iter()
+            .filter(|symbol| matches!(symbol, Symbols::Function {..}) && self.encloses(symbol.get_scope(), scope))
+            .collect()
     }
 
-    fn distance(&self, scope1: u64, scope2: u64) -> isize {
-        let mut mask: u64 = 1;
-        let mut count1 = 0;
-        let mut count2 = 0;
+    /// Every symbol declared with the name, in any scope.
+    fn get_symbols(&self, name: &str) -> &[Symbols] {
+        self.symbols.get(name).map(|symbols| symbols.as_slice()).unwrap_or(&[])
+    }
 
-        for _ in 0..63 {
-            if scope1 & mask > 0 {count1 += 1;}
-            if scope2 & mask > 0 {count2 += 1;}
-            mask <<= 1;
+    pub fn get_reference(&self, span: Span, dest_ident: String) -> Symbols {
+        self.find_reference(span, dest_ident).unwrap()
+    }
+
+    /// Whether the symbol is declared by the time of the reference. A variable only is once
+    /// its declaration has been seen, so a name used before it refers to a variable of an
+    /// enclosing scope instead. Functions, shaders and structs are visible anywhere.
+    fn is_declared_before(symbol: &Symbols, reference: &Reference) -> bool {
+        match symbol {
+            Symbols::Variable {order, ..} => *order < reference.declared,
+            _ => true,
+        }
+    }
+
+    /// The declaration a name refers to: the one in the innermost enclosing scope that is
+    /// declared by the time of the reference. None if no declaration is visible.
+    pub fn find_reference(&self, span: Span, dest_ident: String) -> Option<Symbols> {
+        let reference = self.references.get(&span.lo)?;
+        self.resolve(reference, reference.scope, &dest_ident).cloned()
+    }
+
+    /// The variable a declaration hides: the one its name refers to from the scope enclosing
+    /// the declaration. None if it does not hide one.
+    pub fn find_shadowed(&self, span: Span, dest_ident: String) -> Option<Symbols> {
+        let reference = self.references.get(&span.lo)?;
+        let parent = self.scopes[reference.scope].parent?;
+
+        match self.resolve(reference, parent, &dest_ident)? {
+            symbol @ Symbols::Variable {..} => Some(symbol.clone()),
+            _ => None,
+        }
+    }
+
+    /// Looks a name up from a scope outwards. Only the symbols of the scope and the scopes it
+    /// is nested in are visible, the innermost one hiding the others.
+    fn resolve(&self, reference: &Reference, scope: ScopeId, dest_ident: &str) -> Option<&Symbols> {
+        let candidates = self.get_symbols(dest_ident);
+
+        self.enclosing_scopes(scope)
+            .find_map(|scope| candidates.iter()
+                .filter(|symbol| Self::is_declared_before(symbol, reference))
+                .find(|symbol| symbol.get_scope() == scope))
+    }
+
+    /// Records the scope of every name the expression refers to. Member names and the names
+    /// of metadata entries are not references.
+    fn record_references(&mut self, expr: &Expr) {
+        match &expr.node {
+            Expr_::Ident(name) => {
+                self.references.insert(expr.span.lo, Reference {
+                    name: name.clone(),
+                    span: expr.span,
+                    scope: self.cur_scope,
+                    declared: self.n_variables,
+                });
+            },
+            Expr_::BinaryExpression(_, lhs, rhs) |
+            Expr_::Assignment(lhs, rhs) => {
+                self.record_references(lhs);
+                self.record_references(rhs);
+            },
+            Expr_::PreUnaryExpression(_, operand) |
+            Expr_::PostUnaryExpression(_, operand) |
+            Expr_::ExplicitCast {cast_expr: operand, ..} => self.record_references(operand),
+            Expr_::Parameter {value, metadata, ..} => {
+                self.record_references(value);
+                metadata.iter().for_each(|entry| self.record_references(entry));
+            },
+            Expr_::Metadata {array, values, ..} => {
+                if let Some(array) = array {
+                    self.record_references(array);
+                }
+                values.iter().for_each(|value| self.record_references(value));
+            },
+            Expr_::PointConstructor {x, y, z, space, ..} => {
+                if let Some(space) = space {
+                    self.record_references(space);
+                }
+                [x, y, z].iter().for_each(|component| self.record_references(component));
+            },
+            Expr_::AccessExpression {lhs, value, dot} => {
+                self.record_references(lhs);
+                if !dot {
+                    self.record_references(value);
+                }
+            },
+            Expr_::FunctionCallExpression {name, arguments} => {
+                self.record_references(name);
+                arguments.iter().for_each(|argument| self.record_references(argument));
+            },
+            Expr_::TernaryExpression {condition, if_true, if_false} => {
+                self.record_references(condition);
+                self.record_references(if_true);
+                self.record_references(if_false);
+            },
+            Expr_::InitializerList(values) => values.iter().for_each(|value| self.record_references(value)),
+            Expr_::IntLiteral(_) |
+            Expr_::FloatLiteral(_) |
+            Expr_::StringLiteral(_) |
+            Expr_::GlobalVariable(_) |
+            Expr_::EmptyExpression |
+            Expr_::VariableType(_) |
+            Expr_::ShaderType(_) => {},
         }
+    }
 
-        count1 - count2
+    /// Declares the parameters of a function or shader in the current scope.
+    fn build_parameters(&mut self, params: &[Expr], diagnostics: &mut Diagnostics) {
+        for param in params {
+            if let Expr_::Parameter {par_type, name, out, ..} = &param.node {
+                diagnostics.record(self.add_variable(get_var_type_value(par_type).unwrap(),
+                    get_ident_value(name).unwrap(),
+                    param.span,
+                    *out));
+                self.record_references(name);
+            }
+            self.record_references(param);
+        }
     }
 
-    pub fn build_symbols(&mut self, stmts: &Vec<Stmt>) -> Result<(), OSLCompilerError> {
+    /// Adds the symbols declared by the statements, recording any invalid declarations, and
+    /// the scope every reference is made from. The names of declared variables are recorded
+    /// as references to their own declaration.
+    pub fn build_symbols(&mut self, stmts: &Vec<Stmt>, diagnostics: &mut Diagnostics) {
 
         for stmt in stmts {
             match &stmt.statement {
-                Stmt_::VariableDeclaration{var_type, name,..} => {
-                    self.add_variable(get_var_type_value(var_type).unwrap(),
+                Stmt_::ExpressionStatement(expr) |
+                Stmt_::ReturnStatement(expr) => self.record_references(expr),
+
+                Stmt_::VariableDeclaration{var_type, name, value} => {
+                    diagnostics.record(self.add_variable(get_var_type_value(var_type).unwrap(),
                                                    get_ident_value(name).unwrap(),
                                                    stmt.span,
-                                                   false)?;
+                                                   false));
+                    self.record_references(name);
+                    self.record_references(value);
                 },
 
-                Stmt_::ShaderDeclaration{name, shader_type, params, body, ..} => {
-                    self.add_shader(get_shader_type_value(shader_type).unwrap(),
+                Stmt_::ShaderDeclaration{name, shader_type, params, body, metadata} => {
+                    diagnostics.record(self.add_shader(get_shader_type_value(shader_type).unwrap(),
                                                    get_ident_value(name).unwrap(),
-                                                   stmt.span)?;
-
-                    self.up_scope(Span{lo: name.span.hi, hi: stmt.span.hi, line: 0});
+                                                   stmt.span));
+                    metadata.iter().for_each(|entry| self.record_references(entry));
 
-                    for param in params {
-                        match param.clone().node {
-                            Expr_::Parameter {par_type, name, out, ..} => {
-                                self.add_variable(get_var_type_value(&par_type).unwrap(),
-                                   get_ident_value(&name).unwrap(),
-                                   param.span,
-                                   out)?;
-                            }
-                            _ => {}
-                        }
-                    }
+                    self.up_scope();
+                    self.build_parameters(params, diagnostics);
 
                     match body.clone().statement {
                         Stmt_::BlockStatement(block_stmts) => {
-                            self.build_symbols(&block_stmts)?;
+                            self.build_symbols(&block_stmts, diagnostics);
                         }
                         _ => {}
                     }
@@ -331,31 +566,27 @@ impl SymbolTable {
                 },
 
                 Stmt_::FunctionDeclaration{name, ret_type, params, body} => {
-                    self.add_function(get_var_type_value(ret_type).unwrap(),
+                    let signature = params.iter()
+                        .filter_map(|param| match &param.node {
+                            Expr_::Parameter {par_type, out, ..} => Some((get_var_type_value(par_type).unwrap(), *out)),
+                            _ => None,
+                        })
+                        .collect();
+
+                    diagnostics.record(self.add_function(get_var_type_value(ret_type).unwrap(),
                         get_ident_value(name).unwrap(),
-                        Vec::new(),
+                        signature,
                         stmt.span,
-                        false)?;
+                        false));
 
 
-                    //                                         VV Bug??
-                    self.up_scope(Span{lo: name.span.hi, hi: stmt.span.hi, line: 0});
-
-                    for param in params {
-                        match param.clone().node {
-                            Expr_::Parameter {par_type, name, out, ..} => {
-                                self.add_variable(get_var_type_value(&par_type).unwrap(),
-                                    get_ident_value(&name).unwrap(),
-                                    param.span,
-                                    out)?;
-                            }
-                            _ => {}
-                        }
-                    }
+                    // Parameters and the body share a scope
+                    self.up_scope();
+                    self.build_parameters(params, diagnostics);
 
                     match body.clone().statement {
                         Stmt_::BlockStatement(block_stmts) => {
-                            self.build_symbols(&block_stmts)?;
+                            self.build_symbols(&block_stmts, diagnostics);
                         }
                         _ => {}
                     }
@@ -363,76 +594,251 @@ impl SymbolTable {
                     self.down_scope();
                 },
 
+                Stmt_::StructDeclaration {name, body} => {
+                    let fields = self.get_struct_declaration_fields(body, diagnostics);
+                    diagnostics.record(self.add_struct(get_ident_value(name).unwrap(), fields, stmt.span));
+                },
+
                 Stmt_::BlockStatement(block_stmts) => {
-                    if self.cur_scope == 1 {
-                        return Err(OSLCompilerError::GlobalScopeBlock {
+                    if self.cur_scope == GLOBAL_SCOPE {
+                        diagnostics.push(OSLCompilerError::GlobalScopeBlock {
                             block : Item::new(stmt.span, "")
                         });
+                        continue;
                     }
-                    self.up_scope(stmt.span);
-                    self.build_symbols(block_stmts)?;
+                    self.up_scope();
+                    self.build_symbols(block_stmts, diagnostics);
+                    self.down_scope();
+                },
+
+                Stmt_::IfStatement {condition, body, else_body} => {
+                    self.record_references(condition);
+                    self.build_symbols(&vec![*body.clone()], diagnostics);
+                    if let Some(else_body) = else_body {
+                        self.build_symbols(&vec![*else_body.clone()], diagnostics);
+                    }
+                },
+
+                Stmt_::WhileStatement {condition, body} => {
+                    self.record_references(condition);
+                    self.build_symbols(&vec![*body.clone()], diagnostics);
+                },
+
+                Stmt_::DoWhileStatement {condition, body} => {
+                    self.build_symbols(&vec![*body.clone()], diagnostics);
+                    self.record_references(condition);
+                },
+
+                // Variables declared in the initialization are only visible inside the loop
+                Stmt_::ForStatement {initialization, condition, iteration, body} => {
+                    self.up_scope();
+                    self.build_symbols(initialization, diagnostics);
+                    self.record_references(condition);
+                    iteration.iter().for_each(|expr| self.record_references(expr));
+                    self.build_symbols(&vec![*body.clone()], diagnostics);
                     self.down_scope();
                 },
                 _ => {}
             }
         }
+    }
 
-        Ok(())
+    /// Checks that `break` and `continue` are inside a loop, and that every `return` suits the
+    /// function or shader it leaves.
+    pub fn check_control_flow(&self, stmts: &Vec<Stmt>, diagnostics: &mut Diagnostics) {
+        for stmt in stmts {
+            match &stmt.statement {
+                Stmt_::ShaderDeclaration {body, ..} => {
+                    self.check_flow(body, None, false, diagnostics);
+                },
+
+                Stmt_::FunctionDeclaration {name, ret_type, body, ..} => {
+                    let ret_type = get_var_type_value(ret_type).unwrap();
+                    self.check_flow(body, Some(&ret_type), false, diagnostics);
+
+                    if ret_type != Types::Void && !always_returns(body) {
+                        diagnostics.push(OSLCompilerError::InvalidControlFlow {
+                            message: format!("The function {} does not return a value on every path", get_ident_value(name).unwrap()),
+                            error: Item::new(name.span, format!("Declared to return {:?}", ret_type)),
+                        });
+                    }
+                },
+
+                _ => {},
+            }
+        }
+    }
+
+    /// `ret_type` is the return type of the enclosing function, or None inside a shader.
+    fn check_flow(&self, stmt: &Stmt, ret_type: Option<&Types>, in_loop: bool, diagnostics: &mut Diagnostics) {
+        match &stmt.statement {
+            Stmt_::BreakStatement |
+            Stmt_::ContinueStatement if !in_loop => {
+                let keyword = if let Stmt_::BreakStatement = stmt.statement { "break" } else { "continue" };
+                diagnostics.push(OSLCompilerError::InvalidControlFlow {
+                    message: format!("A {} statement must be inside a loop", keyword),
+                    error: Item::new(stmt.span, ""),
+                });
+            },
+
+            Stmt_::ReturnStatement(expr) => {
+                diagnostics.record(self.check_return(expr, stmt.span, ret_type));
+            },
+
+            Stmt_::BlockStatement(stmts) => {
+                for stmt in stmts.iter() {
+                    self.check_flow(stmt, ret_type, in_loop, diagnostics);
+                }
+            },
+
+            Stmt_::IfStatement {body, else_body, ..} => {
+                self.check_flow(body, ret_type, in_loop, diagnostics);
+                if let Some(else_body) = else_body {
+                    self.check_flow(else_body, ret_type, in_loop, diagnostics);
+                }
+            },
+
+            Stmt_::WhileStatement {body, ..} |
+            Stmt_::DoWhileStatement {body, ..} |
+            Stmt_::ForStatement {body, ..} => {
+                self.check_flow(body, ret_type, true, diagnostics);
+            },
+
+            _ => {},
+        }
     }
 
-    pub fn check_types(&self, stmts: &Vec<Stmt>) -> Result<(), OSLCompilerError> {
+    fn check_return(&self, expr: &Expr, span: Span, ret_type: Option<&Types>) -> Result<(), OSLCompilerError> {
+        let (message, span) = match (ret_type, &expr.node) {
+            (None, Expr_::EmptyExpression) |
+            (Some(Types::Void), Expr_::EmptyExpression) => return Ok(()),
+
+            (None, _) => (String::from("Shaders cannot return a value, use a bare return to exit"), expr.span),
+            (Some(Types::Void), _) => (String::from("A void function cannot return a value"), expr.span),
+            (Some(ret_type), Expr_::EmptyExpression) => (format!("Expected a return value of type {:?}", ret_type), span),
+
+            (Some(ret_type), _) => {
+                let value_type = get_expr_type(expr, self)?;
+                if is_assignable(ret_type, &value_type) {
+                    return Ok(());
+                }
+                (format!("Expected a return value of type {:?}, received {:?}", ret_type, value_type), expr.span)
+            },
+        };
+
+        Err(OSLCompilerError::InvalidControlFlow {
+            message,
+            error: Item::new(span, ""),
+        })
+    }
+
+    /// Type checks the statements, recording the errors of every statement that fails.
+    pub fn check_types(&self, stmts: &Vec<Stmt>, diagnostics: &mut Diagnostics) {
         for stmt in stmts {
             match &stmt.statement {
                 Stmt_::ExpressionStatement(expr) => { 
-                    get_expr_type(expr, &self)?;
+                    diagnostics.record(get_expr_type(expr, &self));
                 },
 
-                Stmt_::VariableDeclaration {name, value, ..} => {
+                Stmt_::VariableDeclaration {var_type, name, value} => {
+                    diagnostics.record(self.check_declared_type(var_type));
+                    diagnostics.record(check_array_type(var_type, name, false));
 
                     match value.node {
                         Expr_::EmptyExpression => {},
 
                         _ => {
                             let expr = Expr {
-                                span: Span{lo: name.span.lo, hi: value.span.hi, line: name.span.line},
+                                span: Span{lo: name.span.lo, hi: value.span.hi, line: name.span.line, file: name.span.file},
                                 node: Expr_::Assignment(
                                     Box::new(name.clone()),
                                     Box::new(value.clone())),
                             };
 
-                            get_expr_type(&expr, &self)?;
+                            diagnostics.record(get_expr_type(&expr, &self));
                         }
                     }
                 }
 
-                Stmt_::ShaderDeclaration {body, ..} => {
+                Stmt_::ShaderDeclaration {params, body, ..} |
+                Stmt_::FunctionDeclaration {params, body, ..} => {
+                    match &stmt.statement {
+                        Stmt_::ShaderDeclaration {metadata, ..} => {
+                            for entry in metadata {
+                                diagnostics.record(get_metadata(entry));
+                            }
+                        },
+                        Stmt_::FunctionDeclaration {ret_type, ..} => {
+                            diagnostics.record(self.check_declared_type(ret_type));
+                        },
+                        _ => {},
+                    }
+
+                    for param in params {
+                        if let Expr_::Parameter {par_type, name, value, metadata, ..} = &param.node {
+                            // Only function parameters can take arrays of any length
+                            let allow_unsized = matches!(stmt.statement, Stmt_::FunctionDeclaration {..});
+                            diagnostics.record(self.check_declared_type(par_type));
+                            diagnostics.record(check_array_type(par_type, name, allow_unsized));
+
+                            if !matches!(value.node, Expr_::EmptyExpression) {
+                                let expr = Expr {
+                                    span: Span{lo: name.span.lo, hi: value.span.hi, line: name.span.line, file: name.span.file},
+                                    node: Expr_::Assignment(name.clone(), value.clone()),
+                                };
+                                diagnostics.record(get_expr_type(&expr, &self));
+                            }
+
+                            for entry in metadata {
+                                diagnostics.record(get_metadata(entry));
+                            }
+                        }
+                    }
+
                     match &body.statement {
                         Stmt_::BlockStatement(stmts) => {
-                            self.check_types(&stmts.clone())?;
+                            self.check_types(&stmts.clone(), diagnostics);
                         }
                         _ => {}
                     }
                 }
 
-                Stmt_::IfStatement { condition, .. } |
-                Stmt_::ElseIfStatement { condition, .. } | 
-                Stmt_::WhileStatement {condition, .. } |
-                Stmt_::DoWhileStatement { condition, .. } => {
-                    let condition_type = get_expr_type(&condition, &self)?;
-                    if condition_type != Types::Int && 
-                       condition_type != Types::Float &&
-                       condition_type != Types::String {
-                        return Err(OSLCompilerError::InvalidCondition { expr: Item::new(condition.span, format!("{:?}", condition_type))});    
-                    }
+                Stmt_::BlockStatement(stmts) => {
+                    self.check_types(stmts, diagnostics);
                 }
 
+                Stmt_::IfStatement { condition, body, else_body: Some(else_body) } => {
+                    diagnostics.record(get_condition_type(condition, &self));
+
+                    self.check_types(&vec![*body.clone(), *else_body.clone()], diagnostics);
+                }
+
+                Stmt_::IfStatement { condition, body, .. } |
+                Stmt_::WhileStatement {condition, body } |
+                Stmt_::DoWhileStatement { condition, body } => {
+                    diagnostics.record(get_condition_type(condition, &self));
+
+                    self.check_types(&vec![*body.clone()], diagnostics);
+                }
+
+                Stmt_::ForStatement {initialization, condition, iteration, body} => {
+                    self.check_types(initialization, diagnostics);
+
+                    if !matches!(condition.node, Expr_::EmptyExpression) {
+                        diagnostics.record(get_condition_type(condition, &self));
+                    }
+
+                    for expr in iteration {
+                        diagnostics.record(get_expr_type(expr, &self));
+                    }
+
+                    self.check_types(&vec![*body.clone()], diagnostics);
+                }
 
 
                 _ => {}
             }
         }
-
-        Ok(())
     }
 }
 
@@ -443,9 +849,189 @@ impl std::fmt::Display for SymbolTable {
         for (key, value) in self.symbols.iter() {
             s = format!("{}\t{}\n", s, key);
             for sym in value {
-                s = format!("{}\t\t{:016b}({})\t{}:{:?}:{}\n", s, sym.get_scope(), sym.get_scope(), sym.get_symbol_type(), sym.get_type(), sym.get_span().line);
+                s = format!("{}\t\tscope {}\t{}:{:?}:{}\n", s, sym.get_scope(), sym.get_symbol_type(), sym.get_type(), sym.get_span().line);
             }
         }
         write!(f, "{}", s)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::lexer::Lexer;
+    use crate::compiler::parser::parse;
+    use crate::compiler::{analyze, Backend, CompileOptions};
+
+    fn build(source: &str) -> SymbolTable {
+        let program = parse(Lexer::new(source)).unwrap();
+        let mut symbol_table = SymbolTable::new().unwrap();
+        let mut diagnostics = Diagnostics::new();
+        symbol_table.build_symbols(&program, &mut diagnostics);
+        assert!(!diagnostics.has_errors());
+        symbol_table
+    }
+
+    /// Runs every check on `source`, giving the message of the first error.
+    fn check(source: &str) -> Result<(), String> {
+        analyze(source, &CompileOptions::new(Backend::OSO))
+            .map(|_| ())
+            .map_err(|diagnostics| diagnostics.errors()[0].report().message)
+    }
+
+    /// Every reference as its name and line, with the line of the declaration it resolves to.
+    fn resolve_all(source: &str) -> Vec<(String, usize, Option<usize>)> {
+        let symbol_table = build(source);
+        symbol_table.get_references()
+            .map(|reference| {
+                let declaration = symbol_table.find_reference(reference.span, reference.name.clone());
+                (reference.name.clone(), reference.span.line, declaration.map(|symbol| symbol.get_span().line))
+            })
+            .collect()
+    }
+
+    fn resolved(name: &str, line: usize, declaration: Option<usize>) -> (String, usize, Option<usize>) {
+        (String::from(name), line, declaration)
+    }
+
+    #[test]
+    fn names_resolve_to_the_innermost_declaration_before_them() {
+        let source = "\
+surface s(float a = 1) {
+    float x = a;
+    {
+        float y = x;
+        float x = y;
+        y = x;
+    }
+    x = a;
+}";
+        assert_eq!(resolve_all(source), vec![
+            resolved("a", 1, Some(1)),
+            resolved("x", 2, Some(2)),
+            resolved("a", 2, Some(1)),
+            resolved("y", 4, Some(4)),
+            // The inner x is only declared on the next line
+            resolved("x", 4, Some(2)),
+            resolved("x", 5, Some(5)),
+            resolved("y", 5, Some(4)),
+            resolved("y", 6, Some(4)),
+            resolved("x", 6, Some(5)),
+            resolved("x", 8, Some(2)),
+            resolved("a", 8, Some(1)),
+        ]);
+    }
+
+    #[test]
+    fn loop_variables_are_local_to_the_loop() {
+        let source = "\
+surface s(output float total = 0) {
+    for (int i = 0; i < 3; i++)
+        total += i;
+    total = i;
+}";
+        let resolutions = resolve_all(source);
+        assert!(resolutions.contains(&resolved("i", 2, Some(2))));
+        assert!(resolutions.contains(&resolved("i", 3, Some(2))));
+        assert!(resolutions.contains(&resolved("i", 4, None)));
+    }
+
+    #[test]
+    fn functions_are_visible_before_their_declaration() {
+        let source = "\
+float twice(float x) { return half(x) * 4; }
+float half(float x) { return x / 2; }
+surface s() {}";
+        let resolutions = resolve_all(source);
+        assert!(resolutions.contains(&resolved("half", 1, Some(2))));
+        assert!(resolutions.contains(&resolved("x", 2, Some(2))));
+    }
+
+    #[test]
+    fn inaccessible_names_are_reported() {
+        let source = "\
+surface s(output float a = 0) {
+    {
+        float hidden = 1;
+    }
+    a = hidden;
+    a = missing;
+}";
+        let symbol_table = build(source);
+        let errors: Vec<OSLCompilerError> = symbol_table.get_references()
+            .filter_map(|reference| symbol_table.check_access(reference.span, reference.name.clone()).err())
+            .collect();
+
+        assert!(matches!(errors.as_slice(), [
+            OSLCompilerError::OutOfScopeIdent {..},
+            OSLCompilerError::NonExistentIdent {..},
+        ]));
+    }
+
+    #[test]
+    fn declarations_shadow_variables_of_enclosing_scopes() {
+        let source = "\
+surface s(float a = 1) {
+    float b = a;
+    {
+        float a = 2;
+        float c = b;
+    }
+}";
+        let symbol_table = build(source);
+        let shadowed: Vec<(String, Option<usize>)> = symbol_table.get_references()
+            .filter(|reference| matches!((reference.name.as_str(), reference.span.line), ("a", 4) | ("c", 5)))
+            .map(|reference| (reference.name.clone(), symbol_table.find_shadowed(reference.span, reference.name.clone()).map(|symbol| symbol.get_span().line)))
+            .collect();
+
+        assert_eq!(shadowed, vec![
+            (String::from("a"), Some(1)),
+            (String::from("c"), None),
+        ]);
+    }
+
+    #[test]
+    fn parameter_defaults_are_checked_against_their_type() {
+        assert_eq!(check("surface s(float x = 1, color c = 0.5, string name = \"a\", float a[2] = {1, 2}) {}"), Ok(()));
+        assert_eq!(check("surface s(float x = \"str\") {}"),
+                   Err(String::from("The type String cannot be implicitly cast to type Float.")));
+        assert_eq!(check("surface s(float a[2] = {1, \"str\"}) {}"),
+                   Err(String::from("The type String cannot be implicitly cast to type Float.")));
+    }
+
+    #[test]
+    fn struct_declarations_are_checked() {
+        assert_eq!(check("struct Pair { float a; color b[2]; };\nsurface s() { Pair p; }"), Ok(()));
+        assert_eq!(check("struct Pair { float a = 1; };\nsurface s() {}"), Err(String::from("Struct fields cannot have an initial value")));
+        assert_eq!(check("struct Pair { float a; float a; };\nsurface s() {}"), Err(String::from("The field a is already declared")));
+        assert_eq!(check("surface s() { Missing m; }"), Err(String::from("There is no struct named Missing")));
+    }
+
+    #[test]
+    fn break_and_continue_must_be_in_loops() {
+        assert_eq!(check("surface s(output float o = 0) { while (o < 10) { o += 1; if (o > 5) break; else continue; } }"), Ok(()));
+        assert_eq!(check("surface s() { break; }"), Err(String::from("A break statement must be inside a loop")));
+        assert_eq!(check("surface s(float f = 0) { if (f > 0) continue; }"), Err(String::from("A continue statement must be inside a loop")));
+    }
+
+    #[test]
+    fn returns_match_the_enclosing_function() {
+        let returns = "float f(float x) { if (x > 0) return 1; else return 2; }\nvoid g() { return; }\n";
+        assert_eq!(check(&format!("{}surface s(output float o = 0) {{ g(); o = f(1); return; }}", returns)), Ok(()));
+        assert_eq!(check("float f(float x) { if (x > 0) return 1; }\nsurface s() {}"),
+                   Err(String::from("The function f does not return a value on every path")));
+        assert_eq!(check("float f() { return \"a\"; }\nsurface s() {}"),
+                   Err(String::from("Expected a return value of type Float, received String")));
+        assert_eq!(check("void g() { return 1; }\nsurface s() {}"), Err(String::from("A void function cannot return a value")));
+        assert_eq!(check("surface s() { return 1; }"), Err(String::from("Shaders cannot return a value, use a bare return to exit")));
+    }
+
+    #[test]
+    fn for_loops_take_declarations_and_optional_clauses() {
+        let loops = "surface s(output int o = 0) { for (;;) break; int k; for (k = 0; k < 2; ++k) o += k; for (int i = 0, j = 5; i < j; i++, j--) o += i; }";
+        assert_eq!(check(loops), Ok(()));
+        assert_eq!(check("surface s(output int o = 0) { for (int i = 0; i < 2; i++) o += i; o = i; }"),
+                   Err(String::from("Reference to an out of scope symbol")));
+        assert_eq!(check("surface s() { for (int i = 0; i < 2) {} }"), Err(String::from("Error parsing OSL file")));
+    }
+}
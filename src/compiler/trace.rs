@@ -0,0 +1,67 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// Stages of the pipeline whose intermediate results can be dumped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stage {
+    /// The source after the preprocessor ran.
+    Preprocessed,
+    Tokens,
+    Ast,
+    Symbols,
+    /// The backend output in text form: LLVM IR, SPIR-V disassembly or OSO.
+    IR,
+}
+
+/// Receives dumps of the stages it is enabled for. The default tracer is silent.
+#[derive(Clone, Default)]
+pub struct Tracer {
+    stages: Vec<Stage>,
+    hook: Option<Arc<dyn Fn(Stage, &str) + Send + Sync>>,
+}
+
+impl Tracer {
+    /// A tracer that passes dumps to `hook`. No stages are enabled until `with_stage` is called.
+    pub fn new(hook: impl Fn(Stage, &str) + Send + Sync + 'static) -> Self {
+        Tracer {
+            stages: Vec::new(),
+            hook: Some(Arc::new(hook)),
+        }
+    }
+
+    /// A tracer that writes dumps to stderr, each under a header naming its stage.
+    pub fn stderr() -> Self {
+        Tracer::new(|stage, dump| eprintln!("==== {:?} ====\n{}", stage, dump))
+    }
+
+    pub fn with_stage(mut self, stage: Stage) -> Self {
+        if !self.stages.contains(&stage) {
+            self.stages.push(stage);
+        }
+        self
+    }
+
+    pub fn with_stages(self, stages: &[Stage]) -> Self {
+        stages.iter().fold(self, |tracer, stage| tracer.with_stage(*stage))
+    }
+
+    pub fn is_enabled(&self, stage: Stage) -> bool {
+        self.hook.is_some() && self.stages.contains(&stage)
+    }
+
+    /// Passes a dump to the hook. The dump is only built when the stage is enabled.
+    pub fn dump(&self, stage: Stage, build: impl FnOnce() -> String) {
+        if let (true, Some(hook)) = (self.stages.contains(&stage), &self.hook) {
+            hook(stage, &build());
+        }
+    }
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("stages", &self.stages)
+            .field("hook", &self.hook.is_some())
+            .finish()
+    }
+}
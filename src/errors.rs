@@ -1,10 +1,11 @@
-use codespan_reporting::diagnostic::{Diagnostic, Label};
+use codespan_reporting::diagnostic::{Diagnostic, Label, LabelStyle, Severity};
+use codespan_reporting::files::SimpleFiles;
 
 
 
 use std::ops::Range;
 
-use crate::compiler::Span;
+use crate::compiler::{SourceFile, Span};
 
 #[derive(Debug, Clone)]
 pub enum OSLCompilerError {
@@ -19,6 +20,8 @@ pub enum OSLCompilerError {
 
     InvalidCondition {expr: Item},
 
+    InvalidAssignment {target: Item},
+
     NonExistentIdent {ident: Item},
 
     OutOfScopeIdent {origin: Item, options: Vec<Item>},
@@ -27,12 +30,33 @@ pub enum OSLCompilerError {
 
     LexerError {message: String, error: Item},
 
+    PreprocessorError {message: String, error: Item},
+
     GlobalScopeVariable {var: Item},
 
     GlobalScopeBlock {block: Item},
 
     ParserError {error: Item},
 
+    InvalidMetadata {message: String, error: Item},
+
+    InvalidArray {message: String, error: Item},
+
+    InvalidStruct {message: String, error: Item},
+
+    InvalidControlFlow {message: String, error: Item},
+
+    /// `candidates` are the signatures of the overloads that were considered.
+    InvalidCall {message: String, error: Item, candidates: Vec<String>},
+
+    CodegenError {message: String, error: Item},
+
+    BackendError (String),
+
+    OsoError {message: String, error: Item},
+
+    ExecutionError (String),
+
     MissingShader,
 
     MultipleShaders,
@@ -41,37 +65,37 @@ pub enum OSLCompilerError {
 }
 
 impl OSLCompilerError {
-    pub fn report(&self) -> Diagnostic<()> {
+    pub fn report(&self) -> Diagnostic<usize> {
         match self {
             OSLCompilerError::MismatchedTypesAssignment {lhs, rhs} => Diagnostic::error()
                 .with_message(format!("The type {} cannot be implicitly cast to type {}.", rhs.content.clone(), lhs.content.clone()))
                 .with_labels(vec![
-                    Label::secondary((), lhs.range.clone())
+                    Label::secondary(lhs.file, lhs.range.clone())
                         .with_message(format!("Type {}", lhs.content.clone())),
-                    Label::primary((), rhs.range.clone())
+                    Label::primary(rhs.file, rhs.range.clone())
                         .with_message(format!("Type {}", rhs.content.clone())),
                 ]),
 
             OSLCompilerError::MismatchedTypesBinary {lhs, rhs} => Diagnostic::error()
                 .with_message("This operation is invalid due to mismatched types.")
                 .with_labels(vec![
-                   Label::secondary((), lhs.range.clone())
+                   Label::secondary(lhs.file, lhs.range.clone())
                        .with_message(format!("Type {}", lhs.content.clone())),
-                   Label::secondary((), rhs.range.clone())
+                   Label::secondary(rhs.file, rhs.range.clone())
                        .with_message(format!("Type {}", rhs.content.clone())),
                 ]),
 
             OSLCompilerError::MismatchedTypesUnary {rhs} => Diagnostic::error()
                 .with_message("This operation is invalid due to an unsupported type.")
                 .with_labels(vec![
-                    Label::secondary((), rhs.range.clone())
+                    Label::secondary(rhs.file, rhs.range.clone())
                         .with_message(format!("Type {}", rhs.content.clone())),
                 ]),
 
             OSLCompilerError::MismatchedTypesArgument {expected, received} => Diagnostic::error()
                 .with_message("A function argument did not have the correct type.")
                 .with_labels(vec![
-                    Label::primary((), received.range.clone())
+                    Label::primary(received.file, received.range.clone())
                         .with_message(format!("Expected type {}, received type {}",
                             expected.content.clone(),
                             received.content.clone())),
@@ -80,22 +104,29 @@ impl OSLCompilerError {
             OSLCompilerError::InvalidCondition {expr} => Diagnostic::error()
                 .with_message("Conditional expressions must evaluate to type Int.")
                 .with_labels(vec![
-                    Label::primary((), expr.range.clone())
+                    Label::primary(expr.file, expr.range.clone())
                         .with_message(format!("Expression of type {}", expr.content)),
                 ]),
 
+            OSLCompilerError::InvalidAssignment {target} => Diagnostic::error()
+                .with_message("Only variables, array elements, components and struct fields can be assigned to.")
+                .with_labels(vec![
+                    Label::primary(target.file, target.range.clone())
+                        .with_message("This value cannot be assigned to"),
+                ]),
+
             OSLCompilerError::NonExistentIdent {ident} => Diagnostic::error()
                 .with_message("Reference to non-existent symbol")
                 .with_labels(vec![
-                    Label::primary((), ident.range.clone())
+                    Label::primary(ident.file, ident.range.clone())
                         .with_message(format!("Symbol {} does not exist", ident.content)),
                 ]),
 
             OSLCompilerError::OutOfScopeIdent{origin, options} => {
-                let mut labels: Vec<Label<_>> = vec![Label::primary((), origin.range.clone())
+                let mut labels: Vec<Label<_>> = vec![Label::primary(origin.file, origin.range.clone())
                                                          .with_message("Referenced here"),];
                 for option in options {
-                    labels.push(Label::secondary((), option.range.clone())
+                    labels.push(Label::secondary(option.file, option.range.clone())
                         .with_message("Declared here"));
                 }
 
@@ -109,7 +140,7 @@ impl OSLCompilerError {
                 //.with_code("E0308")
                 .with_message("Variable cannot be created in the global scope")
                 .with_labels(vec![
-                    Label::primary((), var.range.clone()).with_message(format!(
+                    Label::primary(var.file, var.range.clone()).with_message(format!(
                         "Variable {} is in the global scope", var.content)),
                 ]),
 
@@ -117,20 +148,20 @@ impl OSLCompilerError {
                 //.with_code("E0308")
                 .with_message("Block cannot be created in the global scope")
                 .with_labels(vec![
-                    Label::primary((), block.range.clone()).with_message("Block is in the global scope"),
+                    Label::primary(block.file, block.range.clone()).with_message("Block is in the global scope"),
                 ]),
             
             OSLCompilerError::ExistingVariable{existing, new} => Diagnostic::error()
                 //.with_code("E0384")
                 .with_message("Cannot declare variable twice in same scope")
                 .with_labels(vec![
-                    Label::secondary((), existing.range.clone()).with_message(
+                    Label::secondary(existing.file, existing.range.clone()).with_message(
                         &format!(
                             "Original declaration for {}",
                             existing.content,
                         ),
                     ),
-                    Label::primary((), new.range.clone())
+                    Label::primary(new.file, new.range.clone())
                         .with_message(format!(
                             "New declaration for {}",
                             new.content,
@@ -142,16 +173,81 @@ impl OSLCompilerError {
                 //.with_code("E0308")
                 .with_message(message)
                 .with_labels(vec![
-                    Label::primary((), error.range.clone()),
+                    Label::primary(error.file, error.range.clone()),
+                ]),
+
+            OSLCompilerError::PreprocessorError{message, error} => Diagnostic::error()
+                .with_message(message)
+                .with_labels(vec![
+                    Label::primary(error.file, error.range.clone())
+                        .with_message(error.content.clone())
                 ]),
 
             OSLCompilerError::ParserError {error } => Diagnostic::error()
                 .with_message("Error parsing OSL file")
                 .with_labels(vec![
-                    Label::primary((), error.range.clone())
+                    Label::primary(error.file, error.range.clone())
+                        .with_message(error.content.clone())
+                ]),
+
+            OSLCompilerError::InvalidMetadata{message, error} => Diagnostic::error()
+                .with_message(message)
+                .with_labels(vec![
+                    Label::primary(error.file, error.range.clone())
+                        .with_message(error.content.clone())
+                ]),
+
+            OSLCompilerError::InvalidArray{message, error} => Diagnostic::error()
+                .with_message(message)
+                .with_labels(vec![
+                    Label::primary(error.file, error.range.clone())
+                        .with_message(error.content.clone())
+                ]),
+
+            OSLCompilerError::InvalidStruct{message, error} => Diagnostic::error()
+                .with_message(message)
+                .with_labels(vec![
+                    Label::primary(error.file, error.range.clone())
+                        .with_message(error.content.clone())
+                ]),
+
+            OSLCompilerError::InvalidControlFlow{message, error} => Diagnostic::error()
+                .with_message(message)
+                .with_labels(vec![
+                    Label::primary(error.file, error.range.clone())
+                        .with_message(error.content.clone())
+                ]),
+
+            OSLCompilerError::InvalidCall{message, error, candidates} => Diagnostic::error()
+                .with_message(message)
+                .with_labels(vec![
+                    Label::primary(error.file, error.range.clone())
+                        .with_message(error.content.clone())
+                ])
+                .with_notes(candidates.iter().map(|candidate| format!("candidate: {}", candidate)).collect()),
+
+            OSLCompilerError::CodegenError{message, error} => Diagnostic::error()
+                .with_message(message)
+                .with_labels(vec![
+                    Label::primary(error.file, error.range.clone())
                         .with_message(error.content.clone())
                 ]),
 
+            OSLCompilerError::BackendError(message) => Diagnostic::error()
+                .with_message("Internal error in the code generator")
+                .with_notes(vec![message.clone()]),
+
+            OSLCompilerError::OsoError{message, error} => Diagnostic::error()
+                .with_message(format!("Invalid OSO file: {}", message))
+                .with_labels(vec![
+                    Label::primary(error.file, error.range.clone())
+                        .with_message(error.content.clone())
+                ]),
+
+            OSLCompilerError::ExecutionError(message) => Diagnostic::error()
+                .with_message("Could not execute the shader")
+                .with_notes(vec![message.clone()]),
+
             OSLCompilerError::MissingShader => Diagnostic::error()
                 .with_message("Missing shader function")
                 .with_notes(vec![String::from("At least one shader function is required per OSL file.")]),
@@ -163,17 +259,345 @@ impl OSLCompilerError {
             OSLCompilerError::GenericError(error) => Diagnostic::error()
                 .with_message("This is a temporary generic error...")
                 .with_labels(vec![
-                    Label::primary((), error.range.clone())
+                    Label::primary(error.file, error.range.clone())
                         .with_message(error.content.clone())
                 ]),
         }
     }
 }
 
+/// Problems that do not stop a shader from compiling.
+#[derive(Debug, Clone)]
+pub enum OSLCompilerWarning {
+
+    UnusedVariable {var: Item},
+
+    UnusedParameter {param: Item},
+
+    ShadowedDeclaration {existing: Item, new: Item},
+
+    NarrowingConversion {expr: Item},
+
+    UnwrittenOutput {param: Item},
+
+    UnreachableCode {code: Item, exit: Item},
+}
+
+impl OSLCompilerWarning {
+    /// The stable code of the warning, which is also accepted to silence it.
+    pub fn code(&self) -> &'static str {
+        match self {
+            OSLCompilerWarning::UnusedVariable {..} => "W0001",
+            OSLCompilerWarning::UnusedParameter {..} => "W0002",
+            OSLCompilerWarning::ShadowedDeclaration {..} => "W0003",
+            OSLCompilerWarning::NarrowingConversion {..} => "W0004",
+            OSLCompilerWarning::UnwrittenOutput {..} => "W0005",
+            OSLCompilerWarning::UnreachableCode {..} => "W0006",
+        }
+    }
+
+    /// A readable alternative to the code.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OSLCompilerWarning::UnusedVariable {..} => "unused-variable",
+            OSLCompilerWarning::UnusedParameter {..} => "unused-parameter",
+            OSLCompilerWarning::ShadowedDeclaration {..} => "shadowed-declaration",
+            OSLCompilerWarning::NarrowingConversion {..} => "narrowing-conversion",
+            OSLCompilerWarning::UnwrittenOutput {..} => "unwritten-output",
+            OSLCompilerWarning::UnreachableCode {..} => "unreachable-code",
+        }
+    }
+
+    fn items_mut(&mut self) -> Vec<&mut Item> {
+        match self {
+            OSLCompilerWarning::UnusedVariable {var} => vec![var],
+            OSLCompilerWarning::UnusedParameter {param} |
+            OSLCompilerWarning::UnwrittenOutput {param} => vec![param],
+            OSLCompilerWarning::ShadowedDeclaration {existing, new} => vec![existing, new],
+            OSLCompilerWarning::NarrowingConversion {expr} => vec![expr],
+            OSLCompilerWarning::UnreachableCode {code, exit} => vec![code, exit],
+        }
+    }
+
+    pub fn report(&self) -> Diagnostic<usize> {
+        let report = match self {
+            OSLCompilerWarning::UnusedVariable {var} => Diagnostic::warning()
+                .with_message(format!("Unused variable {}", var.content))
+                .with_labels(vec![
+                    Label::primary(var.file, var.range.clone())
+                        .with_message("Declared here but never read"),
+                ]),
+
+            OSLCompilerWarning::UnusedParameter {param} => Diagnostic::warning()
+                .with_message(format!("Unused parameter {}", param.content))
+                .with_labels(vec![
+                    Label::primary(param.file, param.range.clone())
+                        .with_message("Declared here but never read"),
+                ]),
+
+            OSLCompilerWarning::ShadowedDeclaration {existing, new} => Diagnostic::warning()
+                .with_message(format!("Declaration of {} shadows an earlier declaration", new.content))
+                .with_labels(vec![
+                    Label::secondary(existing.file, existing.range.clone())
+                        .with_message(format!("Original declaration for {}", existing.content)),
+                    Label::primary(new.file, new.range.clone())
+                        .with_message(format!("New declaration for {}", new.content)),
+                ]),
+
+            OSLCompilerWarning::NarrowingConversion {expr} => Diagnostic::warning()
+                .with_message("Implicit conversion from Float to Int")
+                .with_labels(vec![
+                    Label::primary(expr.file, expr.range.clone())
+                        .with_message("The fractional part of this value is dropped"),
+                ]),
+
+            OSLCompilerWarning::UnwrittenOutput {param} => Diagnostic::warning()
+                .with_message(format!("Output parameter {} is never written", param.content))
+                .with_labels(vec![
+                    Label::primary(param.file, param.range.clone())
+                        .with_message("Declared here"),
+                ]),
+
+            OSLCompilerWarning::UnreachableCode {code, exit} => Diagnostic::warning()
+                .with_message("Unreachable code")
+                .with_labels(vec![
+                    Label::secondary(exit.file, exit.range.clone())
+                        .with_message(format!("Any code following this {} is never run", exit.content)),
+                    Label::primary(code.file, code.range.clone())
+                        .with_message("Unreachable statement"),
+                ]),
+        };
+
+        report.with_code(self.code())
+    }
+}
+
+/// Collects the errors and warnings of a compile so that as many as possible can be reported
+/// at once. A parse error is always reported on its own.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    errors: Vec<OSLCompilerError>,
+    warnings: Vec<OSLCompilerWarning>,
+
+    silence_warnings: bool,
+    allowed_warnings: Vec<String>,
+    warnings_as_errors: bool,
+
+    // The files that the reported items point into
+    sources: Vec<SourceFile>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Diagnostics::default()
+    }
+
+    /// Drops every warning.
+    pub fn silence_warnings(mut self, silence_warnings: bool) -> Self {
+        self.silence_warnings = silence_warnings;
+        self
+    }
+
+    /// Drops the warnings with the given code or name.
+    pub fn allow_warning(mut self, warning: impl Into<String>) -> Self {
+        self.allowed_warnings.push(warning.into());
+        self
+    }
+
+    /// Makes any warning fail the compile.
+    pub fn warnings_as_errors(mut self, warnings_as_errors: bool) -> Self {
+        self.warnings_as_errors = warnings_as_errors;
+        self
+    }
+
+    /// Sets the files the reported items point into, as numbered by the preprocessor.
+    pub fn with_sources(mut self, sources: Vec<SourceFile>) -> Self {
+        self.sources = sources;
+        self
+    }
+
+    /// Records an error. Checks that run over the same code can report the same error twice,
+    /// so one with the location and message of an earlier error is dropped.
+    pub fn push(&mut self, error: OSLCompilerError) {
+        let key = error.identity();
+        if key.is_some() && self.errors.iter().any(|existing| existing.identity() == key) {
+            return;
+        }
+
+        self.errors.push(error);
+    }
+
+    /// Records the error of a failed result, if any.
+    pub fn record<T>(&mut self, result: Result<T, OSLCompilerError>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(error) => {
+                self.push(error);
+                None
+            },
+        }
+    }
+
+    /// Records a warning, unless it has been silenced.
+    pub fn warn(&mut self, warning: OSLCompilerWarning) {
+        if self.silence_warnings || self.allowed_warnings.iter().any(|allowed| allowed == warning.code() || allowed == warning.name()) {
+            return;
+        }
+
+        self.warnings.push(warning);
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty() || (self.warnings_as_errors && !self.warnings.is_empty())
+    }
+
+    pub fn errors(&self) -> &[OSLCompilerError] {
+        &self.errors
+    }
+
+    pub fn warnings(&self) -> &[OSLCompilerWarning] {
+        &self.warnings
+    }
+
+    pub fn take_warnings(&mut self) -> Vec<OSLCompilerWarning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Errors out with everything collected so far, if anything went wrong.
+    pub fn check(&mut self) -> Result<(), Diagnostics> {
+        match self.has_errors() {
+            true => Err(std::mem::take(self)),
+            false => Ok(()),
+        }
+    }
+
+    /// Reports for the errors followed by the warnings. Warnings are reported as errors when
+    /// they fail the compile.
+    pub fn reports(&self) -> Vec<Diagnostic<usize>> {
+        let mut reports: Vec<Diagnostic<usize>> = self.errors.iter().map(|error| error.report()).collect();
+
+        for warning in &self.warnings {
+            let mut report = warning.report();
+            if self.warnings_as_errors {
+                report.severity = Severity::Error;
+            }
+            reports.push(report);
+        }
+
+        reports.into_iter().map(|report| include_notes(report, &self.sources)).collect()
+    }
+
+    /// The files to emit the reports with.
+    pub fn files(&self) -> SimpleFiles<String, String> {
+        source_files(&self.sources)
+    }
+
+    /// Moves every reported range, e.g. from the preprocessed source back to the files it came
+    /// from.
+    pub fn map_locations(mut self, map: &dyn Fn(Range<usize>) -> (usize, Range<usize>)) -> Self {
+        for error in &mut self.errors {
+            for item in error.items_mut() {
+                item.relocate(map);
+            }
+        }
+
+        for warning in &mut self.warnings {
+            for item in warning.items_mut() {
+                item.relocate(map);
+            }
+        }
+
+        self
+    }
+}
+
+impl From<OSLCompilerError> for Diagnostics {
+    fn from(error: OSLCompilerError) -> Self {
+        Diagnostics {
+            errors: vec![error],
+            ..Default::default()
+        }
+    }
+}
+
+impl OSLCompilerError {
+    fn items_mut(&mut self) -> Vec<&mut Item> {
+        match self {
+            OSLCompilerError::MismatchedTypesAssignment {lhs, rhs} |
+            OSLCompilerError::MismatchedTypesBinary {lhs, rhs} => vec![lhs, rhs],
+            OSLCompilerError::MismatchedTypesUnary {rhs} => vec![rhs],
+            OSLCompilerError::MismatchedTypesArgument {expected, received} => vec![expected, received],
+            OSLCompilerError::InvalidCondition {expr} => vec![expr],
+            OSLCompilerError::InvalidAssignment {target} => vec![target],
+            OSLCompilerError::NonExistentIdent {ident} => vec![ident],
+            OSLCompilerError::OutOfScopeIdent {origin, options} => {
+                std::iter::once(origin).chain(options.iter_mut()).collect()
+            },
+            OSLCompilerError::ExistingVariable {existing, new} => vec![existing, new],
+            OSLCompilerError::LexerError {error, ..} |
+            OSLCompilerError::PreprocessorError {error, ..} |
+            OSLCompilerError::ParserError {error} |
+            OSLCompilerError::InvalidMetadata {error, ..} |
+            OSLCompilerError::InvalidArray {error, ..} |
+            OSLCompilerError::InvalidStruct {error, ..} |
+            OSLCompilerError::InvalidControlFlow {error, ..} |
+            OSLCompilerError::InvalidCall {error, ..} |
+            OSLCompilerError::CodegenError {error, ..} |
+            OSLCompilerError::OsoError {error, ..} |
+            OSLCompilerError::GenericError(error) => vec![error],
+            OSLCompilerError::GlobalScopeVariable {var} => vec![var],
+            OSLCompilerError::GlobalScopeBlock {block} => vec![block],
+            OSLCompilerError::BackendError(_) |
+            OSLCompilerError::ExecutionError(_) |
+            OSLCompilerError::MissingShader |
+            OSLCompilerError::MultipleShaders => vec![],
+        }
+    }
+
+    /// The file and source range the error is reported at, with its message.
+    fn identity(&self) -> Option<(usize, Range<usize>, String)> {
+        let report = self.report();
+        primary_label(&report).map(|label| (label.file_id, label.range.clone(), report.message.clone()))
+    }
+}
+
+fn primary_label(report: &Diagnostic<usize>) -> Option<&Label<usize>> {
+    report.labels.iter()
+        .find(|label| label.style == LabelStyle::Primary)
+        .or(report.labels.first())
+}
+
+/// Builds the file database for reports from the files that took part in a compile.
+pub fn source_files(sources: &[SourceFile]) -> SimpleFiles<String, String> {
+    let mut files = SimpleFiles::new();
+    for source in sources {
+        files.add(source.name.clone(), source.contents.clone());
+    }
+
+    files
+}
+
+/// Adds the chain of `#include` directives that led to the file of a report.
+pub fn include_notes(report: Diagnostic<usize>, sources: &[SourceFile]) -> Diagnostic<usize> {
+    let mut file = match primary_label(&report) {
+        Some(label) => label.file_id,
+        None => return report,
+    };
+
+    let mut notes = Vec::new();
+    while let Some((parent, directive)) = sources.get(file).and_then(|source| source.included_from.as_ref()) {
+        let parent_file = &sources[*parent];
+        notes.push(format!("Included from {}:{}", parent_file.name, parent_file.line(directive.start)));
+        file = *parent;
+    }
+
+    report.with_notes(notes)
+}
+
 /// An item in the source code to be used in the `Error` enum.
-/// In a more complex program it could also contain a `files::FileId` to handle errors that occur inside multiple files.
 #[derive(Debug, Clone)]
 pub struct Item {
+    file: usize,
     range: Range<usize>,
     content: String,
 }
@@ -182,6 +606,12 @@ impl Item {
     pub fn new(span: Span, content: impl Into<String>) -> Item {
         let range = span.lo..span.hi;
         let content = content.into();
-        Item { range, content }
+        Item { file: span.file, range, content }
+    }
+
+    fn relocate(&mut self, map: &dyn Fn(Range<usize>) -> (usize, Range<usize>)) {
+        let (file, range) = map(self.range.clone());
+        self.file = file;
+        self.range = range;
     }
 }
@@ -3,5 +3,6 @@
 
 pub mod compiler;
 pub mod errors;
+pub mod oso;
 pub mod stdosl;
 pub mod cli;
@@ -1,35 +1,56 @@
 use std::fs;
 
-use codespan_reporting::files::SimpleFile;
 use codespan_reporting::term::termcolor::{StandardStream, ColorChoice};
 use codespan_reporting::term;
 
 use clap::Parser;
 
-use osl::compiler::{compile, Backend};
+use osl::compiler::{Backend, CompileOptions, Compiler};
 use osl::cli::*;
 
 fn main() -> Result<(), String> {
 
     let args = CliArgs::parse();
 
-    let contents = fs::read_to_string(args.input_file).expect("Invalid file");
-    println!("{}", contents.len());
-
-    let file = SimpleFile::new("test.osl", contents.clone());
-
-    match compile(contents.clone(), Backend::LLVM) {
-        Err(e) => {
-            let writer = StandardStream::stderr(ColorChoice::Always);
-            let config = codespan_reporting::term::Config{
-                start_context_lines: 3,
-                end_context_lines: 3,
-                ..Default::default()
-            };
-            term::emit(&mut writer.lock(), &config, &file, &e.report())
-                .map_err(|e| e.to_string())?;
-        }
-        _ => {}
+    let contents = fs::read_to_string(&args.input_file).expect("Invalid file");
+
+    let mut options = CompileOptions::new(Backend::LLVM)
+        .file_name(args.input_file.clone())
+        .silence_warnings(args.no_warnings)
+        .warnings_as_errors(args.warnings_as_errors);
+    for warning in &args.allow {
+        options = options.allow_warning(warning.clone());
+    }
+    for path in &args.include_paths {
+        options = options.include_path(path);
+    }
+    for define in &args.defines {
+        options = match define.split_once('=') {
+            Some((name, value)) => options.define(name, Some(value)),
+            None => options.define(define, None),
+        };
+    }
+    let compiler = Compiler::new(options);
+
+    let (files, reports, failed) = match compiler.compile(&contents) {
+        Err(e) => (e.files(), e.reports(), true),
+        Ok(shader) => (shader.files(), shader.reports(), false),
+    };
+
+    let writer = StandardStream::stderr(ColorChoice::Always);
+    let config = codespan_reporting::term::Config{
+        start_context_lines: 3,
+        end_context_lines: 3,
+        ..Default::default()
+    };
+    for report in reports {
+        term::emit(&mut writer.lock(), &config, &files, &report)
+            .map_err(|e| e.to_string())?;
+    }
+
+    // Errors, including warnings under --Werror, fail the process once they are reported
+    if failed {
+        std::process::exit(1);
     }
 
     Ok(())
@@ -0,0 +1,599 @@
+//! Reader for OpenShadingLanguage 1.00 (.oso) files, as written by oslc or by the OSO backend.
+
+use crate::compiler::{Span, Types, ShaderTypes};
+use crate::errors::*;
+
+/// A parsed .oso file.
+#[derive(Debug, Clone)]
+pub struct OsoFile {
+    pub version: (u32, u32),
+    pub shader_type: ShaderTypes,
+    pub name: String,
+    pub metadata: Vec<Metadata>,
+    pub symbols: Vec<Symbol>,
+    pub sections: Vec<CodeSection>,
+    /// Ops of all code sections. Jump targets index into this list.
+    pub ops: Vec<Op>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Param,
+    OutputParam,
+    Global,
+    Local,
+    Temp,
+    Const,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeSpec {
+    /// `Types::Void` for structs.
+    pub base: Types,
+    pub struct_name: Option<String>,
+    /// `Some(-1)` for arrays of unspecified length.
+    pub array_length: Option<i32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i32),
+    Float(f32),
+    String(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub type_spec: TypeSpec,
+    pub name: String,
+    pub values: Vec<Value>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub kind: SymbolKind,
+    pub type_spec: TypeSpec,
+    pub name: String,
+    /// Default values of params and the value of consts, one entry per component.
+    pub values: Vec<Value>,
+    /// First and last op reading the symbol, if any.
+    pub read: Option<(i32, i32)>,
+    /// First and last op writing the symbol, if any.
+    pub write: Option<(i32, i32)>,
+    pub initexpr: bool,
+    pub derivs: bool,
+    pub metadata: Vec<Metadata>,
+    /// Hints this reader doesn't interpret, e.g. `%structfields{...}`.
+    pub other_hints: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CodeSection {
+    /// `___main___` or the name of the parameter it initializes.
+    pub name: String,
+    /// Range of indices into `OsoFile::ops`.
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Op {
+    pub opname: String,
+    pub args: Vec<String>,
+    pub jumps: Vec<i32>,
+    /// One of `r`, `w`, `W` (read and written) or `-` per argument.
+    pub argrw: Option<String>,
+    /// Indices of the arguments that need derivatives.
+    pub argderivs: Vec<usize>,
+    /// Source location, carried forward from earlier ops like oslc expects.
+    pub filename: Option<String>,
+    pub line: Option<u32>,
+    pub other_hints: Vec<String>,
+}
+
+impl OsoFile {
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    pub fn params(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+            .filter(|symbol| matches!(symbol.kind, SymbolKind::Param | SymbolKind::OutputParam))
+    }
+
+    /// The ops of the code section with the given name.
+    pub fn section(&self, name: &str) -> Option<&[Op]> {
+        self.sections.iter()
+            .find(|section| section.name == name)
+            .map(|section| &self.ops[section.start..section.end])
+    }
+}
+
+/// Parses the text of an .oso file. Error spans point into `contents`.
+pub fn parse(contents: &str) -> Result<OsoFile, OSLCompilerError> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    for (number, line) in contents.split('\n').enumerate() {
+        lines.push((offset, number + 1, line.trim_end_matches('\r')));
+        offset += line.len() + 1;
+    }
+
+    // Comments and blank lines carry no information
+    let mut lines = lines.into_iter()
+        .filter(|(_, _, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'));
+
+    let header = match lines.next() {
+        Some(line) => line,
+        None => return Err(oso_error(Span {lo: 0, hi: 0, line: 1, file: 0}, "The file is empty", "")),
+    };
+    let version = parse_header(header)?;
+
+    let shader_line = match lines.next() {
+        Some(line) => line,
+        None => return Err(oso_error(Span {lo: contents.len(), hi: contents.len(), line: 0, file: 0}, "Missing shader declaration", "")),
+    };
+    let (shader_type, name, metadata) = parse_shader_line(shader_line)?;
+
+    let mut oso = OsoFile {
+        version,
+        shader_type,
+        name,
+        metadata,
+        symbols: Vec::new(),
+        sections: Vec::new(),
+        ops: Vec::new(),
+    };
+
+    let mut filename = None;
+    let mut line = None;
+    for (offset, number, text) in lines {
+        let tokens = tokenize(text, offset, number)?;
+
+        if tokens[0].text == "code" {
+            if tokens.len() != 2 {
+                return Err(oso_error(tokens[0].span, "Expected a code section name", "After this"));
+            }
+            if let Some(section) = oso.sections.last_mut() {
+                section.end = oso.ops.len();
+            }
+            oso.sections.push(CodeSection {
+                name: tokens[1].text.to_owned(),
+                start: oso.ops.len(),
+                end: oso.ops.len(),
+            });
+        }
+
+        else if text.starts_with(|c: char| c.is_whitespace()) {
+            if oso.sections.is_empty() {
+                return Err(oso_error(tokens[0].span, "Op outside of a code section", "Expected a `code` line before this"));
+            }
+            let op = parse_op(&tokens, &oso, &mut filename, &mut line)?;
+            oso.ops.push(op);
+        }
+
+        else {
+            if !oso.sections.is_empty() {
+                return Err(oso_error(tokens[0].span, "Symbol declared after the code", ""));
+            }
+            let symbol = parse_symbol(&tokens)?;
+            oso.symbols.push(symbol);
+        }
+    }
+
+    if let Some(section) = oso.sections.last_mut() {
+        section.end = oso.ops.len();
+    }
+
+    Ok(oso)
+}
+
+//===============
+// Lines
+//===============
+
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    span: Span,
+}
+
+fn oso_error(span: Span, message: impl Into<String>, content: impl Into<String>) -> OSLCompilerError {
+    OSLCompilerError::OsoError {
+        message: message.into(),
+        error: Item::new(span, content),
+    }
+}
+
+/// Splits a line on whitespace, keeping quoted strings and `%hint{...}` groups whole.
+fn tokenize(text: &str, offset: usize, line: usize) -> Result<Vec<Token<'_>>, OSLCompilerError> {
+    let mut tokens = Vec::new();
+    let bytes = text.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i].is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        let mut depth = 0;
+        while i < bytes.len() && (depth > 0 || !bytes[i].is_ascii_whitespace()) {
+            match bytes[i] {
+                b'"' => {
+                    i += 1;
+                    while i < bytes.len() && bytes[i] != b'"' {
+                        if bytes[i] == b'\\' {
+                            i += 1;
+                        }
+                        i += 1;
+                    }
+                    if i >= bytes.len() {
+                        let span = Span {lo: offset + start, hi: offset + text.len(), line, file: 0};
+                        return Err(oso_error(span, "Unterminated string", ""));
+                    }
+                },
+                b'{' => depth += 1,
+                b'}' => depth -= 1,
+                _ => {},
+            }
+            i += 1;
+        }
+
+        if depth > 0 {
+            let span = Span {lo: offset + start, hi: offset + text.len(), line, file: 0};
+            return Err(oso_error(span, "Unterminated hint", "Missing `}`"));
+        }
+
+        tokens.push(Token {
+            text: &text[start..i],
+            span: Span {lo: offset + start, hi: offset + i, line, file: 0},
+        });
+    }
+
+    Ok(tokens)
+}
+
+fn parse_header((offset, number, text): (usize, usize, &str)) -> Result<(u32, u32), OSLCompilerError> {
+    let tokens = tokenize(text, offset, number)?;
+
+    if tokens.len() != 2 || tokens[0].text != "OpenShadingLanguage" {
+        return Err(oso_error(tokens[0].span, "Expected an `OpenShadingLanguage <version>` header", ""));
+    }
+
+    let version = tokens[1].text.split_once('.')
+        .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)));
+
+    match version {
+        Some(version) => Ok(version),
+        None => Err(oso_error(tokens[1].span, "Invalid version", "Expected `<major>.<minor>`")),
+    }
+}
+
+fn parse_shader_line((offset, number, text): (usize, usize, &str)) -> Result<(ShaderTypes, String, Vec<Metadata>), OSLCompilerError> {
+    let tokens = tokenize(text, offset, number)?;
+
+    let shader_type = match tokens[0].text {
+        "surface" => ShaderTypes::Surface,
+        "displacement" => ShaderTypes::Displacement,
+        "volume" => ShaderTypes::Volume,
+        "light" => ShaderTypes::Light,
+        "shader" | "generic" => ShaderTypes::Shader,
+        _ => return Err(oso_error(tokens[0].span, "Unknown shader type", "Expected surface, displacement, volume, light or shader")),
+    };
+
+    let name = match tokens.get(1) {
+        Some(token) if !token.text.starts_with('%') => token.text.to_owned(),
+        _ => return Err(oso_error(tokens[0].span, "Missing shader name", "After this")),
+    };
+
+    let mut metadata = Vec::new();
+    for token in &tokens[2..] {
+        match hint(token) {
+            ("meta", Some(value)) => metadata.push(parse_metadata(token, value)?),
+            _ => return Err(oso_error(token.span, "Unexpected hint on the shader declaration", "")),
+        }
+    }
+
+    Ok((shader_type, name, metadata))
+}
+
+fn parse_symbol(tokens: &[Token]) -> Result<Symbol, OSLCompilerError> {
+    let kind = match tokens[0].text {
+        "param" => SymbolKind::Param,
+        "oparam" => SymbolKind::OutputParam,
+        "global" => SymbolKind::Global,
+        "local" => SymbolKind::Local,
+        "temp" => SymbolKind::Temp,
+        "const" => SymbolKind::Const,
+        _ => return Err(oso_error(tokens[0].span, "Unknown symbol kind", "Expected param, oparam, global, local, temp or const")),
+    };
+
+    // Closure and struct types take two words
+    let mut index = 1;
+    let type_text = match tokens.get(index).map(|token| token.text) {
+        Some("closure") | Some("struct") if tokens.len() > index + 1 => {
+            index += 2;
+            format!("{} {}", tokens[index - 2].text, tokens[index - 1].text)
+        },
+        Some(text) => {
+            index += 1;
+            text.to_owned()
+        },
+        None => return Err(oso_error(tokens[0].span, "Missing symbol type", "After this")),
+    };
+    let type_spec = match parse_type(&type_text) {
+        Some(type_spec) => type_spec,
+        None => return Err(oso_error(tokens[index - 1].span, "Unknown type", "")),
+    };
+
+    let name = match tokens.get(index) {
+        Some(token) if !token.text.starts_with('%') => token.text.to_owned(),
+        _ => return Err(oso_error(tokens[index - 1].span, "Missing symbol name", "After this")),
+    };
+    index += 1;
+
+    let mut symbol = Symbol {
+        kind,
+        type_spec,
+        name,
+        values: Vec::new(),
+        read: None,
+        write: None,
+        initexpr: false,
+        derivs: false,
+        metadata: Vec::new(),
+        other_hints: Vec::new(),
+    };
+
+    while index < tokens.len() && !tokens[index].text.starts_with('%') {
+        symbol.values.push(parse_value(&tokens[index], &symbol.type_spec.base)?);
+        index += 1;
+    }
+
+    if !symbol.values.is_empty() {
+        let components = match symbol.type_spec.base {
+            Types::Color | Types::Point | Types::Vector | Types::Normal => 3,
+            Types::Matrix => 16,
+            _ => 1,
+        };
+        if symbol.values.len() % components != 0 {
+            let span = Span {lo: tokens[index - symbol.values.len()].span.lo, hi: tokens[index - 1].span.hi, line: tokens[0].span.line, file: 0};
+            return Err(oso_error(span, "Wrong number of values", format!("Expected a multiple of {}", components)));
+        }
+    }
+
+    for token in &tokens[index..] {
+        match hint(token) {
+            ("read", Some(value)) => symbol.read = parse_range(token, value)?,
+            ("write", Some(value)) => symbol.write = parse_range(token, value)?,
+            ("initexpr", None) => symbol.initexpr = true,
+            ("derivs", None) => symbol.derivs = true,
+            ("meta", Some(value)) => symbol.metadata.push(parse_metadata(token, value)?),
+            ("", _) => return Err(oso_error(token.span, "Unexpected value after the hints", "")),
+            _ => symbol.other_hints.push(token.text.to_owned()),
+        }
+    }
+
+    Ok(symbol)
+}
+
+fn parse_op(tokens: &[Token], oso: &OsoFile, filename: &mut Option<String>, line: &mut Option<u32>) -> Result<Op, OSLCompilerError> {
+    let mut op = Op {
+        opname: tokens[0].text.to_owned(),
+        args: Vec::new(),
+        jumps: Vec::new(),
+        argrw: None,
+        argderivs: Vec::new(),
+        filename: None,
+        line: None,
+        other_hints: Vec::new(),
+    };
+
+    let mut index = 1;
+    while index < tokens.len() && !tokens[index].text.starts_with('%') {
+        let token = &tokens[index];
+        match token.text.parse::<i32>() {
+            Ok(jump) => op.jumps.push(jump),
+            Err(_) if !op.jumps.is_empty() => {
+                return Err(oso_error(token.span, "Arguments must come before jump targets", ""));
+            },
+            Err(_) => {
+                if oso.symbol(token.text).is_none() {
+                    return Err(oso_error(token.span, format!("Unknown symbol `{}`", token.text), "Not declared in the symbol table"));
+                }
+                op.args.push(token.text.to_owned());
+            },
+        }
+        index += 1;
+    }
+
+    for token in &tokens[index..] {
+        match hint(token) {
+            ("filename", Some(value)) => *filename = Some(unquote(token, value)?),
+            ("line", Some(value)) => match value.parse() {
+                Ok(value) => *line = Some(value),
+                Err(_) => return Err(oso_error(token.span, "Invalid line number", "")),
+            },
+            ("argrw", Some(value)) => {
+                let argrw = unquote(token, value)?;
+                if argrw.len() != op.args.len() || !argrw.chars().all(|c| matches!(c, 'r' | 'w' | 'W' | '-')) {
+                    return Err(oso_error(token.span, "Invalid argrw hint", format!("Expected one of r, w, W or - for each of the {} arguments", op.args.len())));
+                }
+                op.argrw = Some(argrw);
+            },
+            ("argderivs", Some(value)) => {
+                for arg in value.split(',') {
+                    match arg.trim().parse() {
+                        Ok(arg) if arg < op.args.len() => op.argderivs.push(arg),
+                        _ => return Err(oso_error(token.span, "Invalid argderivs hint", "Expected argument indices")),
+                    }
+                }
+            },
+            ("", _) => return Err(oso_error(token.span, "Unexpected argument after the hints", "")),
+            _ => op.other_hints.push(token.text.to_owned()),
+        }
+    }
+
+    op.filename = filename.clone();
+    op.line = *line;
+
+    Ok(op)
+}
+
+//===============
+// Values
+//===============
+
+/// Splits `%name{value}` into its name and value. Tokens that aren't hints have an empty name.
+fn hint<'a>(token: &Token<'a>) -> (&'a str, Option<&'a str>) {
+    let text = match token.text.strip_prefix('%') {
+        Some(text) => text,
+        None => return ("", None),
+    };
+
+    match text.split_once('{') {
+        Some((name, value)) => (name, Some(value.strip_suffix('}').unwrap_or(value))),
+        None => (text, None),
+    }
+}
+
+fn parse_type(text: &str) -> Option<TypeSpec> {
+    let (base_text, array_length) = match text.split_once('[') {
+        Some((base, length)) => {
+            let length = length.strip_suffix(']')?;
+            let length = match length {
+                "" => -1,
+                _ => length.parse().ok()?,
+            };
+            (base, Some(length))
+        },
+        None => (text, None),
+    };
+
+    if let Some(struct_name) = base_text.strip_prefix("struct ") {
+        return Some(TypeSpec {
+            base: Types::Void,
+            struct_name: Some(struct_name.to_owned()),
+            array_length,
+        });
+    }
+
+    let base = match base_text {
+        "int" => Types::Int,
+        "float" => Types::Float,
+        "string" => Types::String,
+        "color" => Types::Color,
+        "point" => Types::Point,
+        "vector" => Types::Vector,
+        "normal" => Types::Normal,
+        "matrix" => Types::Matrix,
+        "void" => Types::Void,
+        "closure color" => Types::Closure(Box::new(Types::Color)),
+        _ => return None,
+    };
+
+    Some(TypeSpec {
+        base,
+        struct_name: None,
+        array_length,
+    })
+}
+
+fn parse_value(token: &Token, base: &Types) -> Result<Value, OSLCompilerError> {
+    let value = match base {
+        Types::Int => token.text.parse().ok().map(Value::Int),
+        Types::String => Some(Value::String(unquote(token, token.text)?)),
+        _ => token.text.parse().ok().map(Value::Float),
+    };
+
+    value.ok_or_else(|| oso_error(token.span, "Invalid value", format!("Expected a value of type {:?}", base)))
+}
+
+/// Parses the `first,last` op range of a %read or %write hint. Unused symbols have an empty range.
+fn parse_range(token: &Token, value: &str) -> Result<Option<(i32, i32)>, OSLCompilerError> {
+    let range = value.split_once(',')
+        .and_then(|(first, last)| Some((first.trim().parse().ok()?, last.trim().parse().ok()?)));
+
+    match range {
+        Some((first, last)) if first <= last => Ok(Some((first, last))),
+        Some(_) => Ok(None),
+        None => Err(oso_error(token.span, "Invalid op range", "Expected `{first,last}`")),
+    }
+}
+
+/// Parses `type,name,value` of a %meta hint. Array values are written as `{a,b,c}`.
+fn parse_metadata(token: &Token, value: &str) -> Result<Metadata, OSLCompilerError> {
+    let error = || oso_error(token.span, "Invalid metadata", "Expected `%meta{type,name,value}`");
+
+    let (type_text, rest) = value.split_once(',').ok_or_else(error)?;
+    let (name, values) = rest.split_once(',').ok_or_else(error)?;
+    let type_spec = parse_type(type_text.trim()).ok_or_else(error)?;
+
+    let values = values.trim();
+    let values = values.strip_prefix('{')
+        .and_then(|values| values.strip_suffix('}'))
+        .unwrap_or(values);
+
+    let mut parsed = Vec::new();
+    for value in split_values(values) {
+        let value_token = Token {text: value, span: token.span};
+        parsed.push(parse_value(&value_token, &type_spec.base)?);
+    }
+
+    Ok(Metadata {
+        type_spec,
+        name: name.trim().to_owned(),
+        values: parsed,
+    })
+}
+
+/// Splits a comma separated list, ignoring commas inside strings.
+fn split_values(text: &str) -> Vec<&str> {
+    let mut values = Vec::new();
+    let mut start = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ',' if !in_string => {
+                values.push(text[start..i].trim());
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    values.push(text[start..].trim());
+
+    values
+}
+
+/// Removes the quotes around a string value and resolves its escapes.
+fn unquote(token: &Token, text: &str) -> Result<String, OSLCompilerError> {
+    let inner = match text.strip_prefix('"').and_then(|text| text.strip_suffix('"')) {
+        Some(inner) => inner,
+        None => return Err(oso_error(token.span, "Expected a quoted string", "")),
+    };
+
+    let mut value = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => value.push('\n'),
+            Some('t') => value.push('\t'),
+            Some('r') => value.push('\r'),
+            Some(c) => value.push(c),
+            None => return Err(oso_error(token.span, "Invalid escape at the end of a string", "")),
+        }
+    }
+
+    Ok(value)
+}
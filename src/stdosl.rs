@@ -7,11 +7,55 @@ use crate::errors::OSLCompilerError;
 
 
 
+/// Builtins only take inputs.
+fn add_builtin(symbol_table: &mut SymbolTable, ret_type: Types, name: &str, arg_types: &[Types]) -> Result<(), OSLCompilerError> {
+    let params = arg_types.iter().map(|arg_type| (arg_type.clone(), false)).collect();
+    symbol_table.add_function(ret_type, String::from(name), params, Span {lo: 0, hi: 0, line: 0, file: 0}, true)
+}
+
 pub fn populate_stdosl_symbols(symbol_table: &mut SymbolTable) -> Result<(), OSLCompilerError>{
-    let default_span = Span {lo: 0, hi: 0, line: 0};
+    add_builtin(symbol_table, Types::Color, "color", &[])?;
+
+    // Math functions, overloaded for floats and each triple type
+    for t in [Types::Float, Types::Color, Types::Point, Types::Vector, Types::Normal] {
+        for name in ["radians", "degrees", "sin", "cos", "tan", "asin", "acos", "atan", "sinh", "cosh", "tanh",
+                     "exp", "exp2", "log", "log2", "sqrt", "inversesqrt", "abs", "fabs", "sign", "floor", "ceil",
+                     "round", "trunc"] {
+            add_builtin(symbol_table, t.clone(), name, &[t.clone()])?;
+        }
+        for name in ["atan2", "pow", "mod", "fmod", "min", "max", "step"] {
+            add_builtin(symbol_table, t.clone(), name, &[t.clone(), t.clone()])?;
+        }
+        for name in ["clamp", "mix", "smoothstep"] {
+            add_builtin(symbol_table, t.clone(), name, &[t.clone(), t.clone(), t.clone()])?;
+        }
+
+        // Triples can also be scaled by a single float
+        if t != Types::Float {
+            add_builtin(symbol_table, t.clone(), "pow", &[t.clone(), Types::Float])?;
+            add_builtin(symbol_table, t.clone(), "mix", &[t.clone(), t.clone(), Types::Float])?;
+        }
+    }
+
+    for name in ["abs", "sign"] {
+        add_builtin(symbol_table, Types::Int, name, &[Types::Int])?;
+    }
+    for name in ["mod", "min", "max"] {
+        add_builtin(symbol_table, Types::Int, name, &[Types::Int, Types::Int])?;
+    }
+    add_builtin(symbol_table, Types::Int, "clamp", &[Types::Int, Types::Int, Types::Int])?;
+
+    // Geometric functions
+    add_builtin(symbol_table, Types::Float, "dot", &[Types::Vector, Types::Vector])?;
+    add_builtin(symbol_table, Types::Vector, "cross", &[Types::Vector, Types::Vector])?;
+    add_builtin(symbol_table, Types::Float, "length", &[Types::Vector])?;
+    add_builtin(symbol_table, Types::Float, "distance", &[Types::Point, Types::Point])?;
+    add_builtin(symbol_table, Types::Vector, "normalize", &[Types::Vector])?;
+    add_builtin(symbol_table, Types::Vector, "reflect", &[Types::Vector, Types::Vector])?;
+    add_builtin(symbol_table, Types::Vector, "faceforward", &[Types::Vector, Types::Vector, Types::Vector])?;
 
-    symbol_table.add_function(Types::Color, String::from("color"), Vec::new(), default_span, true)?;
-    symbol_table.add_function(Types::Float, String::from("mod"), vec![Types::Float, Types::Float], default_span, true)?;
+    // Array functions, which take an array of any type
+    add_builtin(symbol_table, Types::Int, "arraylength", &[])?;
 
     Ok(())
 }
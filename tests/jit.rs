@@ -0,0 +1,292 @@
+use std::fs;
+
+use inkwell::context::Context;
+
+use osl::compiler::{Backend, CompileOptions, OptLevel, Value};
+use osl::compiler::jit::{ShaderExecutable, ShaderGlobals};
+
+
+const RED: Value = Value::Triple([1.0, 0.0, 0.0]);
+const GREEN: Value = Value::Triple([0.0, 1.0, 0.0]);
+
+fn load_checker(context: &Context) -> ShaderExecutable<'_> {
+    let contents = fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/shaders/checker.osl")).unwrap();
+    ShaderExecutable::new(context, contents, &CompileOptions::new(Backend::LLVM)).unwrap()
+}
+
+/// Compiles and runs `source` with the default globals.
+fn run<'ctx>(context: &'ctx Context, source: &str) -> ShaderExecutable<'ctx> {
+    let mut shader = ShaderExecutable::new(context, String::from(source), &CompileOptions::new(Backend::LLVM)).unwrap();
+    shader.run().unwrap();
+    shader
+}
+
+/// The message of the first error raised while compiling `source` for the JIT.
+fn first_error(source: &str) -> String {
+    let context = Context::create();
+    let errors = ShaderExecutable::new(&context, String::from(source), &CompileOptions::new(Backend::LLVM)).err().unwrap();
+    errors.errors()[0].report().message
+}
+
+/// Runs the checker at a point and reads the color it picked.
+fn shade(shader: &mut ShaderExecutable, p: [f32; 3]) -> Value {
+    shader.set_globals(ShaderGlobals {p, ..Default::default()});
+    shader.run().unwrap();
+    shader.get_param("Col").unwrap()
+}
+
+#[test]
+fn checker_alternates_colors() {
+    let context = Context::create();
+    let mut shader = load_checker(&context);
+
+    assert_eq!(shader.outputs(), vec!["Col"]);
+    assert_eq!(shade(&mut shader, [0.5, 0.5, 0.5]), RED);
+    assert_eq!(shade(&mut shader, [1.5, 0.5, 0.5]), GREEN);
+    assert_eq!(shade(&mut shader, [1.5, 1.5, 0.5]), RED);
+    assert_eq!(shade(&mut shader, [1.5, 1.5, 1.5]), GREEN);
+}
+
+#[test]
+fn checker_uses_bound_parameters() {
+    let context = Context::create();
+    let mut shader = load_checker(&context);
+
+    shader.set_param("Scale", Value::Float(2.0)).unwrap();
+    shader.set_param("Color1", Value::Triple([0.0, 0.0, 1.0])).unwrap();
+    assert_eq!(shade(&mut shader, [0.25, 0.25, 0.25]), Value::Triple([0.0, 0.0, 1.0]));
+    assert_eq!(shade(&mut shader, [0.75, 0.25, 0.25]), GREEN);
+
+    shader.clear_param("Color1");
+    assert_eq!(shade(&mut shader, [0.25, 0.25, 0.25]), RED);
+}
+
+#[test]
+fn u_and_v_are_read_from_the_globals() {
+    let context = Context::create();
+    let source = "surface uv(output float a = 0, output float b = 0) { a = u; b = v * 2; }";
+    let mut shader = ShaderExecutable::new(&context, String::from(source), &CompileOptions::new(Backend::LLVM)).unwrap();
+
+    shader.set_globals(ShaderGlobals {u: 0.25, v: 0.75, ..Default::default()});
+    shader.run().unwrap();
+    assert_eq!(shader.get_param("a"), Some(Value::Float(0.25)));
+    assert_eq!(shader.get_param("b"), Some(Value::Float(1.5)));
+}
+
+#[test]
+fn derivatives_are_read_from_the_globals() {
+    let context = Context::create();
+    let source = "surface dp(output vector a = 0) { a = dPdu + dPdv; }";
+    let mut shader = ShaderExecutable::new(&context, String::from(source), &CompileOptions::new(Backend::LLVM)).unwrap();
+
+    shader.set_globals(ShaderGlobals {dpdu: [1.0, 0.0, 0.0], dpdv: [0.0, 2.0, 0.0], ..Default::default()});
+    shader.run().unwrap();
+    assert_eq!(shader.get_param("a"), Some(Value::Triple([1.0, 2.0, 0.0])));
+}
+
+#[test]
+fn compile_options_reach_the_jit() {
+    let context = Context::create();
+    let source = "surface defined(output float a = 0) { a = SCALE; }";
+    let options = CompileOptions::new(Backend::SPIRV)
+        .define("SCALE", Some("3"))
+        .opt_level(OptLevel::Aggressive);
+    let mut shader = ShaderExecutable::new(&context, String::from(source), &options).unwrap();
+
+    shader.run().unwrap();
+    assert_eq!(shader.get_param("a"), Some(Value::Float(3.0)));
+
+    assert_eq!(first_error(source), "Reference to non-existent symbol");
+}
+
+#[test]
+fn statements_and_operators_are_lowered() {
+    let source = "\
+float pick(int i) {
+    if (i == 0) return 10;
+    else if (i == 1) return 20;
+    else return 30;
+}
+
+surface lowering(output float branches = 0, output int loops = 0, output int bits = 0, output float math = 0) {
+    branches = pick(0) + pick(1) + pick(7);
+
+    int i = 0;
+    while (i < 4) i++;
+    do { i += 2; } while (i < 9);
+    for (int j = 0; j < 3; j++) { int k = j * 2; i += k; }
+    loops = i;
+
+    bits = ((5 & 3) | 8) ^ 1;
+    bits += (1 << 4) >> 2;
+    bits += 7 % 3;
+    bits += !(bits > 0);
+
+    math = -2.5 * 2 + 10 / 4.0;
+}";
+    let context = Context::create();
+    let shader = run(&context, source);
+
+    assert_eq!(shader.get_param("branches"), Some(Value::Float(60.0)));
+    assert_eq!(shader.get_param("loops"), Some(Value::Int(16)));
+    assert_eq!(shader.get_param("bits"), Some(Value::Int(13)));
+    assert_eq!(shader.get_param("math"), Some(Value::Float(-2.5)));
+}
+
+#[test]
+fn unsupported_types_are_reported() {
+    assert_eq!(first_error("surface s() { closure color c = Ci; Ci = c; }"),
+               "The type Closure(Color) is not supported by the LLVM backend");
+}
+
+#[test]
+fn arrays_are_indexed_at_runtime() {
+    let source = "\
+float sum(float w[]) {
+    float total = 0;
+    for (int i = 0; i < arraylength(w); i++)
+        total += w[i];
+    return total;
+}
+
+surface arrays(float weights[3] = {1, 2, 3}, output float total = 0, output float picked = 0) {
+    int index = 2;
+    color colors[2] = {color(1, 0, 0), color(0, 1, 0)};
+    total = sum(weights);
+    picked = weights[index] + colors[1][1];
+}";
+    let context = Context::create();
+    let mut shader = run(&context, source);
+    assert_eq!(shader.get_param("total"), Some(Value::Float(6.0)));
+    assert_eq!(shader.get_param("picked"), Some(Value::Float(4.0)));
+
+    shader.set_param("weights", Value::Array(vec![Value::Float(4.0), Value::Float(5.0), Value::Float(6.0)])).unwrap();
+    shader.run().unwrap();
+    assert_eq!(shader.get_param("total"), Some(Value::Float(15.0)));
+}
+
+#[test]
+fn structs_are_passed_and_updated() {
+    let source = "\
+struct Pair { float a; color b; };
+struct Outer { Pair p; int n; };
+
+Pair make(float a) { return Pair(a, color(a)); }
+float first(Pair p) { return p.a; }
+
+surface structs(output float o = 0, output color c = 0) {
+    Outer x = Outer(make(1), 2);
+    x.p.a = 3;
+    x.p.b.g += 0.5;
+    o = first(x.p) + x.n;
+    c = x.p.b;
+}";
+    let context = Context::create();
+    let shader = run(&context, source);
+    assert_eq!(shader.get_param("o"), Some(Value::Float(5.0)));
+    assert_eq!(shader.get_param("c"), Some(Value::Triple([1.0, 1.5, 1.0])));
+}
+
+#[test]
+fn ternaries_pick_a_branch() {
+    let source = "\
+surface ternary(float f = 2, output float o = 0, output color c = 0) {
+    float a = 1, b = 5;
+    o = f > 1 ? a : b;
+    c = f > 3 ? color(1) : 0.5;
+}";
+    let context = Context::create();
+    let shader = run(&context, source);
+    assert_eq!(shader.get_param("o"), Some(Value::Float(1.0)));
+    assert_eq!(shader.get_param("c"), Some(Value::Triple([0.5, 0.5, 0.5])));
+}
+
+#[test]
+fn compound_assignments_store_back() {
+    let source = "\
+struct Q { float x; };
+
+surface compound(output color c = 1, output float total = 0, output int bits = 3) {
+    float a[2] = {1, 2};
+    Q q = Q(4);
+    c[1] += 2;
+    c.r -= 1;
+    a[0] *= 3;
+    a[1]++;
+    q.x /= 2;
+    ++q.x;
+    total = a[0] + a[1] + q.x;
+    bits <<= 2;
+    bits |= 1;
+    bits ^= 4;
+}";
+    let context = Context::create();
+    let shader = run(&context, source);
+    assert_eq!(shader.get_param("c"), Some(Value::Triple([0.0, 3.0, 1.0])));
+    assert_eq!(shader.get_param("total"), Some(Value::Float(9.0)));
+    assert_eq!(shader.get_param("bits"), Some(Value::Int(9)));
+}
+
+#[test]
+fn break_continue_and_return_leave_early() {
+    let source = "\
+float clamp_sum(int n) {
+    float total = 0;
+    for (int i = 0; i < n; i++) {
+        if (i == 1) continue;
+        if (total > 5) break;
+        total += i;
+    }
+    return total;
+}
+
+surface flow(output float sum = 0, output int steps = 0) {
+    sum = clamp_sum(10);
+    while (1) {
+        steps++;
+        if (steps == 3) break;
+    }
+    if (sum > 0) return;
+    steps = 100;
+}";
+    let context = Context::create();
+    let shader = run(&context, source);
+    assert_eq!(shader.get_param("sum"), Some(Value::Float(9.0)));
+    assert_eq!(shader.get_param("steps"), Some(Value::Int(3)));
+}
+
+#[test]
+fn for_loops_run_every_clause() {
+    let source = "\
+surface loops(output int count = 0, output int pairs = 0) {
+    for (;;) {
+        count++;
+        if (count == 4) break;
+    }
+    int k;
+    for (k = 0; k < 2; ++k) count += 10;
+    for (int i = 0, j = 5; i < j; i++, j--) pairs += 1;
+}";
+    let context = Context::create();
+    let shader = run(&context, source);
+    assert_eq!(shader.get_param("count"), Some(Value::Int(24)));
+    assert_eq!(shader.get_param("pairs"), Some(Value::Int(3)));
+}
+
+#[test]
+fn user_calls_write_outputs_and_return_values() {
+    let source = "\
+float one() { return 1; }
+void set(output float x, float value) { x = value; }
+float add(float a, float b) { return a + b; }
+
+surface calls(output float o = 0, output color c = 0) {
+    set(o, one());
+    o = add(1, 2) + add(o, 3);
+    c = add(1, 2);
+}";
+    let context = Context::create();
+    let shader = run(&context, source);
+    assert_eq!(shader.get_param("o"), Some(Value::Float(7.0)));
+    assert_eq!(shader.get_param("c"), Some(Value::Triple([3.0, 3.0, 3.0])));
+}
@@ -0,0 +1,175 @@
+use std::fs;
+
+use osl::compiler::{compile, Backend, ShaderOutput, ShaderTypes, Types, Value as ArtifactValue};
+use osl::oso::{self, OsoFile, SymbolKind, TypeSpec, Value};
+
+
+fn build(source: &str) -> OsoFile {
+    match compile(String::from(source), Backend::OSO).unwrap().output {
+        ShaderOutput::OSO(text) => oso::parse(&text).unwrap(),
+        _ => unreachable!(),
+    }
+}
+
+fn read_shader(name: &str) -> String {
+    fs::read_to_string(format!("{}/shaders/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+}
+
+fn type_spec(base: Types) -> TypeSpec {
+    TypeSpec {base, struct_name: None, array_length: None}
+}
+
+#[test]
+fn reads_my_shader() {
+    let file = oso::parse(&read_shader("my_shader.oso")).unwrap();
+
+    assert_eq!(file.version, (1, 0));
+    assert!(matches!(file.shader_type, ShaderTypes::Shader));
+    assert_eq!(file.name, "my_shader");
+    assert_eq!(file.symbols.len(), 12);
+    assert_eq!(file.params().count(), 0);
+
+    let red = file.symbol("red").unwrap();
+    assert_eq!((red.kind, &red.type_spec), (SymbolKind::Local, &type_spec(Types::Color)));
+    assert_eq!((red.read, red.write), (Some((1, 1)), Some((0, 0))));
+
+    // Symbols that are never written carry oslc's empty range
+    let constant = file.symbol("$const7").unwrap();
+    assert_eq!((constant.kind, &constant.type_spec), (SymbolKind::Const, &type_spec(Types::Float)));
+    assert_eq!(constant.values, vec![Value::Float(0.2)]);
+    assert_eq!(constant.write, None);
+
+    let temp = file.symbol("$tmp1").unwrap();
+    assert_eq!((temp.kind, &temp.type_spec), (SymbolKind::Temp, &type_spec(Types::Int)));
+
+    assert_eq!(file.sections.len(), 1);
+    let ops = file.section("___main___").unwrap();
+    assert_eq!(ops.len(), 10);
+
+    assert_eq!(ops[0].opname, "color");
+    assert_eq!(ops[0].args, vec!["red", "$const1", "$const3", "$const5", "$const5"]);
+    assert_eq!((ops[0].filename.as_deref(), ops[0].line), (Some("test.osl"), Some(2)));
+    assert_eq!(ops[0].argrw.as_deref(), Some("wrrrr"));
+
+    let if_op = &ops[5];
+    assert_eq!((if_op.opname.as_str(), &if_op.args), ("if", &vec![String::from("$tmp1")]));
+    assert_eq!(if_op.jumps, vec![8, 9]);
+    // Locations carry forward from earlier ops
+    assert_eq!((if_op.filename.as_deref(), if_op.line), (Some("test.osl"), Some(7)));
+
+    assert_eq!(ops[9].opname, "end");
+}
+
+#[test]
+fn reads_simple_material() {
+    let file = oso::parse(&read_shader("SimpleMaterial.oso")).unwrap();
+
+    assert!(matches!(file.shader_type, ShaderTypes::Shader));
+    assert_eq!(file.name, "SimpleMaterial");
+
+    let params: Vec<(&str, SymbolKind)> = file.params().map(|symbol| (symbol.name.as_str(), symbol.kind)).collect();
+    assert_eq!(params, vec![
+        ("diffuse_color", SymbolKind::Param),
+        ("noise_factor", SymbolKind::Param),
+        ("bsdf", SymbolKind::OutputParam),
+    ]);
+
+    let diffuse_color = file.symbol("diffuse_color").unwrap();
+    assert_eq!(diffuse_color.values, vec![Value::Float(0.6), Value::Float(0.8), Value::Float(0.6)]);
+
+    let bsdf = file.symbol("bsdf").unwrap();
+    assert_eq!(bsdf.type_spec, type_spec(Types::Closure(Box::new(Types::Color))));
+    assert!(bsdf.initexpr);
+
+    let global = file.symbol("N").unwrap();
+    assert_eq!((global.kind, &global.type_spec), (SymbolKind::Global, &type_spec(Types::Normal)));
+
+    let constant = file.symbol("$const1").unwrap();
+    assert_eq!(constant.values, vec![Value::String(String::from("diffuse"))]);
+
+    // The parameter's initializer comes first, jump targets count across sections
+    let names: Vec<&str> = file.sections.iter().map(|section| section.name.as_str()).collect();
+    assert_eq!(names, vec!["bsdf", "___main___"]);
+    assert_eq!(file.section("bsdf").unwrap().len(), 1);
+    assert_eq!((file.sections[1].start, file.sections[1].end), (1, 9));
+
+    let ops = file.section("___main___").unwrap();
+    assert_eq!(ops[3].opname, "mix");
+    assert_eq!(ops[3].args, vec!["$tmp1", "$tmp2", "$tmp3", "noise_factor"]);
+    assert_eq!(ops[5].opname, "closure");
+    assert_eq!((ops[5].filename.as_deref(), ops[5].line), (Some("SimpleMaterial.osl"), Some(7)));
+}
+
+/// Compares against the output of oslc. Symbols may be declared in another order, and the
+/// file names in the hints depend on how the compiler was invoked.
+#[test]
+fn my_shader_matches_oslc() {
+    let expected = oso::parse(&read_shader("my_shader.oso")).unwrap();
+    let built = build(&read_shader("my_shader.osl"));
+
+    assert!(matches!((&built.shader_type, &expected.shader_type), (ShaderTypes::Shader, ShaderTypes::Shader)));
+    assert_eq!(built.name, expected.name);
+
+    assert_eq!(built.symbols.len(), expected.symbols.len());
+    for symbol in &expected.symbols {
+        let other = built.symbol(&symbol.name).unwrap_or_else(|| panic!("missing {}", symbol.name));
+        assert_eq!((&other.kind, &other.type_spec, &other.values), (&symbol.kind, &symbol.type_spec, &symbol.values), "{}", symbol.name);
+        assert_eq!((other.read, other.write), (symbol.read, symbol.write), "{}", symbol.name);
+    }
+
+    let layout = |file: &OsoFile| -> Vec<_> {
+        file.section("___main___").unwrap().iter()
+            .map(|op| (op.opname.clone(), op.args.clone(), op.jumps.clone(), op.argrw.clone(), op.line))
+            .collect()
+    };
+    assert_eq!(layout(&built), layout(&expected));
+}
+
+/// The right hand side of `&&` and `||` sits in the branch of an `if` on the left hand side.
+#[test]
+fn logical_operators_short_circuit() {
+    for (op, opname) in [("&&", "and"), ("||", "or")] {
+        let source = format!("surface s(int a = 1, output int b = 0, output int c = 0) {{ c = a {} b++; }}", op);
+        let file = build(&source);
+        let ops = file.section("___main___").unwrap();
+
+        let if_op = ops.iter().position(|op| op.opname == "if").unwrap();
+        assert_eq!(ops[if_op].args, vec!["a"]);
+        let (else_label, end_label) = (ops[if_op].jumps[0] as usize, ops[if_op].jumps[1] as usize);
+
+        let increment = ops.iter().position(|op| op.opname == "add").unwrap();
+        let logic = ops.iter().position(|op| op.opname == opname).unwrap();
+        let branch = match op {
+            "&&" => if_op + 1..else_label,
+            _ => else_label..end_label,
+        };
+        assert!(branch.contains(&increment) && branch.contains(&logic), "{:?}", ops);
+        assert_eq!(ops[logic].args[0], "c");
+    }
+}
+
+#[test]
+fn metadata_reaches_the_artifact_and_the_hints() {
+    let source = "shader s [[ string label = \"S\" ]] (float f = 1 [[ int max = 2, float range[2] = {0, 1} ]], output float o = 0) { o = f; }";
+    let shader = compile(String::from(source), Backend::OSO).unwrap();
+
+    let metadata: Vec<(&str, &ArtifactValue)> = shader.parameters[0].metadata.iter().map(|entry| (entry.name.as_str(), &entry.value)).collect();
+    assert_eq!(metadata, vec![
+        ("max", &ArtifactValue::Int(2)),
+        ("range", &ArtifactValue::Array(vec![ArtifactValue::Float(0.0), ArtifactValue::Float(1.0)])),
+    ]);
+    assert_eq!(shader.metadata[0].value, ArtifactValue::String(String::from("S")));
+
+    let file = match shader.output {
+        ShaderOutput::OSO(text) => oso::parse(&text).unwrap(),
+        _ => unreachable!(),
+    };
+    assert_eq!(file.metadata[0].name, "label");
+    let hints: Vec<(&str, &TypeSpec, &Vec<Value>)> = file.symbol("f").unwrap().metadata.iter()
+        .map(|entry| (entry.name.as_str(), &entry.type_spec, &entry.values))
+        .collect();
+    assert_eq!(hints, vec![
+        ("max", &type_spec(Types::Int), &vec![Value::Int(2)]),
+        ("range", &TypeSpec {base: Types::Float, struct_name: None, array_length: Some(2)}, &vec![Value::Float(0.0), Value::Float(1.0)]),
+    ]);
+}
@@ -0,0 +1,83 @@
+use std::fs;
+use std::process::Command;
+
+use rspirv::dr::{Block, Function, Module, Operand};
+use spirv::Op;
+
+use osl::compiler::{compile, Backend, ShaderOutput};
+
+
+fn build(source: &str) -> Vec<u32> {
+    match compile(String::from(source), Backend::SPIRV).unwrap().output {
+        ShaderOutput::SPIRV(words) => words,
+        _ => unreachable!(),
+    }
+}
+
+fn build_checker() -> Vec<u32> {
+    build(&fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/shaders/checker.osl")).unwrap())
+}
+
+fn load(words: &[u32]) -> Module {
+    let mut loader = rspirv::dr::Loader::new();
+    rspirv::binary::parse_words(words, &mut loader).unwrap();
+    loader.module()
+}
+
+/// The block a conditional branch jumps to when its condition is true (or false).
+fn branch_target<'a>(function: &'a Function, block: &Block, if_true: bool) -> &'a Block {
+    let branch = block.instructions.last().unwrap();
+    assert_eq!(branch.class.opcode, Op::BranchConditional);
+    let label = branch.operands[if if_true { 1 } else { 2 }].unwrap_id_ref();
+    function.blocks.iter().find(|block| block.label_id() == Some(label)).unwrap()
+}
+
+fn contains(block: &Block, opcode: Op) -> bool {
+    block.instructions.iter().any(|inst| inst.class.opcode == opcode)
+}
+
+#[test]
+fn entry_point_is_a_fragment_shader() {
+    let module = load(&build_checker());
+
+    let entry_point = &module.entry_points[0];
+    assert_eq!(entry_point.operands[0], Operand::ExecutionModel(spirv::ExecutionModel::Fragment));
+    assert_eq!(entry_point.operands[2], Operand::LiteralString(String::from("checker")));
+
+    let execution_mode = &module.execution_modes[0];
+    assert_eq!(execution_mode.operands[1], Operand::ExecutionMode(spirv::ExecutionMode::OriginUpperLeft));
+}
+
+/// The right hand side of `&&` and `||` is only run when the left one does not decide the result.
+#[test]
+fn logical_operators_short_circuit() {
+    for (op, runs_when) in [("&&", true), ("||", false)] {
+        let source = format!("surface s(int i = 0, output int n = 0) {{ if (i > 0 {} n++ > 0) n = 2; }}", op);
+        let module = load(&build(&source));
+        let function = &module.functions[0];
+
+        assert!(!function.all_inst_iter().any(|inst| matches!(inst.class.opcode, Op::LogicalAnd | Op::LogicalOr)));
+        assert!(function.all_inst_iter().any(|inst| inst.class.opcode == Op::Phi));
+
+        // The increment lives in its own block, only reached through the left hand side
+        let entry = &function.blocks[0];
+        assert!(!contains(entry, Op::IAdd));
+        assert!(contains(branch_target(function, entry, runs_when), Op::IAdd));
+        assert!(!contains(branch_target(function, entry, !runs_when), Op::IAdd));
+    }
+}
+
+/// Runs the validator of the SPIRV-Tools against the Vulkan rules. Needs `spirv-val` on the
+/// path, run it with `cargo test -- --ignored`.
+#[test]
+#[ignore]
+fn checker_passes_spirv_val() {
+    let path = std::env::temp_dir().join("osl_checker.spv");
+    let bytes: Vec<u8> = build_checker().iter().flat_map(|word| word.to_le_bytes()).collect();
+    fs::write(&path, bytes).unwrap();
+
+    let output = Command::new("spirv-val").args(["--target-env", "vulkan1.2"]).arg(&path).output()
+        .expect("spirv-val not found");
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}